@@ -0,0 +1,797 @@
+use crate::{
+    database::Database,
+    error::{is_stopped, Result},
+    progress::ProgressCollector,
+    source::Source,
+    synced::{read_synced_sessions, SyncedSession},
+    types::{BackupRun, BackupTiming, PhaseTimings, SourceName, TrailingSlash, VisitDetail},
+    util::{
+        add_elapsed, default_browser_files, expand_history_pattern, full_timerange, now_ms,
+        profile_name, unixepoch_as_ymdhms, TempCopy,
+    },
+};
+use anyhow::{Context, Error};
+use log::{debug, error, info, warn};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Outcome of backup one history file.
+#[derive(Debug)]
+pub enum BackupStatus {
+    Ok {
+        found: usize,
+        imported: usize,
+        duplicated: usize,
+    },
+    /// File is not backed up, with the reason.
+    Skipped(String),
+    /// File failed to backup, with the reason.
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct SourceReport {
+    pub path: PathBuf,
+    /// Display name of its profile, see `profile_name`, empty for synced tabs
+    pub profile: String,
+    /// Browser detected, `None` when file isn't opened
+    pub browser: Option<SourceName>,
+    pub status: BackupStatus,
+}
+
+/// Outcomes of all history files in one backup.
+#[derive(Debug, Default)]
+pub struct BackupSummary {
+    pub reports: Vec<SourceReport>,
+    /// Visits deleted for being older than `BackupOptions::retention_days`
+    pub pruned: usize,
+    /// Time spent in each history file read, only with `BackupOptions::profile_timing`
+    pub timings: Vec<BackupTiming>,
+}
+
+impl BackupSummary {
+    fn sum_ok(&self, f: impl Fn(usize, usize, usize) -> usize) -> usize {
+        self.reports
+            .iter()
+            .map(|r| match r.status {
+                BackupStatus::Ok {
+                    found,
+                    imported,
+                    duplicated,
+                } => f(found, imported, duplicated),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    pub fn found(&self) -> usize {
+        self.sum_ok(|found, _, _| found)
+    }
+
+    pub fn imported(&self) -> usize {
+        self.sum_ok(|_, imported, _| imported)
+    }
+
+    pub fn duplicated(&self) -> usize {
+        self.sum_ok(|_, _, duplicated| duplicated)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.reports
+            .iter()
+            .filter(|r| matches!(r.status, BackupStatus::Failed(_)))
+            .count()
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.reports
+            .iter()
+            .filter(|r| matches!(r.status, BackupStatus::Skipped(_)))
+            .count()
+    }
+
+    /// Audit record of this backup, which runs between `started_at` and `finished_at`.
+    pub fn to_run(&self, started_at: i64, finished_at: i64) -> BackupRun {
+        BackupRun {
+            started_at,
+            finished_at,
+            sources: self.reports.len(),
+            imported: self.imported(),
+            duplicated: self.duplicated(),
+            skipped: self.skipped(),
+            pruned: self.pruned,
+            // Options are not known by summary, set by caller
+            retention_days: None,
+            errors: self
+                .reports
+                .iter()
+                .filter_map(|r| match &r.status {
+                    BackupStatus::Failed(reason) => Some(format!("{}: {reason}", r.path.display())),
+                    _ => None,
+                })
+                .collect(),
+            timings: self.timings.clone(),
+        }
+    }
+
+    /// Table of `timings`, one history file per row, in milliseconds.
+    pub fn timing_table(&self) -> String {
+        let mut table = format!(
+            "{:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>10}  FILE\n",
+            "COPY", "SELECT", "URLS", "VISITS", "COMMIT", "TOTAL", "ROWS/S"
+        );
+        let ms = |d: Duration| format!("{:.1}", d.as_secs_f64() * 1000.0);
+        for BackupTiming { path, phases } in &self.timings {
+            table += &format!(
+                "{:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>10.0}  {path}\n",
+                ms(phases.copy),
+                ms(phases.select),
+                ms(phases.urls),
+                ms(phases.visits),
+                ms(phases.commit),
+                ms(phases.total),
+                phases.rows_per_sec()
+            );
+        }
+        let rows = self.timings.iter().map(|t| t.phases.rows).sum::<usize>();
+        let total = self
+            .timings
+            .iter()
+            .map(|t| t.phases.total)
+            .sum::<Duration>();
+        table += &format!(
+            "Rows: {rows}, Total: {}ms, Rows/s: {:.0}",
+            ms(total),
+            PhaseTimings {
+                rows,
+                total,
+                ..Default::default()
+            }
+            .rows_per_sec()
+        );
+        table
+    }
+}
+
+impl Display for BackupSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<8} {:>10} {:>10} {:>10}  FILE",
+            "STATUS", "FOUND", "IMPORTED", "DUPLICATED"
+        )?;
+        for SourceReport {
+            path,
+            profile,
+            browser,
+            status,
+        } in &self.reports
+        {
+            let path = match (browser, profile.is_empty()) {
+                (None, true) => path.display().to_string(),
+                (None, false) => format!("{} [{profile}]", path.display()),
+                (Some(browser), true) => format!("{} [{browser}]", path.display()),
+                (Some(browser), false) => format!("{} [{browser}: {profile}]", path.display()),
+            };
+            match status {
+                BackupStatus::Ok {
+                    found,
+                    imported,
+                    duplicated,
+                } => writeln!(
+                    f,
+                    "{:<8} {:>10} {:>10} {:>10}  {path}",
+                    "ok", found, imported, duplicated
+                )?,
+                BackupStatus::Skipped(reason) => writeln!(
+                    f,
+                    "{:<8} {:>10} {:>10} {:>10}  {path} ({reason})",
+                    "skipped", "-", "-", "-"
+                )?,
+                BackupStatus::Failed(reason) => writeln!(
+                    f,
+                    "{:<8} {:>10} {:>10} {:>10}  {path} ({reason})",
+                    "failed", "-", "-", "-"
+                )?,
+            }
+        }
+        write!(
+            f,
+            "Found:{}, Imported:{}, Duplicated: {}, Failed: {}",
+            self.found(),
+            self.imported(),
+            self.duplicated(),
+            self.failed()
+        )
+    }
+}
+
+/// Options to customize backup.
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    /// Only read visits from history files, without writing into database.
+    pub dry_run: bool,
+    /// See `Source::with_min_dwell`.
+    pub min_dwell: Option<Duration>,
+    /// See `Source::with_flatten_redirects`.
+    pub flatten_redirects: bool,
+    /// See `Source::with_exclude_internal`.
+    pub exclude_internal: bool,
+    /// Strip fragments of urls before they are persisted, except urls of these
+    /// domains, see `Database::with_strip_fragments`. `None` keeps urls as they are.
+    pub strip_fragments: Option<Vec<String>>,
+    /// Canonicalize trailing slashes of urls before they are persisted, see
+    /// `Database::with_trailing_slash`. `None` keeps urls as they are.
+    pub trailing_slash: Option<TrailingSlash>,
+    /// Threads reading history files, see `backup`. 0 is taken as 1.
+    pub jobs: usize,
+    /// History files read but not persisted yet to keep at most, see `backup`. 0 is
+    /// taken as 1.
+    pub queue_size: usize,
+    /// Visits in one transaction of `Database::persist` at most, 0 keeps the default,
+    /// see `Database::with_persist_batch`.
+    pub batch_visits: usize,
+    /// Bytes of urls and titles in one transaction at most, 0 keeps the default.
+    pub batch_bytes: usize,
+    /// Only keep visits of last this many days, older ones are not imported, and
+    /// pruned from database at the end of backup. `None` or 0 keeps all visits.
+    pub retention_days: Option<u64>,
+    /// Measure time spent in each phase of every history file, reported in
+    /// `BackupSummary::timings`. Nothing is measured when it's false.
+    pub profile_timing: bool,
+}
+
+impl BackupOptions {
+    // Whether visits are filtered or normalized before persisted
+    fn filtered(&self) -> bool {
+        self.min_dwell.is_some()
+            || self.flatten_redirects
+            || self.exclude_internal
+            || self.strip_fragments.is_some()
+            || self.trailing_slash.is_some()
+    }
+}
+
+/// Backup `history_files` into 1History database at `db_file`.
+///
+/// Backup of one file failing doesn't abort others, outcome of each file is returned
+/// in `BackupSummary`. Progress of each file is reported to `collector`. Each backup
+/// except dry run is recorded as a backup session, see `Database::select_whatsnew`.
+/// How visits are read and persisted is customized by `options`.
+///
+/// History files are read by `options.jobs` reader threads, which send visits of
+/// each file through a queue of `options.queue_size` files to the calling thread,
+/// the only one writing into database. Files are written in the order passed, so
+/// results don't depend on which reader finishes first. Readers wait when the queue
+/// is full, or when they get too far ahead of the writer, so visits in memory are
+/// bounded.
+///
+/// Glob patterns in `history_files`, such as `~/.config/chromium/*/History`, are
+/// expanded, patterns matching nothing are reported as skipped. The same file passed
+/// more than once, such as detected and in `history_files`, or via symlinks, is only
+/// backed up once, under the path first passed.
+///
+/// History files locked by running browsers, or on read-only file systems such as
+/// Time Machine backups, are copied into temp directory first, progress of copy is
+/// reported to `copy_collector` in bytes.
+///
+/// Backup of a file interrupted before resumes from visits not committed yet, see
+/// `Database::select_resume_point`. When stopped by `request_stop`, backup aborts
+/// after the in-flight batch is committed.
+///
+/// With `options.retention_days`, visits older than the cutoff are skipped when read,
+/// and deleted from database after all files are backed up, see
+/// `Database::prune_visits_before`. Stopped backups prune nothing.
+pub fn backup<P: AsRef<Path>>(
+    history_files: Vec<PathBuf>,
+    db_file: P,
+    options: &BackupOptions,
+    collector: &impl ProgressCollector,
+    copy_collector: &impl ProgressCollector,
+) -> Result<BackupSummary> {
+    // 0 would prune everything, so it's taken as disabled
+    let cutoff = options
+        .retention_days
+        .filter(|days| *days > 0)
+        .map(retention_cutoff);
+    let (start, end) = full_timerange();
+    let start = cutoff.map_or(start, |cutoff| start.max(cutoff));
+    debug!("start:{}, end:{}", start, end);
+
+    let db = Database::open(db_file)
+        .context("open 1History DB")?
+        .with_strip_fragments(options.strip_fragments.clone())
+        .with_trailing_slash(options.trailing_slash)
+        .with_persist_batch(options.batch_visits, options.batch_bytes);
+    if !options.dry_run {
+        db.begin_backup_session().context("begin backup session")?;
+    }
+
+    let expanded = dedup_history_files(expand_history_files(history_files));
+    let history_files = expanded
+        .iter()
+        .filter_map(|f| f.as_ref().ok().cloned())
+        .collect::<Vec<_>>();
+    let defaults = default_browser_files();
+    let next = AtomicUsize::new(0);
+    // Number of files written, readers stay within `window` files ahead of it, it's
+    // `usize::MAX` once writer is gone
+    let written_files = (Mutex::new(0), Condvar::new());
+    let window = options.jobs.max(1) + options.queue_size.max(1);
+    let mut statuses = history_files.iter().map(|_| None).collect::<Vec<_>>();
+    let mut timings = history_files.iter().map(|_| None).collect::<Vec<_>>();
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::sync_channel(options.queue_size.max(1));
+        for _ in 0..options.jobs.max(1) {
+            let tx = tx.clone();
+            let (db, history_files, defaults, next, written_files) =
+                (&db, &history_files, &defaults, &next, &written_files);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(history_file) = history_files.get(index) else {
+                    break;
+                };
+                let (lock, cvar) = written_files;
+                let done = *cvar
+                    .wait_while(lock.lock().unwrap(), |done| {
+                        *done != usize::MAX && index >= *done + window
+                    })
+                    .unwrap();
+                if done == usize::MAX {
+                    break;
+                }
+                let mut timings = options.profile_timing.then(PhaseTimings::default);
+                let begin = timings.is_some().then(Instant::now);
+                let visits = read_visits(
+                    history_file,
+                    db,
+                    options,
+                    (start, end),
+                    copy_collector,
+                    timings.as_mut(),
+                );
+                if let Some(begin) = begin {
+                    add_elapsed(timings.as_mut().map(|t| &mut t.total), begin);
+                }
+                let batch = Batch {
+                    index,
+                    history_file,
+                    default: defaults.contains(history_file),
+                    visits,
+                    timings,
+                };
+                // Writer is gone, such as stopped by `request_stop`
+                if tx.send(batch).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // Files are written in the order passed, batches read ahead wait in `pending`
+        let mut pending = BTreeMap::new();
+        let write_in_order = || -> anyhow::Result<()> {
+            for batch in rx {
+                pending.insert(batch.index, batch);
+                let (lock, cvar) = &written_files;
+                loop {
+                    let index = *lock.lock().unwrap();
+                    let Some(Batch {
+                        history_file,
+                        default,
+                        visits,
+                        timings: mut file_timings,
+                        ..
+                    }) = pending.remove(&index)
+                    else {
+                        break;
+                    };
+                    let browser = visits.as_ref().ok().and_then(Visits::name);
+                    let written = visits.and_then(|visits| {
+                        write_visits(
+                            history_file,
+                            default,
+                            visits,
+                            &db,
+                            options,
+                            (start, end),
+                            collector,
+                            file_timings.as_mut(),
+                        )
+                    });
+                    timings[index] = file_timings;
+                    let status = match written {
+                        Ok(status) => status,
+                        Err(e) if is_stopped(&e) => return Err(e),
+                        Err(e) => {
+                            error!("{} persist failed, err: {:?}", history_file.display(), e);
+                            BackupStatus::Failed(format!("{e:#}"))
+                        }
+                    };
+                    statuses[index] = Some((status, browser));
+                    *lock.lock().unwrap() = index + 1;
+                    cvar.notify_all();
+                }
+            }
+            Ok(())
+        };
+        let res = write_in_order();
+        // Readers waiting for writer stop
+        let (lock, cvar) = &written_files;
+        *lock.lock().unwrap() = usize::MAX;
+        cvar.notify_all();
+        res
+    })?;
+
+    let mut summary = BackupSummary::default();
+    if let Some(cutoff) = cutoff.filter(|_| !options.dry_run) {
+        let (visits, urls) = db.prune_visits_before(cutoff).context("prune visits")?;
+        info!(
+            "Pruned {visits} visits before {}, and {urls} urls left without visits",
+            unixepoch_as_ymdhms(cutoff)
+        );
+        summary.pruned = visits;
+    }
+    for (his_file, phases) in history_files.iter().zip(timings) {
+        if let Some(phases) = phases {
+            summary.timings.push(BackupTiming {
+                path: his_file.display().to_string(),
+                phases,
+            });
+        }
+    }
+    // Reports are in the order passed, patterns matching nothing included
+    let mut statuses = history_files.into_iter().zip(statuses);
+    for file in expanded {
+        summary.reports.push(match file {
+            Ok(_) => {
+                let (his_file, status) = statuses.next().expect("status of each file");
+                // Every file is read unless backup is stopped, which returns early
+                let (status, browser) =
+                    status.unwrap_or_else(|| (BackupStatus::Failed("not read".to_string()), None));
+                SourceReport {
+                    profile: profile_name(&his_file),
+                    path: his_file,
+                    browser,
+                    status,
+                }
+            }
+            Err(pattern) => SourceReport {
+                profile: String::new(),
+                browser: None,
+                path: pattern,
+                status: BackupStatus::Skipped("no files matched".to_string()),
+            },
+        });
+    }
+
+    Ok(summary)
+}
+
+// Start of visits kept by retention of `days`, in unix_epoch_ms
+fn retention_cutoff(days: u64) -> i64 {
+    now_ms() - days as i64 * 86_400_000
+}
+
+// Visits read from one history file, sent from readers to the writer of `backup`
+struct Batch<'a> {
+    // Index of history file, so reports are in the order passed
+    index: usize,
+    history_file: &'a Path,
+    // Whether it's history file of default browser
+    default: bool,
+    visits: anyhow::Result<Visits>,
+    // Time spent in reading it, only with `BackupOptions::profile_timing`
+    timings: Option<PhaseTimings>,
+}
+
+enum Visits {
+    Skipped(String),
+    Rows {
+        name: SourceName,
+        rows: Vec<VisitDetail>,
+        schema_version: Option<String>,
+    },
+    // Chromium history file imported by set-based SQL, see `Database::persist_chromium`
+    Chromium {
+        name: SourceName,
+        schema_version: Option<String>,
+    },
+}
+
+impl Visits {
+    fn name(&self) -> Option<SourceName> {
+        match self {
+            Visits::Skipped(_) => None,
+            Visits::Rows { name, .. } | Visits::Chromium { name, .. } => Some(*name),
+        }
+    }
+}
+
+// Read visits of `history_file` between `[start, end]` not backed up yet, database
+// is only read for its resume point. Time spent is added to `timings` if any.
+fn read_visits(
+    history_file: &Path,
+    db: &Database,
+    options: &BackupOptions,
+    (start, end): (i64, i64),
+    copy_collector: &impl ProgressCollector,
+    mut timings: Option<&mut PhaseTimings>,
+) -> anyhow::Result<Visits> {
+    if !history_file.exists() {
+        return Ok(Visits::Skipped("not found".to_string()));
+    }
+    let dry_run = options.dry_run;
+    // Declared before `s`, so it's removed after `s` is closed
+    let mut copy = None;
+    let begin = timings.is_some().then(Instant::now);
+    let s = match Source::open(history_file) {
+        Ok(s) => s,
+        Err(e) if e.is_locked() || e.is_read_only() => {
+            let reason = if e.is_locked() { "locked" } else { "read-only" };
+            info!(
+                "{} is {reason}, backup a copy of it",
+                history_file.display()
+            );
+            let copying = timings.is_some().then(Instant::now);
+            let copy = copy
+                .insert(TempCopy::new(history_file, copy_collector).context("copy history file")?);
+            if let Some(copying) = copying {
+                add_elapsed(timings.as_deref_mut().map(|t| &mut t.copy), copying);
+            }
+            Source::open(copy.path())
+                .context("open copy")?
+                .copied_from(history_file)
+        }
+        Err(e) => return Err(Error::from(e).context("open")),
+    }
+    .with_min_dwell(options.min_dwell)
+    .with_flatten_redirects(options.flatten_redirects)
+    .with_exclude_internal(options.exclude_internal);
+    let resume_from = if dry_run {
+        None
+    } else {
+        db.select_resume_point(&history_file.to_string_lossy())
+            .context("select resume point")?
+    };
+    if let Some(ts) = resume_from {
+        info!(
+            "Resume interrupted backup of {} from {}",
+            history_file.display(),
+            unixepoch_as_ymdhms(ts / 1000)
+        );
+    }
+    // Chromium history files are imported by set-based SQL when visits are
+    // persisted as they are, except temp copies, interrupted imports, and paths
+    // not in UTF-8, which can't be attached
+    let fast = !dry_run
+        && copy.is_none()
+        && history_file.to_str().is_some()
+        && resume_from.is_none()
+        && s.name().is_chromium()
+        && s.has_chromium_columns()
+        && !options.filtered();
+    if fast {
+        if let Some(begin) = begin {
+            add_elapsed(timings.map(|t| &mut t.select), begin);
+        }
+        return Ok(Visits::Chromium {
+            name: s.name(),
+            schema_version: s.schema_version().map(str::to_string),
+        });
+    }
+    // Visits are ordered by visit_time, so ones after resume point are not committed
+    let start = resume_from.map_or(start, |ts| start.max(ts / 1000));
+    let rows = s
+        .select(start, end)
+        .context("select")?
+        .filter(|v| resume_from.is_none_or(|ts| v.visit_time > ts))
+        .collect::<Vec<_>>();
+    debug!("{:?} select {} histories", s.name(), rows.len());
+    if let (Some(begin), Some(timings)) = (begin, timings) {
+        // Copy is measured on its own
+        timings.select += begin.elapsed().saturating_sub(timings.copy);
+        timings.rows = rows.len();
+    }
+
+    Ok(Visits::Rows {
+        name: s.name(),
+        rows,
+        schema_version: s.schema_version().map(str::to_string),
+    })
+}
+
+// Persist `visits` of `history_file` into `db`, the only place writing during `backup`.
+// Time spent is added to `timings` if any.
+#[allow(clippy::too_many_arguments)]
+fn write_visits(
+    history_file: &Path,
+    default: bool,
+    visits: Visits,
+    db: &Database,
+    options: &BackupOptions,
+    (start, end): (i64, i64),
+    collector: &impl ProgressCollector,
+    mut timings: Option<&mut PhaseTimings>,
+) -> anyhow::Result<BackupStatus> {
+    let begin = timings.is_some().then(Instant::now);
+    let src_path = history_file.to_string_lossy();
+    let default = if default { " (default browser)" } else { "" };
+    let (name, rows, schema_version) = match visits {
+        Visits::Skipped(reason) => return Ok(BackupStatus::Skipped(reason)),
+        Visits::Chromium {
+            name,
+            schema_version,
+        } => (name, None, schema_version),
+        Visits::Rows {
+            name,
+            rows,
+            schema_version,
+        } => (name, Some(rows), schema_version),
+    };
+
+    info!("Begin backup {}{default}...", history_file.display());
+    if !options.dry_run {
+        db.set_source_browser(&src_path, name)
+            .context("set source browser")?;
+        db.set_source_schema_version(&src_path, schema_version.as_deref())
+            .context("set source schema version")?;
+    }
+    let (found, imported, duplicated) = match rows {
+        None => {
+            let importing = timings.is_some().then(Instant::now);
+            let res = db
+                .persist_chromium(&src_path, history_file, start, end, collector)
+                .context("persist")?;
+            if let (Some(importing), Some(timings)) = (importing, timings.as_deref_mut()) {
+                timings.visits += importing.elapsed();
+                timings.rows = res.0;
+            }
+            res
+        }
+        Some(rows) if options.dry_run => (rows.len(), 0, 0),
+        Some(rows) => {
+            let found = rows.len();
+            let (imported, duplicated) = db
+                .persist_timed(&src_path, rows, collector, timings.as_deref_mut())
+                .context("persist")?;
+            (found, imported, duplicated)
+        }
+    };
+    if let Some(begin) = begin {
+        add_elapsed(timings.map(|t| &mut t.total), begin);
+    }
+    debug!(
+        "{:?} found:{}, affected:{}, duplicated:{}",
+        name, found, imported, duplicated
+    );
+    info!("Finish backup {}{default}", history_file.display());
+
+    Ok(BackupStatus::Ok {
+        found,
+        imported,
+        duplicated,
+    })
+}
+
+/// Backup tabs synced from other devices, read from Chrome's `sync_dirs`(see
+/// `synced_session_dirs`), into 1History database at `db_file`.
+///
+/// Each device is reported as `<sync dir>#<device>`, other arguments are the same
+/// as `backup`, only `dry_run`, `strip_fragments` and `trailing_slash` of `options`
+/// are used.
+pub fn backup_synced<P: AsRef<Path>>(
+    sync_dirs: Vec<PathBuf>,
+    db_file: P,
+    options: &BackupOptions,
+    collector: &impl ProgressCollector,
+) -> Result<BackupSummary> {
+    let db = Database::open(db_file)
+        .context("open 1History DB")?
+        .with_strip_fragments(options.strip_fragments.clone())
+        .with_trailing_slash(options.trailing_slash);
+
+    let mut summary = BackupSummary::default();
+    for dir in sync_dirs {
+        let sessions = match read_synced_sessions(&dir) {
+            Ok(sessions) if sessions.is_empty() => {
+                summary.reports.push(SourceReport {
+                    path: dir,
+                    profile: String::new(),
+                    browser: None,
+                    status: BackupStatus::Skipped("no synced tabs".to_string()),
+                });
+                continue;
+            }
+            Ok(sessions) => sessions,
+            Err(e) => {
+                error!("{} read synced tabs failed, err: {:?}", dir.display(), e);
+                summary.reports.push(SourceReport {
+                    path: dir,
+                    profile: String::new(),
+                    browser: None,
+                    status: BackupStatus::Failed(format!("{e:#}")),
+                });
+                continue;
+            }
+        };
+        for SyncedSession { device, visits } in sessions {
+            let path = PathBuf::from(format!("{}#{device}", dir.display()));
+            let found = visits.len();
+            let status = if options.dry_run {
+                Ok((0, 0))
+            } else {
+                db.persist(&path.to_string_lossy(), visits, collector)
+            };
+            let status = match status {
+                Ok((imported, duplicated)) => BackupStatus::Ok {
+                    found,
+                    imported,
+                    duplicated,
+                },
+                Err(e) if e.is_stopped() => return Err(e),
+                Err(e) => BackupStatus::Failed(format!("{e:#}")),
+            };
+            summary.reports.push(SourceReport {
+                path,
+                profile: String::new(),
+                browser: None,
+                status,
+            });
+        }
+    }
+
+    Ok(summary)
+}
+
+// Expand glob patterns of `history_files` in the order passed, patterns matching
+// nothing are kept as `Err`
+fn expand_history_files(history_files: Vec<PathBuf>) -> Vec<std::result::Result<PathBuf, PathBuf>> {
+    let mut files = Vec::new();
+    for file in history_files {
+        match expand_history_pattern(&file) {
+            None => files.push(Ok(file)),
+            Some(matched) if matched.is_empty() => {
+                warn!("No history files matched {}", file.display());
+                files.push(Err(file));
+            }
+            Some(matched) => {
+                debug!("{} matched {matched:?}", file.display());
+                files.extend(matched.into_iter().map(Ok));
+            }
+        }
+    }
+    files
+}
+
+// Remove files whose canonical path is the same as a file before them, paths can't
+// be canonicalized(such as not found) and unmatched patterns are kept as they are
+fn dedup_history_files(
+    history_files: Vec<std::result::Result<PathBuf, PathBuf>>,
+) -> Vec<std::result::Result<PathBuf, PathBuf>> {
+    let mut seen = HashSet::new();
+    history_files
+        .into_iter()
+        .filter(|f| {
+            let Ok(f) = f else {
+                return true;
+            };
+            let canonical = fs::canonicalize(f).unwrap_or_else(|_| f.clone());
+            let first = seen.insert(canonical);
+            if !first {
+                info!("Skip {}, which is already in history files", f.display());
+            }
+            first
+        })
+        .collect()
+}
@@ -0,0 +1,205 @@
+use chrono::Local;
+use std::{
+    backtrace::Backtrace,
+    env, fs, io,
+    panic::{self, PanicHookInfo},
+    path::{Path, PathBuf},
+    process,
+};
+
+const ISSUES_URL: &str = "https://github.com/1History/1History/issues";
+const REDACTED: &str = "<redacted>";
+
+// Options whose value is a path
+const PATH_OPTIONS: &[&str] = &[
+    "-d",
+    "--db-file",
+    "-f",
+    "--history-files",
+    "-o",
+    "--output",
+    "--log-file",
+    "--template",
+];
+
+fn is_path_like(arg: &str) -> bool {
+    arg.contains('/')
+        || arg.contains('\\')
+        || arg.starts_with('~')
+        || Path::new(arg).extension().is_some_and(|ext| {
+            !ext.to_string_lossy()
+                .starts_with(|c: char| c.is_ascii_digit())
+        })
+}
+
+/// Replace values of path-like arguments in `args` with `<redacted>`, since they
+/// may contain user names, program itself is kept as its file name.
+pub(crate) fn redact_args(args: &[String]) -> Vec<String> {
+    let mut redacted = Vec::with_capacity(args.len());
+    let mut path_value = false;
+    for (i, arg) in args.iter().enumerate() {
+        let arg = if i == 0 {
+            Path::new(arg)
+                .file_name()
+                .map_or_else(|| REDACTED.to_string(), |n| n.to_string_lossy().to_string())
+        } else if path_value || (!arg.starts_with('-') && is_path_like(arg)) {
+            REDACTED.to_string()
+        } else {
+            match arg.split_once('=') {
+                Some((opt, value)) if PATH_OPTIONS.contains(&opt) || is_path_like(value) => {
+                    format!("{opt}={REDACTED}")
+                }
+                _ => arg.to_string(),
+            }
+        };
+        path_value = PATH_OPTIONS.contains(&arg.as_str());
+        redacted.push(arg);
+    }
+    redacted
+}
+
+fn crash_report(info: &PanicHookInfo, args: &[String], backtrace: &Backtrace) -> String {
+    format!(
+        r#"# Panic
+{info}
+
+# Version
+onehistory {}
+
+# OS
+{} {}
+
+# Arguments
+{}
+
+# Backtrace
+{backtrace}
+"#,
+        env!("CARGO_PKG_VERSION"),
+        env::consts::OS,
+        env::consts::ARCH,
+        redact_args(args).join(" "),
+    )
+}
+
+/// Write crash report of panic `info` under `dir`, with version, OS, redacted `args`
+/// and backtrace. Return path of the report.
+pub fn write_crash_report(
+    dir: &Path,
+    info: &PanicHookInfo,
+    args: &[String],
+) -> io::Result<PathBuf> {
+    let path = dir.join(format!(
+        "onehistory-crash-{}-{}.txt",
+        Local::now().format("%Y%m%d-%H%M%S"),
+        process::id()
+    ));
+    fs::write(&path, crash_report(info, args, &Backtrace::force_capture()))?;
+    Ok(path)
+}
+
+/// Replace raw panic message and backtrace with a crash report written under
+/// system temp directory, and a short message asking users to attach it to an issue.
+///
+/// Previous hook is still called when report can't be written.
+pub fn install_crash_handler() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let args = env::args().collect::<Vec<_>>();
+        match write_crash_report(&env::temp_dir(), info, &args) {
+            Ok(path) => {
+                // Start a new line, in case progress bar is drawn at current line
+                eprintln!();
+                eprintln!("onehistory crashed: {info}");
+                eprintln!(
+                    "A crash report is written to {}, please attach it to an issue at {ISSUES_URL}",
+                    path.display()
+                );
+            }
+            Err(_) => default_hook(info),
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_redact_args() {
+        assert_eq!(
+            redact_args(&args(&[
+                "/home/alice/bin/onehistory",
+                "-d",
+                "onehistory",
+                "--log-file=oh.log",
+                "backup",
+                "-f",
+                "/home/alice/History",
+                "--min-dwell",
+                "3",
+            ])),
+            args(&[
+                "onehistory",
+                "-d",
+                "<redacted>",
+                "--log-file=<redacted>",
+                "backup",
+                "-f",
+                "<redacted>",
+                "--min-dwell",
+                "3",
+            ])
+        );
+        assert_eq!(
+            redact_args(&args(&[
+                "onehistory",
+                "convert",
+                "C:\\History",
+                "demo.db",
+                "-v"
+            ])),
+            args(&["onehistory", "convert", "<redacted>", "<redacted>", "-v"])
+        );
+        assert_eq!(
+            redact_args(&args(&["onehistory", "serve", "-a", "127.0.0.1:9960"])),
+            args(&["onehistory", "serve", "-a", "127.0.0.1:9960"])
+        );
+    }
+
+    #[test]
+    fn test_crash_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let report_dir = dir.path().to_path_buf();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            write_crash_report(
+                &report_dir,
+                info,
+                &args(&["onehistory", "-d", "/home/alice/oh.db", "backup"]),
+            )
+            .unwrap();
+        }));
+        let res = panic::catch_unwind(|| panic!("controlled panic"));
+        panic::set_hook(previous);
+        assert!(res.is_err());
+
+        let reports = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(reports.len(), 1);
+        let report = fs::read_to_string(&reports[0]).unwrap();
+        for section in ["# Panic", "# Version", "# OS", "# Arguments", "# Backtrace"] {
+            assert!(report.contains(section), "{section} missing");
+        }
+        assert!(report.contains("controlled panic"));
+        assert!(report.contains(&format!("onehistory {}", env!("CARGO_PKG_VERSION"))));
+        assert!(report.contains("onehistory -d <redacted> backup"));
+        assert!(!report.contains("alice"));
+    }
+}
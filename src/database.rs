@@ -1,118 +1,938 @@
 use crate::{
+    error::{Error, Result},
+    fuzzy::{min_shared_trigrams, similarity, trigrams},
     progress::ProgressCollector,
-    types::VisitDetail,
-    util::{domain_from, ymd_midnight},
+    session::SessionStatsBuilder,
+    source::Source,
+    types::{
+        BackupRun, CountMode, DayStats, DbDiff, DiffSide, DomainTrend, DomainTrends, HourRange,
+        ImportRecord, KeywordOp, PhaseTimings, RecoveredTable, SessionStats, SourceFilter,
+        SourceName, SourceSummary, TitleDiff, TrailingSlash, VisitDetail, VisitTrace, WhatsNew,
+    },
+    util::{
+        add_elapsed, canonical_counts, canonical_domain, domain_from, internal_url_sql,
+        normalize_trailing_slash, now_ms, split_keyword, strip_fragment, take_stop_request,
+        tld_from, whole_days, ymd_midnight, Persisting,
+    },
 };
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context};
 use log::debug;
-use rusqlite::{named_params, Connection, Error as sqlError, ErrorCode, Transaction};
-use std::{collections::HashMap, sync::Mutex};
+use rusqlite::{
+    named_params, params, params_from_iter, types::Value, Connection, Error as sqlError, ErrorCode,
+    OpenFlags, OptionalExtension, ToSql, Transaction,
+};
+use std::{
+    collections::HashMap,
+    fs, mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
 
 #[derive(Debug)]
 struct HistoryVisit {
     item_id: i64,
     visit_time: i64,
     visit_type: i64,
+    // See `summary_host`
+    host: String,
+    source_visit_id: Option<i64>,
+    // Url as visited when it's rewritten, see `get_or_persist_url`
+    raw_url: Option<String>,
+}
+
+// SQL condition of visits, with the named parameters it binds
+struct VisitFilter {
+    sql: String,
+    params: Vec<(String, String)>,
+}
+
+impl VisitFilter {
+    // `params` of a query followed by those of the filter
+    fn with<'a>(&'a self, params: &[(&'a str, &'a dyn ToSql)]) -> Vec<(&'a str, &'a dyn ToSql)> {
+        let mut all = params.to_vec();
+        all.extend(
+            self.params
+                .iter()
+                .map(|(name, value)| (name.as_str(), value as &dyn ToSql)),
+        );
+        all
+    }
 }
 
-const DEFAULT_BATCH_NUM: usize = 100;
+impl std::fmt::Display for VisitFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.sql)
+    }
+}
 
-pub(crate) struct Database {
-    conn: Mutex<Connection>,
+/// Visits committed in one batch by `Database::persist` by default.
+pub const DEFAULT_BATCH_NUM: usize = 100;
+/// Bytes of urls and titles committed in one batch by `Database::persist` by
+/// default, so batches of giant urls(data: urls...) stay small.
+pub const DEFAULT_BATCH_BYTES: usize = 4 << 20;
+// Day of visit_time in onehistory_daily_summary. visit_time is stored in UTC, but
+// days are bucketed in local timezone like daily counts of raw visits, so the summary
+// is only valid for the timezone it's aggregated in
+const SUMMARY_DAY: &str =
+    "coalesce(strftime('%Y-%m-%d', visit_time / 1000000, 'unixepoch', 'localtime'), '')";
+// Number of SQLite virtual machine instructions between two checks of query timeout
+const TIMEOUT_CHECK_OPS: i32 = 1000;
+
+/// The 1History database, where histories of all browsers are stored.
+pub struct Database {
+    // Shared with databases created by `view`
+    conn: Arc<Mutex<Connection>>,
     persist_batch: usize,
+    persist_batch_bytes: usize,
+    query_timeout: Option<Duration>,
+    // Deadline of the running statement, shared with the progress handler of `conn`
+    deadline: Arc<Mutex<Instant>>,
+    exclude_internal: bool,
+    hours: Option<HourRange>,
+    source: Option<SourceFilter>,
+    include_hidden: bool,
+    canonical: bool,
+    keyword_op: KeywordOp,
+    // Domains whose fragments are kept, `None` when fragments are not stripped
+    strip_fragments: Option<Vec<String>>,
+    trailing_slash: Option<TrailingSlash>,
+    raw_urls: bool,
 }
 
 impl Database {
-    pub fn open(sqlite_datafile: String) -> Result<Database> {
-        let conn = Connection::open(&sqlite_datafile)?;
-        let db = Self {
-            conn: Mutex::new(conn),
-            persist_batch: DEFAULT_BATCH_NUM,
-        };
+    /// Open (or create) 1History database at `sqlite_datafile`.
+    pub fn open<P: AsRef<Path>>(sqlite_datafile: P) -> Result<Database> {
+        let sqlite_datafile = sqlite_datafile.as_ref();
+        if let Some(dir) = sqlite_datafile
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+        }
+        let conn = Connection::open(sqlite_datafile)?;
+        let db = Self::of_conn(conn);
         db.init().context("init")?;
 
         Ok(db)
     }
 
+    /// Open existing 1History database at `sqlite_datafile` read-only, such as for
+    /// `diff`. It's neither created nor migrated, so queries of columns added by
+    /// newer versions may fail.
+    pub fn open_read_only<P: AsRef<Path>>(sqlite_datafile: P) -> Result<Database> {
+        // URI is kept, so other databases can be attached by `read_only_uri`
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(sqlite_datafile, flags)?;
+        Ok(Self::of_conn(conn))
+    }
+
+    fn of_conn(conn: Connection) -> Self {
+        Self {
+            conn: Arc::new(Mutex::new(conn)),
+            persist_batch: DEFAULT_BATCH_NUM,
+            persist_batch_bytes: DEFAULT_BATCH_BYTES,
+            query_timeout: None,
+            deadline: Arc::new(Mutex::new(Instant::now())),
+            exclude_internal: false,
+            hours: None,
+            source: None,
+            include_hidden: false,
+            canonical: false,
+            keyword_op: KeywordOp::And,
+            strip_fragments: None,
+            trailing_slash: None,
+            raw_urls: false,
+        }
+    }
+
+    /// Commit visits persisted by `persist` once a batch has `visits` visits, or
+    /// `bytes` bytes of urls and titles, whichever comes first. Zero keeps the
+    /// default, which is 100 visits or 4 MiB.
+    pub fn with_persist_batch(mut self, visits: usize, bytes: usize) -> Self {
+        if visits > 0 {
+            self.persist_batch = visits;
+        }
+        if bytes > 0 {
+            self.persist_batch_bytes = bytes;
+        }
+        self
+    }
+
+    /// Abort statements running longer than `timeout`, they fail with an interrupted
+    /// error. `None` or zero disables it.
+    pub fn with_query_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.query_timeout = timeout.filter(|t| !t.is_zero());
+        self
+    }
+
+    /// Leave out local files and browser internal pages(see `util::internal_domain`)
+    /// from queries of visits, statistics are then always computed from raw visits.
+    pub fn with_exclude_internal(mut self, exclude_internal: bool) -> Self {
+        self.exclude_internal = exclude_internal;
+        self
+    }
+
+    /// Only include visits in `hours` of day from queries of visits, statistics are
+    /// then always computed from raw visits. `None` includes all visits.
+    pub fn with_hours(mut self, hours: Option<HourRange>) -> Self {
+        self.hours = hours;
+        self
+    }
+
+    /// Only include visits of `source` from queries of visits, statistics are then
+    /// always computed from raw visits. `None` includes all visits.
+    pub fn with_source(mut self, source: Option<SourceFilter>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Include visits hidden by `set_visit_hidden` or `hide_visits` in queries of
+    /// visits, statistics are then always computed from raw visits.
+    pub fn with_include_hidden(mut self, include_hidden: bool) -> Self {
+        self.include_hidden = include_hidden;
+        self
+    }
+
+    /// Group variants of urls by `util::canonical_url`, and domains by
+    /// `util::canonical_domain`, in top lists and day stats. Visits are still listed
+    /// with urls visited.
+    pub fn with_canonical(mut self, canonical: bool) -> Self {
+        self.canonical = canonical;
+        self
+    }
+
+    /// Match visits whose url or title contains all terms of keyword, or any of
+    /// them, see `util::split_keyword`.
+    pub fn with_keyword_op(mut self, op: KeywordOp) -> Self {
+        self.keyword_op = op;
+        self
+    }
+
+    /// Database sharing connection and filters with `self`, so one opened database
+    /// can be filtered per request by `with_source` and others.
+    pub fn view(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            persist_batch: self.persist_batch,
+            persist_batch_bytes: self.persist_batch_bytes,
+            query_timeout: self.query_timeout,
+            deadline: self.deadline.clone(),
+            exclude_internal: self.exclude_internal,
+            hours: self.hours,
+            source: self.source.clone(),
+            include_hidden: self.include_hidden,
+            canonical: self.canonical,
+            keyword_op: self.keyword_op,
+            strip_fragments: self.strip_fragments.clone(),
+            trailing_slash: self.trailing_slash,
+            raw_urls: self.raw_urls,
+        }
+    }
+
+    // Whether visits are filtered, so daily summary can't be used. Hidden visits
+    // are not in daily summary, so including them is filtering too.
+    fn filtered(&self) -> bool {
+        self.exclude_internal
+            || self.hours.is_some()
+            || self.source.is_some()
+            || self.include_hidden
+    }
+
+    /// Strip fragments of urls persisted, except urls of `keep_domains`, see
+    /// `util::strip_fragment`. `None` keeps urls as they are. Urls as visited are
+    /// kept with visits, see `with_raw_urls`.
+    pub fn with_strip_fragments(mut self, keep_domains: Option<Vec<String>>) -> Self {
+        self.strip_fragments = keep_domains;
+        self
+    }
+
+    /// Canonicalize trailing slashes of urls persisted by `mode`, see
+    /// `util::normalize_trailing_slash`. `None` keeps urls as they are. Urls as
+    /// visited are kept with visits, see `with_raw_urls`.
+    pub fn with_trailing_slash(mut self, mode: Option<TrailingSlash>) -> Self {
+        self.trailing_slash = mode;
+        self
+    }
+
+    /// List visits by `select_visits` and `for_each_visit` with urls as visited,
+    /// before they are rewritten by `with_strip_fragments`, `with_trailing_slash`
+    /// or `normalize` subcommand. Statistics always use rewritten urls.
+    pub fn with_raw_urls(mut self, raw_urls: bool) -> Self {
+        self.raw_urls = raw_urls;
+        self
+    }
+
+    // Lock the connection, statements run with it are aborted after `query_timeout`
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        let conn = self.conn.lock().unwrap();
+        if let Some(timeout) = self.query_timeout {
+            *self.deadline.lock().unwrap() = Instant::now() + timeout;
+            let deadline = self.deadline.clone();
+            conn.progress_handler(
+                TIMEOUT_CHECK_OPS,
+                Some(move || Instant::now() > *deadline.lock().unwrap()),
+            );
+        }
+        conn
+    }
+
+    // Run `f` between steps of a statement, time spent in it doesn't count towards
+    // `query_timeout`
+    fn outside_query<T>(&self, f: impl FnOnce() -> T) -> T {
+        let begin = Instant::now();
+        let ret = f();
+        if self.query_timeout.is_some() {
+            *self.deadline.lock().unwrap() += begin.elapsed();
+        }
+        ret
+    }
+
     fn init(&self) -> Result<()> {
-        self.conn
-            .lock()
-            .unwrap()
-            .execute_batch(
-                r#"
+        let conn = self.conn.lock().unwrap();
+        let fts_exists = Self::table_exists(&conn, "onehistory_urls_fts")?;
+        let summary_exists = Self::table_exists(&conn, "onehistory_daily_summary")?;
+        conn.execute_batch(
+            r#"
 CREATE TABLE IF NOT EXISTS onehistory_urls (
     id integer PRIMARY KEY AUTOINCREMENT,
     url text NOT NULL UNIQUE,
     title text
 );
 
+-- source_id is id of import_records the visit is imported from, source_visit_id is
+-- its id in that history file, both are NULL for visits imported by older versions.
+-- raw_url is the url as visited when url of item_id is rewritten, such as by
+-- stripping fragments, NULL otherwise.
 CREATE TABLE IF NOT EXISTS onehistory_visits (
     id integer PRIMARY KEY AUTOINCREMENT,
     item_id integer,
     visit_time integer,
     visit_type integer NOT NULL DEFAULT 0,
+    source_id integer,
+    source_visit_id integer,
+    hidden integer NOT NULL DEFAULT 0,
+    raw_url text,
     UNIQUE(item_id, visit_time)
 );
 
+CREATE INDEX IF NOT EXISTS onehistory_visits_visit_time ON onehistory_visits (visit_time);
+
+-- Visits grouped by local day and host, so dashboards don't scan all visits. Unlike
+-- visit_time in UTC, day depends on timezone of the machine aggregating it. host
+-- is empty for urls without title, and day is empty when visit_time is out of
+-- range of SQLite date functions.
+CREATE TABLE IF NOT EXISTS onehistory_daily_summary (
+    day text NOT NULL,
+    host text NOT NULL,
+    visits integer NOT NULL,
+    PRIMARY KEY (day, host)
+);
+
 
+-- imported/duplicated are cumulative visits of all imports from data_path,
+-- in_progress is 1 while an import of it is not finished
 CREATE TABLE IF NOT EXISTS import_records (
     id integer PRIMARY KEY AUTOINCREMENT,
     last_import integer,
-    data_path text NOT NULL UNIQUE);
+    data_path text NOT NULL UNIQUE,
+    imported integer NOT NULL DEFAULT 0,
+    duplicated integer NOT NULL DEFAULT 0,
+    in_progress integer NOT NULL DEFAULT 0);
+
+-- One row for each backup, visits imported by it have id greater than max_visit_id
+CREATE TABLE IF NOT EXISTS backup_sessions (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    started_at integer NOT NULL,
+    max_visit_id integer NOT NULL);
+
+-- One row for each backup invocation, errors is a JSON array of strings
+CREATE TABLE IF NOT EXISTS backup_runs (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    started_at integer NOT NULL,
+    finished_at integer NOT NULL,
+    sources integer NOT NULL,
+    imported integer NOT NULL,
+    duplicated integer NOT NULL,
+    skipped integer NOT NULL,
+    errors text NOT NULL,
+    pruned integer NOT NULL DEFAULT 0,
+    retention_days integer);
+
+-- One row for each peer whose sync bundles are imported, visits of it with id
+-- not greater than watermark are already imported
+CREATE TABLE IF NOT EXISTS sync_peers (
+    peer text PRIMARY KEY,
+    watermark integer NOT NULL,
+    updated_at integer NOT NULL);
+
+-- Full text search index of onehistory_urls, trigram is used to support substring match
+CREATE VIRTUAL TABLE IF NOT EXISTS onehistory_urls_fts USING fts5 (
+    url,
+    title,
+    content = 'onehistory_urls',
+    content_rowid = 'id',
+    tokenize = 'trigram'
+);
 "#,
+        )
+        .context("create table")?;
+        // Added after import_records is created by older versions
+        for column in ["imported", "duplicated", "in_progress"] {
+            Self::add_column(
+                &conn,
+                "import_records",
+                column,
+                "integer NOT NULL DEFAULT 0",
+            )?;
+        }
+        Self::add_column(&conn, "import_records", "browser", "text")?;
+        Self::add_column(&conn, "import_records", "schema_version", "text")?;
+        Self::add_column(&conn, "backup_runs", "pruned", "integer NOT NULL DEFAULT 0")?;
+        Self::add_column(&conn, "backup_runs", "retention_days", "integer")?;
+        Self::add_column(&conn, "backup_runs", "timings", "text")?;
+        for column in ["source_id", "source_visit_id"] {
+            Self::add_column(&conn, "onehistory_visits", column, "integer")?;
+        }
+        Self::add_column(
+            &conn,
+            "onehistory_visits",
+            "hidden",
+            "integer NOT NULL DEFAULT 0",
+        )?;
+        Self::add_column(&conn, "onehistory_visits", "raw_url", "text")?;
+        // Created after source_id is added, so sources are counted without scanning
+        // all visits
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS onehistory_visits_source_id ON onehistory_visits (source_id)",
+            [],
+        )
+        .context("create index")?;
+        if !fts_exists {
+            debug!("onehistory_urls_fts created, build index...");
+            Self::rebuild_fts(&conn)?;
+        }
+        if !summary_exists {
+            debug!("onehistory_daily_summary created, backfill it...");
+            Self::rebuild_daily_summary(&conn)?;
+        }
+        Ok(())
+    }
+
+    fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+        let exists = conn.query_row(
+            "SELECT count(1) FROM pragma_table_info(?1) WHERE name = ?2",
+            [table, column],
+            |row| row.get::<_, i64>(0),
+        )? > 0;
+        if !exists {
+            debug!("add column {column} to {table}");
+            conn.execute(
+                &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+                [],
             )
-            .context("create table")?;
+            .with_context(|| format!("add column {column} to {table}"))?;
+        }
         Ok(())
     }
 
-    fn get_or_persist_url(&self, url: String, title: String) -> Result<i64> {
-        let conn = self.conn.lock().unwrap();
-        let query_id = || -> rusqlite::Result<i64> {
-            let mut stat = conn.prepare(
-                r#"
-         SELECT id FROM "onehistory_urls" WHERE url = :url;
-"#,
+    fn table_exists(conn: &Connection, name: &str) -> Result<bool> {
+        let exists = conn.query_row(
+            "SELECT count(1) FROM sqlite_master WHERE name = ?1",
+            [name],
+            |row| row.get::<_, i64>(0),
+        )? > 0;
+        Ok(exists)
+    }
+
+    // Host of a summary bucket, urls without title are not counted in domain top
+    fn summary_host(url: &str, titled: bool) -> String {
+        if titled {
+            domain_from(url.to_string())
+        } else {
+            String::new()
+        }
+    }
+
+    // Aggregate onehistory_daily_summary from raw visits, hidden ones are left out
+    fn daily_summary_of(conn: &Connection) -> Result<HashMap<(String, String), i64>> {
+        Self::daily_summary_where(conn, "hidden = 0", &[])
+    }
+
+    // Like `daily_summary_of`, but only visits matching `filter` are aggregated
+    fn daily_summary_where(
+        conn: &Connection,
+        filter: &str,
+        params: &[(&str, &dyn ToSql)],
+    ) -> Result<HashMap<(String, String), i64>> {
+        let mut stat = conn.prepare(&format!(
+            r#"
+SELECT
+    {SUMMARY_DAY} AS day,
+    url,
+    coalesce(title != '', 0),
+    count(1)
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    {filter}
+GROUP BY
+    day, item_id
+"#
+        ))?;
+        let rows = stat.query_map(params, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+
+        let mut summary = HashMap::new();
+        for r in rows {
+            let (day, url, titled, cnt): (String, String, bool, i64) = r?;
+            *summary
+                .entry((day, Self::summary_host(&url, titled)))
+                .or_insert(0) += cnt;
+        }
+        Ok(summary)
+    }
+
+    fn rebuild_daily_summary(conn: &Connection) -> Result<usize> {
+        let summary = Self::daily_summary_of(conn)?;
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM onehistory_daily_summary", [])?;
+        {
+            let mut stat = tx.prepare(
+                "INSERT INTO onehistory_daily_summary (day, host, visits) VALUES (?1, ?2, ?3)",
             )?;
-            stat.query_row(
-                named_params! {
-                    ":url": url,
-                },
-                |row| row.get(0),
+            for ((day, host), visits) in &summary {
+                stat.execute(params![day, host, visits])?;
+            }
+        }
+        tx.commit().context("rebuild onehistory_daily_summary")?;
+
+        Ok(summary.len())
+    }
+
+    /// Compare daily summary with raw visits, return number of buckets which differ.
+    pub fn verify_daily_summary(&self) -> Result<usize> {
+        let conn = self.conn();
+        let mut expected = Self::daily_summary_of(&conn)?;
+        let mut stat = conn.prepare("SELECT day, host, visits FROM onehistory_daily_summary")?;
+        let rows = stat.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+
+        let mut mismatched = 0;
+        for r in rows {
+            let (day, host, visits): (String, String, i64) = r?;
+            if expected.remove(&(day, host)) != Some(visits) {
+                mismatched += 1;
+            }
+        }
+        Ok(mismatched + expected.len())
+    }
+
+    /// Strip fragments of urls already persisted, except urls of `keep_domains`, see
+    /// `util::strip_fragment`. Visits of urls which become the same are merged into one
+    /// url, and visits at the same time are kept once.
+    ///
+    /// Return number of urls normalized, and number of duplicated visits removed.
+    pub fn strip_url_fragments(&self, keep_domains: &[String]) -> Result<(usize, usize)> {
+        self.normalize_urls("url LIKE '%#%'", |url| {
+            strip_fragment(url, keep_domains).to_string()
+        })
+    }
+
+    /// Canonicalize trailing slashes of urls already persisted by `mode`, see
+    /// `util::normalize_trailing_slash`. Urls are merged like `strip_url_fragments`.
+    ///
+    /// Return number of urls normalized, and number of duplicated visits removed.
+    pub fn normalize_trailing_slashes(&self, mode: TrailingSlash) -> Result<(usize, usize)> {
+        self.normalize_urls("1", |url| normalize_trailing_slash(url, mode))
+    }
+
+    // Rewrite urls matching `filter` by `normalize`, visits of urls which become the
+    // same are merged into one url
+    fn normalize_urls<F>(&self, filter: &str, normalize: F) -> Result<(usize, usize)>
+    where
+        F: Fn(&str) -> String,
+    {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let urls = {
+            let mut stat = tx.prepare(&format!(
+                "SELECT id, url FROM onehistory_urls WHERE {filter} ORDER BY id"
+            ))?;
+            let rows = stat.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<Vec<(i64, String)>>>()?
+        };
+        let fuzzy = Self::table_exists(&tx, "onehistory_title_trigrams")?;
+
+        let (mut normalized, mut removed) = (0, 0);
+        for (id, url) in urls {
+            let new_url = normalize(&url);
+            if new_url == url {
+                continue;
+            }
+            // Visits keep url as visited, unless it's rewritten before
+            tx.execute(
+                "UPDATE onehistory_visits SET raw_url = ?1 WHERE item_id = ?2 AND raw_url IS NULL",
+                params![url, id],
+            )?;
+            let target: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM onehistory_urls WHERE url = ?1",
+                    [&new_url],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match target {
+                None => {
+                    tx.execute(
+                        "UPDATE onehistory_urls SET url = ?1 WHERE id = ?2",
+                        params![new_url, id],
+                    )?;
+                }
+                Some(target) => {
+                    tx.execute(
+                        "UPDATE OR IGNORE onehistory_visits SET item_id = ?1 WHERE item_id = ?2",
+                        params![target, id],
+                    )?;
+                    // Left ones collide with visits of target
+                    removed +=
+                        tx.execute("DELETE FROM onehistory_visits WHERE item_id = ?1", [id])?;
+                    let title: Option<String> = tx
+                        .query_row(
+                            r#"
+UPDATE onehistory_urls SET title = (SELECT title FROM onehistory_urls WHERE id = ?2)
+WHERE id = ?1 AND coalesce(title, '') = ''
+RETURNING title
+"#,
+                            params![target, id],
+                            |row| row.get(0),
+                        )
+                        .optional()?
+                        .flatten();
+                    tx.execute("DELETE FROM onehistory_urls WHERE id = ?1", [id])?;
+                    // Fuzzy search finds merged titles under target
+                    if fuzzy {
+                        tx.execute(
+                            "DELETE FROM onehistory_title_trigrams WHERE url_id = ?1",
+                            [id],
+                        )?;
+                        if let Some(title) = title {
+                            Self::index_title_trigrams(&tx, target, &title)?;
+                        }
+                    }
+                }
+            }
+            normalized += 1;
+        }
+        if normalized > 0 {
+            Self::rebuild_fts(&tx)?;
+        }
+        tx.commit()?;
+        // Merged urls may change titles, and hosts of summary buckets
+        if normalized > 0 {
+            Self::rebuild_daily_summary(&conn)?;
+        }
+
+        Ok((normalized, removed))
+    }
+
+    /// Rebuild daily summary from raw visits, return number of buckets.
+    pub fn rebuild_summary(&self) -> Result<usize> {
+        Self::rebuild_daily_summary(&self.conn())
+    }
+
+    fn rebuild_fts(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "INSERT INTO onehistory_urls_fts (onehistory_urls_fts) VALUES ('rebuild')",
+            [],
+        )
+        .context("rebuild onehistory_urls_fts")?;
+        Ok(())
+    }
+
+    // Index trigrams of `title` of url `id` for fuzzy search
+    fn index_title_trigrams(conn: &Connection, id: i64, title: &str) -> Result<()> {
+        let mut stat = conn.prepare_cached(
+            "INSERT OR IGNORE INTO onehistory_title_trigrams (trigram, url_id) VALUES (?1, ?2)",
+        )?;
+        for trigram in trigrams(title) {
+            stat.execute(params![trigram, id])
+                .context("insert onehistory_title_trigrams")?;
+        }
+        Ok(())
+    }
+
+    /// Build trigram index of titles from scratch, which is required by
+    /// `select_fuzzy_visits`. Urls persisted afterwards are indexed as well.
+    ///
+    /// Return number of urls indexed.
+    pub fn rebuild_fuzzy_index(&self) -> Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            r#"
+DROP TABLE IF EXISTS onehistory_title_trigrams;
+
+-- Trigrams of words in titles, see `fuzzy::trigrams`
+CREATE TABLE onehistory_title_trigrams (
+    trigram text NOT NULL,
+    url_id integer NOT NULL,
+    PRIMARY KEY (trigram, url_id)
+) WITHOUT ROWID;
+"#,
+        )
+        .context("create onehistory_title_trigrams")?;
+        let titles = {
+            let mut stat = tx.prepare("SELECT id, title FROM onehistory_urls WHERE title != ''")?;
+            let rows = stat.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<Vec<(i64, String)>>>()?
+        };
+        for (id, title) in &titles {
+            Self::index_title_trigrams(&tx, *id, title)?;
+        }
+        tx.commit()?;
+
+        Ok(titles.len())
+    }
+
+    /// Select visits between `[start, end]` whose title is similar to `query` at
+    /// least `threshold`(0 to 1, see `fuzzy::similarity`), together with their
+    /// similarity. Visits are ordered by similarity desc, then by visit time, most
+    /// recent first when `reverse` is true.
+    ///
+    /// It fails when trigram index isn't built by `rebuild_fuzzy_index`.
+    pub fn select_fuzzy_visits(
+        &self,
+        start: i64,
+        end: i64,
+        query: &str,
+        threshold: f64,
+        reverse: bool,
+    ) -> Result<Vec<(VisitDetail, f64)>> {
+        let conn = self.conn();
+        if !Self::table_exists(&conn, "onehistory_title_trigrams")? {
+            return Err(anyhow!(
+                "fuzzy search index not found, run `onehistory reindex --fuzzy` to build it"
             )
+            .into());
+        }
+        let query_trigrams = trigrams(query);
+        let min_shared = min_shared_trigrams(query, threshold);
+
+        let sql = format!(
+            r#"
+SELECT
+    id,
+    title
+FROM
+    onehistory_urls
+WHERE
+    id IN (
+        SELECT
+            url_id
+        FROM
+            onehistory_title_trigrams
+        WHERE
+            trigram IN ({})
+        GROUP BY
+            url_id
+        HAVING
+            count(1) >= {min_shared})
+"#,
+            vec!["?"; query_trigrams.len()].join(",")
+        );
+        let mut stat = conn.prepare(&sql)?;
+        let rows = stat.query_map(params_from_iter(&query_trigrams), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        let mut similar = HashMap::new();
+        for r in rows {
+            let (id, title): (i64, String) = r?;
+            let score = similarity(query, &title);
+            if score >= threshold {
+                similar.insert(id, score);
+            }
+        }
+        if similar.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let ids = similar
+            .keys()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let filter = self.visit_filter(None);
+        let sql = format!(
+            r#"
+SELECT
+    u.id,
+    url,
+    title,
+    CAST(visit_time / 1000 as integer),
+    visit_type
+FROM
+    onehistory_urls u,
+    onehistory_visits v ON u.id = v.item_id
+WHERE
+    visit_time BETWEEN :start AND :end
+    AND u.id IN ({ids})
+    AND {}
+"#,
+            filter
+        );
+        let mut stat = conn.prepare(&sql)?;
+        let rows = stat.query_map(
+            &*filter.with(named_params! {
+                ":start": Self::unixepoch_to_prtime(start),
+                ":end": Self::unixepoch_to_prtime(end),
+            }),
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    VisitDetail {
+                        url: row.get(1)?,
+                        title: row.get(2)?,
+                        visit_time: row.get(3)?,
+                        visit_type: row.get(4)?,
+                        source_visit_id: None,
+                    },
+                ))
+            },
+        )?;
+        let mut res = Vec::new();
+        for r in rows {
+            let (id, visit) = r?;
+            res.push((visit, similar[&id]));
+        }
+        res.sort_by(|(a, a_score), (b, b_score)| {
+            let by_time = if reverse {
+                b.visit_time.cmp(&a.visit_time)
+            } else {
+                a.visit_time.cmp(&b.visit_time)
+            };
+            b_score.total_cmp(a_score).then(by_time)
+        });
+
+        Ok(res)
+    }
+
+    /// Rebuild full text search index from `onehistory_urls` if it's stale.
+    ///
+    /// Return true when index is rebuilt.
+    pub fn reindex(&self) -> Result<bool> {
+        let conn = self.conn();
+        let check = conn.execute(
+            "INSERT INTO onehistory_urls_fts (onehistory_urls_fts, rank) VALUES ('integrity-check', 1)",
+            [],
+        );
+        match check {
+            Ok(_) => Ok(false),
+            Err(e) => {
+                debug!("onehistory_urls_fts is stale, err:{e}");
+                Self::rebuild_fts(&conn)?;
+                Ok(true)
+            }
+        }
+    }
+
+    // Return id of `url` rewritten by `strip_fragments` and `trailing_slash`, together
+    // with whether its title is non-empty, and `url` itself when it's rewritten
+    fn get_or_persist_url(
+        &self,
+        url: String,
+        title: String,
+    ) -> Result<(i64, bool, Option<String>)> {
+        let mut new_url = match &self.strip_fragments {
+            Some(keep_domains) => strip_fragment(&url, keep_domains).to_string(),
+            None => url.clone(),
         };
-        match query_id() {
-            Err(e) if e == rusqlite::Error::QueryReturnedNoRows => {
-                let mut stat = conn.prepare(
-                    r#"
+        if let Some(mode) = self.trailing_slash {
+            new_url = normalize_trailing_slash(&new_url, mode);
+        }
+        let raw_url = (new_url != url).then_some(url);
+        let url = new_url;
+        let mut conn = self.conn();
+        let query_id = conn.query_row(
+            r#"
+         SELECT id, coalesce(title != '', 0) FROM "onehistory_urls" WHERE url = :url;
+"#,
+            named_params! {
+                ":url": url,
+            },
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        );
+        match query_id {
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                // Only new urls are indexed, so url and its index are written in one transaction
+                let tx = conn.transaction()?;
+                let affected = tx
+                    .execute(
+                        r#"
     INSERT INTO "onehistory_urls" (url, title) VALUES(:url, :title);
 "#,
-                )?;
-                let affected = stat
-                    .execute(named_params! {
-                        ":url": url,
-                        ":title": title,
-                    })
+                        named_params! {
+                            ":url": url,
+                            ":title": title,
+                        },
+                    )
                     .context("insert onehistory_urls")?;
                 assert_eq!(affected, 1);
 
-                let id = query_id()?;
-                Ok(id)
+                let id = tx.last_insert_rowid();
+                tx.execute(
+                    r#"
+    INSERT INTO onehistory_urls_fts (rowid, url, title) VALUES(:id, :url, :title);
+"#,
+                    named_params! {
+                        ":id": id,
+                        ":url": url,
+                        ":title": title,
+                    },
+                )
+                .context("insert onehistory_urls_fts")?;
+                if Self::table_exists(&tx, "onehistory_title_trigrams")? {
+                    Self::index_title_trigrams(&tx, id, &title)?;
+                }
+                tx.commit()?;
+                Ok((id, !title.is_empty(), raw_url))
             }
             Err(e) => Err(e.into()),
-            Ok(id) => Ok(id),
+            Ok((id, titled)) => Ok((id, titled, raw_url)),
         }
     }
 
-    fn persist_visits(&self, src_path: &str, batch: Vec<HistoryVisit>) -> Result<(usize, usize)> {
+    fn persist_visits(
+        &self,
+        src_path: &str,
+        batch: Vec<HistoryVisit>,
+        mut timings: Option<&mut PhaseTimings>,
+    ) -> Result<(usize, usize)> {
         assert!(!batch.is_empty());
+        let begin = timings.is_some().then(Instant::now);
 
         let sql = r#"
-INSERT INTO onehistory_visits (item_id, visit_time, visit_type)
-    VALUES (?1, ?2, ?3);
+INSERT INTO onehistory_visits (item_id, visit_time, visit_type, source_id, source_visit_id, raw_url)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6);
 "#;
 
-        let mut conn = self.conn.lock().unwrap();
+        let summary_sql = format!(
+            r#"
+-- WHERE is required to parse ON CONFLICT after SELECT
+INSERT INTO onehistory_daily_summary (day, host, visits)
+    SELECT {SUMMARY_DAY}, ?2, 1 FROM (SELECT ?1 AS visit_time) WHERE true
+ON CONFLICT (day, host)
+    DO UPDATE SET
+        visits = visits + 1;
+"#
+        );
+
+        let mut conn = self.conn();
         let tx = conn.transaction()?;
+        let source_id = Self::source_id(&tx, src_path)?;
         let last_ts = batch[batch.len() - 1].visit_time;
         let mut affected = 0;
         let mut duplicated = 0;
@@ -120,10 +940,28 @@ INSERT INTO onehistory_visits (item_id, visit_time, visit_type)
             item_id,
             visit_time,
             visit_type,
+            host,
+            source_visit_id,
+            raw_url,
         } in batch
         {
-            match tx.execute(sql, &[&item_id, &visit_time, &visit_type]) {
-                Ok(ret) => affected += ret,
+            match tx.execute(
+                sql,
+                params![
+                    item_id,
+                    visit_time,
+                    visit_type,
+                    source_id,
+                    source_visit_id,
+                    raw_url
+                ],
+            ) {
+                Ok(ret) => {
+                    tx.prepare_cached(&summary_sql)?
+                        .execute(params![visit_time, host])
+                        .context("update onehistory_daily_summary")?;
+                    affected += ret
+                }
                 Err(e) => {
                     if let sqlError::SqliteFailure(ffi_err, _msg) = &e {
                         if ffi_err.code == ErrorCode::ConstraintViolation {
@@ -140,228 +978,1878 @@ INSERT INTO onehistory_visits (item_id, visit_time, visit_type)
                 }
             }
         }
-        Self::update_process(&tx, src_path, last_ts)?;
+        Self::update_process(&tx, src_path, last_ts, affected, duplicated)?;
+        let committing = begin.map(|begin| {
+            let now = Instant::now();
+            add_elapsed(timings.as_deref_mut().map(|t| &mut t.visits), begin);
+            now
+        });
         tx.commit()?;
+        if let Some(begin) = committing {
+            add_elapsed(timings.map(|t| &mut t.commit), begin);
+        }
 
         Ok((affected, duplicated))
     }
 
+    /// Persist visits read from `src_path` into database, `visit_time` of `details`
+    /// should be in PRTime(microseconds since unix epoch).
+    ///
+    /// Visits are committed in batches(see `with_persist_batch`), `last_import` of
+    /// `src_path` advances with each batch. When stopped by `request_stop` or failed
+    /// halfway, visits ordered by `visit_time` can be resumed from
+    /// `select_resume_point`.
+    ///
+    /// Return `(affected, duplicated)` rows.
     pub fn persist(
         &self,
         src_path: &str,
         details: Vec<VisitDetail>,
-        collector: impl ProgressCollector,
+        collector: &impl ProgressCollector,
+    ) -> Result<(usize, usize)> {
+        self.persist_timed(src_path, details, collector, None)
+    }
+
+    /// Like `persist`, adding time spent in looking up urls, inserting visits and
+    /// committing to `timings`. Nothing is measured when it's `None`.
+    pub fn persist_timed(
+        &self,
+        src_path: &str,
+        details: Vec<VisitDetail>,
+        collector: &impl ProgressCollector,
+        mut timings: Option<&mut PhaseTimings>,
     ) -> Result<(usize, usize)> {
-        let mut i = 0;
-        let mut batch = None; // Use Option so we can take it out later
+        let _persisting = Persisting::new();
+        collector.start(details.len() as u64);
+        let mut batch: Vec<HistoryVisit> = Vec::with_capacity(self.persist_batch);
+        let mut batch_bytes = 0;
         let mut affected = 0;
         let mut duplicated = 0;
-        let mut persist_helper = |batch: Vec<HistoryVisit>| -> Result<()> {
-            let len = batch.len();
-            let (a, d) = self.persist_visits(src_path, batch)?;
-            affected += a;
-            duplicated += d;
-            collector.inc(len as u64);
-            Ok(())
-        };
+        let mut persist_helper =
+            |batch: Vec<HistoryVisit>, timings: Option<&mut PhaseTimings>| -> Result<()> {
+                let len = batch.len();
+                let (a, d) = self.persist_visits(src_path, batch, timings)?;
+                affected += a;
+                duplicated += d;
+                collector.inc(len as u64);
+                if take_stop_request() {
+                    return Err(Error::Stopped);
+                }
+                Ok(())
+            };
         for VisitDetail {
             url,
             title,
             visit_time,
             visit_type,
+            source_visit_id,
         } in details
         {
-            i += 1;
-            let one_batch = batch.get_or_insert(Vec::with_capacity(self.persist_batch));
-            let item_id = self.get_or_persist_url(url, title)?;
-            one_batch.push(HistoryVisit {
-                item_id,
+            // Visits of the same time are committed in one batch, so all visits not
+            // newer than `last_import` are committed
+            let full = (batch.len() >= self.persist_batch
+                || batch_bytes >= self.persist_batch_bytes)
+                && batch.last().map(|v| v.visit_time) != Some(visit_time);
+            if full {
+                persist_helper(mem::take(&mut batch), timings.as_deref_mut())?;
+                batch_bytes = 0;
+            }
+            batch_bytes += url.len() + title.len();
+            // Rewriting urls never changes their hosts
+            let host = Self::summary_host(&url, true);
+            let begin = timings.is_some().then(Instant::now);
+            let (item_id, titled, raw_url) = self.get_or_persist_url(url, title)?;
+            if let Some(begin) = begin {
+                add_elapsed(timings.as_deref_mut().map(|t| &mut t.urls), begin);
+            }
+            batch.push(HistoryVisit {
+                item_id,
                 visit_time,
                 visit_type,
+                host: if titled { host } else { String::new() },
+                source_visit_id,
+                raw_url,
             });
-            if i % self.persist_batch == 0 {
-                persist_helper(batch.take().unwrap())?;
-            }
         }
-        if batch.is_some() {
-            persist_helper(batch.take().unwrap())?;
+        if !batch.is_empty() {
+            persist_helper(batch, timings)?;
         }
+        self.conn().execute(
+            "UPDATE import_records SET in_progress = 0 WHERE data_path = ?1",
+            [src_path],
+        )?;
         collector.finish();
 
         Ok((affected, duplicated))
     }
 
-    fn update_process(tx: &Transaction<'_>, src_path: &str, ts: i64) -> Result<()> {
+    /// Like `persist`, but visits between `[start, end]`(unix epoch in milliseconds)
+    /// of Chromium history file at `history_file` are imported by set-based SQL, with
+    /// the file attached read-only to this database. Rows written are the same as
+    /// persisting visits selected by `Source`, without any filters, but much faster
+    /// for big profiles.
+    ///
+    /// Visits are committed in one transaction, so it's not resumable, and stop
+    /// requested by `request_stop` takes effect after it.
+    ///
+    /// Return `(found, affected, duplicated)` rows.
+    pub fn persist_chromium(
+        &self,
+        src_path: &str,
+        history_file: &Path,
+        start: i64,
+        end: i64,
+        collector: &impl ProgressCollector,
+    ) -> Result<(usize, usize, usize)> {
+        let _persisting = Persisting::new();
+        let mut conn = self.conn();
+        conn.execute(
+            "ATTACH DATABASE ?1 AS chromium",
+            [read_only_uri(history_file)],
+        )
+        .context("attach history file")?;
+        let res = Self::import_chromium(
+            &mut conn,
+            src_path,
+            Source::unixepoch_ms_to_webkit(start),
+            Source::unixepoch_ms_to_webkit(end),
+            collector,
+        );
+        conn.execute("DETACH DATABASE chromium", [])?;
+        let res = res?;
+        if take_stop_request() {
+            return Err(Error::Stopped);
+        }
+
+        Ok(res)
+    }
+
+    // Import visits between `[start, end]`(WebKit time) of attached Chromium history
+    fn import_chromium(
+        conn: &mut Connection,
+        src_path: &str,
+        start: i64,
+        end: i64,
+        collector: &impl ProgressCollector,
+    ) -> Result<(usize, usize, usize)> {
+        // Same as `Source::select_chrome`, visit_time is converted to PRTime
+        let range = named_params! {
+            ":start": start,
+            ":end": end,
+        };
+        let tx = conn.transaction()?;
+        let (found, last_ts): (i64, Option<i64>) = tx.query_row(
+            r#"
+SELECT
+    count(1),
+    max(v.visit_time) - 11644473600*1000000
+FROM
+    chromium.visits v,
+    chromium.urls u ON v.url = u.id
+WHERE
+    v.visit_time >= :start
+    AND v.visit_time <= :end
+"#,
+            range,
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let found = found as usize;
+        collector.start(found as u64);
+        let last_ts = match last_ts {
+            Some(ts) => ts,
+            None => {
+                collector.finish();
+                return Ok((0, 0, 0));
+            }
+        };
+
+        let max_id = |table: &str| -> Result<i64> {
+            let id = tx.query_row(
+                &format!("SELECT coalesce(max(id), 0) FROM main.{table}"),
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(id)
+        };
+        let (max_url_id, max_visit_id) = (max_id("onehistory_urls")?, max_id("onehistory_visits")?);
+        let source_id = Self::source_id(&tx, src_path)?;
+        // New urls are inserted in order of their first visit, with title of it,
+        // the same as `get_or_persist_url` called in order of visit time. Bare
+        // columns are taken from the row of min(visit_time).
+        tx.execute(
+            r#"
+INSERT INTO main.onehistory_urls (url, title)
+SELECT
+    url,
+    title
+FROM (
+    SELECT
+        u.url,
+        coalesce(u.title, '') AS title,
+        min(v.visit_time) AS first_visit,
+        v.id AS first_id
+    FROM
+        chromium.visits v,
+        chromium.urls u ON v.url = u.id
+    WHERE
+        v.visit_time >= :start
+        AND v.visit_time <= :end
+    GROUP BY
+        u.url) AS n
+WHERE
+    NOT EXISTS (SELECT 1 FROM main.onehistory_urls o WHERE o.url = n.url)
+ORDER BY
+    first_visit, first_id
+"#,
+            range,
+        )
+        .context("insert onehistory_urls")?;
+        tx.execute(
+            r#"
+INSERT INTO onehistory_urls_fts (rowid, url, title)
+    SELECT id, url, title FROM main.onehistory_urls WHERE id > ?1
+"#,
+            [max_url_id],
+        )
+        .context("insert onehistory_urls_fts")?;
+        if Self::table_exists(&tx, "onehistory_title_trigrams")? {
+            let titles = {
+                let mut stat =
+                    tx.prepare("SELECT id, title FROM main.onehistory_urls WHERE id > ?1")?;
+                let rows = stat.query_map([max_url_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<rusqlite::Result<Vec<(i64, String)>>>()?
+            };
+            for (id, title) in &titles {
+                Self::index_title_trigrams(&tx, *id, title)?;
+            }
+        }
+
+        // Visits already persisted, or seen before in history file, are duplicated.
+        // They are skipped in SELECT rather than by INSERT OR IGNORE, which would
+        // take ids of AUTOINCREMENT.
+        let affected = tx
+            .execute(
+                r#"
+-- Urls are persisted as they are, so raw_url is NULL
+INSERT INTO main.onehistory_visits (item_id, visit_time, visit_type, source_id, source_visit_id, raw_url)
+SELECT
+    item_id,
+    visit_time,
+    visit_type,
+    :source_id,
+    first_id,
+    NULL
+FROM (
+    SELECT
+        o.id AS item_id,
+        v.visit_time - 11644473600*1000000 AS visit_time,
+        v.transition & 0xFF AS visit_type,
+        min(v.id) AS first_id
+    FROM
+        chromium.visits v,
+        chromium.urls u ON v.url = u.id,
+        main.onehistory_urls o ON o.url = u.url
+    WHERE
+        v.visit_time >= :start
+        AND v.visit_time <= :end
+    GROUP BY
+        o.id, v.visit_time) AS n
+WHERE
+    NOT EXISTS (
+        SELECT 1 FROM main.onehistory_visits e
+        WHERE e.item_id = n.item_id AND e.visit_time = n.visit_time)
+ORDER BY
+    visit_time, first_id
+"#,
+                named_params! {
+                    ":start": start,
+                    ":end": end,
+                    ":source_id": source_id,
+                },
+            )
+            .context("insert onehistory_visits")?;
+        let duplicated = found - affected;
+
+        // Hosts are derived from urls in Rust, so summary of new visits is
+        // aggregated by url first, like `daily_summary_of`
+        let summary = {
+            let mut stat = tx.prepare(&format!(
+                r#"
+SELECT
+    {SUMMARY_DAY} AS day,
+    url,
+    coalesce(title != '', 0),
+    count(1)
+FROM
+    main.onehistory_visits v,
+    main.onehistory_urls u ON v.item_id = u.id
+WHERE
+    v.id > ?1
+GROUP BY
+    day, item_id
+"#
+            ))?;
+            let rows = stat.query_map([max_visit_id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?;
+            let mut summary = HashMap::new();
+            for r in rows {
+                let (day, url, titled, cnt): (String, String, bool, i64) = r?;
+                *summary
+                    .entry((day, Self::summary_host(&url, titled)))
+                    .or_insert(0) += cnt;
+            }
+            summary
+        };
+        {
+            let mut stat = tx.prepare(
+                r#"
+INSERT INTO onehistory_daily_summary (day, host, visits) VALUES (?1, ?2, ?3)
+ON CONFLICT (day, host)
+    DO UPDATE SET
+        visits = visits + excluded.visits
+"#,
+            )?;
+            for ((day, host), visits) in &summary {
+                stat.execute(params![day, host, visits])
+                    .context("update onehistory_daily_summary")?;
+            }
+        }
+
+        Self::update_process(&tx, src_path, last_ts, affected, duplicated)?;
+        tx.execute(
+            "UPDATE import_records SET in_progress = 0 WHERE data_path = ?1",
+            [src_path],
+        )?;
+        tx.commit()?;
+        collector.inc(found as u64);
+        collector.finish();
+
+        Ok((found, affected, duplicated))
+    }
+
+    // Id of import record of `src_path`, which is created when missing
+    fn source_id(tx: &Transaction<'_>, src_path: &str) -> Result<i64> {
+        let id = tx
+            .query_row(
+                "SELECT id FROM import_records WHERE data_path = ?1",
+                [src_path],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match id {
+            Some(id) => Ok(id),
+            None => {
+                tx.execute(
+                    "INSERT INTO import_records (data_path) VALUES (?1)",
+                    [src_path],
+                )?;
+                Ok(tx.last_insert_rowid())
+            }
+        }
+    }
+
+    fn update_process(
+        tx: &Transaction<'_>,
+        src_path: &str,
+        ts: i64,
+        imported: usize,
+        duplicated: usize,
+    ) -> Result<()> {
         let sql = r#"
-INSERT INTO import_records (last_import, data_path)
-    VALUES (:last_import, :data_path)
+INSERT INTO import_records (last_import, data_path, imported, duplicated, in_progress)
+    VALUES (:last_import, :data_path, :imported, :duplicated, 1)
 ON CONFLICT (data_path)
     DO UPDATE SET
-        last_import = :last_import;
+        last_import = :last_import,
+        imported = imported + :imported,
+        duplicated = duplicated + :duplicated,
+        in_progress = 1;
 "#;
         tx.execute(
             sql,
             named_params! {
                 ":last_import": ts,
                 ":data_path": src_path,
+                ":imported": imported,
+                ":duplicated": duplicated,
             },
         )?;
 
         Ok(())
     }
 
+    /// Record `browser` detected from history file at `src_path`, so its visits can
+    /// be filtered by browser name, see `SourceFilter`.
+    pub fn set_source_browser(&self, src_path: &str, browser: SourceName) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let id = Self::source_id(&tx, src_path)?;
+        tx.execute(
+            "UPDATE import_records SET browser = ?1 WHERE id = ?2",
+            params![browser.to_string(), id],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Record schema version of history file `src_path`, kept for debugging
+    /// imports of different browser versions.
+    pub fn set_source_schema_version(&self, src_path: &str, version: Option<&str>) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let id = Self::source_id(&tx, src_path)?;
+        tx.execute(
+            "UPDATE import_records SET schema_version = ?1 WHERE id = ?2",
+            params![version, id],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Return history files visits come from with their number of visits, most
+    /// visits first. Visits imported before sources are recorded are counted in
+    /// one without id.
+    pub fn select_sources(&self) -> Result<Vec<SourceSummary>> {
+        let sql = r#"
+SELECT
+    c.source_id,
+    r.data_path,
+    r.browser,
+    c.visits,
+    r.schema_version
+FROM (
+    SELECT
+        source_id,
+        count(1) AS visits
+    FROM
+        onehistory_visits
+    GROUP BY
+        source_id) c
+    LEFT JOIN import_records r ON r.id = c.source_id
+ORDER BY
+    c.visits DESC,
+    c.source_id
+"#;
+        let conn = self.conn();
+        let mut stat = conn.prepare(sql)?;
+        let rows = stat.query_map([], |row| {
+            Ok(SourceSummary {
+                id: row.get(0)?,
+                data_path: row.get(1)?,
+                browser: row.get(2)?,
+                visits: row.get::<_, i64>(3)? as u64,
+                schema_version: row.get(4)?,
+            })
+        })?;
+
+        let mut sources = Vec::new();
+        for r in rows {
+            sources.push(r?);
+        }
+        Ok(sources)
+    }
+
+    /// Return `last_import` of history file at `src_path`, which is the visit time
+    /// (PRTime) of the newest visit imported from it, `None` if never imported.
+    pub fn select_last_import(&self, src_path: &str) -> Result<Option<i64>> {
+        let sql = r#"
+SELECT
+    last_import
+FROM
+    import_records
+WHERE
+    data_path = :data_path
+"#;
+        let conn = self.conn();
+        let mut stat = conn.prepare(sql)?;
+        let last_import = stat
+            .query_row(named_params! {":data_path": src_path}, |row| row.get(0))
+            .optional()?;
+
+        Ok(last_import)
+    }
+
+    /// Return `last_import` of history file at `src_path` when its last import is
+    /// not finished, such as interrupted by Ctrl-C. Visits not newer than it are
+    /// already committed, so the next import can start from it.
+    pub fn select_resume_point(&self, src_path: &str) -> Result<Option<i64>> {
+        let sql = r#"
+SELECT
+    last_import
+FROM
+    import_records
+WHERE
+    data_path = :data_path
+    AND in_progress = 1
+"#;
+        let conn = self.conn();
+        let mut stat = conn.prepare(sql)?;
+        let last_import = stat
+            .query_row(named_params! {":data_path": src_path}, |row| row.get(0))
+            .optional()?;
+
+        Ok(last_import)
+    }
+
+    /// Return import records of all history files ever imported, ordered by path.
+    pub fn select_import_records(&self) -> Result<Vec<ImportRecord>> {
+        let sql = r#"
+SELECT
+    data_path,
+    last_import / 1000,
+    imported,
+    duplicated
+FROM
+    import_records
+ORDER BY
+    data_path
+"#;
+        let conn = self.conn();
+        let mut stat = conn.prepare(sql)?;
+        let rows = stat.query_map([], |row| {
+            Ok(ImportRecord {
+                data_path: row.get(0)?,
+                last_import: row.get(1)?,
+                imported: row.get::<_, i64>(2)? as usize,
+                duplicated: row.get::<_, i64>(3)? as usize,
+            })
+        })?;
+
+        let mut records = Vec::new();
+        for r in rows {
+            records.push(r?);
+        }
+        Ok(records)
+    }
+
     fn unixepoch_to_prtime(ts: i64) -> i64 {
         ts * 1_000
     }
 
-    fn keyword_to_like(kw: Option<String>) -> String {
-        kw.map_or_else(
-            || "1".to_string(),
-            |v| {
-                let v = v.replace("'", "");
-                format!("(url like '%{v}%' or title like '%{v}%')")
-            },
-        )
+    // Condition of url or title containing `term`, bound to parameter `name`
+    fn term_to_like(term: &str, name: &str) -> (String, String) {
+        // trigram tokenizer requires at least 3 characters to match
+        if term.chars().count() >= 3 {
+            (
+                format!("(u.id IN (SELECT rowid FROM onehistory_urls_fts WHERE onehistory_urls_fts MATCH {name}))"),
+                format!("\"{}\"", term.replace('"', "\"\"")),
+            )
+        } else {
+            (
+                format!(r"(url LIKE {name} ESCAPE '\' OR title LIKE {name} ESCAPE '\')"),
+                format!("%{}%", escape_like(term)),
+            )
+        }
+    }
+
+    // Terms of `kw` combined by `keyword_op`, see `split_keyword`
+    fn keyword_to_like(&self, kw: Option<String>) -> VisitFilter {
+        let terms = kw.map(|kw| split_keyword(&kw)).unwrap_or_default();
+        if terms.is_empty() {
+            return VisitFilter {
+                sql: "1".to_string(),
+                params: Vec::new(),
+            };
+        }
+        let op = match self.keyword_op {
+            KeywordOp::And => " AND ",
+            KeywordOp::Or => " OR ",
+        };
+        let (likes, params): (Vec<_>, Vec<_>) = terms
+            .iter()
+            .enumerate()
+            .map(|(i, term)| {
+                let name = format!(":kw{i}");
+                let (like, value) = Self::term_to_like(term, &name);
+                (like, (name, value))
+            })
+            .unzip();
+        VisitFilter {
+            sql: format!("({})", likes.join(op)),
+            params,
+        }
+    }
+
+    // Condition of visits matching `kw`, internal urls are left out when excluded
+    fn visit_filter(&self, kw: Option<String>) -> VisitFilter {
+        self.visit_filter_at(kw, "visit_time")
+    }
+
+    // Like `visit_filter`, with visit time in `time_column`
+    fn visit_filter_at(&self, kw: Option<String>, time_column: &str) -> VisitFilter {
+        let VisitFilter {
+            sql: mut filter,
+            mut params,
+        } = self.keyword_to_like(kw);
+        if self.exclude_internal {
+            filter = format!("{filter} AND NOT {}", internal_url_sql("url"));
+        }
+        if let Some(hours) = self.hours {
+            filter = format!("{filter} AND {}", hours.to_sql(time_column));
+        }
+        if let Some(source) = &self.source {
+            let (sql, browser) = source.to_sql("source_id", ":source_browser");
+            filter = format!("{filter} AND {sql}");
+            params.extend(browser.map(|b| (":source_browser".to_string(), b)));
+        }
+        if !self.include_hidden {
+            filter = format!("{filter} AND hidden = 0");
+        }
+        VisitFilter {
+            sql: filter,
+            params,
+        }
+    }
+
+    /// Count visits between `[start, end]` matching `keyword`, which is cheaper than
+    /// `select_visits`.
+    pub fn count_visits(&self, start: i64, end: i64, keyword: Option<String>) -> Result<u64> {
+        let filter = self.visit_filter(keyword);
+        let sql = format!(
+            r#"
+SELECT
+    count(1)
+FROM
+    onehistory_urls u,
+    onehistory_visits v ON u.id = v.item_id
+WHERE
+    visit_time BETWEEN :start AND :end and {}
+"#,
+            filter
+        );
+        let conn = self.conn();
+        let cnt: i64 = conn.query_row(
+            &sql,
+            &*filter.with(named_params! {
+                ":start": Self::unixepoch_to_prtime(start),
+                ":end": Self::unixepoch_to_prtime(end),
+            }),
+            |row| row.get(0),
+        )?;
+
+        Ok(cnt as u64)
     }
 
+    /// Select visits between `[start, end]` matching `keyword`, ordered by visit time,
+    /// most recent first when `reverse` is true.
     pub fn select_visits(
         &self,
         start: i64,
         end: i64,
         keyword: Option<String>,
+        reverse: bool,
+    ) -> Result<Vec<VisitDetail>> {
+        let mut res: Vec<VisitDetail> = Vec::new();
+        self.for_each_visit(start, end, keyword, reverse, |visit| {
+            res.push(visit);
+            Ok(())
+        })?;
+
+        Ok(res)
+    }
+
+    /// Like `select_visits`, but visits are passed to `f` one by one as they are
+    /// read, so memory usage doesn't grow with database size. Iteration stops at
+    /// the first error returned by `f`.
+    ///
+    /// The database is locked during iteration, so `f` must not call back into it.
+    pub fn for_each_visit<F>(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+        reverse: bool,
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(VisitDetail) -> Result<()>,
+    {
+        self.query_visits(start, end, keyword, reverse, "", f)
+    }
+
+    /// Urls matching `keyword` by their last visit, most recent first, at most `limit`
+    /// ones. `visit_time` of each is its last visit.
+    pub fn select_recent_urls(
+        &self,
+        keyword: Option<String>,
+        limit: usize,
     ) -> Result<Vec<VisitDetail>> {
+        let filter = self.visit_filter(keyword);
         let sql = format!(
             r#"
 SELECT
     url,
     title,
-    CAST(visit_time / 1000 as integer),
-    visit_type
+    CAST(max(visit_time) / 1000 as integer) AS last_visit
 FROM
     onehistory_urls u,
     onehistory_visits v ON u.id = v.item_id
 WHERE
-    visit_time BETWEEN :start AND :end and {}
+    {}
+GROUP BY
+    u.id
 ORDER BY
-    visit_time
+    last_visit DESC
+LIMIT :limit
 "#,
-            Self::keyword_to_like(keyword)
+            filter
         );
-
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn();
         let mut stat = conn.prepare(&sql)?;
-
         let rows = stat.query_map(
-            named_params! {
-                ":start": Self::unixepoch_to_prtime(start),
-                ":end": Self::unixepoch_to_prtime(end),
-
-            },
+            &*filter.with(named_params! {":limit": limit as i64}),
             |row| {
-                let detail = VisitDetail {
+                Ok(VisitDetail {
                     url: row.get(0)?,
                     title: row.get(1).unwrap_or_else(|_| "".to_string()),
                     visit_time: row.get(2)?,
                     visit_type: 0,
-                };
-                Ok(detail)
+                    source_visit_id: None,
+                })
             },
         )?;
 
-        let mut res: Vec<VisitDetail> = Vec::new();
+        let mut res = Vec::new();
         for r in rows {
             res.push(r?);
         }
-
         Ok(res)
     }
 
-    pub fn select_daily_count(
+    /// Like `select_visits`, but only `limit` visits after skipping first `offset`
+    /// ones are returned, used to browse visits page by page.
+    pub fn select_visits_page(
         &self,
         start: i64,
         end: i64,
         keyword: Option<String>,
-    ) -> Result<Vec<(i64, i64)>> {
+        reverse: bool,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<VisitDetail>> {
+        let mut res: Vec<VisitDetail> = Vec::with_capacity(limit);
+        self.query_visits(
+            start,
+            end,
+            keyword,
+            reverse,
+            &format!("LIMIT {limit} OFFSET {offset}"),
+            |visit| {
+                res.push(visit);
+                Ok(())
+            },
+        )?;
+
+        Ok(res)
+    }
+
+    /// Select where visit of `id` comes from, `None` when it doesn't exist.
+    pub fn select_visit_trace(&self, id: i64) -> Result<Option<VisitTrace>> {
+        let traces = self.query_visit_traces("v.id = :id", named_params! {":id": id})?;
+        Ok(traces.into_iter().next())
+    }
+
+    /// Select where visits between `[start, end]` come from, ordered by visit time.
+    pub fn select_visit_traces(&self, start: i64, end: i64) -> Result<Vec<VisitTrace>> {
+        self.query_visit_traces(
+            "visit_time BETWEEN :start AND :end",
+            named_params! {
+                ":start": Self::unixepoch_to_prtime(start),
+                ":end": Self::unixepoch_to_prtime(end),
+            },
+        )
+    }
+
+    fn query_visit_traces(
+        &self,
+        filter: &str,
+        params: &[(&str, &dyn ToSql)],
+    ) -> Result<Vec<VisitTrace>> {
         let sql = format!(
             r#"
 SELECT
-    visit_day,
-    count(1)
-FROM (
-    SELECT
-        strftime ('%Y-%m-%d', visit_time / 1000000, 'unixepoch', 'localtime') AS visit_day
-    FROM
-        onehistory_visits v,
-        onehistory_urls u ON v.item_id = u.id
+    v.id,
+    url,
+    title,
+    CAST(visit_time / 1000 as integer),
+    visit_type,
+    source_visit_id,
+    r.data_path,
+    hidden
+FROM
+    onehistory_visits v
+    JOIN onehistory_urls u ON u.id = v.item_id
+    LEFT JOIN import_records r ON r.id = v.source_id
+WHERE
+    {filter}
+ORDER BY
+    visit_time
+"#
+        );
+        let conn = self.conn();
+        let mut stat = conn.prepare(&sql)?;
+        let rows = stat.query_map(params, |row| {
+            Ok(VisitTrace {
+                id: row.get(0)?,
+                visit: VisitDetail {
+                    url: row.get(1)?,
+                    title: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    visit_time: row.get(3)?,
+                    visit_type: row.get(4)?,
+                    source_visit_id: row.get(5)?,
+                },
+                source: row.get(6)?,
+                hidden: row.get(7)?,
+            })
+        })?;
+
+        let mut traces = Vec::new();
+        for r in rows {
+            traces.push(r?);
+        }
+        Ok(traces)
+    }
+
+    fn query_visits<F>(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+        reverse: bool,
+        limit: &str,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(VisitDetail) -> Result<()>,
+    {
+        let filter = self.visit_filter(keyword);
+        let url = if self.raw_urls {
+            "coalesce(v.raw_url, u.url)"
+        } else {
+            "u.url"
+        };
+        let sql = format!(
+            r#"
+SELECT
+    {url},
+    title,
+    CAST(visit_time / 1000 as integer),
+    visit_type
+FROM
+    onehistory_urls u,
+    onehistory_visits v ON u.id = v.item_id
+WHERE
+    visit_time BETWEEN :start AND :end and {}
+ORDER BY
+    visit_time {}
+{}
+"#,
+            filter,
+            if reverse { "DESC" } else { "ASC" },
+            limit
+        );
+
+        let conn = self.conn();
+        let mut stat = conn.prepare(&sql)?;
+
+        let rows = stat.query_map(
+            &*filter.with(named_params! {
+                ":start": Self::unixepoch_to_prtime(start),
+                ":end": Self::unixepoch_to_prtime(end),
+
+            }),
+            |row| {
+                let detail = VisitDetail {
+                    url: row.get(0)?,
+                    title: row.get(1).unwrap_or_else(|_| "".to_string()),
+                    visit_time: row.get(2)?,
+                    visit_type: 0,
+                    source_visit_id: None,
+                };
+                Ok(detail)
+            },
+        )?;
+
+        for r in rows {
+            let detail = r?;
+            self.outside_query(|| f(detail))?;
+        }
+
+        Ok(())
+    }
+
+    // First visit of each url, only urls first visited in `[start, end]` are included
+    const FIRST_VISITS: &'static str = r#"
+FROM (
+    SELECT
+        item_id,
+        min(visit_time) AS first_visit,
+        visit_type,
+        source_id,
+        hidden
+    FROM
+        onehistory_visits
+    GROUP BY
+        item_id) v,
+    onehistory_urls u ON u.id = v.item_id
+WHERE
+    first_visit BETWEEN :start AND :end
+"#;
+
+    /// Count urls first visited between `[start, end]`.
+    pub fn count_first_visits(&self, start: i64, end: i64) -> Result<u64> {
+        let filter = self.visit_filter_at(None, "first_visit");
+        let sql = format!("SELECT count(1) {} AND {}", Self::FIRST_VISITS, filter);
+        let cnt: i64 = self.conn().query_row(
+            &sql,
+            &*filter.with(named_params! {
+                ":start": Self::unixepoch_to_prtime(start),
+                ":end": Self::unixepoch_to_prtime(end),
+            }),
+            |row| row.get(0),
+        )?;
+
+        Ok(cnt as u64)
+    }
+
+    /// Pass first visit of each url to `f`, ordered by visit time, only urls first
+    /// visited between `[start, end]` are included, so each one is a discovery of
+    /// something new.
+    ///
+    /// The database is locked during iteration, so `f` must not call back into it.
+    pub fn for_each_first_visit<F>(&self, start: i64, end: i64, mut f: F) -> Result<()>
+    where
+        F: FnMut(VisitDetail) -> Result<()>,
+    {
+        let filter = self.visit_filter_at(None, "first_visit");
+        let sql = format!(
+            r#"
+SELECT
+    url,
+    title,
+    CAST(first_visit / 1000 AS integer),
+    visit_type
+{}
+    AND {}
+ORDER BY
+    first_visit
+"#,
+            Self::FIRST_VISITS,
+            filter
+        );
+        let conn = self.conn();
+        let mut stat = conn.prepare(&sql)?;
+        let rows = stat.query_map(
+            &*filter.with(named_params! {
+                ":start": Self::unixepoch_to_prtime(start),
+                ":end": Self::unixepoch_to_prtime(end),
+            }),
+            |row| {
+                Ok(VisitDetail {
+                    url: row.get(0)?,
+                    title: row.get(1).unwrap_or_else(|_| "".to_string()),
+                    visit_time: row.get(2)?,
+                    visit_type: row.get(3)?,
+                    source_visit_id: None,
+                })
+            },
+        )?;
+        for r in rows {
+            let detail = r?;
+            self.outside_query(|| f(detail))?;
+        }
+
+        Ok(())
+    }
+
+    /// Count all visits of `url`.
+    pub fn count_url_visits(&self, url: &str) -> Result<u64> {
+        let sql = r#"
+SELECT
+    count(1)
+FROM
+    onehistory_urls u,
+    onehistory_visits v ON u.id = v.item_id
+WHERE
+    url = :url
+"#;
+        let conn = self.conn();
+        let cnt: i64 = conn.query_row(sql, named_params! {":url": url}, |row| row.get(0))?;
+
+        Ok(cnt as u64)
+    }
+
+    /// Delete visits of `url` at `visit_time`(unix epoch in milliseconds), as returned
+    /// by `select_visits`. The url is also removed when it has no visits left.
+    /// Return number of deleted visits.
+    pub fn delete_visit(&self, url: &str, visit_time: i64) -> Result<usize> {
+        let sql = r#"
+DELETE FROM onehistory_visits
+WHERE item_id = (
+        SELECT
+            id
+        FROM
+            onehistory_urls
+        WHERE
+            url = :url)
+    AND visit_time BETWEEN :start AND :end
+"#;
+        let start = Self::unixepoch_to_prtime(visit_time);
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let params = named_params! {
+            ":url": url,
+            ":start": start,
+            // select_visits truncates PRTime into milliseconds
+            ":end": start + 999,
+        };
+        let titled: Option<bool> = tx
+            .query_row(
+                "SELECT coalesce(title != '', 0) FROM onehistory_urls WHERE url = :url",
+                named_params! {":url": url},
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(titled) = titled {
+            tx.execute(
+                &format!(
+                    r#"
+UPDATE onehistory_daily_summary AS s
+SET visits = visits - d.cnt
+FROM (
+    SELECT
+        {SUMMARY_DAY} AS day,
+        count(1) AS cnt
+    FROM
+        onehistory_visits
+    WHERE
+        item_id = (SELECT id FROM onehistory_urls WHERE url = :url)
+        AND visit_time BETWEEN :start AND :end
+        AND hidden = 0
+    GROUP BY
+        day) AS d
+WHERE
+    s.day = d.day AND s.host = :host
+"#
+                ),
+                named_params! {
+                    ":url": url,
+                    ":start": start,
+                    ":end": start + 999,
+                    ":host": Self::summary_host(url, titled),
+                },
+            )
+            .context("update onehistory_daily_summary")?;
+            tx.execute("DELETE FROM onehistory_daily_summary WHERE visits <= 0", [])?;
+        }
+        let deleted = tx.execute(sql, params)?;
+        let orphans = Self::delete_orphan_urls(&tx, Some(url))?;
+        tx.commit()?;
+        debug!("delete {deleted} visits of {url}, {orphans} urls without visits");
+
+        Ok(deleted)
+    }
+
+    /// Hide visit of `id` from queries of visits when `hidden` is true, or show it
+    /// again otherwise, see `with_include_hidden`. Hidden visits are kept, so they
+    /// still block imports of the same visit. Return false when there is no visit
+    /// of `id`.
+    pub fn set_visit_hidden(&self, id: i64, hidden: bool) -> Result<bool> {
+        let exists: i64 = self.conn().query_row(
+            "SELECT count(1) FROM onehistory_visits WHERE id = ?1",
+            [id],
+            |row| row.get(0),
+        )?;
+        if exists == 0 {
+            return Ok(false);
+        }
+        self.update_hidden("v.id = :id", &[(":id", &id)], hidden)?;
+
+        Ok(true)
+    }
+
+    /// Like `set_visit_hidden`, but for all visits of urls matching `url_like`, a
+    /// pattern of SQL LIKE such as `%example.com%`. Return number of visits changed.
+    pub fn hide_visits(&self, url_like: &str, hidden: bool) -> Result<usize> {
+        self.update_hidden("url LIKE :pattern", &[(":pattern", &url_like)], hidden)
+    }
+
+    // Set `hidden` of visits matching `filter`, daily summary only counts visible
+    // visits, so buckets of visits changed are updated too
+    fn update_hidden(
+        &self,
+        filter: &str,
+        params: &[(&str, &dyn ToSql)],
+        hidden: bool,
+    ) -> Result<usize> {
+        let filter = format!("{filter} AND hidden != :hidden");
+        let mut params = params.to_vec();
+        params.push((":hidden", &hidden));
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let summary = Self::daily_summary_where(&tx, &filter, &params)?;
+        {
+            let mut stat = tx.prepare(
+                r#"
+INSERT INTO onehistory_daily_summary (day, host, visits) VALUES (?1, ?2, ?3)
+ON CONFLICT (day, host)
+    DO UPDATE SET
+        visits = visits + excluded.visits
+"#,
+            )?;
+            for ((day, host), visits) in &summary {
+                let delta = if hidden { -visits } else { *visits };
+                stat.execute(params![day, host, delta])
+                    .context("update onehistory_daily_summary")?;
+            }
+        }
+        tx.execute("DELETE FROM onehistory_daily_summary WHERE visits <= 0", [])?;
+        let changed = tx.execute(
+            &format!(
+                r#"
+UPDATE onehistory_visits SET hidden = :hidden
+WHERE id IN (
+    SELECT
+        v.id
+    FROM
+        onehistory_visits v,
+        onehistory_urls u ON v.item_id = u.id
+    WHERE
+        {filter})
+"#
+            ),
+            &*params,
+        )?;
+        tx.commit()?;
+        debug!("set hidden of {changed} visits to {hidden}");
+
+        Ok(changed)
+    }
+
+    // Delete urls without visits, together with their full text index. Only `url` is
+    // checked when given, so single deletes avoid scanning all urls.
+    fn delete_orphan_urls(tx: &Transaction<'_>, url: Option<&str>) -> Result<usize> {
+        let filter = match url {
+            Some(_) => {
+                "url = :url AND NOT EXISTS (SELECT 1 FROM onehistory_visits WHERE item_id = onehistory_urls.id)"
+            }
+            None => "id NOT IN (SELECT item_id FROM onehistory_visits)",
+        };
+        let params: Vec<(&str, &dyn ToSql)> = match &url {
+            Some(url) => vec![(":url", url)],
+            None => vec![],
+        };
+        tx.execute(
+            &format!(
+                r#"
+INSERT INTO onehistory_urls_fts (onehistory_urls_fts, rowid, url, title)
+    SELECT 'delete', id, url, title FROM onehistory_urls
+    WHERE {filter};
+"#
+            ),
+            &*params,
+        )
+        .context("delete onehistory_urls_fts")?;
+        let deleted = tx
+            .execute(
+                &format!(
+                    r#"
+DELETE FROM onehistory_urls
+WHERE {filter};
+"#
+                ),
+                &*params,
+            )
+            .context("delete onehistory_urls")?;
+
+        Ok(deleted)
+    }
+
+    /// Delete visits before `before`(unix epoch in milliseconds), together with urls
+    /// left without visits, see `BackupOptions::retention_days`.
+    ///
+    /// Return number of deleted visits and urls.
+    pub fn prune_visits_before(&self, before: i64) -> Result<(usize, usize)> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let visits = tx
+            .execute(
+                "DELETE FROM onehistory_visits WHERE visit_time < ?1",
+                [Self::unixepoch_to_prtime(before)],
+            )
+            .context("delete onehistory_visits")?;
+        let urls = if visits > 0 {
+            Self::delete_orphan_urls(&tx, None)?
+        } else {
+            0
+        };
+        tx.commit()?;
+        // Pruned days may be partial, so summary is aggregated again
+        if visits > 0 {
+            Self::rebuild_daily_summary(&conn)?;
+        }
+
+        Ok((visits, urls))
+    }
+
+    /// Count urls without visits, which are left by deleting visits.
+    pub fn count_orphan_urls(&self) -> Result<u64> {
+        let cnt: i64 = self.conn().query_row(
+            "SELECT count(1) FROM onehistory_urls WHERE id NOT IN (SELECT item_id FROM onehistory_visits)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(cnt as u64)
+    }
+
+    /// Delete urls without visits, return number of deleted urls.
+    pub fn cleanup_orphan_urls(&self) -> Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let deleted = Self::delete_orphan_urls(&tx, None)?;
+        tx.commit()?;
+
+        Ok(deleted)
+    }
+
+    /// Urls visits point to which don't exist any more, together with number of
+    /// their visits, ordered by url id.
+    pub fn select_dangling_items(&self) -> Result<Vec<(i64, i64)>> {
+        let conn = self.conn();
+        let mut stat = conn.prepare(
+            r#"
+SELECT item_id, count(1) FROM onehistory_visits v
+WHERE NOT EXISTS (SELECT 1 FROM onehistory_urls u WHERE u.id = v.item_id)
+GROUP BY item_id
+ORDER BY item_id
+"#,
+        )?;
+        let rows = stat.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Run `PRAGMA foreign_key_check`, return number of violations. Tables of 1History
+    /// declare no foreign keys, so this only finds problems of tables added by others,
+    /// see `select_dangling_items` for visits without urls.
+    pub fn foreign_key_check(&self) -> Result<usize> {
+        let conn = self.conn();
+        let mut stat = conn.prepare("PRAGMA foreign_key_check")?;
+        let mut rows = stat.query([])?;
+        let mut violations = 0;
+        while rows.next()?.is_some() {
+            violations += 1;
+        }
+        Ok(violations)
+    }
+
+    /// Re-create urls of dangling visits by reading visits again from history files
+    /// they are imported from, matched by `source_visit_id`. A url keeps its old id,
+    /// or visits are merged into the url when it's persisted again under a new id.
+    /// Urls of visits whose history files are gone or rewritten are left missing.
+    ///
+    /// Return number of urls restored.
+    pub fn restore_dangling_urls(&self) -> Result<usize> {
+        let visits = {
+            let conn = self.conn();
+            let mut stat = conn.prepare(
+                r#"
+SELECT v.item_id, v.visit_time, v.source_visit_id, r.data_path
+FROM
+    onehistory_visits v,
+    import_records r ON r.id = v.source_id
+WHERE
+    v.source_visit_id IS NOT NULL
+    AND NOT EXISTS (SELECT 1 FROM onehistory_urls u WHERE u.id = v.item_id)
+ORDER BY r.data_path, v.item_id
+"#,
+            )?;
+            let rows = stat.query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<(i64, i64, i64, String)>>>()?
+        };
+
+        // One visit found in history file is enough for all visits of its url
+        let mut found: HashMap<i64, (String, String)> = HashMap::new();
+        let mut source: Option<Source> = None;
+        for (item_id, visit_time, source_visit_id, data_path) in visits {
+            if found.contains_key(&item_id) {
+                continue;
+            }
+            if source.as_ref().map(|s| s.path()) != Some(Path::new(&data_path)) {
+                source = match Source::open_read_only(&data_path) {
+                    Ok(s) => Some(s),
+                    Err(e) => {
+                        debug!("skip {data_path}, err:{e:?}");
+                        None
+                    }
+                };
+            }
+            let Some(src) = &source else {
+                continue;
+            };
+            let ms = visit_time / 1000;
+            let detail = src
+                .select(ms, ms + 1)?
+                .find(|d| d.source_visit_id == Some(source_visit_id));
+            if let Some(d) = detail {
+                found.insert(item_id, (d.url, d.title));
+            }
+        }
+        if found.is_empty() {
+            return Ok(0);
+        }
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for (item_id, (url, title)) in &found {
+            let target: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM onehistory_urls WHERE url = ?1",
+                    [url],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            match target {
+                None => {
+                    tx.execute(
+                        "INSERT INTO onehistory_urls (id, url, title) VALUES (?1, ?2, ?3)",
+                        params![item_id, url, title],
+                    )
+                    .context("insert onehistory_urls")?;
+                    if Self::table_exists(&tx, "onehistory_title_trigrams")? {
+                        Self::index_title_trigrams(&tx, *item_id, title)?;
+                    }
+                }
+                Some(target) => {
+                    tx.execute(
+                        "UPDATE OR IGNORE onehistory_visits SET item_id = ?1 WHERE item_id = ?2",
+                        params![target, item_id],
+                    )?;
+                    // Left ones collide with visits of target
+                    tx.execute(
+                        "DELETE FROM onehistory_visits WHERE item_id = ?1",
+                        [item_id],
+                    )?;
+                }
+            }
+        }
+        Self::rebuild_fts(&tx)?;
+        tx.commit()?;
+        // Restored visits are counted again
+        Self::rebuild_daily_summary(&conn)?;
+
+        Ok(found.len())
+    }
+
+    /// Delete visits whose urls don't exist, return number of deleted visits.
+    pub fn delete_dangling_visits(&self) -> Result<usize> {
+        let deleted = self
+            .conn()
+            .execute(
+                r#"
+DELETE FROM onehistory_visits AS v
+WHERE NOT EXISTS (SELECT 1 FROM onehistory_urls u WHERE u.id = v.item_id)
+"#,
+                [],
+            )
+            .context("delete onehistory_visits")?;
+        Ok(deleted)
+    }
+
+    /// Visit counts of each local day between `[start, end]`, counted as `mode`. Days
+    /// wholly in range are read from daily summary when there is no `keyword` and
+    /// visits are counted raw.
+    pub fn select_daily_count(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+        mode: CountMode,
+    ) -> Result<Vec<(i64, i64)>> {
+        let prtime = Self::unixepoch_to_prtime;
+        let days = match (&keyword, mode) {
+            (None, CountMode::Raw) if !self.filtered() => whole_days(start, end),
+            _ => None,
+        };
+        let Some(days) = days else {
+            return self.select_daily_count_raw(prtime(start), prtime(end), keyword, mode);
+        };
+
+        let raw = CountMode::Raw;
+        let mut res =
+            self.select_daily_count_raw(prtime(start), prtime(days.start) - 1, None, raw)?;
+        let conn = self.conn();
+        let mut stat = conn.prepare(
+            r#"
+SELECT
+    day,
+    sum(visits)
+FROM
+    onehistory_daily_summary
+WHERE
+    day BETWEEN :first AND :last
+GROUP BY
+    day
+ORDER BY
+    day
+"#,
+        )?;
+        let rows = stat.query_map(
+            named_params! {
+                ":first": days.first.to_string(),
+                ":last": days.last.to_string(),
+            },
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        for r in rows {
+            let (ymd, cnt): (String, i64) = r?;
+            res.push((ymd_midnight(&ymd)?, cnt));
+        }
+        drop(stat);
+        drop(conn);
+        res.extend(self.select_daily_count_raw(prtime(days.end), prtime(end), None, raw)?);
+
+        Ok(res)
+    }
+
+    // `start` and `end` are PRTime
+    fn select_daily_count_raw(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+        mode: CountMode,
+    ) -> Result<Vec<(i64, i64)>> {
+        let visit_day = "strftime ('%Y-%m-%d', visit_time / 1000000, 'unixepoch', 'localtime')";
+        let (columns, gap_filter) = match mode {
+            CountMode::Raw => (format!("{visit_day} AS visit_day"), "1".to_string()),
+            CountMode::UrlHour => (
+                format!("DISTINCT {visit_day} AS visit_day, item_id, visit_time / 3600000000"),
+                "1".to_string(),
+            ),
+            CountMode::MinInterval(interval) => (
+                format!(
+                    "{visit_day} AS visit_day, \
+                     visit_time - lag(visit_time) OVER (PARTITION BY item_id ORDER BY visit_time) AS gap"
+                ),
+                format!("gap IS NULL OR gap >= {}", interval.as_micros()),
+            ),
+        };
+        let filter = self.visit_filter(keyword);
+        let sql = format!(
+            r#"
+SELECT
+    visit_day,
+    count(1)
+FROM (
+    SELECT
+        {columns}
+    FROM
+        onehistory_visits v,
+        onehistory_urls u ON v.item_id = u.id
     WHERE
         visit_time BETWEEN :start AND :end
         AND {})
+    WHERE
+        {gap_filter}
     GROUP BY
         visit_day
     ORDER BY
         visit_day;
 "#,
-            Self::keyword_to_like(keyword)
+            filter
         );
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn();
         let mut stat = conn.prepare(&sql)?;
 
         let rows = stat.query_map(
-            named_params! {
-                ":start": Self::unixepoch_to_prtime(start),
-                ":end": Self::unixepoch_to_prtime(end),
-            },
+            &*filter.with(named_params! {
+                ":start": start,
+                ":end": end,
+            }),
             |row| Ok((row.get(0)?, row.get(1)?)),
         )?;
 
-        let mut res = Vec::new();
-        for r in rows {
-            let (ymd, cnt): (String, i64) = r?;
-            res.push((ymd_midnight(&ymd)?, cnt));
+        let mut res = Vec::new();
+        for r in rows {
+            // visit_day is NULL when visit_time is out of range of SQLite date functions
+            let (ymd, cnt): (Option<String>, i64) = r?;
+            match ymd.as_deref().map(ymd_midnight) {
+                Some(Ok(ts)) => res.push((ts, cnt)),
+                _ => debug!("skip {cnt} visits of invalid day {ymd:?}"),
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Top 100 domains by visits of urls with title between `[start, end]`. Days
+    /// wholly in range are read from daily summary when there is no `keyword`.
+    pub fn select_domain_top100(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+    ) -> Result<Vec<(String, i64)>> {
+        let prtime = Self::unixepoch_to_prtime;
+        let days = match keyword {
+            None if !self.filtered() => whole_days(start, end),
+            _ => None,
+        };
+        let mut url_counts = Vec::new();
+        let mut domain_top = HashMap::new();
+        match days {
+            None => url_counts = self.select_url_counts(prtime(start), prtime(end), keyword)?,
+            Some(days) => {
+                url_counts.extend(self.select_url_counts(
+                    prtime(start),
+                    prtime(days.start) - 1,
+                    None,
+                )?);
+                url_counts.extend(self.select_url_counts(prtime(days.end), prtime(end), None)?);
+                let conn = self.conn();
+                let mut stat = conn.prepare(
+                    r#"
+SELECT
+    host,
+    sum(visits)
+FROM
+    onehistory_daily_summary
+WHERE
+    day BETWEEN :first AND :last
+    AND host != ''
+GROUP BY
+    host
+"#,
+                )?;
+                let rows = stat.query_map(
+                    named_params! {
+                        ":first": days.first.to_string(),
+                        ":last": days.last.to_string(),
+                    },
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                for r in rows {
+                    let (host, cnt): (String, i64) = r?;
+                    *domain_top.entry(host).or_insert(0) += cnt;
+                }
+            }
+        }
+
+        for (url, cnt) in url_counts {
+            *domain_top.entry(domain_from(url)).or_insert(0) += cnt;
+        }
+        if self.canonical {
+            let mut merged = HashMap::new();
+            for (domain, cnt) in domain_top {
+                *merged
+                    .entry(canonical_domain(&domain).to_string())
+                    .or_insert(0) += cnt;
+            }
+            domain_top = merged;
+        }
+        let mut top_arr = domain_top.into_iter().collect::<Vec<(String, i64)>>();
+        top_arr.sort_by_key(|b| std::cmp::Reverse(b.1));
+
+        Ok(top_arr.into_iter().take(100).collect::<Vec<_>>())
+    }
+
+    /// Total visits, distinct urls and domains, and top 10 domains of visits between
+    /// `[start, end]` matching `keyword`, urls without title are also counted.
+    pub fn select_day_stats(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+    ) -> Result<DayStats> {
+        let filter = self.visit_filter(keyword.clone());
+        let sql = format!(
+            r#"
+SELECT
+    url,
+    count(1) AS cnt
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    visit_time BETWEEN :start AND :end
+    AND {}
+GROUP BY
+    url
+"#,
+            filter
+        );
+        let mut url_counts = self.select_pairs(
+            &sql,
+            &filter,
+            Self::unixepoch_to_prtime(start),
+            Self::unixepoch_to_prtime(end),
+        )?;
+        if self.canonical {
+            url_counts = canonical_counts(url_counts);
+        }
+        let mut stats = DayStats::from_url_counts(url_counts);
+        if !self.include_hidden {
+            // Same filters as visible visits, with `hidden = 1` instead
+            let filter = self.view().with_include_hidden(true).visit_filter(keyword);
+            let sql = format!(
+                r#"
+SELECT
+    count(1)
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    visit_time BETWEEN :start AND :end
+    AND {} AND hidden = 1
+"#,
+                filter
+            );
+            let hidden: i64 = self.conn().query_row(
+                &sql,
+                &*filter.with(named_params! {
+                    ":start": Self::unixepoch_to_prtime(start),
+                    ":end": Self::unixepoch_to_prtime(end),
+                }),
+                |row| row.get(0),
+            )?;
+            stats.hidden = hidden as u64;
+        }
+
+        Ok(stats)
+    }
+
+    /// Domains whose visits change most between the first and second half of
+    /// `[start, end]`, at most `limit` of each direction. Domains visited in only
+    /// one half are included, as they start or stop being visited.
+    pub fn select_domain_trends(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+        limit: usize,
+    ) -> Result<DomainTrends> {
+        let split_at = start + (end - start + 1) / 2;
+        let mut trends =
+            self.select_domain_changes((start, split_at - 1), (split_at, end), keyword)?;
+        trends.sort_by(|a, b| {
+            b.change()
+                .cmp(&a.change())
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+
+        let rising = trends
+            .iter()
+            .take_while(|t| t.change() > 0)
+            .take(limit)
+            .count();
+        let falling = trends
+            .iter()
+            .rev()
+            .take_while(|t| t.change() < 0)
+            .take(limit)
+            .count();
+        let mut falling = trends.split_off(trends.len() - falling);
+        falling.reverse();
+        trends.truncate(rising);
+
+        Ok(DomainTrends {
+            split_at,
+            rising: trends,
+            falling,
+        })
+    }
+
+    // Visits of each domain in `before` and `after`, which are `[start, end]` in
+    // unix_epoch_ms, domains visited in only one of them are included, in no order
+    pub(crate) fn select_domain_changes(
+        &self,
+        before: (i64, i64),
+        after: (i64, i64),
+        keyword: Option<String>,
+    ) -> Result<Vec<DomainTrend>> {
+        let before = self.select_domain_counts(before.0, before.1, keyword.clone())?;
+        let mut after = self.select_domain_counts(after.0, after.1, keyword)?;
+
+        let mut changes = before
+            .into_iter()
+            .map(|(domain, before)| {
+                let after = after.remove(&domain).unwrap_or(0);
+                DomainTrend {
+                    domain,
+                    before,
+                    after,
+                }
+            })
+            .collect::<Vec<_>>();
+        changes.extend(after.into_iter().map(|(domain, after)| DomainTrend {
+            domain,
+            before: 0,
+            after,
+        }));
+        Ok(changes)
+    }
+
+    // Visit counts grouped by domain between `[start, end]`, which are unix_epoch_ms
+    pub(crate) fn select_domain_counts(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+    ) -> Result<HashMap<String, i64>> {
+        let filter = self.visit_filter(keyword);
+        let sql = format!(
+            r#"
+SELECT
+    url,
+    count(1) AS cnt
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    visit_time BETWEEN :start AND :end
+    AND {}
+GROUP BY
+    url
+"#,
+            filter
+        );
+        let url_counts = self.select_pairs(
+            &sql,
+            &filter,
+            Self::unixepoch_to_prtime(start),
+            Self::unixepoch_to_prtime(end),
+        )?;
+
+        let mut domain_counts = HashMap::new();
+        for (url, cnt) in url_counts {
+            *domain_counts.entry(domain_from(url)).or_insert(0) += cnt;
+        }
+        Ok(domain_counts)
+    }
+
+    // Visit counts of urls with title, `start` and `end` are PRTime
+    fn select_url_counts(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+    ) -> Result<Vec<(String, i64)>> {
+        let filter = self.visit_filter(keyword);
+        let sql = format!(
+            r#"
+SELECT
+    url,
+    count(1) AS cnt
+FROM (
+    SELECT
+        url
+    FROM
+        onehistory_visits v,
+        onehistory_urls u ON v.item_id = u.id
+    WHERE
+        visit_time BETWEEN :start AND :end
+        AND title != '' AND {})
+GROUP BY
+    url
+ORDER BY
+    cnt DESC
+"#,
+            filter
+        );
+        self.select_pairs(&sql, &filter, start, end)
+    }
+
+    /// Visit counts grouped by top-level domain, such as `.com`, ordered by count
+    /// desc. URLs without a domain host(`data:`, `file:`...) are counted as `other`.
+    pub fn select_tld_breakdown(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+    ) -> Result<Vec<(String, i64)>> {
+        let filter = self.visit_filter(keyword);
+        let sql = format!(
+            r#"
+SELECT
+    url,
+    count(1) AS cnt
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    visit_time BETWEEN :start AND :end
+    AND {}
+GROUP BY
+    url
+"#,
+            filter
+        );
+        let url_counts = self.select_top100(&sql, &filter, start, end)?;
+
+        let mut tld_counts = HashMap::new();
+        for (url, cnt) in url_counts {
+            let tld = tld_from(&url).unwrap_or_else(|| "other".to_string());
+            *tld_counts.entry(tld).or_insert(0) += cnt;
         }
+        let mut res = tld_counts.into_iter().collect::<Vec<(String, i64)>>();
+        res.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
 
         Ok(res)
     }
 
-    pub fn select_domain_top100(
+    /// Visit counts by weekday and hour in local time, rows are weekdays starting
+    /// from Sunday, columns are hours.
+    pub fn select_hour_weekday_count(
         &self,
         start: i64,
         end: i64,
         keyword: Option<String>,
-    ) -> Result<Vec<(String, i64)>> {
+    ) -> Result<[[i64; 24]; 7]> {
+        let filter = self.visit_filter(keyword);
         let sql = format!(
             r#"
 SELECT
-    url,
-    count(1) AS cnt
-FROM (
-    SELECT
-        url
-    FROM
-        onehistory_visits v,
-        onehistory_urls u ON v.item_id = u.id
-    WHERE
-        visit_time BETWEEN :start AND :end
-        AND title != '' AND {})
+    CAST(strftime ('%w', visit_time / 1000000, 'unixepoch', 'localtime') AS integer) AS weekday,
+    CAST(strftime ('%H', visit_time / 1000000, 'unixepoch', 'localtime') AS integer) AS hour,
+    count(1)
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    visit_time BETWEEN :start AND :end
+    AND {}
 GROUP BY
-    url
-ORDER BY
-    cnt DESC
+    weekday, hour
 "#,
-            Self::keyword_to_like(keyword)
+            filter
         );
-        let url_top100 = self.select_top100(&sql, start, end)?;
+        let conn = self.conn();
+        let mut stat = conn.prepare(&sql)?;
 
-        let mut domain_top = HashMap::new();
-        for (url, cnt) in url_top100 {
-            let domain = domain_from(url);
-            let total = domain_top.entry(domain).or_insert(cnt);
-            *total += cnt;
+        let rows = stat.query_map(
+            &*filter.with(named_params! {
+                ":start": Self::unixepoch_to_prtime(start),
+                ":end": Self::unixepoch_to_prtime(end),
+            }),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        let mut res = [[0; 24]; 7];
+        for r in rows {
+            // NULL when visit_time is out of range of SQLite date functions
+            let (weekday, hour, cnt): (Option<usize>, Option<usize>, i64) = r?;
+            match (weekday, hour) {
+                (Some(weekday), Some(hour)) if weekday < 7 && hour < 24 => res[weekday][hour] = cnt,
+                _ => debug!("skip {cnt} visits of invalid time"),
+            }
         }
-        let mut top_arr = domain_top.into_iter().collect::<Vec<(String, i64)>>();
-        top_arr.sort_by(|a, b| b.1.cmp(&a.1));
 
-        Ok(top_arr.into_iter().take(100).collect::<Vec<_>>())
+        Ok(res)
     }
 
     pub fn select_title_top100(
@@ -370,6 +2858,7 @@ ORDER BY
         end: i64,
         keyword: Option<String>,
     ) -> Result<Vec<(String, i64)>> {
+        let filter = self.visit_filter(keyword);
         let sql = format!(
             r#"
 SELECT
@@ -390,20 +2879,55 @@ ORDER BY
     cnt DESC
 LIMIT 100;
 "#,
-            Self::keyword_to_like(keyword)
+            filter
         );
-        self.select_top100(&sql, start, end)
+        self.select_top100(&sql, &filter, start, end)
     }
 
-    fn select_top100(&self, sql: &str, start: i64, end: i64) -> Result<Vec<(String, i64)>> {
-        let conn = self.conn.lock().unwrap();
+    /// Top 100 urls with title by visits between `[start, end]` matching `keyword`,
+    /// variants of urls are grouped under their canonical url with `with_canonical`.
+    pub fn select_url_top100(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+    ) -> Result<Vec<(String, i64)>> {
+        let prtime = Self::unixepoch_to_prtime;
+        let mut url_counts = self.select_url_counts(prtime(start), prtime(end), keyword)?;
+        if self.canonical {
+            url_counts = canonical_counts(url_counts);
+        }
+        url_counts.truncate(100);
+        Ok(url_counts)
+    }
+
+    fn select_top100(
+        &self,
+        sql: &str,
+        filter: &VisitFilter,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<(String, i64)>> {
+        let prtime = Self::unixepoch_to_prtime;
+        self.select_pairs(sql, filter, prtime(start), prtime(end))
+    }
+
+    // `start` and `end` are PRTime, `filter` binds parameters of its part in `sql`
+    fn select_pairs(
+        &self,
+        sql: &str,
+        filter: &VisitFilter,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn();
         let mut stat = conn.prepare(sql)?;
 
         let rows = stat.query_map(
-            named_params! {
-                ":start": Self::unixepoch_to_prtime(start),
-                ":end": Self::unixepoch_to_prtime(end),
-            },
+            &*filter.with(named_params! {
+                ":start": start,
+                ":end": end,
+            }),
             |row| Ok((row.get(0)?, row.get(1)?)),
         )?;
 
@@ -415,6 +2939,479 @@ LIMIT 100;
         Ok(res)
     }
 
+    /// Statistics of browsing sessions between `start` and `end`, a gap longer than
+    /// `gap` between two visits starts a new session.
+    pub fn select_session_stats(
+        &self,
+        start: i64,
+        end: i64,
+        keyword: Option<String>,
+        gap: Duration,
+    ) -> Result<SessionStats> {
+        let mut builder = SessionStatsBuilder::new(gap);
+        self.for_each_visit(start, end, keyword, false, |visit| {
+            builder.push(visit);
+            Ok(())
+        })?;
+
+        Ok(builder.finish())
+    }
+
+    /// Record the beginning of a backup, so visits imported by it can be told
+    /// apart by `select_whatsnew`. Return id of the backup session.
+    pub fn begin_backup_session(&self) -> Result<i64> {
+        let now = now_ms();
+        let conn = self.conn();
+        conn.execute(
+            r#"
+INSERT INTO backup_sessions (started_at, max_visit_id)
+    SELECT :started_at, coalesce(max(id), 0) FROM onehistory_visits;
+"#,
+            named_params! {":started_at": now},
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Record one backup invocation, see `select_backup_runs`.
+    pub fn record_backup_run(&self, run: &BackupRun) -> Result<()> {
+        let errors = serde_json::to_string(&run.errors).context("serialize errors")?;
+        let timings = if run.timings.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&run.timings).context("serialize timings")?)
+        };
+        self.conn().execute(
+            r#"
+INSERT INTO backup_runs (started_at, finished_at, sources, imported, duplicated, skipped, errors, pruned, retention_days, timings)
+    VALUES (:started_at, :finished_at, :sources, :imported, :duplicated, :skipped, :errors, :pruned, :retention_days, :timings);
+"#,
+            named_params! {
+                ":started_at": run.started_at,
+                ":finished_at": run.finished_at,
+                ":sources": run.sources as i64,
+                ":imported": run.imported as i64,
+                ":duplicated": run.duplicated as i64,
+                ":skipped": run.skipped as i64,
+                ":errors": errors,
+                ":pruned": run.pruned as i64,
+                ":retention_days": run.retention_days.map(|days| days as i64),
+                ":timings": timings,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    /// Most recent `limit` backup runs, most recent first.
+    pub fn select_backup_runs(&self, limit: usize) -> Result<Vec<BackupRun>> {
+        let conn = self.conn();
+        let mut stat = conn.prepare(
+            r#"
+SELECT
+    started_at,
+    finished_at,
+    sources,
+    imported,
+    duplicated,
+    skipped,
+    errors,
+    pruned,
+    retention_days,
+    timings
+FROM
+    backup_runs
+ORDER BY
+    id DESC
+LIMIT :limit
+"#,
+        )?;
+        let rows = stat.query_map(named_params! {":limit": limit as i64}, |row| {
+            Ok((
+                BackupRun {
+                    started_at: row.get(0)?,
+                    finished_at: row.get(1)?,
+                    sources: row.get::<_, i64>(2)? as usize,
+                    imported: row.get::<_, i64>(3)? as usize,
+                    duplicated: row.get::<_, i64>(4)? as usize,
+                    skipped: row.get::<_, i64>(5)? as usize,
+                    pruned: row.get::<_, i64>(7)? as usize,
+                    retention_days: row.get::<_, Option<i64>>(8)?.map(|days| days as u64),
+                    errors: Vec::new(),
+                    timings: Vec::new(),
+                },
+                row.get::<_, String>(6)?,
+                row.get::<_, Option<String>>(9)?,
+            ))
+        })?;
+
+        let mut res = Vec::new();
+        for r in rows {
+            let (mut run, errors, timings) = r?;
+            run.errors = serde_json::from_str(&errors).context("parse errors of backup run")?;
+            if let Some(timings) = timings {
+                run.timings =
+                    serde_json::from_str(&timings).context("parse timings of backup run")?;
+            }
+            res.push(run);
+        }
+        Ok(res)
+    }
+
+    /// Max id of visits, used as watermark of sync bundles.
+    pub fn max_visit_id(&self) -> Result<i64> {
+        let id = self.conn().query_row(
+            "SELECT coalesce(max(id), 0) FROM onehistory_visits",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(id)
+    }
+
+    /// Pass visits with id in `(since, until]` to `f`, ordered by id. Unlike
+    /// `for_each_visit`, `visit_time` is kept as PRTime, so visits can be persisted
+    /// into another database as is.
+    ///
+    /// The database is locked during iteration, so `f` must not call back into it.
+    pub fn for_each_visit_between_ids<F>(&self, since: i64, until: i64, mut f: F) -> Result<()>
+    where
+        F: FnMut(VisitDetail) -> Result<()>,
+    {
+        let sql = r#"
+SELECT
+    url,
+    title,
+    visit_time,
+    visit_type
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    v.id > :since AND v.id <= :until
+ORDER BY
+    v.id
+"#;
+        let conn = self.conn();
+        let mut stat = conn.prepare(sql)?;
+        let mut rows = stat.query(named_params! {
+            ":since": since,
+            ":until": until,
+        })?;
+        while let Some(row) = rows.next()? {
+            let detail = VisitDetail {
+                url: row.get(0)?,
+                title: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                visit_time: row.get(2)?,
+                visit_type: row.get(3)?,
+                source_visit_id: None,
+            };
+            self.outside_query(|| f(detail))?;
+        }
+
+        Ok(())
+    }
+
+    /// Watermarks of peers whose sync bundles are imported, as
+    /// `(peer, watermark, updated_at)`, `updated_at` is in unix epoch milliseconds.
+    pub fn select_sync_peers(&self) -> Result<Vec<(String, i64, i64)>> {
+        let conn = self.conn();
+        let mut stat =
+            conn.prepare("SELECT peer, watermark, updated_at FROM sync_peers ORDER BY peer")?;
+        let rows = stat.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+
+        let mut res = Vec::new();
+        for r in rows {
+            res.push(r?);
+        }
+        Ok(res)
+    }
+
+    /// Advance watermark of `peer`, it never goes backwards, so applying an older
+    /// bundle again is harmless. Return the watermark after update.
+    pub fn update_sync_watermark(&self, peer: &str, watermark: i64) -> Result<i64> {
+        let now = now_ms();
+        let conn = self.conn();
+        conn.execute(
+            r#"
+INSERT INTO sync_peers (peer, watermark, updated_at)
+    VALUES (:peer, :watermark, :updated_at)
+ON CONFLICT (peer)
+    DO UPDATE SET
+        watermark = max(watermark, :watermark),
+        updated_at = :updated_at;
+"#,
+            named_params! {
+                ":peer": peer,
+                ":watermark": watermark,
+                ":updated_at": now,
+            },
+        )?;
+        let watermark = conn.query_row(
+            "SELECT watermark FROM sync_peers WHERE peer = ?",
+            [peer],
+            |row| row.get(0),
+        )?;
+
+        Ok(watermark)
+    }
+
+    /// Visits imported by the most recent backup session, grouped by domain.
+    /// `None` if no backup session is recorded.
+    pub fn select_whatsnew(&self) -> Result<Option<WhatsNew>> {
+        let conn = self.conn();
+        let session = conn
+            .query_row(
+                r#"
+SELECT
+    started_at,
+    max_visit_id
+FROM
+    backup_sessions
+ORDER BY
+    id DESC
+LIMIT 1
+"#,
+                [],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()?;
+        let (backup_at, max_visit_id) = match session {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        let mut stat = conn.prepare(
+            r#"
+SELECT
+    url,
+    count(1) AS cnt
+FROM
+    onehistory_visits v,
+    onehistory_urls u ON v.item_id = u.id
+WHERE
+    v.id > :max_visit_id
+GROUP BY
+    url
+"#,
+        )?;
+        let rows = stat.query_map(named_params! {":max_visit_id": max_visit_id}, |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        let mut domain_counts = HashMap::new();
+        for row in rows {
+            let (url, cnt) = row?;
+            *domain_counts.entry(domain_from(url)).or_insert(0) += cnt;
+        }
+        let mut domains = domain_counts.into_iter().collect::<Vec<(String, i64)>>();
+        domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Ok(Some(WhatsNew {
+            backup_at,
+            visits: domains.iter().map(|(_, cnt)| *cnt as u64).sum(),
+            domains,
+        }))
+    }
+
+    /// Compare visits and titles of this database with another one at `other`, which
+    /// is attached read-only. Visits are matched by url and visit time, with up to
+    /// `samples` rows of each difference listed.
+    pub fn diff(&self, other: &Path, samples: usize) -> Result<DbDiff> {
+        let conn = self.conn();
+        conn.execute("ATTACH DATABASE ?1 AS other", [read_only_uri(other)])
+            .context("attach other database")?;
+        let res = Self::diff_attached(&conn, samples);
+        conn.execute("DETACH DATABASE other", [])?;
+        res
+    }
+
+    fn diff_attached(conn: &Connection, samples: usize) -> Result<DbDiff> {
+        let only_here = Self::diff_side(conn, "main", "other", samples)?;
+        let only_other = Self::diff_side(conn, "other", "main", samples)?;
+
+        let titles = r#"
+FROM
+    main.onehistory_urls u,
+    other.onehistory_urls ou ON ou.url = u.url
+WHERE
+    u.title IS NOT ou.title
+"#;
+        let title_diffs = conn.query_row(&format!("SELECT count(1) {titles}"), [], |row| {
+            row.get::<_, i64>(0)
+        })? as u64;
+        let mut stat = conn.prepare(&format!(
+            "SELECT u.url, u.title, ou.title {titles} ORDER BY u.url LIMIT :samples"
+        ))?;
+        let title_samples = stat
+            .query_map(named_params! {":samples": samples as i64}, |row| {
+                Ok(TitleDiff {
+                    url: row.get(0)?,
+                    title: row.get(1)?,
+                    other_title: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(DbDiff {
+            only_here,
+            only_other,
+            title_diffs,
+            title_samples,
+        })
+    }
+
+    // Visits of schema `this` without the same url and visit time in schema `that`
+    fn diff_side(conn: &Connection, this: &str, that: &str, samples: usize) -> Result<DiffSide> {
+        // Databases of older versions don't record sources
+        let has_source = conn.query_row(
+            "SELECT count(1) FROM pragma_table_info('onehistory_visits', ?1) WHERE name = 'source_id'",
+            [this],
+            |row| row.get::<_, i64>(0),
+        )? > 0;
+        let source_id = if has_source { "v.source_id" } else { "NULL" };
+        let only = format!(
+            r#"
+SELECT
+    u.url,
+    u.title,
+    v.visit_time,
+    v.visit_type,
+    {source_id} AS source_id
+FROM
+    {this}.onehistory_visits v,
+    {this}.onehistory_urls u ON v.item_id = u.id
+WHERE
+    NOT EXISTS (
+        SELECT
+            1
+        FROM
+            {that}.onehistory_visits tv,
+            {that}.onehistory_urls tu ON tv.item_id = tu.id
+        WHERE
+            tu.url = u.url
+            AND tv.visit_time = v.visit_time
+    )
+"#
+        );
+
+        let mut stat = conn.prepare(&format!(
+            r#"
+SELECT
+    strftime('%Y-%m', visit_time / 1000000, 'unixepoch', 'localtime') AS month,
+    count(1)
+FROM ({only})
+GROUP BY
+    month
+ORDER BY
+    month
+"#
+        ))?;
+        let by_month = stat
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut stat = conn.prepare(&format!(
+            r#"
+SELECT
+    r.data_path,
+    count(1) AS cnt
+FROM ({only}) d
+    LEFT JOIN {this}.import_records r ON d.source_id = r.id
+GROUP BY
+    r.data_path
+ORDER BY
+    cnt DESC,
+    r.data_path
+"#
+        ))?;
+        let by_source = stat
+            .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut stat = conn.prepare(&format!(
+            "SELECT url, title, visit_time, visit_type FROM ({only}) ORDER BY visit_time LIMIT :samples"
+        ))?;
+        let samples = stat
+            .query_map(named_params! {":samples": samples as i64}, |row| {
+                Ok(VisitDetail {
+                    url: row.get(0)?,
+                    title: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    visit_time: row.get::<_, i64>(2)? / 1000,
+                    visit_type: row.get(3)?,
+                    source_visit_id: None,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(DiffSide {
+            visits: by_month.iter().map(|(_, cnt)| cnt).sum(),
+            by_month,
+            by_source,
+            samples,
+        })
+    }
+
+    /// Check integrity of the whole database, error with problems found by SQLite.
+    pub fn integrity_check(&self) -> Result<()> {
+        let conn = self.conn();
+        let mut stat = conn.prepare("PRAGMA integrity_check")?;
+        let problems = stat
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if problems != ["ok"] {
+            return Err(anyhow!("integrity check failed, {}", problems.join("; ")).into());
+        }
+        Ok(())
+    }
+
+    /// DDL of tables, indexes, triggers and views as they currently exist in database,
+    /// in the order they are created, together with `user_version`. Objects of SQLite
+    /// itself(`sqlite_*`) and shadow tables of full text search are left out, the latter
+    /// are created by their virtual table.
+    pub fn select_schema(&self) -> Result<(i64, Vec<String>)> {
+        let conn = self.conn();
+        let user_version = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let mut stat = conn.prepare(
+            r#"
+SELECT sql FROM sqlite_master m
+WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite\_%' ESCAPE '\'
+    AND NOT EXISTS (
+        SELECT 1 FROM sqlite_master v
+        WHERE v.sql LIKE 'CREATE VIRTUAL TABLE%'
+            AND m.name IN (v.name || '_data', v.name || '_idx', v.name || '_content',
+                           v.name || '_docsize', v.name || '_config'))
+ORDER BY rowid
+"#,
+        )?;
+        let ddl = stat
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok((user_version, ddl))
+    }
+
+    /// Stamp of database, such as `27534-27534-53-53-2-0`, which changes when visits
+    /// or urls are added or removed, or anything is committed, such as title edits,
+    /// url normalization or hidden domains, by this connection(`total_changes`) or
+    /// others(`data_version`).
+    pub fn select_data_version(&self) -> Result<String> {
+        let sql = r#"
+SELECT
+    (SELECT count(1) FROM onehistory_visits),
+    (SELECT coalesce(max(id), 0) FROM onehistory_visits),
+    (SELECT count(1) FROM onehistory_urls),
+    (SELECT coalesce(max(id), 0) FROM onehistory_urls),
+    (SELECT data_version FROM pragma_data_version),
+    total_changes()
+"#;
+        let version = self.conn().query_row(sql, [], |row| {
+            let nums = (0..6)
+                .map(|i| row.get::<_, i64>(i).map(|n| n.to_string()))
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(nums.join("-"))
+        })?;
+
+        Ok(version)
+    }
+
     pub fn select_min_max_time(&self) -> Result<(i64, i64)> {
         let sql = r#"
 SELECT
@@ -423,7 +3420,7 @@ SELECT
 FROM
     onehistory_visits
 "#;
-        let conn = self.conn.lock().unwrap();
+        let conn = self.conn();
         let mut stat = conn.prepare(sql)?;
 
         let time_range = stat.query_row([], |row| Ok((row.get(0)?, row.get(1)?)))?;
@@ -431,3 +3428,234 @@ FROM
         Ok(time_range)
     }
 }
+
+// URI of SQLite file at `path`, opened read-only
+fn read_only_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let path = path
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    // Windows paths such as C:/History are written as /C:/History
+    let slash = if path.chars().nth(1) == Some(':') {
+        "/"
+    } else {
+        ""
+    };
+    format!("file:{slash}{path}?mode=ro")
+}
+
+// Escape `\`, `%` and `_` of `s` for LIKE with `ESCAPE '\'`
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Move 1History database at `from` to `to`, such as from `LEGACY_DB_FILE` to
+/// `DATA_DB_FILE`. It's copied and checked before `from` is removed, and `to` must
+/// not exist.
+pub fn migrate_db<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    if !from.exists() {
+        return Err(anyhow!("{} not found", from.display()).into());
+    }
+    if to.exists() {
+        return Err(anyhow!("{} already exists", to.display()).into());
+    }
+    if let Some(dir) = to.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+    }
+    // Copied under another name first, so `to` is either absent or complete
+    let mut copy = to.as_os_str().to_owned();
+    copy.push(".migrating");
+    let copy = PathBuf::from(copy);
+    fs::copy(from, &copy).with_context(|| format!("copy to {}", copy.display()))?;
+    let checked = Database::open(&copy).and_then(|db| db.integrity_check());
+    if let Err(e) = checked {
+        let _ = fs::remove_file(&copy);
+        return Err(e);
+    }
+    fs::rename(&copy, to).with_context(|| format!("rename to {}", to.display()))?;
+    fs::remove_file(from).with_context(|| format!("remove {}", from.display()))?;
+
+    Ok(())
+}
+
+// Tables salvaged by `recover_db` with their columns, urls go before visits of them
+const RECOVER_TABLES: [(&str, &str); 3] = [
+    ("onehistory_urls", "id, url, title"),
+    ("onehistory_visits", "id, item_id, visit_time, visit_type"),
+    (
+        "import_records",
+        "id, last_import, data_path, imported, duplicated",
+    ),
+];
+
+/// Problems found by `PRAGMA integrity_check` of SQLite file at `db_file`, empty when
+/// it's healthy. Unlike `Database::integrity_check`, the file is opened read-only,
+/// so it works for databases too broken to be opened by `Database::open`.
+pub fn check_db_integrity<P: AsRef<Path>>(db_file: P) -> Result<Vec<String>> {
+    let conn = Connection::open_with_flags(db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let problems = conn.prepare("PRAGMA integrity_check").and_then(|mut stat| {
+        stat.query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()
+    });
+    match problems {
+        Ok(problems) if problems == ["ok"] => Ok(Vec::new()),
+        Ok(problems) => Ok(problems),
+        // Some corruptions even fail the check itself
+        Err(e) => Ok(vec![e.to_string()]),
+    }
+}
+
+/// Salvage rows still readable from corrupted 1History database at `from` into a
+/// fresh database at `to`, which must not exist. `from` is opened read-only and
+/// left untouched.
+///
+/// Rows are read in order of id, unreadable ranges are skipped, and visits whose url
+/// is lost are dropped. Full text search index and daily summary of `to` are rebuilt
+/// from rows recovered.
+pub fn recover_db<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<Vec<RecoveredTable>> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    if !from.exists() {
+        return Err(anyhow!("{} not found", from.display()).into());
+    }
+    if to.exists() {
+        return Err(anyhow!("{} already exists", to.display()).into());
+    }
+    let src = Connection::open_with_flags(from, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let db = Database::open(to).context("create recovered DB")?;
+
+    let mut tables = Vec::with_capacity(RECOVER_TABLES.len());
+    {
+        let mut conn = db.conn();
+        let tx = conn.transaction()?;
+        for (table, columns) in RECOVER_TABLES {
+            let total = src
+                .query_row(&format!("SELECT count(1) FROM {table}"), [], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .map_err(|e| debug!("count {table} failed, err:{e}"))
+                .ok()
+                .map(|n| n as usize);
+            let recovered = salvage_table(&src, &tx, table, columns)?;
+            tables.push(RecoveredTable {
+                table: table.to_string(),
+                recovered,
+                total,
+            });
+        }
+        let dropped = tx.execute(
+            "DELETE FROM onehistory_visits WHERE item_id NOT IN (SELECT id FROM onehistory_urls)",
+            [],
+        )?;
+        if let Some(visits) = tables.iter_mut().find(|t| t.table == "onehistory_visits") {
+            visits.recovered -= dropped;
+        }
+        Database::rebuild_fts(&tx)?;
+        tx.commit()?;
+    }
+    db.rebuild_summary()?;
+
+    Ok(tables)
+}
+
+// Copy rows of `table` from `src` into `dst` in order of id, and return number of
+// rows copied. Reading is resumed after an unreadable row with growing steps, so a
+// corrupted page costs a few retries rather than one for each id in it.
+fn salvage_table(src: &Connection, dst: &Connection, table: &str, columns: &str) -> Result<usize> {
+    let select = format!("SELECT {columns} FROM {table} WHERE id >= ?1 ORDER BY id");
+    let placeholders = vec!["?"; columns.split(',').count()].join(", ");
+    let insert = format!("INSERT OR IGNORE INTO {table} ({columns}) VALUES ({placeholders})");
+    let mut insert = dst.prepare(&insert)?;
+
+    let (mut next_id, mut step, mut recovered) = (i64::MIN, 1_i64, 0);
+    loop {
+        let mut last_id = None;
+        let failed = match src.prepare(&select) {
+            Ok(mut stat) => {
+                let mut rows = stat.query([next_id])?;
+                loop {
+                    match rows.next() {
+                        Ok(Some(row)) => {
+                            let values = (0..row.as_ref().column_count())
+                                .map(|i| row.get::<_, Value>(i))
+                                .collect::<rusqlite::Result<Vec<_>>>();
+                            let Ok(values) = values else {
+                                break true;
+                            };
+                            if let Value::Integer(id) = values[0] {
+                                last_id = Some(id);
+                            }
+                            recovered += insert.execute(params_from_iter(values))?;
+                        }
+                        Ok(None) => break false,
+                        Err(e) => {
+                            debug!("read {table} after id {last_id:?} failed, err:{e}");
+                            break true;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("read {table} failed, err:{e}");
+                return Ok(recovered);
+            }
+        };
+        if !failed {
+            return Ok(recovered);
+        }
+        // Skip the unreadable row, further when it fails again right there
+        step = match last_id {
+            Some(_) => 1,
+            None => step.saturating_mul(2),
+        };
+        let from = last_id.unwrap_or(next_id);
+        match from.checked_add(step) {
+            Some(id) if step < i64::MAX => next_id = id,
+            _ => return Ok(recovered),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::Error, types::ServerError};
+
+    #[test]
+    fn test_query_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(dir.path().join("onehistory.db"))
+            .unwrap()
+            .with_query_timeout(Some(Duration::from_millis(50)));
+        // Never ends without timeout
+        let slow = "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c";
+        let begin = Instant::now();
+        let err = Error::from(
+            db.conn()
+                .query_row(slow, [], |row| row.get::<_, i64>(0))
+                .unwrap_err(),
+        );
+        assert!(begin.elapsed() < Duration::from_secs(5));
+        assert!(err.is_interrupted());
+        assert_eq!(ServerError::from(err).e, "query timed out");
+
+        // Deadline is renewed for each statement
+        std::thread::sleep(Duration::from_millis(100));
+        db.count_visits(0, 1_642_204_800_000, None).unwrap();
+
+        // Time spent by callers between rows is not counted
+        let conn = db.conn();
+        let mut stat = conn
+            .prepare("WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 5000) SELECT x FROM c")
+            .unwrap();
+        let mut rows = stat.query([]).unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            if row.get::<_, i64>(0).unwrap() <= 3 {
+                db.outside_query(|| std::thread::sleep(Duration::from_millis(30)));
+            }
+        }
+    }
+}
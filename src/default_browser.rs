@@ -0,0 +1,198 @@
+use lazy_static::lazy_static;
+use log::debug;
+use std::process::Command;
+
+lazy_static! {
+    // Detected once, it runs external commands
+    static ref DEFAULT_BROWSER: Option<&'static str> = detect();
+}
+
+/// Browser of `DEFAULT_PROFILES` keys, such as `chrome` or `brave-beta`, which is set
+/// as default browser of current user. `None` when it can't be determined.
+pub(crate) fn default_browser() -> Option<&'static str> {
+    *DEFAULT_BROWSER
+}
+
+/// Whether `DEFAULT_PROFILES` key `key`, such as `chrome-flatpak-linux`, belongs to
+/// `browser`.
+pub(crate) fn is_browser_of(key: &str, browser: &str) -> bool {
+    let key = ["-linux", "-macos", "-windows"]
+        .iter()
+        .find_map(|os| key.strip_suffix(os))
+        .unwrap_or(key);
+    key.strip_suffix("-flatpak").unwrap_or(key) == browser
+}
+
+// Map identifier of default browser, which is a desktop file on Linux, bundle id
+// on macOS, and ProgId on Windows, to browser of `DEFAULT_PROFILES` keys.
+fn browser_of_handler(handler: &str) -> Option<&'static str> {
+    let handler = handler.to_lowercase();
+    let browser = if handler.contains("brave") {
+        if handler.contains("beta") || handler.contains("bravebhtml") {
+            "brave-beta"
+        } else if handler.contains("nightly") || handler.contains("bravesshtm") {
+            "brave-nightly"
+        } else {
+            "brave"
+        }
+    } else if handler.contains("operagx") || handler.contains("opera-gx") {
+        "opera-gx"
+    } else if handler.contains("opera") {
+        "opera"
+    } else if handler.contains("vivaldi") {
+        "vivaldi"
+    } else if handler.contains("chromium") {
+        "chromium"
+    } else if handler.contains("chrome") {
+        "chrome"
+    } else if handler.contains("firefox") {
+        "firefox"
+    } else if handler.contains("edge") {
+        "edge"
+    } else if handler.contains("safari") {
+        "safari"
+    } else {
+        return None;
+    };
+    Some(browser)
+}
+
+// Stdout of `cmd`, `None` when it fails to run or exits with error
+fn output_of(cmd: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(cmd).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn default_handler() -> Option<String> {
+    output_of("xdg-settings", &["get", "default-web-browser"])
+        .filter(|h| !h.is_empty())
+        .or_else(|| output_of("xdg-mime", &["query", "default", "x-scheme-handler/https"]))
+}
+
+#[cfg(target_os = "macos")]
+fn default_handler() -> Option<String> {
+    let handlers = output_of(
+        "defaults",
+        &[
+            "read",
+            "com.apple.LaunchServices/com.apple.launchservices.secure",
+            "LSHandlers",
+        ],
+    )?;
+    Some(https_handler(&handlers).unwrap_or_else(|| "com.apple.safari".to_string()))
+}
+
+// Keys are sorted in output of `defaults`, so LSHandlerRoleAll of a handler comes
+// right before its LSHandlerURLScheme.
+#[cfg(any(target_os = "macos", test))]
+fn https_handler(handlers: &str) -> Option<String> {
+    let mut role = None;
+    for line in handlers.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("LSHandlerRoleAll = ") {
+            role = Some(value.trim_end_matches(';').trim_matches('"').to_string());
+        } else if line == "LSHandlerURLScheme = https;" {
+            return role;
+        } else if line.starts_with('}') {
+            role = None;
+        }
+    }
+    None
+}
+
+#[cfg(windows)]
+fn default_handler() -> Option<String> {
+    let output = output_of(
+        "reg",
+        &[
+            "query",
+            r"HKCU\Software\Microsoft\Windows\Shell\Associations\UrlAssociations\https\UserChoice",
+            "/v",
+            "ProgId",
+        ],
+    )?;
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("ProgId"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .map(|prog_id| prog_id.to_string())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn default_handler() -> Option<String> {
+    None
+}
+
+fn detect() -> Option<&'static str> {
+    let handler = default_handler();
+    debug!("default browser handler: {handler:?}");
+    browser_of_handler(&handler?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser_of_handler() {
+        let cases = vec![
+            ("google-chrome.desktop", Some("chrome")),
+            ("org.mozilla.firefox.desktop", Some("firefox")),
+            ("brave-browser-beta.desktop", Some("brave-beta")),
+            ("com.brave.Browser.nightly", Some("brave-nightly")),
+            ("BraveHTML", Some("brave")),
+            ("chromium-browser.desktop", Some("chromium")),
+            ("com.operasoftware.OperaGX", Some("opera-gx")),
+            ("opera.desktop", Some("opera")),
+            ("com.operasoftware.Opera", Some("opera")),
+            ("OperaStable", Some("opera")),
+            ("MSEdgeHTM", Some("edge")),
+            ("vivaldi-stable.desktop", Some("vivaldi")),
+            ("com.vivaldi.Vivaldi", Some("vivaldi")),
+            ("FirefoxURL-308046B0AF4A39CB", Some("firefox")),
+            ("com.apple.safari", Some("safari")),
+            ("org.gnome.Epiphany.desktop", None),
+        ];
+        for (handler, expected) in cases {
+            assert_eq!(browser_of_handler(handler), expected, "{handler}");
+        }
+    }
+
+    #[test]
+    fn test_is_browser_of() {
+        assert!(is_browser_of("chrome-macos", "chrome"));
+        assert!(is_browser_of("chrome-flatpak-linux", "chrome"));
+        assert!(!is_browser_of("chromium-linux", "chrome"));
+        assert!(!is_browser_of("brave-beta-windows", "brave"));
+        assert!(is_browser_of("brave-beta-windows", "brave-beta"));
+        assert!(!is_browser_of("opera-gx-windows", "opera"));
+    }
+
+    #[test]
+    fn test_https_handler() {
+        let handlers = r#"(
+        {
+        LSHandlerContentType = "public.html";
+        LSHandlerPreferredVersions =         {
+            LSHandlerRoleAll = "-";
+        };
+        LSHandlerRoleAll = "org.mozilla.firefox";
+    },
+        {
+        LSHandlerPreferredVersions =         {
+            LSHandlerRoleAll = "-";
+        };
+        LSHandlerRoleAll = "com.google.chrome";
+        LSHandlerURLScheme = https;
+    }
+)"#;
+        assert_eq!(
+            https_handler(handlers).as_deref(),
+            Some("com.google.chrome")
+        );
+        assert_eq!(https_handler("()"), None);
+    }
+}
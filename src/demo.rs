@@ -0,0 +1,355 @@
+use crate::{
+    database::Database,
+    error::Result,
+    progress::ProgressCollector,
+    types::VisitDetail,
+    util::{tomorrow_midnight, ymd_midnight},
+};
+use anyhow::{anyhow, Context};
+use std::path::Path;
+
+const ONE_DAY: i64 = 3_600_000 * 24;
+
+// Domain, weight and pages of (path, title)
+type Site = (&'static str, u32, &'static [(&'static str, &'static str)]);
+
+const SITES: &[Site] = &[
+    (
+        "github.com",
+        30,
+        &[
+            ("/", "GitHub"),
+            ("/notifications", "Notifications"),
+            (
+                "/rust-lang/rust",
+                "rust-lang/rust: Empowering everyone to build reliable and efficient software.",
+            ),
+            (
+                "/1History/1History",
+                "1History/1History: All your history in one file.",
+            ),
+            ("/pulls", "Pull Requests"),
+        ],
+    ),
+    (
+        "stackoverflow.com",
+        18,
+        &[
+            (
+                "/questions/tagged/rust",
+                "Newest 'rust' Questions - Stack Overflow",
+            ),
+            (
+                "/questions/tagged/sqlite",
+                "Newest 'sqlite' Questions - Stack Overflow",
+            ),
+            (
+                "/questions/tagged/emacs",
+                "Newest 'emacs' Questions - Stack Overflow",
+            ),
+        ],
+    ),
+    ("www.google.com", 25, &[("/search", "Google Search")]),
+    (
+        "news.ycombinator.com",
+        12,
+        &[
+            ("/", "Hacker News"),
+            ("/newest", "New Links | Hacker News"),
+            ("/ask", "Ask | Hacker News"),
+        ],
+    ),
+    (
+        "www.youtube.com",
+        10,
+        &[
+            ("/", "YouTube"),
+            ("/feed/subscriptions", "Subscriptions - YouTube"),
+            ("/watch", "Video - YouTube"),
+        ],
+    ),
+    (
+        "doc.rust-lang.org",
+        9,
+        &[
+            ("/std/", "std - Rust"),
+            ("/book/", "The Rust Programming Language"),
+            (
+                "/std/collections/struct.HashMap.html",
+                "HashMap in std::collections - Rust",
+            ),
+        ],
+    ),
+    (
+        "docs.rs",
+        6,
+        &[
+            ("/rusqlite/latest/rusqlite/", "rusqlite - Rust"),
+            ("/tokio/latest/tokio/", "tokio - Rust"),
+            ("/serde/latest/serde/", "serde - Rust"),
+        ],
+    ),
+    (
+        "en.wikipedia.org",
+        7,
+        &[
+            ("/wiki/SQLite", "SQLite - Wikipedia"),
+            ("/wiki/Web_browser", "Web browser - Wikipedia"),
+            ("/wiki/Unix_time", "Unix time - Wikipedia"),
+        ],
+    ),
+    (
+        "www.reddit.com",
+        6,
+        &[
+            ("/r/rust/", "The Rust Programming Language - Reddit"),
+            ("/r/emacs/", "Emacs - Reddit"),
+        ],
+    ),
+    (
+        "emacs-china.org",
+        5,
+        &[
+            ("/", "Emacs China"),
+            ("/latest", "Latest topics - Emacs China"),
+        ],
+    ),
+    (
+        "mail.google.com",
+        8,
+        &[("/mail/u/0/#inbox", "Inbox - Gmail")],
+    ),
+    (
+        "twitter.com",
+        5,
+        &[
+            ("/home", "Home / Twitter"),
+            ("/notifications", "Notifications / Twitter"),
+        ],
+    ),
+    (
+        "crates.io",
+        3,
+        &[
+            ("/", "crates.io: Rust Package Registry"),
+            ("/crates/onehistory", "onehistory - crates.io"),
+        ],
+    ),
+    ("www.bbc.co.uk", 3, &[("/news", "Home - BBC News")]),
+    ("localhost:9960", 2, &[("/", "1History Statistics")]),
+];
+
+// Appended to search and video pages, so they are not all the same url
+const QUERIES: &[&str] = &[
+    "rust+lifetime",
+    "sqlite+fts5",
+    "emacs+org-mode",
+    "warp+compression",
+    "chrome+history+file",
+    "firefox+places.sqlite",
+    "tokio+spawn_blocking",
+    "clap+derive",
+    "minijinja",
+    "dst+timezone",
+];
+
+// Relative visits in each hour of a day, peak in working hours and evening
+const HOUR_WEIGHTS: [u32; 24] = [
+    2, 1, 1, 0, 0, 0, 1, 3, 6, 9, 10, 9, 6, 8, 10, 10, 9, 7, 6, 8, 9, 8, 6, 4,
+];
+
+/// Options of `generate_demo`.
+pub struct DemoOptions {
+    /// Number of days with history, ending on `until`.
+    pub days: u32,
+    /// Average visits per day, actual count varies by weekday and random bursts.
+    pub visits_per_day: u32,
+    /// Same seed always generates same visits for each day.
+    pub seed: u64,
+    /// Last day of history in YYYY-MM-DD, yesterday when `None`.
+    pub until: Option<String>,
+}
+
+// SplitMix64, good enough for fake data, and stable across versions
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, n)
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    // Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Index picked with probability proportional to its weight
+    fn weighted(&mut self, weights: &[u32]) -> usize {
+        let total = weights.iter().sum::<u32>() as u64;
+        let mut n = self.below(total) as u32;
+        for (i, w) in weights.iter().enumerate() {
+            if n < *w {
+                return i;
+            }
+            n -= w;
+        }
+        unreachable!("n is less than total weight")
+    }
+}
+
+// Generate visits of `days` ending before `end`(unix epoch in milliseconds), visit_time
+// is in PRTime. Each day is generated from `seed` and its date only, so a date has
+// the same visits whenever it's generated.
+fn demo_visits(options: &DemoOptions, end: i64) -> Vec<VisitDetail> {
+    let site_weights = SITES.iter().map(|site| site.1).collect::<Vec<_>>();
+    let mut visits = Vec::new();
+    for day in (1..=options.days as i64).rev() {
+        let midnight = end - day * ONE_DAY;
+        // Days since 1970-01-01, midnight is local so round it to the nearest UTC day
+        let date = (midnight + ONE_DAY / 2).div_euclid(ONE_DAY);
+        let mut rng = Rng(options.seed ^ (date as u64).wrapping_mul(0xd1b5_4a32_d192_ed03));
+        // Monday is 0, 1970-01-01 is Thursday
+        let weekday = (date + 3).rem_euclid(7);
+        let mut factor = if weekday >= 5 { 0.6 } else { 1.0 };
+        factor *= 0.7 + 0.6 * rng.unit();
+        // Occasional bursts, such as digging into some problem
+        if rng.below(20) == 0 {
+            factor *= 2.5;
+        }
+        let count = (options.visits_per_day as f64 * factor).round() as u64;
+
+        let mut day_visits = (0..count)
+            .map(|_| {
+                let hour = rng.weighted(&HOUR_WEIGHTS) as i64;
+                let visit_time = midnight + hour * 3_600_000 + rng.below(3_600_000) as i64;
+                let (domain, _, pages) = SITES[rng.weighted(&site_weights)];
+                let (path, title) = pages[rng.below(pages.len() as u64) as usize];
+                let url = match path {
+                    "/search" | "/watch" => format!(
+                        "https://{domain}{path}?q={}",
+                        QUERIES[rng.below(QUERIES.len() as u64) as usize]
+                    ),
+                    _ => format!("https://{domain}{path}"),
+                };
+                VisitDetail {
+                    url,
+                    title: title.to_string(),
+                    visit_time: visit_time * 1_000,
+                    // Mostly link, sometimes typed or bookmark
+                    visit_type: [1, 1, 1, 1, 2, 3][rng.below(6) as usize],
+                    source_visit_id: None,
+                }
+            })
+            .collect::<Vec<_>>();
+        day_visits.sort_by_key(|v| v.visit_time);
+        visits.extend(day_visits);
+    }
+
+    visits
+}
+
+/// Generate plausible fake visits into a new 1History database at `db_file`, which
+/// is handy to try 1History without a real history. Progress is reported to
+/// `collector` in rows.
+///
+/// Return number of generated visits.
+pub fn generate_demo<P: AsRef<Path>>(
+    db_file: P,
+    options: &DemoOptions,
+    collector: &impl ProgressCollector,
+) -> Result<usize> {
+    let db_file = db_file.as_ref();
+    if db_file.exists() {
+        return Err(anyhow!("{} already exists", db_file.display()).into());
+    }
+    let end = match &options.until {
+        Some(ymd) => ymd_midnight(ymd).with_context(|| format!("until {ymd}"))? + ONE_DAY,
+        None => tomorrow_midnight() - ONE_DAY,
+    };
+    let visits = demo_visits(options, end);
+    let len = visits.len();
+    let db = Database::open(db_file).context("open 1History DB")?;
+    db.persist("demo", visits, collector).context("persist")?;
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demo_visits() {
+        let options = DemoOptions {
+            days: 14,
+            visits_per_day: 50,
+            seed: 42,
+            until: None,
+        };
+        // 2022-01-15 00:00:00 UTC
+        let end = 1_642_204_800_000;
+        let visits = demo_visits(&options, end);
+        let same = demo_visits(&options, end);
+        assert_eq!(
+            visits
+                .iter()
+                .map(|v| (&v.url, v.visit_time))
+                .collect::<Vec<_>>(),
+            same.iter()
+                .map(|v| (&v.url, v.visit_time))
+                .collect::<Vec<_>>()
+        );
+        let other = demo_visits(
+            &DemoOptions {
+                seed: 7,
+                until: None,
+                ..options
+            },
+            end,
+        );
+        assert_ne!(visits.len(), 0);
+        assert_ne!(
+            visits.iter().map(|v| &v.url).collect::<Vec<_>>(),
+            other.iter().map(|v| &v.url).collect::<Vec<_>>()
+        );
+
+        let start = (end - 14 * ONE_DAY) * 1_000;
+        assert!(visits
+            .iter()
+            .all(|v| v.visit_time >= start && v.visit_time < end * 1_000));
+        assert!(visits
+            .windows(2)
+            .all(|w| w[0].visit_time <= w[1].visit_time));
+        // Every day has visits
+        let mut days = visits
+            .iter()
+            .map(|v| v.visit_time / 1_000 / ONE_DAY)
+            .collect::<Vec<_>>();
+        days.dedup();
+        assert_eq!(days.len(), 14);
+
+        // Days shared by two runs on different days are the same
+        let next = demo_visits(&options, end + ONE_DAY);
+        let urls_between = |visits: &[VisitDetail], start: i64, end: i64| {
+            visits
+                .iter()
+                .filter(|v| v.visit_time >= start * 1_000 && v.visit_time < end * 1_000)
+                .map(|v| (v.url.clone(), v.visit_time))
+                .collect::<Vec<_>>()
+        };
+        let overlap = (end - 13 * ONE_DAY, end);
+        assert_eq!(
+            urls_between(&visits, overlap.0, overlap.1),
+            urls_between(&next, overlap.0, overlap.1)
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::Context;
+use chrono::{Duration, Local};
+use minijinja::{context, Environment};
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use crate::{
+    database::Database,
+    error::Result,
+    types::{CountMode, DomainTrend, IsoWeek},
+    util::{add_template_functions, midnight_in, unixepoch_as_ymd},
+    web::Asset,
+};
+
+// Domains listed in each section of digest
+const DIGEST_LIMIT: usize = 10;
+
+/// Browsing of one week compared with the week before, see `weekly_digest`.
+#[derive(Debug, Serialize)]
+pub struct Digest {
+    /// Such as `2024-W21`
+    pub week: String,
+    /// unix_epoch_ms of the week, `[start, end]`
+    pub start: i64,
+    pub end: i64,
+    pub visits: i64,
+    /// Visits of the week before
+    pub previous_visits: i64,
+    /// Local day(`%Y-%m-%d`) with most visits, and its visits
+    pub busiest_day: Option<(String, i64)>,
+    /// Most visited domains of the week, `after` is visits of this week, `before`
+    /// of the week before
+    pub top_domains: Vec<DomainTrend>,
+    /// Domains never visited before this week, most visited first, with visits
+    pub new_domains: Vec<(String, i64)>,
+    /// Number of all domains first seen this week, not only listed ones
+    pub new_domain_count: usize,
+}
+
+/// Output format of digest, see `render_digest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestFormat {
+    /// Self-contained HTML page, with styles inlined
+    Html,
+    Markdown,
+    Json,
+}
+
+impl FromStr for DigestFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "html" => Ok(Self::Html),
+            "markdown" | "md" => Ok(Self::Markdown),
+            "json" => Ok(Self::Json),
+            _ => anyhow::bail!("invalid digest format {s}, expect html, markdown or json"),
+        }
+    }
+}
+
+impl Display for DigestFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DigestFormat::Html => write!(f, "html"),
+            DigestFormat::Markdown => write!(f, "markdown"),
+            DigestFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+// `[start, end]` of `week` in local time, unix_epoch_ms
+fn week_range(week: IsoWeek) -> (i64, i64) {
+    let monday = week.monday();
+    (
+        midnight_in(&Local, monday),
+        midnight_in(&Local, monday + Duration::weeks(1)) - 1,
+    )
+}
+
+/// Summarize visits of `week` in `db`, compared with the week before. Days are
+/// counted like daily counts of dashboards, domains like domain top.
+pub fn weekly_digest(db: &Database, week: IsoWeek) -> Result<Digest> {
+    let (start, end) = week_range(week);
+    let (prev_start, prev_end) = week_range(week.previous());
+
+    let daily_counts = db
+        .select_daily_count(start, end, None, CountMode::Raw)
+        .context("daily_count")?;
+    let visits = daily_counts.iter().map(|(_, cnt)| cnt).sum();
+    let busiest_day = daily_counts
+        .iter()
+        .filter(|(_, cnt)| *cnt > 0)
+        // Earliest day wins ties
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(day, cnt)| (unixepoch_as_ymd(*day), *cnt));
+    let previous_visits = db
+        .select_daily_count(prev_start, prev_end, None, CountMode::Raw)
+        .context("previous daily_count")?
+        .iter()
+        .map(|(_, cnt)| cnt)
+        .sum();
+
+    // Compared like domain trends, with the previous week as the first half
+    let mut top_domains = db
+        .select_domain_changes((prev_start, prev_end), (start, end), None)
+        .context("domain_changes")?
+        .into_iter()
+        .filter(|t| t.after > 0)
+        .collect::<Vec<_>>();
+    top_domains.sort_by(|a, b| b.after.cmp(&a.after).then_with(|| a.domain.cmp(&b.domain)));
+
+    let seen: HashMap<String, i64> = db
+        .select_domain_counts(0, start - 1, None)
+        .context("seen domain_counts")?;
+    let mut new_domains = top_domains
+        .iter()
+        .filter(|t| !seen.contains_key(&t.domain))
+        .map(|t| (t.domain.clone(), t.after))
+        .collect::<Vec<_>>();
+    top_domains.truncate(DIGEST_LIMIT);
+    new_domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let new_domain_count = new_domains.len();
+    new_domains.truncate(DIGEST_LIMIT);
+
+    Ok(Digest {
+        week: week.to_string(),
+        start,
+        end,
+        visits,
+        previous_visits,
+        busiest_day,
+        top_domains,
+        new_domains,
+        new_domain_count,
+    })
+}
+
+/// Render `digest` as `format`, by templates embedded in binary except JSON.
+pub fn render_digest(digest: &Digest, format: DigestFormat) -> Result<String> {
+    let name = match format {
+        DigestFormat::Json => {
+            return Ok(serde_json::to_string_pretty(digest).context("serialize digest")? + "\n")
+        }
+        DigestFormat::Html => "digest.html",
+        DigestFormat::Markdown => "digest.md",
+    };
+    let asset = Asset::get(name).context("digest template")?;
+    let tmpl = std::str::from_utf8(&asset.data).context("digest template")?;
+    let mut env = Environment::new();
+    env.add_template(name, tmpl)
+        .context("add digest template")?;
+    add_template_functions(&mut env);
+    let body = env
+        .get_template(name)
+        .context("get digest template")?
+        .render(context!(
+            digest => digest,
+            version => clap::crate_version!(),
+        ))
+        .context("render digest")?;
+
+    Ok(body)
+}
@@ -0,0 +1,111 @@
+use rusqlite::ErrorCode;
+use std::{error::Error as StdError, iter};
+use thiserror::Error as ThisError;
+
+/// Errors returned by the public API of 1History.
+#[derive(Debug, ThisError)]
+pub enum Error {
+    /// Error reported by SQLite, either from 1History DB or a browser history file.
+    #[error("sqlite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    /// The history file doesn't belong to any supported browser.
+    #[error("unsupported history file: {0}")]
+    UnsupportedSource(String),
+    /// 1History database is locked by another process writing to it.
+    #[error("another onehistory process is running (pid {pid}, started at {started_at})")]
+    AlreadyRunning { pid: u32, started_at: String },
+    /// Persist is stopped by `request_stop`, visits persisted so far are committed.
+    #[error("interrupted, backup again to resume")]
+    Stopped,
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    // Whether any error in the chain is a SQLite failure with one of `codes`
+    fn is_sqlite_failure(&self, codes: &[ErrorCode]) -> bool {
+        iter::successors(Some(self as &(dyn StdError + 'static)), |&e| e.source()).any(|e| {
+            matches!(
+                e.downcast_ref::<rusqlite::Error>(),
+                Some(rusqlite::Error::SqliteFailure(ffi_err, _)) if codes.contains(&ffi_err.code)
+            )
+        })
+    }
+
+    /// Whether the SQLite file is locked by others, such as a running browser.
+    pub(crate) fn is_locked(&self) -> bool {
+        self.is_sqlite_failure(&[ErrorCode::DatabaseBusy, ErrorCode::DatabaseLocked])
+    }
+
+    /// Whether the SQLite file can't be written, such as on a read-only Time Machine
+    /// backup, which has to be read from a copy. Extended codes `SQLITE_READONLY_*`
+    /// are all `ReadOnly`, files which can't be opened at all are not included.
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.is_sqlite_failure(&[ErrorCode::ReadOnly])
+    }
+
+    /// Whether the SQLite file is corrupted, such as after a power loss, see
+    /// `recover_db`.
+    pub fn is_corrupt(&self) -> bool {
+        self.is_sqlite_failure(&[ErrorCode::DatabaseCorrupt, ErrorCode::NotADatabase])
+    }
+
+    /// Whether persist is stopped by `request_stop`, either directly or wrapped in
+    /// `Other`.
+    pub fn is_stopped(&self) -> bool {
+        iter::successors(Some(self as &(dyn StdError + 'static)), |&e| e.source())
+            .any(|e| matches!(e.downcast_ref::<Error>(), Some(Error::Stopped)))
+    }
+
+    /// Whether the query is aborted, such as exceeding query timeout of `Database`.
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.is_sqlite_failure(&[ErrorCode::OperationInterrupted])
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Whether `e` is caused by `request_stop`, which aborts the whole backup, see
+/// `Error::is_stopped`.
+pub fn is_stopped(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<Error>())
+        .any(|e| e.is_stopped())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use rusqlite::{Connection, OpenFlags};
+
+    #[test]
+    fn test_is_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("History.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE visits (id INTEGER PRIMARY KEY)")
+            .unwrap();
+
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_URI;
+        let conn =
+            Connection::open_with_flags(format!("file:{}?mode=ro", path.display()), flags).unwrap();
+        let err = conn
+            .pragma_update(None, "journal_mode", "WAL")
+            .and_then(|_| conn.execute("INSERT INTO visits DEFAULT VALUES", []))
+            .context("open")
+            .unwrap_err();
+        let err = Error::from(err);
+        assert!(err.is_read_only(), "{err:?}");
+        assert!(!err.is_locked());
+
+        let missing = dir.path().join("missing").join("History.db");
+        let err = Connection::open_with_flags(missing, OpenFlags::SQLITE_OPEN_READ_WRITE)
+            .context("open")
+            .unwrap_err();
+        assert!(!Error::from(err).is_read_only());
+    }
+}
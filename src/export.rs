@@ -1,44 +1,658 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context};
 use log::{debug, info};
+use minijinja::{context, Environment};
+use serde_json::{json, Value};
 use std::{
-    fs::OpenOptions,
-    io::{BufWriter, Write},
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "detect-language")]
+use crate::util::detect_language;
 use crate::{
     database::Database,
-    util::{full_timerange, unixepoch_as_ymdhms},
+    error::Result,
+    favicon::fetch_favicons,
+    manifest::{manifest_file_of, HashWriter, Manifest, ManifestPart},
+    progress::{ProgressCollector, SilentCollector},
+    session::{Session, Sessionizer},
+    source::Source,
+    types::{CountMode, HourRange, SourceFilter, VisitDetail},
+    util::{
+        add_template_functions, full_timerange, now_ms, porcelain_line, unixepoch_as_ymdhms,
+        BROWSER_INTERNAL_DOMAIN, LOCAL_FILE_DOMAIN,
+    },
 };
 
-pub fn export_csv(csv_file: String, db_file: String) -> Result<()> {
+// Number of rows written between two progress updates
+const PROGRESS_BATCH: usize = 1000;
+
+fn rows_per_sec(rows: usize, since: Instant) -> f64 {
+    rows as f64 / since.elapsed().as_secs_f64().max(0.001)
+}
+
+/// How CSV is written, the default is comma separated, with `\n` line endings and
+/// without BOM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvDialect {
+    /// Start with UTF-8 BOM, so Excel doesn't read it in the legacy code page
+    pub bom: bool,
+    /// End lines with `\r\n` instead of `\n`
+    pub crlf: bool,
+    /// Separator of fields, fields containing it are quoted
+    pub delimiter: char,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            bom: false,
+            crlf: false,
+            delimiter: ',',
+        }
+    }
+}
+
+impl CsvDialect {
+    // Quote `field` when it contains delimiter, quotes or line breaks
+    fn quote<'a>(&self, field: &'a str) -> Cow<'a, str> {
+        if field.contains([self.delimiter, '"', '\r', '\n']) {
+            Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+        } else {
+            Cow::Borrowed(field)
+        }
+    }
+
+    fn write_row<W: Write>(&self, w: &mut W, fields: &[&str]) -> io::Result<()> {
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                write!(w, "{}", self.delimiter)?;
+            }
+            w.write_all(self.quote(field).as_bytes())?;
+        }
+        w.write_all(if self.crlf { b"\r\n" } else { b"\n" })
+    }
+}
+
+// Write visits as CSV rows as they are produced, `visit_time` should be in unix
+// epoch milliseconds. Language of title is written as the last column when
+// `detect_language` is true.
+struct CsvWriter<'a, W: Write, C: ProgressCollector> {
+    w: W,
+    rows: usize,
+    #[cfg(feature = "detect-language")]
+    detect_language: bool,
+    dialect: CsvDialect,
+    collector: &'a C,
+}
+
+impl<'a, W: Write, C: ProgressCollector> CsvWriter<'a, W, C> {
+    fn new(
+        mut w: W,
+        detect_language: bool,
+        dialect: CsvDialect,
+        collector: &'a C,
+    ) -> io::Result<Self> {
+        if detect_language && !cfg!(feature = "detect-language") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "detecting language requires onehistory built with feature detect-language",
+            ));
+        }
+        if dialect.bom {
+            w.write_all("\u{feff}".as_bytes())?;
+        }
+        let header = ["time", "title", "url", "visit_type", "lang"];
+        let columns = if detect_language { 5 } else { 4 };
+        dialect.write_row(&mut w, &header[..columns])?;
+        Ok(Self {
+            w,
+            rows: 0,
+            #[cfg(feature = "detect-language")]
+            detect_language,
+            dialect,
+            collector,
+        })
+    }
+
+    fn write(&mut self, visit: &VisitDetail) -> io::Result<()> {
+        if self.rows > 0 && self.rows.is_multiple_of(PROGRESS_BATCH) {
+            self.collector.inc(PROGRESS_BATCH as u64);
+        }
+        let time = unixepoch_as_ymdhms(visit.visit_time);
+        // Commas in titles are dropped, as 1History always does
+        let title = visit.title.replace(',', "");
+        let visit_type = visit.visit_type.to_string();
+        let mut fields = vec![time.as_str(), &title, &visit.url, &visit_type];
+        fields.extend(self.language(&visit.title));
+        self.dialect.write_row(&mut self.w, &fields)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    // Value of `lang` column of `title`, `None` when there is no such column
+    #[cfg(feature = "detect-language")]
+    fn language(&self, title: &str) -> Option<&'static str> {
+        self.detect_language
+            .then(|| detect_language(title).unwrap_or_default())
+    }
+
+    #[cfg(not(feature = "detect-language"))]
+    fn language(&self, _title: &str) -> Option<&'static str> {
+        None
+    }
+
+    // Return number of rows written.
+    fn finish(mut self) -> io::Result<usize> {
+        self.w.flush()?;
+        self.collector.inc((self.rows % PROGRESS_BATCH) as u64);
+        Ok(self.rows)
+    }
+}
+
+/// Options shared by exports.
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Leave out local files and browser internal pages, see
+    /// `Database::with_exclude_internal`, not used by audit.
+    pub exclude_internal: bool,
+    /// Only export visits in these hours of day, see `Database::with_hours`, not
+    /// used by audit.
+    pub hours: Option<HourRange>,
+    /// Only export visits of this source, see `Database::with_source`, not used by
+    /// audit.
+    pub source: Option<SourceFilter>,
+    /// Include hidden visits, see `Database::with_include_hidden`.
+    pub include_hidden: bool,
+    /// Export urls as visited, see `Database::with_raw_urls`, not used by audit.
+    pub raw_urls: bool,
+    /// Write `<output>.manifest.json` alongside output, with filters, row count and
+    /// SHA-256 of output, see `Manifest`. Output must be a file.
+    pub manifest: bool,
+    /// Add `lang` column of language detected from title to CSV, it's empty when
+    /// detection isn't reliable. Only used by CSV, and requires feature
+    /// `detect-language`.
+    pub detect_language: bool,
+    /// Separator, line endings and BOM of CSV, only used by CSV.
+    pub csv_dialect: CsvDialect,
+}
+
+impl ExportOptions {
+    fn open_db<P: AsRef<Path>>(&self, db_file: P) -> Result<Database> {
+        Ok(Database::open(db_file)
+            .context("open 1History DB")?
+            .with_exclude_internal(self.exclude_internal)
+            .with_hours(self.hours)
+            .with_source(self.source.clone())
+            .with_include_hidden(self.include_hidden)
+            .with_raw_urls(self.raw_urls))
+    }
+
+    // Fail before exporting, rather than after
+    fn check_output(&self, output: Option<&Path>) -> Result<()> {
+        if self.manifest && output.is_none() {
+            return Err(anyhow!("manifest requires an output file").into());
+        }
+        Ok(())
+    }
+
+    // Write manifest of `output` produced by `format` when it's enabled, `filters`
+    // are specific to `format`.
+    fn write_manifest(
+        &self,
+        output: Option<&Path>,
+        db_file: &Path,
+        format: &str,
+        mut filters: BTreeMap<String, Value>,
+        rows: usize,
+        (sha256, bytes): (String, u64),
+    ) -> Result<()> {
+        let Some(output) = output.filter(|_| self.manifest) else {
+            return Ok(());
+        };
+        if format != "audit" {
+            filters.insert("exclude_internal".to_string(), json!(self.exclude_internal));
+            filters.insert(
+                "hours".to_string(),
+                json!(self.hours.map(|h| h.to_string())),
+            );
+            filters.insert(
+                "source".to_string(),
+                json!(self.source.as_ref().map(|s| s.to_string())),
+            );
+            filters.insert("include_hidden".to_string(), json!(self.include_hidden));
+            filters.insert("raw_urls".to_string(), json!(self.raw_urls));
+        }
+        let manifest = Manifest {
+            created_at: now_ms(),
+            version: clap::crate_version!().to_string(),
+            db_file: db_file.display().to_string(),
+            format: format.to_string(),
+            filters,
+            parts: vec![ManifestPart {
+                file: output
+                    .file_name()
+                    .map(|f| f.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                rows,
+                bytes,
+                sha256,
+            }],
+        };
+        let manifest_file = manifest_file_of(output);
+        let content = serde_json::to_string_pretty(&manifest).context("write manifest")?;
+        fs::write(&manifest_file, content + "\n")
+            .with_context(|| manifest_file.display().to_string())?;
+        info!("Write manifest into {}.", manifest_file.display());
+        Ok(())
+    }
+}
+
+// Write into `output`, or stdout when it's `None`.
+fn output_writer(output: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match output {
+        Some(output) => Box::new(BufWriter::new(
+            File::create(output).with_context(|| output.display().to_string())?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
+
+/// Export all visits in 1History database at `db_file` into `csv_file`, progress
+/// is reported to `collector` in rows. Rows are written as they are read, so memory
+/// usage stays flat regardless of database size.
+///
+/// Which visits are exported is decided by `options`, so are other exports.
+pub fn export_csv<P: AsRef<Path>>(
+    csv_file: PathBuf,
+    db_file: P,
+    options: &ExportOptions,
+    collector: &impl ProgressCollector,
+) -> Result<()> {
+    write_csv(csv_file, db_file, false, options, collector)
+}
+
+/// Like `export_csv`, but only the first visit of each url is exported, as a
+/// chronological log of new things found.
+pub fn export_new_urls_csv<P: AsRef<Path>>(
+    csv_file: PathBuf,
+    db_file: P,
+    options: &ExportOptions,
+    collector: &impl ProgressCollector,
+) -> Result<()> {
+    write_csv(csv_file, db_file, true, options, collector)
+}
+
+fn write_csv<P: AsRef<Path>>(
+    csv_file: PathBuf,
+    db_file: P,
+    only_new_urls: bool,
+    options: &ExportOptions,
+    collector: &impl ProgressCollector,
+) -> Result<()> {
     let (start, end) = full_timerange();
     debug!("start:{}, end:{}", start, end);
 
-    let db = Database::open(db_file).context("open 1History DB")?;
+    let begin = Instant::now();
+    let db_file = db_file.as_ref();
+    let db = options.open_db(db_file)?;
+    collector.start(if only_new_urls {
+        db.count_first_visits(start, end)?
+    } else {
+        db.count_visits(start, end, None)?
+    });
+    if let Some(dir) = csv_file.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+    }
     let f = OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
         .open(&csv_file)
-        .context(csv_file.clone())?;
-    let mut buf_writer = BufWriter::new(f);
+        .with_context(|| csv_file.display().to_string())?;
+    let mut w = HashWriter::new(BufWriter::new(f));
+    let len = write_csv_to(
+        &db,
+        &mut w,
+        only_new_urls,
+        options.detect_language,
+        options.csv_dialect,
+        collector,
+    )?;
+    collector.finish();
+    options.write_manifest(
+        Some(&csv_file),
+        db_file,
+        "csv",
+        BTreeMap::from([
+            ("only_new_urls".to_string(), json!(only_new_urls)),
+            (
+                "detect_language".to_string(),
+                json!(options.detect_language),
+            ),
+        ]),
+        len,
+        w.finish(),
+    )?;
+    info!(
+        "Export {len} histories in {}, {:.0} rows/s.",
+        csv_file.display(),
+        rows_per_sec(len, begin)
+    );
+
+    Ok(())
+}
+
+/// Write visits of `db` in full timerange as CSV into `w`, the same as `export_csv`,
+/// or `export_new_urls_csv` when `only_new_urls` is true, see `ExportOptions` for
+/// `detect_language` and `dialect`. Return number of rows.
+pub(crate) fn write_csv_to<W: Write>(
+    db: &Database,
+    w: W,
+    only_new_urls: bool,
+    detect_language: bool,
+    dialect: CsvDialect,
+    collector: &impl ProgressCollector,
+) -> Result<usize> {
+    let (start, end) = full_timerange();
+    let mut writer = CsvWriter::new(w, detect_language, dialect, collector)?;
+    let write = |visit: VisitDetail| Ok(writer.write(&visit)?);
+    if only_new_urls {
+        db.for_each_first_visit(start, end, write)?;
+    } else {
+        db.for_each_visit(start, end, None, false, write)?;
+    }
+    Ok(writer.finish()?)
+}
+
+/// Write visits whose title or url matches `keyword` to `w` one by one as they are
+/// read from `db`, most recent first when `reverse` is true. Each visit is a line of
+/// time, title and url separated by tabs and escaped by `porcelain_line`, or a JSON
+/// object when `jsonl` is true, so results can be piped into `jq` and other
+/// line-oriented tools.
+///
+/// Return number of visits written.
+pub fn search_visits<W: Write>(
+    db: &Database,
+    keyword: &str,
+    reverse: bool,
+    jsonl: bool,
+    mut w: W,
+) -> Result<usize> {
+    let (start, end) = full_timerange();
+    let mut rows = 0;
+    db.for_each_visit(start, end, Some(keyword.to_string()), reverse, |visit| {
+        if jsonl {
+            serde_json::to_writer(&mut w, &visit).map_err(io::Error::from)?;
+            writeln!(w)?;
+        } else {
+            let time = unixepoch_as_ymdhms(visit.visit_time);
+            writeln!(w, "{}", porcelain_line(&[&time, &visit.title, &visit.url]))?;
+        }
+        rows += 1;
+        Ok(())
+    })?;
+    w.flush()?;
+    Ok(rows)
+}
 
-    buf_writer.write_all(b"time,title,url,visit_type\n")?;
-    let visits = db.select_visits(start, end, None)?;
+/// Render all visits in 1History database at `db_file` with the minijinja template
+/// at `template_file`, and write result into `output`, or stdout when it's `None`.
+/// Progress is reported to `collector` in rows, once template is rendered.
+///
+/// Besides `visits`, aggregates `daily_counts`, `title_top100` and `domain_top100`
+/// are also available in template, together with functions used by web templates,
+/// such as `format_as_ymd`.
+///
+/// When `with_favicons` is true, favicons of domains in `domain_top100` are fetched,
+/// and available in template as `favicons`, a map from domain to data URI, see
+/// `fetch_favicons`. Otherwise no network access is made.
+pub fn export_template<P: AsRef<Path>>(
+    template_file: &Path,
+    output: Option<PathBuf>,
+    db_file: P,
+    with_favicons: bool,
+    options: &ExportOptions,
+    collector: &impl ProgressCollector,
+) -> Result<()> {
+    let (start, end) = full_timerange();
+    debug!("start:{}, end:{}", start, end);
+
+    options.check_output(output.as_deref())?;
+    let begin = Instant::now();
+    let db_file = db_file.as_ref();
+    let db = options.open_db(db_file)?;
+    collector.start(db.count_visits(start, end, None)?);
+    let tmpl_source =
+        fs::read_to_string(template_file).with_context(|| template_file.display().to_string())?;
+    let mut env = Environment::new();
+    env.add_template("export", &tmpl_source)
+        .context("parse template")?;
+    add_template_functions(&mut env);
+
+    let visits = db.select_visits(start, end, None, false)?;
     let len = visits.len();
-    for visit in visits {
-        buf_writer.write_all(
-            format!(
-                "{},{},{},{}\n",
-                unixepoch_as_ymdhms(visit.visit_time),
-                visit.title.replace(",", ""),
-                visit.url,
-                visit.visit_type
-            )
-            .as_bytes(),
-        )?;
-    }
-    info!("Export {len} histories in {csv_file}.");
+    let daily_counts = db.select_daily_count(start, end, None, CountMode::Raw)?;
+    let title_top100 = db.select_title_top100(start, end, None)?;
+    let domain_top100 = db.select_domain_top100(start, end, None)?;
+    let favicons = if with_favicons {
+        // Synthetic domains of internal urls have no favicon
+        let domains = domain_top100
+            .iter()
+            .filter(|(d, _)| d != LOCAL_FILE_DOMAIN && d != BROWSER_INTERNAL_DOMAIN)
+            .map(|(d, _)| d.clone())
+            .collect::<Vec<_>>();
+        let favicons = fetch_favicons(&domains);
+        info!(
+            "Fetch favicons of {}/{} domains.",
+            favicons.len(),
+            domains.len()
+        );
+        favicons
+    } else {
+        HashMap::new()
+    };
+    let tmpl = env.get_template("export").unwrap();
+    let body = tmpl
+        .render(context!(
+            start => start,
+            end => end,
+            visits => visits,
+            daily_counts => daily_counts,
+            title_top100 => title_top100,
+            domain_top100 => domain_top100,
+            favicons => favicons,
+            version => clap::crate_version!(),
+        ))
+        .context("render template")?;
+    collector.inc(len as u64);
+    collector.finish();
+
+    match &output {
+        Some(output) => {
+            fs::write(output, &body).with_context(|| output.display().to_string())?;
+            info!(
+                "Export {len} histories in {}, {:.0} rows/s.",
+                output.display(),
+                rows_per_sec(len, begin)
+            );
+        }
+        None => io::stdout().write_all(body.as_bytes())?,
+    }
+    let mut hash = HashWriter::new(io::sink());
+    hash.write_all(body.as_bytes())?;
+    options.write_manifest(
+        output.as_deref(),
+        db_file,
+        "template",
+        BTreeMap::from([
+            (
+                "template".to_string(),
+                json!(template_file.display().to_string()),
+            ),
+            ("with_favicons".to_string(), json!(with_favicons)),
+        ]),
+        len,
+        hash.finish(),
+    )?;
 
     Ok(())
 }
+
+/// Export all visits in 1History database at `db_file` as a JSON timeline, written
+/// into `output`, or stdout when it's `None`. Visits are grouped into sessions, a
+/// gap longer than `session_gap` between two visits starts a new session. Progress
+/// is reported to `collector` in rows.
+///
+/// Return number of sessions.
+pub fn export_timeline<P: AsRef<Path>>(
+    output: Option<PathBuf>,
+    db_file: P,
+    session_gap: Duration,
+    options: &ExportOptions,
+    collector: &impl ProgressCollector,
+) -> Result<usize> {
+    let (start, end) = full_timerange();
+    debug!("start:{}, end:{}", start, end);
+
+    options.check_output(output.as_deref())?;
+    let db_file = db_file.as_ref();
+    let db = options.open_db(db_file)?;
+    collector.start(db.count_visits(start, end, None)?);
+    let mut w = HashWriter::new(output_writer(output.as_deref())?);
+    let (mut sessions, mut visits) = (0, 0);
+    let mut write_session = |session: Session| -> Result<()> {
+        w.write_all(if sessions == 0 { b"[\n" } else { b",\n" })?;
+        serde_json::to_writer_pretty(&mut w, &session).context("write json")?;
+        collector.inc(session.visits.len() as u64);
+        sessions += 1;
+        visits += session.visits.len();
+        Ok(())
+    };
+
+    let mut sessionizer = Sessionizer::new(session_gap);
+    db.for_each_visit(start, end, None, false, |visit| {
+        match sessionizer.push(visit) {
+            Some(session) => write_session(session),
+            None => Ok(()),
+        }
+    })?;
+    if let Some(session) = sessionizer.finish() {
+        write_session(session)?;
+    }
+    w.write_all(if sessions == 0 { b"[]\n" } else { b"\n]\n" })?;
+    w.flush()?;
+    collector.finish();
+    options.write_manifest(
+        output.as_deref(),
+        db_file,
+        "timeline",
+        BTreeMap::from([("session_gap_secs".to_string(), json!(session_gap.as_secs()))]),
+        visits,
+        w.finish(),
+    )?;
+
+    Ok(sessions)
+}
+
+// Quote CSV field when it contains separators, paths may contain commas
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Export import records of 1History database at `db_file`, which is an audit trail
+/// of where and when its visits come from, written into `output`, or stdout when
+/// it's `None`. Records are written as a JSON array when `json` is true, otherwise
+/// as CSV. Only `manifest` of `options` is used.
+///
+/// Return number of records.
+pub fn export_audit<P: AsRef<Path>>(
+    output: Option<PathBuf>,
+    db_file: P,
+    json: bool,
+    options: &ExportOptions,
+) -> Result<usize> {
+    options.check_output(output.as_deref())?;
+    let db_file = db_file.as_ref();
+    let db = Database::open(db_file).context("open 1History DB")?;
+    let records = db.select_import_records()?;
+    let mut w = HashWriter::new(output_writer(output.as_deref())?);
+    if json {
+        serde_json::to_writer_pretty(&mut w, &records).context("write json")?;
+        w.write_all(b"\n")?;
+    } else {
+        w.write_all(b"data_path,last_import,imported,duplicated\n")?;
+        for r in &records {
+            writeln!(
+                w,
+                "{},{},{},{}",
+                csv_field(&r.data_path),
+                r.last_import.map(unixepoch_as_ymdhms).unwrap_or_default(),
+                r.imported,
+                r.duplicated
+            )?;
+        }
+    }
+    w.flush()?;
+    options.write_manifest(
+        output.as_deref(),
+        db_file,
+        "audit",
+        BTreeMap::from([("json".to_string(), json!(json))]),
+        records.len(),
+        w.finish(),
+    )?;
+
+    Ok(records.len())
+}
+
+// Read all visits of `history_file`, with `visit_time` in unix epoch milliseconds.
+fn read_history_file(history_file: &Path) -> Result<Vec<VisitDetail>> {
+    let (start, end) = full_timerange();
+    let source = Source::open_read_only(history_file).context("open")?;
+    Ok(source
+        .select(start, end)
+        .context("select")?
+        .map(|v| VisitDetail {
+            visit_time: v.visit_time / 1_000, // PRTime
+            ..v
+        })
+        .collect())
+}
+
+/// Convert browser history file at `history_file` into CSV, written into `output`,
+/// or stdout when it's `None`. 1History database is not involved.
+///
+/// Return number of converted visits.
+pub fn convert_csv<P: AsRef<Path>>(history_file: P, output: Option<PathBuf>) -> Result<usize> {
+    let visits = read_history_file(history_file.as_ref())?;
+    let mut writer = CsvWriter::new(
+        output_writer(output.as_deref())?,
+        false,
+        CsvDialect::default(),
+        &SilentCollector,
+    )?;
+    for visit in &visits {
+        writer.write(visit)?;
+    }
+    Ok(writer.finish()?)
+}
+
+/// Like `convert_csv`, but visits are written as a JSON array, in the same format
+/// as `/api/details`.
+pub fn convert_json<P: AsRef<Path>>(history_file: P, output: Option<PathBuf>) -> Result<usize> {
+    let visits = read_history_file(history_file.as_ref())?;
+    let mut w = output_writer(output.as_deref())?;
+    serde_json::to_writer_pretty(&mut w, &visits).context("write json")?;
+    w.write_all(b"\n")?;
+    w.flush()?;
+    Ok(visits.len())
+}
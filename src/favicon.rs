@@ -0,0 +1,168 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::debug;
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crate::util::cache_dir;
+
+// Number of favicons fetched at the same time
+const CONCURRENCY: usize = 8;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+// Larger responses are not favicons
+const MAX_FAVICON_BYTES: u64 = 256 * 1024;
+
+/// Favicons of `domains` as data URIs, which keeps exported reports self-contained.
+///
+/// Favicons are fetched from `https://<domain>/favicon.ico` concurrently, and cached
+/// in `<cache dir>/favicons`, domains failed to fetch are skipped.
+pub fn fetch_favicons(domains: &[String]) -> HashMap<String, String> {
+    let cache = cache_dir().map(|dir| dir.join("favicons"));
+    fetch_all(domains, cache.as_deref(), |domain| {
+        format!("https://{domain}/favicon.ico")
+    })
+}
+
+// Cache file of `domain`, `None` when domain can't be used as filename
+fn cache_file(cache: &Path, domain: &str) -> Option<PathBuf> {
+    let valid = !domain.is_empty()
+        && !domain.starts_with('.')
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    valid.then(|| cache.join(domain))
+}
+
+fn fetch(agent: &ureq::Agent, url: &str) -> Option<String> {
+    let resp = agent
+        .get(url)
+        .call()
+        .map_err(|e| debug!("fetch {url} failed, err:{e}"))
+        .ok()?;
+    let mime = match resp.header("Content-Type") {
+        Some(mime) if mime.starts_with("image/") => mime.to_string(),
+        Some(mime) => {
+            debug!("skip {url}, not image: {mime}");
+            return None;
+        }
+        None => "image/x-icon".to_string(),
+    };
+    let mut body = Vec::new();
+    resp.into_reader()
+        .take(MAX_FAVICON_BYTES + 1)
+        .read_to_end(&mut body)
+        .ok()?;
+    if body.is_empty() || body.len() as u64 > MAX_FAVICON_BYTES {
+        debug!("skip {url}, size:{}", body.len());
+        return None;
+    }
+    Some(format!("data:{mime};base64,{}", STANDARD.encode(body)))
+}
+
+fn fetch_all(
+    domains: &[String],
+    cache: Option<&Path>,
+    url_of: impl Fn(&str) -> String + Sync,
+) -> HashMap<String, String> {
+    let favicons = Mutex::new(HashMap::new());
+    let mut pending = Vec::new();
+    for domain in domains {
+        let cached = cache
+            .and_then(|cache| cache_file(cache, domain))
+            .and_then(|file| fs::read_to_string(file).ok());
+        match cached {
+            Some(uri) => {
+                favicons.lock().unwrap().insert(domain.clone(), uri);
+            }
+            None => pending.push(domain),
+        }
+    }
+
+    let agent = ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build();
+    let pending = Mutex::new(pending.into_iter());
+    thread::scope(|s| {
+        for _ in 0..CONCURRENCY {
+            s.spawn(|| loop {
+                let next = pending.lock().unwrap().next();
+                let Some(domain) = next else {
+                    break;
+                };
+                let Some(uri) = fetch(&agent, &url_of(domain)) else {
+                    continue;
+                };
+                if let Some(file) = cache.and_then(|cache| cache_file(cache, domain)) {
+                    let _ = fs::create_dir_all(file.parent().expect("under cache"))
+                        .and_then(|_| fs::write(&file, &uri))
+                        .map_err(|e| debug!("cache {} failed, err:{e}", file.display()));
+                }
+                favicons.lock().unwrap().insert(domain.clone(), uri);
+            });
+        }
+    });
+
+    favicons.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::TcpListener};
+
+    // Serve `responses` in order, one for each connection
+    fn serve(responses: Vec<&'static [u8]>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for resp in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                stream.write_all(resp).unwrap();
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[test]
+    fn test_fetch_favicons() {
+        let cache = tempfile::tempdir().unwrap();
+        let base = serve(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 3\r\n\r\nico",
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let domains = ["github.com".to_string(), "missing.org".to_string()];
+        let url_of = |domain: &str| {
+            let path = if domain == "github.com" { "ok" } else { "404" };
+            format!("{base}/{path}")
+        };
+        // Fetch one by one, so responses match domains
+        let favicons = fetch_all(&domains[..1], Some(cache.path()), url_of);
+        assert_eq!(favicons["github.com"], "data:image/png;base64,aWNv");
+        let favicons = fetch_all(&domains[1..], Some(cache.path()), url_of);
+        assert!(favicons.is_empty());
+
+        // Cached favicons are not fetched again
+        let favicons = fetch_all(&domains[..1], Some(cache.path()), |_| {
+            "http://127.0.0.1:1/".to_string()
+        });
+        assert_eq!(favicons["github.com"], "data:image/png;base64,aWNv");
+    }
+
+    #[test]
+    fn test_cache_file() {
+        let cache = Path::new("cache");
+        assert_eq!(
+            cache_file(cache, "github.com"),
+            Some(cache.join("github.com"))
+        );
+        assert_eq!(cache_file(cache, "../etc"), None);
+        assert_eq!(cache_file(cache, "a/b"), None);
+        assert_eq!(cache_file(cache, "localhost:8080"), None);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+
+/// Similarity of titles to a fuzzy query at least this are matched by default.
+pub const DEFAULT_FUZZY_THRESHOLD: f64 = 0.3;
+
+// Lowercased words of `text`, split by characters other than letters and digits
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+// Trigrams of one word, padded like pg_trgm, so `rust` has `  r`, ` ru`, `rus`, `ust`
+// and `st `, which makes beginning of words weigh more.
+fn word_trigrams(word: &str) -> HashSet<String> {
+    let chars = ["  ", word, " "].concat().chars().collect::<Vec<_>>();
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+/// Trigrams of all words in `text`, which are indexed for titles, see
+/// `Database::rebuild_fuzzy_index`.
+pub(crate) fn trigrams(text: &str) -> HashSet<String> {
+    words(text).iter().flat_map(|w| word_trigrams(w)).collect()
+}
+
+// Dice coefficient, which is more forgiving than Jaccard index to one typo in
+// short words
+fn dice(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let shared = a.intersection(b).count();
+    2.0 * shared as f64 / (a.len() + b.len()) as f64
+}
+
+/// Trigrams a title shares with `query` at least, when its similarity reaches
+/// `threshold`, used to skip titles before computing similarity.
+pub(crate) fn min_shared_trigrams(query: &str, threshold: f64) -> usize {
+    // Average reaches threshold only if one word does, whose dice coefficient is at
+    // most 2 * shared / its trigrams
+    words(query)
+        .iter()
+        .map(|w| word_trigrams(w).len())
+        .min()
+        .map_or(1, |len| {
+            ((threshold * len as f64 / 2.0).ceil() as usize).max(1)
+        })
+}
+
+/// Similarity between 0 and 1 of `title` to `query`, which is the average of each
+/// query word's best match among words of title, so misspelled words such as
+/// `kubernets` still match `Kubernetes`, and words not in query don't lower it.
+pub(crate) fn similarity(query: &str, title: &str) -> f64 {
+    let query = words(query);
+    if query.is_empty() {
+        return 0.0;
+    }
+    let title = words(title)
+        .iter()
+        .map(|w| word_trigrams(w))
+        .collect::<Vec<_>>();
+    let total = query
+        .iter()
+        .map(|w| {
+            let q = word_trigrams(w);
+            title.iter().map(|t| dice(&q, t)).fold(0.0, f64::max)
+        })
+        .sum::<f64>();
+    total / query.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trigrams() {
+        let mut got = trigrams("Rust-rust").into_iter().collect::<Vec<_>>();
+        got.sort();
+        assert_eq!(got, vec!["  r", " ru", "rus", "st ", "ust"]);
+        assert!(trigrams(" - ").is_empty());
+    }
+
+    #[test]
+    fn test_similarity() {
+        let title = "Overview | Kubernetes";
+        assert_eq!(similarity("kubernetes", title), 1.0);
+        assert!(similarity("kubernets", title) > 0.5);
+        assert!(similarity("recepie", "Easy Pancake Recipe") > DEFAULT_FUZZY_THRESHOLD);
+        assert!(similarity("kubernets", "The Rust Programming Language") < 0.1);
+        assert_eq!(similarity("", title), 0.0);
+        assert_eq!(similarity("kubernetes", ""), 0.0);
+    }
+
+    #[test]
+    fn test_min_shared_trigrams() {
+        // `rust` has 5 trigrams
+        assert_eq!(min_shared_trigrams("rust kubernetes", 0.3), 1);
+        assert_eq!(min_shared_trigrams("rust", 0.8), 2);
+        assert_eq!(min_shared_trigrams("", 0.8), 1);
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::Context;
+use std::{
+    io::Read,
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{backup::BackupSummary, error::Result};
+
+// Interval between two checks whether hook exits
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Command run after backup via shell, such as pushing database to remote storage.
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub command: String,
+    /// Hook is killed when it runs longer than this
+    pub timeout: Duration,
+}
+
+/// How a hook exits, together with its output.
+#[derive(Debug)]
+pub struct HookOutcome {
+    /// `None` when it's killed for timeout
+    pub status: Option<ExitStatus>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl HookOutcome {
+    pub fn success(&self) -> bool {
+        self.status.is_some_and(|s| s.success())
+    }
+}
+
+/// Environment variables passed to hooks, `summary` is `None` when backup fails
+/// before any history file is read.
+pub fn hook_env(summary: Option<&BackupSummary>, db_file: &Path) -> Vec<(&'static str, String)> {
+    let count = |f: fn(&BackupSummary) -> usize| summary.map_or(0, f).to_string();
+    vec![
+        ("OH_FOUND", count(BackupSummary::found)),
+        ("OH_IMPORTED", count(BackupSummary::imported)),
+        ("OH_DUPLICATED", count(BackupSummary::duplicated)),
+        ("OH_FAILED_SOURCES", count(BackupSummary::failed)),
+        ("OH_DB_FILE", db_file.display().to_string()),
+    ]
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    }
+}
+
+// Pipes are drained in background, otherwise hooks with large output block forever
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        String::from_utf8_lossy(&buf).to_string()
+    })
+}
+
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl Hook {
+    /// Run hook with `envs`, wait until it exits or times out.
+    ///
+    /// Err is only returned when it can't be started.
+    pub fn run(&self, envs: &[(&str, String)]) -> Result<HookOutcome> {
+        let mut child = shell(&self.command)
+            .envs(envs.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("run hook `{}`", self.command))?;
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+        let status = wait_timeout(&mut child, self.timeout)?;
+        // Grandchildren may keep pipes open after timeout, don't wait for them
+        let output = |h: JoinHandle<String>| match status {
+            Some(_) => h.join().unwrap_or_default(),
+            None => String::new(),
+        };
+
+        Ok(HookOutcome {
+            status,
+            stdout: output(stdout),
+            stderr: output(stderr),
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::backup::{BackupStatus, SourceReport};
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    fn test_hook_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("env.txt");
+        let summary = BackupSummary {
+            reports: vec![
+                SourceReport {
+                    path: PathBuf::from("History"),
+                    profile: String::new(),
+                    browser: None,
+                    status: BackupStatus::Ok {
+                        found: 10,
+                        imported: 7,
+                        duplicated: 3,
+                    },
+                },
+                SourceReport {
+                    path: PathBuf::from("places.sqlite"),
+                    profile: String::new(),
+                    browser: None,
+                    status: BackupStatus::Failed("permission denied".to_string()),
+                },
+            ],
+            pruned: 0,
+            timings: Vec::new(),
+        };
+        let hook = Hook {
+            command: format!(
+                "echo $OH_FOUND $OH_IMPORTED $OH_DUPLICATED $OH_FAILED_SOURCES $OH_DB_FILE > {}; echo done; exit 3",
+                out.display()
+            ),
+            timeout: Duration::from_secs(10),
+        };
+        let outcome = hook
+            .run(&hook_env(Some(&summary), Path::new("/tmp/oh.db")))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "10 7 3 1 /tmp/oh.db\n");
+        assert_eq!(outcome.status.unwrap().code(), Some(3));
+        assert_eq!(outcome.stdout, "done\n");
+        assert!(!outcome.success());
+
+        hook.run(&hook_env(None, Path::new("oh.db"))).unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "0 0 0 0 oh.db\n");
+    }
+
+    #[test]
+    fn test_hook_timeout() {
+        let hook = Hook {
+            command: "sleep 10".to_string(),
+            timeout: Duration::from_millis(200),
+        };
+        let begin = Instant::now();
+        let outcome = hook.run(&[]).unwrap();
+        assert!(outcome.status.is_none());
+        assert!(!outcome.success());
+        assert!(begin.elapsed() < Duration::from_secs(5));
+    }
+}
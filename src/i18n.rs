@@ -0,0 +1,135 @@
+use lazy_static::lazy_static;
+use minijinja::{Environment, State};
+use std::collections::HashMap;
+
+/// Language used when none requested is supported, and for missing keys.
+pub const DEFAULT_LANG: &str = "en";
+
+lazy_static! {
+    // Messages of bundled locales, keyed by language tag
+    static ref LOCALES: HashMap<&'static str, HashMap<String, String>> = [
+        ("en", include_str!("../static/locales/en.json")),
+        ("zh-CN", include_str!("../static/locales/zh-CN.json")),
+    ]
+    .into_iter()
+    .map(|(lang, json)| {
+        let messages = serde_json::from_str(json).expect("bundled locale is valid JSON");
+        (lang, messages)
+    })
+    .collect();
+}
+
+// Bundled locale matching language tag `tag`, such as `zh`, `zh-cn` or `zh_CN`
+fn supported(tag: &str) -> Option<&'static str> {
+    let tag = tag.trim().replace('_', "-");
+    if tag.is_empty() {
+        return None;
+    }
+    let primary = |t: &str| t.split('-').next().unwrap_or_default().to_lowercase();
+    LOCALES
+        .keys()
+        .find(|lang| lang.eq_ignore_ascii_case(&tag))
+        .or_else(|| LOCALES.keys().find(|lang| primary(lang) == primary(&tag)))
+        .copied()
+}
+
+/// Choose bundled locale by `lang` query parameter first, then by `Accept-Language`
+/// header, `DEFAULT_LANG` when none is supported.
+pub fn negotiate(lang: Option<&str>, accept_language: Option<&str>) -> &'static str {
+    if let Some(lang) = lang.and_then(supported) {
+        return lang;
+    }
+    let mut accepted = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?;
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((supported(tag)?, q))
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .collect::<Vec<_>>();
+    // Stable sort keeps header order of equal weights
+    accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    accepted.first().map_or(DEFAULT_LANG, |(lang, _)| lang)
+}
+
+/// Message of `key` in `lang`, falls back to English, then `key` itself.
+pub fn translate(lang: &str, key: &str) -> String {
+    [lang, DEFAULT_LANG]
+        .iter()
+        .find_map(|lang| LOCALES.get(lang)?.get(key))
+        .cloned()
+        .unwrap_or_else(|| key.to_string())
+}
+
+/// All messages of `lang` as a JSON object, with missing keys filled by English,
+/// used by scripts of web pages.
+pub fn messages_json(lang: &str) -> String {
+    let mut messages = LOCALES[DEFAULT_LANG].clone();
+    if let Some(localized) = LOCALES.get(lang) {
+        messages.extend(localized.clone());
+    }
+    serde_json::to_string(&messages).expect("messages are serializable")
+}
+
+// Language of template being rendered, set by `lang` variable
+pub(crate) fn lang_of(state: &State) -> String {
+    state
+        .lookup("lang")
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| DEFAULT_LANG.to_string())
+}
+
+#[allow(clippy::result_large_err)]
+fn minijinja_translate(state: &State, key: String) -> Result<String, minijinja::Error> {
+    Ok(translate(&lang_of(state), &key))
+}
+
+/// Add `t(key)` function, which translates `key` into language of `lang` variable.
+pub fn add_i18n_functions(env: &mut Environment) {
+    env.add_function("t", minijinja_translate);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let cases = [
+            (Some("zh-CN"), None, "zh-CN"),
+            (Some("zh_cn"), Some("en"), "zh-CN"),
+            (Some("fr"), Some("zh-TW,zh;q=0.9"), "zh-CN"),
+            (None, Some("fr-FR,zh;q=0.8,en;q=0.9"), "en"),
+            (None, Some("fr-FR,zh;q=0.8"), "zh-CN"),
+            (None, Some("zh;q=0"), "en"),
+            (None, Some("en-US,en;q=0.9"), "en"),
+            (None, None, "en"),
+        ];
+        for (lang, accept, expected) in cases {
+            assert_eq!(negotiate(lang, accept), expected, "{lang:?} {accept:?}");
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate("zh-CN", "search.submit"), "搜索");
+        assert_eq!(translate("en", "search.submit"), "Search");
+        assert_eq!(translate("fr", "search.submit"), "Search");
+        assert_eq!(translate("zh-CN", "no.such.key"), "no.such.key");
+    }
+
+    #[test]
+    fn test_locales_have_same_keys() {
+        let en = &LOCALES[DEFAULT_LANG];
+        for (lang, messages) in LOCALES.iter() {
+            for key in en.keys() {
+                assert!(messages.contains_key(key), "{lang} misses {key}");
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! All your history in one file.
+//!
+//! 1History backups histories of different browsers(Chrome/Firefox/Safari...) into
+//! one SQLite database, this crate exposes the building blocks used by `onehistory`
+//! command line tool, so it can be embedded in other tools.
+
+mod backup;
+mod crash;
+mod database;
+mod default_browser;
+mod demo;
+mod digest;
+mod error;
+mod export;
+mod favicon;
+mod fuzzy;
+mod hook;
+mod i18n;
+mod lock;
+mod logfile;
+mod manifest;
+mod notify;
+mod progress;
+mod schedule;
+mod session;
+mod show;
+mod source;
+mod sync;
+mod synced;
+mod tui;
+mod types;
+mod util;
+mod web;
+
+pub use crate::backup::{
+    backup, backup_synced, BackupOptions, BackupStatus, BackupSummary, SourceReport,
+};
+pub use crate::crash::{install_crash_handler, write_crash_report};
+pub use crate::database::{
+    check_db_integrity, migrate_db, recover_db, Database, DEFAULT_BATCH_BYTES, DEFAULT_BATCH_NUM,
+};
+pub use crate::demo::{generate_demo, DemoOptions};
+pub use crate::digest::{render_digest, weekly_digest, Digest, DigestFormat};
+pub use crate::error::{is_stopped, Error, Result};
+pub use crate::export::{
+    convert_csv, convert_json, export_audit, export_csv, export_new_urls_csv, export_template,
+    export_timeline, search_visits, CsvDialect, ExportOptions,
+};
+pub use crate::fuzzy::DEFAULT_FUZZY_THRESHOLD;
+#[doc(hidden)]
+pub use crate::hook::hook_env;
+pub use crate::hook::{Hook, HookOutcome};
+pub use crate::lock::{lock_db, lock_file_of, DbLock};
+pub use crate::logfile::RotatingFile;
+pub use crate::manifest::{manifest_file_of, verify_manifest, Manifest, ManifestPart, PartCheck};
+pub use crate::notify::Notice;
+pub use crate::progress::{
+    ByteCollector, ChannelCollector, LogCollector, ProgressCollector, SilentCollector, TUICollector,
+};
+pub use crate::schedule::{
+    install_schedule, schedule_status, schedule_status_porcelain, uninstall_schedule, Schedule,
+    ScheduleInterval, ScheduleTime,
+};
+pub use crate::show::{
+    inspect_history_files, list_browsers, BrowserPattern, FileStatus, HistoryFileInfo,
+};
+pub use crate::source::Source;
+pub use crate::sync::{
+    export_sync_bundle, import_sync_bundle, SyncHeader, SyncImport, BUNDLE_VERSION,
+};
+pub use crate::synced::{
+    read_synced_sessions, synced_session_dirs, SyncedSession, SYNCED_VISIT_TYPE,
+};
+pub use crate::tui::browse;
+pub use crate::types::{
+    BackupRun, BackupTiming, CountMode, DayStats, DbDiff, DiffSide, DomainTrend, DomainTrends,
+    HourRange, ImportRecord, IsoWeek, KeywordOp, MatchMode, PhaseTimings, RecoveredTable,
+    SessionSpan, SessionStats, SourceFilter, SourceName, SourceSummary, TitleDiff, TrailingSlash,
+    VisitDetail, VisitTrace, WhatsNew,
+};
+pub use crate::util::{
+    detect_history_files, full_timerange, internal_domain, is_internal_url, open_url,
+    BROWSER_INTERNAL_DOMAIN, DATA_DB_FILE, DEFAULT_CSV_FILE, DEFAULT_DB_FILE,
+    DEFAULT_KEEP_FRAGMENT_DOMAINS, LEGACY_DB_FILE, LOCAL_FILE_DOMAIN,
+};
+pub use crate::web::{serve, ServeOptions};
+
+// Helpers of the `onehistory` binary, not part of the stable API
+#[doc(hidden)]
+pub use crate::util::{
+    normalize_trailing_slash, now_ms, porcelain_line, remove_temp_copies, request_stop,
+    unixepoch_as_ymdhms,
+};
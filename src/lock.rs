@@ -0,0 +1,157 @@
+use crate::{
+    error::{Error, Result},
+    util::{now_ms, unixepoch_as_ymdhms},
+};
+use anyhow::Context;
+use fs2::FileExt;
+use log::{debug, warn};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+// Interval between two attempts when waiting for the lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Advisory lock on 1History database, held by processes writing to it, so they
+/// don't fail each other with SQLITE_BUSY. It's released when dropped.
+#[derive(Debug)]
+pub struct DbLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl DbLock {
+    /// Path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Path of lock file of `db_file`, `onehistory.db.lock` for `onehistory.db`.
+pub fn lock_file_of(db_file: &Path) -> PathBuf {
+    let mut name = db_file.as_os_str().to_owned();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+// pid and start time(unix epoch milliseconds) of the lock holder, written in lock file
+fn read_holder(file: &mut File) -> Option<(u32, i64)> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    let mut lines = content.lines();
+    let pid = lines.next()?.parse().ok()?;
+    let started_at = lines.next()?.parse().ok()?;
+    Some((pid, started_at))
+}
+
+fn write_holder(file: &mut File) -> std::io::Result<()> {
+    let now = now_ms();
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "{}\n{now}\n", process::id())?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists, EPERM means it exists but
+    // belongs to another user.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+// Whether `file` is still the one at `path`, it may be removed by others reclaiming
+// a stale lock between open and lock.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+enum Attempt {
+    Locked(File),
+    Held { pid: u32, started_at: i64 },
+    Retry,
+}
+
+fn try_lock(path: &Path) -> Result<Attempt> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| path.display().to_string())?;
+    if file.try_lock_exclusive().is_ok() {
+        if !is_current(&file, path) {
+            return Ok(Attempt::Retry);
+        }
+        write_holder(&mut file).context("write lock file")?;
+        return Ok(Attempt::Locked(file));
+    }
+
+    match read_holder(&mut file) {
+        Some((pid, _)) if !is_alive(pid) => {
+            // Lock is inherited by processes spawned by the crashed holder, such as
+            // hooks of `backup`, remove it so a new lock file is used.
+            warn!("Reclaim stale lock of dead process {pid}");
+            match fs::remove_file(path) {
+                Ok(()) => Ok(Attempt::Retry),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Attempt::Retry),
+                Err(e) => Err(Error::from(e)),
+            }
+        }
+        Some((pid, started_at)) => Ok(Attempt::Held { pid, started_at }),
+        // Holder hasn't written its pid yet
+        None => Ok(Attempt::Held {
+            pid: 0,
+            started_at: 0,
+        }),
+    }
+}
+
+/// Lock 1History database at `db_file` with `<db_file>.lock`, waiting at most `wait`
+/// when it's held by another process.
+///
+/// Locks left by crashed processes are detected by checking whether the holder is
+/// still alive, and reclaimed.
+pub fn lock_db<P: AsRef<Path>>(db_file: P, wait: Duration) -> Result<DbLock> {
+    let path = lock_file_of(db_file.as_ref());
+    let begin = Instant::now();
+    loop {
+        match try_lock(&path)? {
+            Attempt::Locked(file) => {
+                debug!("{} locked", path.display());
+                return Ok(DbLock { _file: file, path });
+            }
+            Attempt::Retry => continue,
+            Attempt::Held { pid, started_at } => {
+                if begin.elapsed() >= wait {
+                    return Err(Error::AlreadyRunning {
+                        pid,
+                        started_at: unixepoch_as_ymdhms(started_at),
+                    });
+                }
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Log file rotated by size, `<path>.1` is the most recent rotated file, and at most
+/// `keep` of them are kept.
+///
+/// Writes are line-buffered, so complete lines are always on disk, callers writing
+/// from multiple threads should lock it, then a line is never split by rotation.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    size: u64,
+    file: LineWriter<File>,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    /// Open log file at `path` for appending, it's rotated before it grows over
+    /// `max_size` bytes.
+    pub fn open<P: AsRef<Path>>(path: P, max_size: u64, keep: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            keep,
+            size,
+            file: LineWriter::new(file),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file = LineWriter::new(File::create(&self.path)?);
+        } else {
+            // Oldest one is overwritten
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = LineWriter::new(open_append(&self.path)?);
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("onehistory.log");
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        for i in 0..5 {
+            writeln!(file, "record {i:02} ......").unwrap();
+        }
+        let read = |p: PathBuf| fs::read_to_string(p).unwrap();
+        assert_eq!(read(path.clone()), "record 04 ......\n");
+        assert_eq!(read(file.rotated(1)), "record 03 ......\n");
+        assert_eq!(read(file.rotated(2)), "record 02 ......\n");
+        assert!(!file.rotated(3).exists());
+
+        // Size of existing file counts
+        drop(file);
+        let mut file = RotatingFile::open(&path, 40, 0).unwrap();
+        writeln!(file, "record 05 ......").unwrap();
+        assert_eq!(read(path.clone()), "record 04 ......\nrecord 05 ......\n");
+        writeln!(file, "record 06 ......").unwrap();
+        assert_eq!(read(path), "record 06 ......\n");
+    }
+}
@@ -1,31 +1,77 @@
-mod database;
-mod export;
-mod progress;
-mod source;
-mod types;
-mod util;
-mod web;
-
-use crate::database::Database;
-use crate::progress::TUICollector;
-use crate::source::Source;
-use crate::util::{full_timerange, DEFAULT_CSV_FILE, DEFAULT_DB_FILE};
-use anyhow::{Context, Error, Result};
-use clap::{Parser, Subcommand};
-use export::export_csv;
-use log::{debug, error, info, LevelFilter};
-use util::detect_history_files;
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use clap::{ArgEnum, Parser, Subcommand};
+use env_logger::Target;
+use lazy_static::lazy_static;
+use log::{error, info, warn, LevelFilter};
+use onehistory::{
+    backup, backup_synced, browse, check_db_integrity, convert_csv, convert_json,
+    detect_history_files, export_audit, export_csv, export_new_urls_csv, export_sync_bundle,
+    export_template, export_timeline, generate_demo, hook_env, import_sync_bundle,
+    inspect_history_files, install_crash_handler, install_schedule, is_stopped, list_browsers,
+    lock_db, lock_file_of, migrate_db, now_ms, open_url, porcelain_line, recover_db,
+    remove_temp_copies, render_digest, request_stop, schedule_status, schedule_status_porcelain,
+    search_visits, serve, synced_session_dirs, uninstall_schedule, unixepoch_as_ymdhms,
+    verify_manifest, weekly_digest, BackupOptions, BackupRun, BackupSummary, ByteCollector,
+    CountMode, CsvDialect, Database, DemoOptions, DigestFormat, ExportOptions, Hook, HourRange,
+    IsoWeek, KeywordOp, LogCollector, Notice, ProgressCollector, RotatingFile,
+    Schedule as BackupSchedule, ScheduleInterval, ScheduleTime, ServeOptions, SourceFilter,
+    TUICollector, TrailingSlash, VisitDetail, DATA_DB_FILE, DEFAULT_BATCH_BYTES, DEFAULT_BATCH_NUM,
+    DEFAULT_CSV_FILE, DEFAULT_DB_FILE, DEFAULT_KEEP_FRAGMENT_DOMAINS, LEGACY_DB_FILE,
+};
+use std::{
+    env, fs,
+    io::{self, BufWriter, IsTerminal, Write},
+    net::SocketAddr,
+    panic,
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+lazy_static! {
+    // Defaults of `--batch-visits` and `--batch-bytes`, the same as `Database`
+    static ref BATCH_VISITS: String = DEFAULT_BATCH_NUM.to_string();
+    static ref BATCH_BYTES: String = DEFAULT_BATCH_BYTES.to_string();
+}
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     /// Database path
-    #[clap(short, long, env("OH_DB_FILE"), default_value(&DEFAULT_DB_FILE))]
-    db_file: String,
+    #[clap(
+        short,
+        long,
+        env("OH_DB_FILE"),
+        default_value_os(DEFAULT_DB_FILE.as_os_str()),
+        parse(from_os_str)
+    )]
+    db_file: PathBuf,
 
     #[clap(short, long)]
     verbose: bool,
 
+    /// Also write logs into this file, rotated by size
+    #[clap(long, env("OH_LOG_FILE"), parse(from_os_str))]
+    log_file: Option<PathBuf>,
+    /// Rotate log file before it grows over this size, such as 512K or 10M
+    #[clap(
+        long,
+        env("OH_LOG_MAX_SIZE"),
+        default_value("10M"),
+        parse(try_from_str = parse_size),
+        value_name("SIZE")
+    )]
+    log_max_size: u64,
+    /// Number of rotated log files to keep
+    #[clap(long, env("OH_LOG_KEEP"), default_value("5"), value_name("N"))]
+    log_keep: usize,
+
+    /// Seconds to wait when database is locked by another onehistory process
+    #[clap(long, global(true), default_value("0"), value_name("SECONDS"))]
+    lock_wait: u64,
+
     #[clap(subcommand)]
     command: Command,
 }
@@ -36,114 +82,1510 @@ enum Command {
     Backup(Backup),
     /// Start HTTP server to visualize history
     Serve(Serve),
+    /// Browse history interactively in terminal
+    Tui,
     /// Show default history files on your computer
-    Show,
+    Show(Show),
     Export(Export),
+    /// Convert a browser history file to CSV/JSON, without 1History database
+    Convert(Convert),
+    /// Search visits by keyword in titles and urls, written to stdout
+    Search(Search),
+    /// Open the most recently visited url matching query in default browser
+    Open(Open),
+    /// Rebuild full text search index if it's stale
+    Reindex(Reindex),
+    /// Normalize urls already backed up, such as stripping their fragments
+    Normalize(Normalize),
+    /// Report visits imported by the most recent backup, grouped by domain
+    Whatsnew(Whatsnew),
+    /// Report of one week compared with the week before, such as visits and new domains
+    Digest(Digest),
+    /// Print which history file a visit is imported from, with its id there
+    Trace(Trace),
+    /// Hide visits from dashboards and exports without deleting them
+    Hide(Hide),
+    /// Compare with another 1History database, exit with 1 when they differ and 2 on
+    /// errors
+    Diff(Diff),
+    /// Move database from home directory to platform data directory
+    MigrateDb,
+    /// Check database for problems, such as urls left without visits
+    Doctor(Doctor),
+    /// Find visits whose urls are missing, and restore or remove them
+    Repair(Repair),
+    /// Print DDL of tables, indexes and full text search of the database, for writing
+    /// your own SQL
+    Schema,
+    /// Salvage readable rows of a corrupted database into a new file
+    Recover(Recover),
+    /// List recent backup runs with their summaries
+    Runs(Runs),
+    /// Move visits between machines with bundles of visits added since last time
+    #[clap(subcommand)]
+    Sync(SyncCommand),
+    /// Generate a database with fake history, to try 1History out
+    Demo(Demo),
+    /// Manage scheduled backup, via systemd on Linux and launchd on macOS
+    #[clap(subcommand)]
+    Schedule(ScheduleCommand),
 }
 
 #[derive(Parser, Debug)]
 struct Backup {
-    /// SQLite file path of different browsers(History.db/places.sqlite...)
-    #[clap(short('f'), long, required(false))]
-    history_files: Vec<String>,
+    /// SQLite file path of different browsers(History.db/places.sqlite...), glob
+    /// patterns such as '~/.config/chromium/*/History' are expanded
+    #[clap(short('f'), long, required(false), parse(from_os_str))]
+    history_files: Vec<PathBuf>,
     /// Disable auto detect history files
     #[clap(short('d'), long)]
     disable_detect: bool,
     #[clap(short('D'), long)]
     dry_run: bool,
+    /// Also backup tabs synced from other devices, read from Chrome's Sync Data(experimental)
+    #[clap(long)]
+    include_synced: bool,
+    /// Skip visits shorter than this many seconds, only works for Chrome
+    #[clap(long, value_name("SECONDS"))]
+    min_dwell: Option<u64>,
+    /// Only keep the final landing URL of redirect chains
+    #[clap(long)]
+    flatten_redirects: bool,
+    /// Skip local files(file://) and browser internal pages(about:, chrome://...)
+    #[clap(long)]
+    exclude_internal: bool,
+    #[clap(flatten)]
+    urls: UrlArgs,
+    /// Threads reading history files, visits read are written by one thread
+    #[clap(long, default_value("1"), value_name("N"))]
+    jobs: usize,
+    /// History files read but not written yet to keep in memory at most, readers
+    /// wait when it's full
+    #[clap(long, default_value("2"), value_name("N"))]
+    queue_size: usize,
+    /// Commit visits once this many are read, smaller batches lose less work when
+    /// interrupted
+    #[clap(long, default_value(BATCH_VISITS.as_str()), value_name("N"))]
+    batch_visits: usize,
+    /// Also commit visits once their urls and titles take this many bytes, so
+    /// giant urls(data: urls...) don't make huge transactions
+    #[clap(long, default_value(BATCH_BYTES.as_str()), value_name("BYTES"))]
+    batch_bytes: usize,
+    /// Only keep visits of last this many days, older ones are not imported and are
+    /// deleted after backup, 0 to disable
+    #[clap(long, env("OH_RETENTION_DAYS"), value_name("DAYS"))]
+    retention_days: Option<u64>,
+    /// Print time spent in copying, reading, url lookups, visit inserts and commits
+    /// of each history file, with rows per second, also kept in `runs --json`
+    #[clap(long)]
+    profile_timing: bool,
+    /// Send a desktop notification when backup finishes
+    #[clap(long)]
+    notify: bool,
+    /// Shell command to run after backup succeeds, with summary in OH_* env vars
+    #[clap(long, value_name("CMD"))]
+    on_success: Option<String>,
+    /// Shell command to run after backup fails, with summary in OH_* env vars
+    #[clap(long, value_name("CMD"))]
+    on_failure: Option<String>,
+    /// Kill hook command running longer than this many seconds
+    #[clap(long, default_value("60"), value_name("SECONDS"))]
+    hook_timeout: u64,
+    /// Fail backup when hook command fails or times out
+    #[clap(long)]
+    hook_strict: bool,
 }
 
 #[derive(Parser, Debug)]
 struct Serve {
-    /// Listening address
-    #[clap(short, long, default_value("127.0.0.1:9960"))]
-    addr: String,
+    /// Listening address, such as 0.0.0.0:9960 or [::1]:9960, repeat it or separate
+    /// by comma to listen on more than one
+    #[clap(
+        short,
+        long,
+        env("OH_SERVE_ADDR"),
+        default_value("127.0.0.1:9960"),
+        parse(try_from_str = parse_listen_addr),
+        use_delimiter(true),
+        value_name("ADDR")
+    )]
+    addr: Vec<SocketAddr>,
+    /// Show "(no title)" instead of url for visits without title
+    #[clap(long)]
+    no_title_fallback: bool,
+    /// Reload pages periodically, such as 30s, 5m or 1h, 0 to disable
+    #[clap(long, parse(try_from_str = parse_duration), value_name("DURATION"))]
+    read_refresh: Option<Duration>,
+    /// Abort queries running longer than this many milliseconds, 0 to disable
+    #[clap(long, default_value("10000"), value_name("MILLISECONDS"))]
+    query_timeout_ms: u64,
+    /// Minutes without visits that start a new browsing session in statistics
+    #[clap(long, default_value("30"), value_name("MINUTES"))]
+    session_gap: u64,
+    /// How visits are counted in daily counts chart, url-hour counts visits of the
+    /// same url within one hour once
+    #[clap(long, arg_enum, default_value("raw"))]
+    count_mode: DailyCountMode,
+    /// Count visits of the same url in daily counts chart only when this many minutes
+    /// passed since its previous visit, so reloads don't inflate the chart
+    #[clap(long, value_name("MINUTES"), conflicts_with("count-mode"))]
+    min_interval: Option<u64>,
+    /// Leave out local files(file://) and browser internal pages(about:, chrome://...)
+    /// from statistics, otherwise they are grouped under synthetic domains
+    #[clap(long)]
+    exclude_internal: bool,
+    /// Only include visits in these hours of local time, such as 9-18, or 22-6 which
+    /// wraps around midnight
+    #[clap(long, value_name("START-END"))]
+    hours: Option<HourRange>,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum DailyCountMode {
+    Raw,
+    UrlHour,
+}
+
+#[derive(Parser, Debug)]
+struct Show {
+    /// Output format, json is written to stdout
+    #[clap(short, long, arg_enum, default_value("text"))]
+    format: ShowFormat,
+    /// Print history file patterns of supported browsers on this OS as JSON
+    #[clap(long)]
+    list_browsers: bool,
+    /// Print one tab-separated line per file(or pattern) to stdout, whose fields are
+    /// stable across versions, for scripts
+    #[clap(long, conflicts_with("format"))]
+    porcelain: bool,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum ShowFormat {
+    Text,
+    Json,
 }
 
 #[derive(Parser, Debug)]
 struct Export {
+    #[clap(short, long, arg_enum, default_value("csv"))]
+    format: ExportFormat,
     /// Output cse file
-    #[clap(short, long, env("OH_EXPORT_CSV_FILE"), default_value(&DEFAULT_CSV_FILE))]
-    csv_file: String,
+    #[clap(
+        short,
+        long,
+        env("OH_EXPORT_CSV_FILE"),
+        default_value_os(DEFAULT_CSV_FILE.as_os_str()),
+        parse(from_os_str)
+    )]
+    csv_file: PathBuf,
+    /// Minijinja template file used to render histories, required by template format
+    #[clap(short, long, required_if_eq("format", "template"), parse(from_os_str))]
+    template: Option<PathBuf>,
+    /// Output file of template/timeline/audit format, default to stdout
+    #[clap(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Minutes without visits that start a new session in timeline format
+    #[clap(long, default_value("30"), value_name("MINUTES"))]
+    session_gap: u64,
+    /// Only export first visit of each url in csv format, as a log of new things found
+    #[clap(long)]
+    only_new_urls: bool,
+    /// Fetch favicons of top domains for template format, inlined as data URIs
+    #[clap(long)]
+    with_favicons: bool,
+    /// Leave out local files(file://) and browser internal pages(about:, chrome://...),
+    /// not used by audit format
+    #[clap(long)]
+    exclude_internal: bool,
+    /// Only export visits in these hours of local time, such as 9-18, or 22-6 which
+    /// wraps around midnight, not used by audit format
+    #[clap(long, value_name("START-END"))]
+    hours: Option<HourRange>,
+    /// Only export visits of this source, which is its id listed in source menu of
+    /// serve, browser name such as chrome, or unknown for visits imported before
+    /// sources are recorded, not used by audit format
+    #[clap(long, value_name("SOURCE"))]
+    source: Option<SourceFilter>,
+    /// Include visits hidden by hide subcommand
+    #[clap(long)]
+    include_hidden: bool,
+    /// Export urls as visited, before they are rewritten by --strip-fragments,
+    /// --normalize-trailing-slash or normalize subcommand, not used by audit format
+    #[clap(long)]
+    raw_urls: bool,
+    /// Write <OUTPUT>.manifest.json with filters, row count and SHA-256 of output
+    #[clap(long)]
+    manifest: bool,
+    /// Add lang column of language detected from title in csv format, empty when
+    /// detection isn't reliable, such as for short titles
+    #[clap(long)]
+    detect_language: bool,
+    /// Start csv with UTF-8 BOM, so Excel on Windows reads titles correctly
+    #[clap(long)]
+    bom: bool,
+    /// End lines of csv with CRLF
+    #[clap(long)]
+    crlf: bool,
+    /// Separator of csv fields, such as ; expected by Excel of some locales, or \t
+    #[clap(long, default_value(","), parse(try_from_str = parse_delimiter), value_name("CHAR"))]
+    delimiter: char,
+    /// Hash files listed in this manifest again and report mismatches, instead of export
+    #[clap(long, parse(from_os_str), value_name("MANIFEST"))]
+    verify_manifest: Option<PathBuf>,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum ExportFormat {
+    Csv,
+    Template,
+    /// JSON array of sessions, each with start/end and its visits
+    Timeline,
+    /// CSV of imported history files, with last import time and cumulative counts
+    Audit,
+    /// Like audit, but as a JSON array
+    AuditJson,
+}
+
+#[derive(Parser, Debug)]
+struct Convert {
+    /// SQLite file path of a browser(History.db/places.sqlite...)
+    #[clap(short, long, parse(from_os_str))]
+    input: PathBuf,
+    #[clap(short, long, arg_enum, default_value("csv"))]
+    format: ConvertFormat,
+    /// Output file, default to stdout
+    #[clap(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum ConvertFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum ScheduleCommand {
+    /// Install scheduled backup of current user
+    Install(ScheduleInstall),
+    /// Disable and remove scheduled backup
+    Uninstall,
+    /// Show whether scheduled backup is installed
+    Status(ScheduleStatus),
+}
+
+#[derive(Parser, Debug)]
+struct ScheduleStatus {
+    /// Print tab-separated lines whose fields are stable across versions, for scripts
+    #[clap(long)]
+    porcelain: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ScheduleInstall {
+    #[clap(long, arg_enum, default_value("daily"))]
+    interval: Interval,
+    /// Local time to run backup at, only minute is used by hourly interval
+    #[clap(long, default_value("03:30"), value_name("HH:MM"))]
+    time: ScheduleTime,
+    /// Enable it right now, otherwise commands to enable it are printed
+    #[clap(long)]
+    enable: bool,
+    /// Extra arguments of backup subcommand, such as `-- --include-synced`
+    #[clap(last(true))]
+    backup_args: Vec<String>,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum Interval {
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+#[derive(Parser, Debug)]
+struct Whatsnew {
+    /// Output format, json is written to stdout
+    #[clap(short, long, arg_enum, default_value("text"))]
+    format: ShowFormat,
+}
+
+#[derive(Parser, Debug)]
+struct Digest {
+    /// ISO week to report, such as 2024-W21, last week by default
+    #[clap(short, long, value_name("YYYY-Www"))]
+    week: Option<IsoWeek>,
+    /// html and markdown are rendered by built-in templates, json has raw numbers
+    #[clap(short, long, default_value("html"), value_name("html|markdown|json"))]
+    format: DigestFormat,
+    /// Output file, default to stdout
+    #[clap(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct Trace {
+    /// Id of visit in 1History, shown by details page with sources
+    visit_id: i64,
+    /// Output format, json is written to stdout
+    #[clap(short, long, arg_enum, default_value("text"))]
+    format: ShowFormat,
+}
+
+#[derive(Parser, Debug)]
+struct Hide {
+    /// Hide visits whose url matches this SQL LIKE pattern, such as %example.com%
+    #[clap(long, value_name("PATTERN"))]
+    url_like: String,
+    /// Show visits hidden before again
+    #[clap(long)]
+    undo: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Diff {
+    /// Another 1History database, opened read-only
+    #[clap(parse(from_os_str))]
+    other: PathBuf,
+    /// List sample visits and urls of each difference
+    #[clap(short, long)]
+    verbose: bool,
+    /// Output format, json is written to stdout
+    #[clap(short, long, arg_enum, default_value("text"))]
+    format: ShowFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum SyncCommand {
+    /// Export visits added after a watermark into a bundle
+    Export(SyncExport),
+    /// Import a bundle exported on another machine
+    Import(SyncImportArgs),
+    /// Show watermarks of machines whose bundles are imported
+    Status,
+}
+
+#[derive(Parser, Debug)]
+struct SyncExport {
+    /// Watermark printed when importing previous bundle on the other machine, 0 to
+    /// export all visits
+    #[clap(long, default_value("0"))]
+    since: i64,
+    /// Name of this machine, hostname by default
+    #[clap(long)]
+    peer: Option<String>,
+    #[clap(short, long, parse(from_os_str))]
+    output: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct SyncImportArgs {
+    /// Bundle written by `sync export`, such as delta.jsonl.gz
+    #[clap(parse(from_os_str))]
+    bundle: PathBuf,
+    #[clap(flatten)]
+    urls: UrlArgs,
+}
+
+#[derive(Parser, Debug)]
+struct UrlArgs {
+    /// Strip fragments of urls(#...), so routes of single-page apps are one url
+    #[clap(long, env("OH_STRIP_FRAGMENTS"))]
+    strip_fragments: bool,
+    /// Keep fragments of urls of these domains and their subdomains
+    #[clap(
+        long,
+        env("OH_KEEP_FRAGMENT_DOMAINS"),
+        default_value(DEFAULT_KEEP_FRAGMENT_DOMAINS),
+        use_delimiter(true),
+        value_name("DOMAINS")
+    )]
+    keep_fragment_domains: Vec<String>,
+    /// Strip(or keep) trailing slashes of url paths consistently, so urls only
+    /// differing by it are one url, root paths always keep it
+    #[clap(long, env("OH_NORMALIZE_TRAILING_SLASH"), value_name("strip|keep"))]
+    normalize_trailing_slash: Option<TrailingSlash>,
+}
+
+impl UrlArgs {
+    // Domains whose fragments are kept, `None` when fragments are not stripped
+    fn keep_domains(&self) -> Option<Vec<String>> {
+        self.strip_fragments
+            .then(|| self.keep_fragment_domains.clone())
+    }
+}
+
+#[derive(Parser, Debug)]
+struct Search {
+    /// Terms separated by whitespace all have to match, quote a phrase to keep its
+    /// spaces, such as `rust "async trait"`
+    keyword: String,
+    /// Match visits containing any term of keyword, rather than all
+    #[clap(long)]
+    any: bool,
+    /// Output format, jsonl writes one JSON object per visit as it's read
+    #[clap(short, long, arg_enum, default_value("text"))]
+    format: SearchFormat,
+    /// List most recent visits first
+    #[clap(short, long)]
+    reverse: bool,
+    /// Leave out local files(file://) and browser internal pages(about:, chrome://...)
+    #[clap(long)]
+    exclude_internal: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Open {
+    /// Matched against titles and urls like `search`, such as `grafana latency`
+    query: String,
+    /// List most recently visited matches, and open the one picked by its number
+    #[clap(short, long)]
+    list: bool,
+    /// Matches listed by --list at most
+    #[clap(short('n'), long, default_value("10"))]
+    limit: usize,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+enum SearchFormat {
+    /// Time, title and url separated by tabs
+    Text,
+    Jsonl,
+}
+
+#[derive(Parser, Debug)]
+struct Reindex {
+    /// Also build trigram index of titles from scratch, which is required by fuzzy
+    /// search(match=fuzzy of serve)
+    #[clap(long)]
+    fuzzy: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Normalize {
+    #[clap(flatten)]
+    urls: UrlArgs,
+}
+
+#[derive(Parser, Debug)]
+struct Recover {
+    /// File to write rows salvaged into, <DB_FILE>.recovered by default
+    #[clap(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct Doctor {
+    /// Fix problems found, such as removing urls left without visits
+    #[clap(long)]
+    fix: bool,
+    /// Warn when the most recent backup run is older than this many hours
+    #[clap(long, default_value("48"), value_name("HOURS"))]
+    stale_hours: u64,
+    /// Also print one tab-separated line per check to stdout, whose fields are stable
+    /// across versions, for scripts
+    #[clap(long)]
+    porcelain: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Repair {
+    /// Re-create missing urls by reading visits again from history files they are
+    /// imported from, when those files are still there
+    #[clap(long)]
+    restore_urls: bool,
+    /// Delete visits whose urls are still missing
+    #[clap(long)]
+    remove_dangling: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Runs {
+    #[clap(long, default_value("20"))]
+    limit: usize,
+    /// Print runs as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+struct Demo {
+    /// Number of days with history
+    #[clap(long, default_value("365"))]
+    days: u32,
+    /// Average visits per day
+    #[clap(long, default_value("80"))]
+    visits_per_day: u32,
+    /// Same seed always generates same history
+    #[clap(long, default_value("1"))]
+    seed: u64,
+    /// Last day of history(YYYY-MM-DD), default yesterday
+    #[clap(long)]
+    until: Option<String>,
+    /// Output database, must not exist
+    #[clap(short, long, default_value("demo.db"), parse(from_os_str))]
+    output: PathBuf,
 }
 
 fn main() {
+    install_crash_handler();
     let cli = Cli::parse();
     let level = if cli.verbose {
         LevelFilter::Debug
     } else {
         LevelFilter::Info
     };
-    env_logger::Builder::new().filter_level(level).init();
+    init_logger(&cli, level);
+    if cli.db_file == *LEGACY_DB_FILE && !matches!(cli.command, Command::MigrateDb) {
+        warn!(
+            "Database in home directory is deprecated, run `onehistory migrate-db` to move it to {}",
+            DATA_DB_FILE.display()
+        );
+    }
+    if let Err(e) = ctrlc::set_handler(|| {
+        // Backup stops by itself after committing its in-flight batch, so it can be
+        // resumed accurately, pressing again exits directly
+        if request_stop() {
+            warn!("Stopping after visits being persisted are committed...");
+            return;
+        }
+        remove_temp_copies();
+        process::exit(130);
+    }) {
+        error!("Set Ctrl-C handler failed, err:{:?}", e);
+    }
 
+    // Like diff(1), 1 of diff means they differ
+    let failure = if matches!(cli.command, Command::Diff(_)) {
+        2
+    } else {
+        1
+    };
     if let Err(e) = run(cli) {
+        if e.is::<DatabasesDiffer>() {
+            process::exit(1);
+        }
+        if is_stopped(&e) {
+            remove_temp_copies();
+            warn!("Interrupted, run backup again to resume from visits not committed yet.");
+            process::exit(130);
+        }
+        if is_corrupt(&e) {
+            error!("Run failed, err:{e:#}");
+            error!("The database is corrupted, run `onehistory recover` to salvage its readable rows into a new file.");
+            process::exit(failure);
+        }
         error!("Run failed, err:{:?}", e);
+        process::exit(failure);
     }
 }
 
-fn show(db_file: String) -> Result<()> {
-    info!("Local database:{}", db_file);
-    let mut cnt = 0;
-    for f in detect_history_files() {
-        cnt += 1;
-        info!("found:{}", f);
+// Returned by `diff` when databases differ, which exits with 1 like diff(1), so it
+// can gate scripts
+#[derive(Debug)]
+struct DatabasesDiffer;
+
+impl std::fmt::Display for DatabasesDiffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "databases differ")
     }
-    info!("Total:{cnt}");
-    Ok(())
 }
 
-fn backup(history_files: Vec<String>, db_file: String, dry_run: bool) -> Result<()> {
-    let (start, end) = full_timerange();
-    debug!("start:{}, end:{}", start, end);
-
-    let db = Database::open(db_file).context("open 1History DB")?;
-
-    let mut found = 0;
-    let mut total_affected = 0;
-    let mut total_duplicated = 0;
-    let mut persist = |history_file: String| {
-        let s = Source::open(&history_file).context("open")?;
-        let rows = s.select(start, end).context("select")?.collect::<Vec<_>>();
-        debug!("{:?} select {} histories", s.name(), rows.len());
-        found += rows.len();
-
-        info!("Begin backup {}...", &history_file);
-        let collector = TUICollector::new(rows.len() as u64);
-        if !dry_run {
-            let (affected, duplicated) =
-                db.persist(s.path(), rows, collector).context("persist")?;
-            debug!(
-                "{:?} affected:{}, duplicated:{}",
-                s.name(),
-                affected,
-                duplicated
-            );
-            total_affected += affected;
-            total_duplicated += duplicated;
+impl std::error::Error for DatabasesDiffer {}
+
+// Writes each log record to stderr and log file
+struct TeeWriter(Arc<Mutex<RotatingFile>>);
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = io::stderr().write_all(buf);
+        // env_logger writes one record at a time while holding its own lock, so
+        // records of backup/progress threads are never interleaved or lost by rotation
+        self.0.lock().unwrap().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+fn init_logger(cli: &Cli, level: LevelFilter) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level);
+    let path = match &cli.log_file {
+        Some(path) => path,
+        None => return builder.init(),
+    };
+    let file = match RotatingFile::open(path, cli.log_max_size, cli.log_keep) {
+        Ok(file) => Arc::new(Mutex::new(file)),
+        Err(e) => {
+            builder.init();
+            error!("Open log file {} failed, err:{:?}", path.display(), e);
+            process::exit(1);
+        }
+    };
+    builder.target(Target::Pipe(Box::new(TeeWriter(file.clone()))));
+    builder.init();
+
+    // Panic messages only go to stderr, keep them in log file too. Lock may be held by
+    // the panicking thread itself, so don't wait for it.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if let Ok(mut file) = file.try_lock() {
+            let _ = writeln!(file, "{info}");
+            let _ = file.flush();
+        }
+        default_hook(info);
+    }));
+}
+
+// Whether `e` is caused by a corrupted SQLite file
+fn is_corrupt(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<onehistory::Error>())
+        .any(|e| e.is_corrupt())
+}
+
+// Whether `e` is caused by reader of stdout going away, such as `search | head`
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::BrokenPipe)
+}
+
+// List `visits` on stderr, and read the number of one picked from stdin, the first
+// one when nothing is typed
+fn pick_url(visits: &[VisitDetail]) -> Result<&str> {
+    let mut stderr = io::stderr().lock();
+    for (i, visit) in visits.iter().enumerate() {
+        let title = if visit.title.is_empty() {
+            &visit.url
+        } else {
+            &visit.title
         };
-        info!("Finish backup {}", &history_file);
+        writeln!(
+            stderr,
+            "{:>3}  {}  {title}\n     {}",
+            i + 1,
+            unixepoch_as_ymdhms(visit.visit_time),
+            visit.url
+        )?;
+    }
+    write!(stderr, "Open [1-{}, Enter for 1]: ", visits.len())?;
+    stderr.flush()?;
+
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        bail!("nothing picked");
+    }
+    let picked = match line.trim() {
+        "" => 1,
+        n => n
+            .parse::<usize>()
+            .ok()
+            .filter(|n| (1..=visits.len()).contains(n))
+            .with_context(|| format!("expect a number between 1 and {}", visits.len()))?,
+    };
+    Ok(&visits[picked - 1].url)
+}
 
-        Ok::<_, Error>(())
+// Size in bytes, with optional K/M/G unit
+fn parse_size(s: &str) -> Result<u64> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let num: u64 = num.parse()?;
+    let size = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => num,
+        "K" => num << 10,
+        "M" => num << 20,
+        "G" => num << 30,
+        _ => bail!("invalid unit {unit}, expect K, M or G"),
     };
-    for his_file in history_files {
-        if let Err(e) = persist(his_file.clone()) {
-            error!("{} persist failed, err: {:?}", his_file, e);
+    Ok(size)
+}
+
+// Separator of csv fields, `\t` for tab
+fn parse_delimiter(s: &str) -> Result<char> {
+    let delimiter = match s {
+        "\\t" => '\t',
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => bail!("delimiter should be one character"),
+            }
         }
+    };
+    if matches!(delimiter, '"' | '\r' | '\n') {
+        bail!("delimiter can't be quote or line break");
+    }
+    Ok(delimiter)
+}
+
+// `IP:PORT`, IPv6 addresses are in brackets, such as `[::1]:9960`
+fn parse_listen_addr(s: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    if !s.starts_with('[') && s.matches(':').count() > 1 {
+        bail!("IPv6 address should be in brackets, such as [::1]:9960");
+    }
+    bail!("invalid address {s}, expect IP:PORT such as 127.0.0.1:9960 or [::1]:9960")
+}
+
+// Duration in seconds, with optional s/m/h unit
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let num: u64 = num.parse()?;
+    let secs = match unit {
+        "s" => num,
+        "m" => num * 60,
+        "h" => num * 3600,
+        _ => bail!("invalid unit {unit}, expect s, m or h"),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+#[derive(Clone, Copy)]
+enum ProgressUnit {
+    Rows,
+    Bytes,
+}
+
+/// Progress bar when stderr is a terminal, otherwise periodic log lines.
+fn progress_collector(unit: ProgressUnit) -> Box<dyn ProgressCollector> {
+    if !io::stderr().is_terminal() {
+        return Box::new(LogCollector::new());
+    }
+    match unit {
+        ProgressUnit::Rows => Box::new(TUICollector::new()),
+        ProgressUnit::Bytes => Box::new(ByteCollector::new()),
+    }
+}
+
+fn show(db_file: PathBuf, format: ShowFormat, list: bool, porcelain: bool) -> Result<()> {
+    if list {
+        let patterns = list_browsers();
+        if porcelain {
+            patterns.iter().for_each(|p| println!("{}", p.porcelain()));
+        } else {
+            println!("{}", serde_json::to_string_pretty(&patterns)?);
+        }
+        return Ok(());
+    }
+    let infos = inspect_history_files(&detect_history_files(), &db_file)?;
+    if porcelain {
+        infos
+            .iter()
+            .for_each(|info| println!("{}", info.porcelain()));
+        return Ok(());
+    }
+    if let ShowFormat::Json = format {
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+        return Ok(());
     }
 
-    info!("Summary\nFound:{found}, Imported:{total_affected}, Duplicated: {total_duplicated}");
+    info!("Local database:{}", db_file.display());
+    let ready = infos.iter().filter(|info| info.is_ready()).count();
+    for info in &infos {
+        info!("{info}");
+    }
+    info!(
+        "Total:{}, ready to backup:{ready}, problematic:{}",
+        infos.len(),
+        infos.len() - ready
+    );
+    Ok(())
+}
+
+// Run hook and log how it exits, return whether it succeeds
+fn run_hook(hook: Hook, envs: &[(&str, String)]) -> bool {
+    let outcome = match hook.run(envs) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            warn!("Hook failed to start, err: {e:#}");
+            return false;
+        }
+    };
+    for (name, output) in [("stdout", &outcome.stdout), ("stderr", &outcome.stderr)] {
+        if !output.trim().is_empty() {
+            info!("Hook {name}:\n{}", output.trim_end());
+        }
+    }
+    match outcome.status {
+        Some(status) if status.success() => info!("Hook `{}` succeeded.", hook.command),
+        Some(status) => warn!("Hook `{}` failed, {status}", hook.command),
+        None => warn!(
+            "Hook `{}` killed after {}s timeout",
+            hook.command,
+            hook.timeout.as_secs()
+        ),
+    }
+    outcome.success()
+}
+
+// Notify how backup ends, backup is done anyway so failure of it is only logged
+fn send_notice(summary: &onehistory::Result<BackupSummary>) {
+    let notice = match summary {
+        Ok(summary) => Notice::from_summary(summary),
+        Err(e) => Notice::from_error(e),
+    };
+    if let Err(e) = notice.send() {
+        warn!("Send notification failed, err: {e:#}");
+    }
+}
+
+fn sync(db_file: PathBuf, lock_wait: Duration, cmd: SyncCommand) -> Result<()> {
+    match cmd {
+        SyncCommand::Export(SyncExport {
+            since,
+            peer,
+            output,
+        }) => {
+            let peer =
+                peer.unwrap_or_else(|| gethostname::gethostname().to_string_lossy().to_string());
+            let db = Database::open(&db_file)?;
+            let (header, count) = export_sync_bundle(&db, &peer, since, &output)?;
+            info!(
+                "Exported {count} visits of {peer} into {}, watermark is {}.",
+                output.display(),
+                header.watermark
+            );
+        }
+        SyncCommand::Import(SyncImportArgs { bundle, urls }) => {
+            let _lock = lock_db(&db_file, lock_wait)?;
+            let db = Database::open(&db_file)?
+                .with_strip_fragments(urls.keep_domains())
+                .with_trailing_slash(urls.normalize_trailing_slash);
+            let import = import_sync_bundle(&db, &bundle, &progress_collector(ProgressUnit::Rows))?;
+            info!(
+                "Imported {} new visits from {}, {} duplicated. Export on {} with `--since {}` next time.",
+                import.imported, import.peer, import.duplicated, import.peer, import.watermark
+            );
+        }
+        SyncCommand::Status => {
+            let db = Database::open(&db_file)?;
+            let peers = db.select_sync_peers()?;
+            if peers.is_empty() {
+                info!("No sync bundle imported yet.");
+            }
+            for (peer, watermark, updated_at) in peers {
+                let updated_at = unixepoch_as_ymdhms(updated_at);
+                println!("{peer}\twatermark {watermark}\tupdated at {updated_at}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn schedule(db_file: PathBuf, cmd: ScheduleCommand) -> Result<()> {
+    let install = match cmd {
+        ScheduleCommand::Install(install) => install,
+        ScheduleCommand::Uninstall => {
+            let files = uninstall_schedule()?;
+            if files.is_empty() {
+                info!("Scheduled backup is not installed.");
+            }
+            for f in files {
+                info!("Removed {}", f.display());
+            }
+            return Ok(());
+        }
+        ScheduleCommand::Status(ScheduleStatus { porcelain }) => {
+            if porcelain {
+                schedule_status_porcelain()?
+                    .iter()
+                    .for_each(|line| println!("{line}"));
+            } else {
+                println!("{}", schedule_status()?);
+            }
+            return Ok(());
+        }
+    };
+
+    // Scheduled backup runs in another working directory
+    let mut args = vec![
+        "-d".to_string(),
+        std::path::absolute(&db_file)?.to_string_lossy().to_string(),
+        "backup".to_string(),
+    ];
+    args.extend(install.backup_args);
+    let schedule = BackupSchedule {
+        exe: env::current_exe()?,
+        args,
+        interval: match install.interval {
+            Interval::Hourly => ScheduleInterval::Hourly,
+            Interval::Daily => ScheduleInterval::Daily,
+            Interval::Weekly => ScheduleInterval::Weekly,
+        },
+        time: install.time,
+    };
+    let (files, cmds) = install_schedule(&schedule, install.enable)?;
+    for f in files {
+        info!("Written {}", f.display());
+    }
+    if cmds.is_empty() {
+        info!("Scheduled backup enabled.");
+    } else {
+        info!("Run following commands to enable it:\n{}", cmds.join("\n"));
+    }
     Ok(())
 }
 
 fn run(cli: Cli) -> Result<()> {
     match cli.command {
-        Command::Show => show(cli.db_file),
-        Command::Export(Export { csv_file }) => export_csv(csv_file, cli.db_file),
-        Command::Serve(Serve { addr }) => web::serve(addr, cli.db_file),
+        Command::Show(Show {
+            format,
+            list_browsers,
+            porcelain,
+        }) => show(cli.db_file, format, list_browsers, porcelain),
+        Command::Tui => Ok(browse(cli.db_file, Duration::from_secs(cli.lock_wait))?),
+        Command::Schedule(cmd) => schedule(cli.db_file, cmd),
+        Command::Sync(cmd) => sync(cli.db_file, Duration::from_secs(cli.lock_wait), cmd),
+        Command::Demo(Demo {
+            days,
+            visits_per_day,
+            seed,
+            until,
+            output,
+        }) => {
+            let options = DemoOptions {
+                days,
+                visits_per_day,
+                seed,
+                until,
+            };
+            let len = generate_demo(&output, &options, &progress_collector(ProgressUnit::Rows))?;
+            info!(
+                "Generate {len} visits into {}, run `onehistory -d {} serve` to explore.",
+                output.display(),
+                output.display()
+            );
+            Ok(())
+        }
+        Command::Whatsnew(Whatsnew { format }) => {
+            let db = Database::open(&cli.db_file)?;
+            let whatsnew = match db.select_whatsnew()? {
+                Some(whatsnew) => whatsnew,
+                None => bail!("no backup recorded yet, run backup first"),
+            };
+            match format {
+                ShowFormat::Json => println!("{}", serde_json::to_string_pretty(&whatsnew)?),
+                ShowFormat::Text => print!("{whatsnew}"),
+            }
+            Ok(())
+        }
+        Command::Digest(Digest {
+            week,
+            format,
+            output,
+        }) => {
+            let week =
+                week.unwrap_or_else(|| IsoWeek::of(Local::now().naive_local().date()).previous());
+            let db = Database::open(&cli.db_file)?;
+            let digest = render_digest(&weekly_digest(&db, week)?, format)?;
+            match output {
+                Some(output) => {
+                    fs::write(&output, digest)?;
+                    info!("Digest of {week} is written into {}.", output.display());
+                }
+                None => print!("{digest}"),
+            }
+            Ok(())
+        }
+        Command::Trace(Trace { visit_id, format }) => {
+            let db = Database::open(&cli.db_file)?;
+            let trace = match db.select_visit_trace(visit_id)? {
+                Some(trace) => trace,
+                None => bail!("no visit of id {visit_id}"),
+            };
+            match format {
+                ShowFormat::Json => println!("{}", serde_json::to_string_pretty(&trace)?),
+                ShowFormat::Text => print!("{trace}"),
+            }
+            Ok(())
+        }
+        Command::Hide(Hide { url_like, undo }) => {
+            let _lock = lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?;
+            let db = Database::open(&cli.db_file)?;
+            let changed = db.hide_visits(&url_like, !undo)?;
+            if undo {
+                info!("Unhid {changed} visits.");
+            } else {
+                info!("Hid {changed} visits.");
+            }
+            Ok(())
+        }
+        Command::Diff(Diff {
+            other,
+            verbose,
+            format,
+        }) => {
+            if !other.exists() {
+                bail!("{} not found", other.display());
+            }
+            let db = Database::open_read_only(&cli.db_file)
+                .with_context(|| format!("open {}", cli.db_file.display()))?;
+            let samples = if verbose { 10 } else { 0 };
+            let diff = db.diff(&other, samples)?;
+            match format {
+                ShowFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+                ShowFormat::Text => print!("{diff}"),
+            }
+            if !diff.is_empty() {
+                return Err(DatabasesDiffer.into());
+            }
+            Ok(())
+        }
+        Command::Recover(Recover { output }) => {
+            let _lock = lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?;
+            let problems = check_db_integrity(&cli.db_file)?;
+            if problems.is_empty() {
+                info!("No corruption found in {}.", cli.db_file.display());
+                return Ok(());
+            }
+            warn!("Integrity check failed, {}", problems.join("; "));
+            let output = output.unwrap_or_else(|| {
+                let mut output = cli.db_file.clone().into_os_string();
+                output.push(".recovered");
+                PathBuf::from(output)
+            });
+            let tables = recover_db(&cli.db_file, &output)?;
+            for t in &tables {
+                let lost = t.lost().map_or("unknown".to_string(), |n| n.to_string());
+                info!("{}: recovered {}, lost {lost}", t.table, t.recovered);
+            }
+            info!(
+                "Rows salvaged into {}, {} is left untouched. Check it with `onehistory -d {} doctor`, then replace the original with it.",
+                output.display(),
+                cli.db_file.display(),
+                output.display()
+            );
+            Ok(())
+        }
+        Command::MigrateDb => {
+            // Only legacy database is moved to its new default place, databases set
+            // by -d or OH_DB_FILE are left alone
+            if cli.db_file != *LEGACY_DB_FILE {
+                bail!(
+                    "{} is not in home directory, nothing to migrate",
+                    cli.db_file.display()
+                );
+            }
+            let lock = lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?;
+            migrate_db(&cli.db_file, &*DATA_DB_FILE)?;
+            drop(lock);
+            let _ = fs::remove_file(lock_file_of(&cli.db_file));
+            info!(
+                "Database moved to {}, it's used by default from now on.",
+                DATA_DB_FILE.display()
+            );
+            Ok(())
+        }
+        Command::Doctor(Doctor {
+            fix,
+            stale_hours,
+            porcelain,
+        }) => {
+            // Lines of --porcelain, `<check>\t<status>\t<value>`
+            let report = |check: &str, status: &str, value: String| {
+                if porcelain {
+                    println!("{}", porcelain_line(&[check, status, &value]));
+                }
+            };
+            let _lock = if fix {
+                Some(lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?)
+            } else {
+                None
+            };
+            let db = Database::open(&cli.db_file)?;
+            db.integrity_check()?;
+            info!("Integrity check passed.");
+            report("integrity", "ok", "-".to_string());
+            let orphans = db.count_orphan_urls()?;
+            if orphans == 0 {
+                info!("No urls without visits.");
+                report("orphan_urls", "ok", "0".to_string());
+            } else if fix {
+                let removed = db.cleanup_orphan_urls()?;
+                info!("Removed {removed} urls without visits.");
+                report("orphan_urls", "fixed", removed.to_string());
+            } else {
+                info!("Found {orphans} urls without visits, run with --fix to remove them.");
+                report("orphan_urls", "found", orphans.to_string());
+            }
+            let mismatched = db.verify_daily_summary()?;
+            if mismatched == 0 {
+                info!("Daily summary matches visits.");
+                report("daily_summary", "ok", "0".to_string());
+            } else if fix {
+                let buckets = db.rebuild_summary()?;
+                info!("Rebuilt daily summary with {buckets} buckets.");
+                report("daily_summary", "fixed", mismatched.to_string());
+            } else {
+                warn!("Found {mismatched} stale buckets in daily summary, run with --fix to rebuild it.");
+                report("daily_summary", "found", mismatched.to_string());
+            }
+            match db.select_backup_runs(1)?.first() {
+                Some(run) if now_ms() - run.started_at > stale_hours as i64 * 3_600_000 => {
+                    warn!(
+                        "Most recent backup ran at {}, more than {stale_hours} hours ago.",
+                        unixepoch_as_ymdhms(run.started_at)
+                    );
+                    report("last_backup", "stale", run.started_at.to_string());
+                }
+                Some(run) => {
+                    info!("Backup ran within {stale_hours} hours.");
+                    report("last_backup", "ok", run.started_at.to_string());
+                }
+                None => {
+                    warn!("No backup run recorded.");
+                    report("last_backup", "none", "-".to_string());
+                }
+            }
+            Ok(())
+        }
+        Command::Repair(Repair {
+            restore_urls,
+            remove_dangling,
+        }) => {
+            let _lock = if restore_urls || remove_dangling {
+                Some(lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?)
+            } else {
+                None
+            };
+            let db = Database::open(&cli.db_file)?;
+            db.integrity_check()?;
+            let violations = db.foreign_key_check()?;
+            if violations > 0 {
+                warn!("Found {violations} foreign key violations.");
+            }
+            info!("Integrity check passed.");
+            let dangling = db.select_dangling_items()?;
+            if dangling.is_empty() {
+                info!("All visits point to existing urls.");
+                return Ok(());
+            }
+            for (item_id, visits) in &dangling {
+                println!("{item_id}\t{visits}");
+            }
+            let visits: i64 = dangling.iter().map(|(_, visits)| visits).sum();
+            info!(
+                "Found {visits} visits of {} missing urls, listed above as `<url id>\t<visits>`.",
+                dangling.len()
+            );
+            if restore_urls {
+                let restored = db.restore_dangling_urls()?;
+                info!("Restored {restored} urls from history files.");
+            }
+            if remove_dangling {
+                let removed = db.delete_dangling_visits()?;
+                info!("Removed {removed} visits without urls.");
+            }
+            if !restore_urls && !remove_dangling {
+                info!("Run with --restore-urls or --remove-dangling to repair them.");
+            }
+            Ok(())
+        }
+        Command::Schema => {
+            let db = Database::open(&cli.db_file)?;
+            let (user_version, ddl) = db.select_schema()?;
+            let mut w = io::stdout().lock();
+            let written = writeln!(w, "-- user_version: {user_version}")
+                .and_then(|_| ddl.iter().try_for_each(|sql| writeln!(w, "\n{sql};")));
+            match written {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                written => Ok(written?),
+            }
+        }
+        Command::Runs(Runs { limit, json }) => {
+            let db = Database::open(&cli.db_file)?;
+            let runs = db.select_backup_runs(limit)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&runs)?);
+                return Ok(());
+            }
+            println!(
+                "{:<19} {:>8} {:>7} {:>9} {:>10} {:>7}  ERRORS",
+                "STARTED", "SECONDS", "SOURCES", "IMPORTED", "DUPLICATED", "SKIPPED"
+            );
+            for run in runs {
+                println!(
+                    "{:<19} {:>8} {:>7} {:>9} {:>10} {:>7}  {}",
+                    unixepoch_as_ymdhms(run.started_at),
+                    (run.finished_at - run.started_at) / 1000,
+                    run.sources,
+                    run.imported,
+                    run.duplicated,
+                    run.skipped,
+                    if run.ok() {
+                        "-".to_string()
+                    } else {
+                        run.errors.join("; ")
+                    }
+                );
+            }
+            Ok(())
+        }
+        Command::Open(Open { query, list, limit }) => {
+            let db = Database::open(&cli.db_file)?;
+            let urls = db.select_recent_urls(Some(query.clone()), if list { limit } else { 1 })?;
+            if urls.is_empty() {
+                bail!("no visit matches {query}");
+            }
+            let url = if list { pick_url(&urls)? } else { &urls[0].url };
+            println!("{url}");
+            open_url(url).context("open url")?;
+            Ok(())
+        }
+        Command::Search(Search {
+            keyword,
+            any,
+            format,
+            reverse,
+            exclude_internal,
+        }) => {
+            let db = Database::open(&cli.db_file)?
+                .with_exclude_internal(exclude_internal)
+                .with_keyword_op(if any { KeywordOp::Or } else { KeywordOp::And });
+            let jsonl = matches!(format, SearchFormat::Jsonl);
+            let w = BufWriter::new(io::stdout().lock());
+            match search_visits(&db, &keyword, reverse, jsonl, w) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    if is_broken_pipe(&e) {
+                        Ok(())
+                    } else {
+                        Err(e)
+                    }
+                }
+            }
+        }
+        Command::Reindex(Reindex { fuzzy }) => {
+            let _lock = lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?;
+            let db = Database::open(&cli.db_file)?;
+            if db.reindex()? {
+                info!("Full text search index rebuilt.");
+            } else {
+                info!("Full text search index is up to date.");
+            }
+            if fuzzy {
+                let urls = db.rebuild_fuzzy_index()?;
+                info!("Fuzzy search index of {urls} titles built.");
+            }
+            Ok(())
+        }
+        Command::Normalize(Normalize { urls }) => {
+            let keep_domains = urls.keep_domains();
+            if keep_domains.is_none() && urls.normalize_trailing_slash.is_none() {
+                bail!("nothing to normalize, try --strip-fragments or --normalize-trailing-slash");
+            }
+            let _lock = lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?;
+            let db = Database::open(&cli.db_file)?;
+            if let Some(keep_domains) = keep_domains {
+                let (normalized, removed) = db.strip_url_fragments(&keep_domains)?;
+                info!(
+                    "Stripped fragments of {normalized} urls, removed {removed} duplicated visits."
+                );
+            }
+            if let Some(mode) = urls.normalize_trailing_slash {
+                let (normalized, removed) = db.normalize_trailing_slashes(mode)?;
+                info!(
+                    "Normalized trailing slashes of {normalized} urls, removed {removed} duplicated visits."
+                );
+            }
+            Ok(())
+        }
+        Command::Export(Export {
+            format,
+            csv_file,
+            template,
+            output,
+            session_gap,
+            only_new_urls,
+            with_favicons,
+            exclude_internal,
+            hours,
+            source,
+            include_hidden,
+            raw_urls,
+            manifest,
+            detect_language,
+            bom,
+            crlf,
+            delimiter,
+            verify_manifest: None,
+        }) => {
+            let csv_dialect = CsvDialect {
+                bom,
+                crlf,
+                delimiter,
+            };
+            let options = ExportOptions {
+                exclude_internal,
+                hours,
+                source,
+                include_hidden,
+                raw_urls,
+                manifest,
+                detect_language,
+                csv_dialect,
+            };
+            match format {
+                _ if with_favicons && !matches!(format, ExportFormat::Template) => {
+                    bail!("--with-favicons only works with template format")
+                }
+                _ if detect_language && !matches!(format, ExportFormat::Csv) => {
+                    bail!("--detect-language only works with csv format")
+                }
+                _ if csv_dialect != CsvDialect::default()
+                    && !matches!(format, ExportFormat::Csv) =>
+                {
+                    bail!("--bom, --crlf and --delimiter only work with csv format")
+                }
+                ExportFormat::Csv if only_new_urls => Ok(export_new_urls_csv(
+                    csv_file,
+                    cli.db_file,
+                    &options,
+                    &progress_collector(ProgressUnit::Rows),
+                )?),
+                ExportFormat::Csv => Ok(export_csv(
+                    csv_file,
+                    cli.db_file,
+                    &options,
+                    &progress_collector(ProgressUnit::Rows),
+                )?),
+                _ if only_new_urls => bail!("--only-new-urls only works with csv format"),
+                ExportFormat::Template => {
+                    // Progress bar would be mixed with output in terminal
+                    let collector = if output.is_none() {
+                        Box::new(LogCollector::new())
+                    } else {
+                        progress_collector(ProgressUnit::Rows)
+                    };
+                    Ok(export_template(
+                        &template.expect("required by clap"),
+                        output,
+                        cli.db_file,
+                        with_favicons,
+                        &options,
+                        &collector,
+                    )?)
+                }
+                ExportFormat::Timeline => {
+                    let collector = if output.is_none() {
+                        Box::new(LogCollector::new())
+                    } else {
+                        progress_collector(ProgressUnit::Rows)
+                    };
+                    let sessions = export_timeline(
+                        output,
+                        cli.db_file,
+                        Duration::from_secs(session_gap * 60),
+                        &options,
+                        &collector,
+                    )?;
+                    info!("Export {sessions} sessions.");
+                    Ok(())
+                }
+                ExportFormat::Audit | ExportFormat::AuditJson => {
+                    let json = matches!(format, ExportFormat::AuditJson);
+                    let records = export_audit(output, cli.db_file, json, &options)?;
+                    info!("Export {records} import records.");
+                    Ok(())
+                }
+            }
+        }
+        Command::Export(Export {
+            verify_manifest: Some(manifest_file),
+            ..
+        }) => {
+            let checks = verify_manifest(&manifest_file)?;
+            let mismatches = checks.iter().filter(|c| !c.is_ok()).count();
+            for check in checks {
+                match check.actual {
+                    Some(actual) if actual == check.expected => {
+                        info!("ok {}", check.file.display())
+                    }
+                    Some(actual) => error!(
+                        "mismatch {}, expected sha256 {}, got {actual}",
+                        check.file.display(),
+                        check.expected
+                    ),
+                    None => error!("missing {}", check.file.display()),
+                }
+            }
+            if mismatches > 0 {
+                bail!("{mismatches} files don't match {}", manifest_file.display());
+            }
+            Ok(())
+        }
+        Command::Convert(Convert {
+            input,
+            format,
+            output,
+        }) => {
+            let len = match format {
+                ConvertFormat::Csv => convert_csv(&input, output)?,
+                ConvertFormat::Json => convert_json(&input, output)?,
+            };
+            info!("Convert {len} histories from {}.", input.display());
+            Ok(())
+        }
+        Command::Serve(Serve {
+            addr,
+            no_title_fallback,
+            read_refresh,
+            query_timeout_ms,
+            session_gap,
+            count_mode,
+            min_interval,
+            exclude_internal,
+            hours,
+        }) => {
+            let options = ServeOptions {
+                title_fallback: !no_title_fallback,
+                refresh: read_refresh,
+                query_timeout: Some(Duration::from_millis(query_timeout_ms)),
+                session_gap: Duration::from_secs(session_gap * 60),
+                daily_count_mode: match (min_interval, count_mode) {
+                    (Some(minutes), _) => CountMode::MinInterval(Duration::from_secs(minutes * 60)),
+                    (None, DailyCountMode::Raw) => CountMode::Raw,
+                    (None, DailyCountMode::UrlHour) => CountMode::UrlHour,
+                },
+                exclude_internal,
+                hours,
+            };
+            Ok(serve(addr, cli.db_file, options)?)
+        }
         Command::Backup(Backup {
             history_files,
             disable_detect,
             dry_run,
+            include_synced,
+            min_dwell,
+            flatten_redirects,
+            exclude_internal,
+            urls,
+            jobs,
+            queue_size,
+            batch_visits,
+            batch_bytes,
+            retention_days,
+            profile_timing,
+            notify,
+            on_success,
+            on_failure,
+            hook_timeout,
+            hook_strict,
         }) => {
             let mut fs = if disable_detect {
                 Vec::new()
@@ -151,7 +1593,89 @@ fn run(cli: Cli) -> Result<()> {
                 detect_history_files()
             };
             fs.extend(history_files);
-            backup(fs, cli.db_file, dry_run)
+            let _lock = if dry_run {
+                None
+            } else {
+                Some(lock_db(&cli.db_file, Duration::from_secs(cli.lock_wait))?)
+            };
+            let started_at = now_ms();
+            let sync_dirs = synced_session_dirs(&fs);
+            let collector = progress_collector(ProgressUnit::Rows);
+            let options = BackupOptions {
+                dry_run,
+                min_dwell: min_dwell.map(Duration::from_secs),
+                flatten_redirects,
+                exclude_internal,
+                strip_fragments: urls.keep_domains(),
+                trailing_slash: urls.normalize_trailing_slash,
+                jobs,
+                queue_size,
+                batch_visits,
+                batch_bytes,
+                retention_days: retention_days.filter(|days| *days > 0),
+                profile_timing,
+            };
+            let summary = backup(
+                fs,
+                &cli.db_file,
+                &options,
+                &collector,
+                &progress_collector(ProgressUnit::Bytes),
+            )
+            .and_then(|mut summary| {
+                if include_synced {
+                    let synced = backup_synced(sync_dirs, &cli.db_file, &options, &collector)?;
+                    summary.reports.extend(synced.reports);
+                }
+                Ok(summary)
+            });
+            if !dry_run {
+                let mut run = match &summary {
+                    Ok(summary) => summary.to_run(started_at, now_ms()),
+                    Err(e) => BackupRun::failed(started_at, now_ms(), e),
+                };
+                run.retention_days = options.retention_days;
+                // Database may be the cause of failure, so it's only logged
+                if let Err(e) =
+                    Database::open(&cli.db_file).and_then(|db| db.record_backup_run(&run))
+                {
+                    warn!("Record backup run failed, err: {e:#}");
+                }
+            }
+            if notify {
+                send_notice(&summary);
+            }
+            let hook = match &summary {
+                Ok(summary) if summary.failed() == 0 => on_success,
+                _ => on_failure,
+            };
+            // Hooks have side effects, such as uploading database, skip them in dry run
+            let hook_ok = match hook.filter(|_| !dry_run) {
+                Some(command) => run_hook(
+                    Hook {
+                        command,
+                        timeout: Duration::from_secs(hook_timeout),
+                    },
+                    &hook_env(summary.as_ref().ok(), &cli.db_file),
+                ),
+                None => true,
+            };
+            let summary = summary?;
+            info!("Summary\n{summary}");
+            if profile_timing {
+                info!("Timing(ms)\n{}", summary.timing_table());
+            }
+            if summary.failed() > 0 {
+                bail!(
+                    "{} of {} history files failed to backup",
+                    summary.failed(),
+                    summary.reports.len()
+                );
+            }
+            if hook_strict && !hook_ok {
+                bail!("hook failed, see logs above");
+            }
+            Ok(())
         }
     }
 }
@@ -0,0 +1,145 @@
+use crate::error::Result;
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Written alongside an export as `<output>.manifest.json`, which records how the
+/// export is produced, and checksums to detect bit rot later, see `verify_manifest`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// unix_epoch_ms when the export finished
+    pub created_at: i64,
+    /// Version of onehistory which produced the export
+    pub version: String,
+    pub db_file: String,
+    /// Export format, such as `csv` or `timeline`
+    pub format: String,
+    /// Options deciding what's exported, such as `exclude_internal`
+    pub filters: BTreeMap<String, serde_json::Value>,
+    /// Files of the export, one for each part
+    pub parts: Vec<ManifestPart>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestPart {
+    /// File name, relative to directory of manifest
+    pub file: String,
+    /// Visits exported, or import records for audit format
+    pub rows: usize,
+    pub bytes: u64,
+    /// Hex encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Outcome of checking one part of a manifest.
+#[derive(Debug, PartialEq)]
+pub struct PartCheck {
+    pub file: PathBuf,
+    pub expected: String,
+    /// SHA-256 of the file now, `None` when it can't be read
+    pub actual: Option<String>,
+}
+
+impl PartCheck {
+    pub fn is_ok(&self) -> bool {
+        self.actual.as_ref() == Some(&self.expected)
+    }
+}
+
+/// Manifest file of export at `output`, `<output>.manifest.json`.
+pub fn manifest_file_of(output: &Path) -> PathBuf {
+    let mut file = output.as_os_str().to_owned();
+    file.push(".manifest.json");
+    PathBuf::from(file)
+}
+
+/// Writer computing SHA-256 of bytes written through it, so exports are hashed
+/// while they are written, without reading them again.
+pub(crate) struct HashWriter<W: Write> {
+    w: W,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub(crate) fn new(w: W) -> Self {
+        Self {
+            w,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Hex encoded SHA-256 and length of bytes written.
+    pub(crate) fn finish(self) -> (String, u64) {
+        (hex(&self.hasher.finalize()), self.bytes)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn sha256_of(file: &Path) -> io::Result<String> {
+    let mut w = HashWriter::new(io::sink());
+    io::copy(&mut File::open(file)?, &mut w)?;
+    Ok(w.finish().0)
+}
+
+/// Read manifest at `manifest_file`, and hash each part again to detect files
+/// changed or lost since exported.
+pub fn verify_manifest<P: AsRef<Path>>(manifest_file: P) -> Result<Vec<PartCheck>> {
+    let manifest_file = manifest_file.as_ref();
+    let content =
+        fs::read_to_string(manifest_file).with_context(|| manifest_file.display().to_string())?;
+    let manifest: Manifest = serde_json::from_str(&content).context("parse manifest")?;
+    let dir = manifest_file.parent().unwrap_or_else(|| Path::new(""));
+    Ok(manifest
+        .parts
+        .into_iter()
+        .map(|part| {
+            let file = dir.join(&part.file);
+            PartCheck {
+                actual: sha256_of(&file).ok(),
+                file,
+                expected: part.sha256,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_writer() {
+        let mut w = HashWriter::new(Vec::new());
+        w.write_all(b"onehistory").unwrap();
+        let (sha256, bytes) = w.finish();
+        assert_eq!(
+            sha256,
+            "6613e8cb4f4bdd9b11522fc2e55e253dba84c35653048de3b9d0b8525be38719"
+        );
+        assert_eq!(bytes, 10);
+    }
+}
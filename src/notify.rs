@@ -0,0 +1,181 @@
+use crate::{
+    backup::{BackupStatus, BackupSummary},
+    error::Result,
+};
+use anyhow::Context;
+use notify_rust::{Notification, Timeout};
+use std::fmt::Display;
+
+const APP_NAME: &str = "1History";
+
+/// Desktop notification sent when backup finishes.
+#[derive(Debug, PartialEq)]
+pub struct Notice {
+    pub title: String,
+    pub body: String,
+    /// Backup failed or is incomplete, notified more prominently.
+    pub alert: bool,
+}
+
+impl Notice {
+    /// Notice of a finished backup, described by `summary`.
+    pub fn from_summary(summary: &BackupSummary) -> Self {
+        let total = summary.reports.len();
+        let imported = summary.imported();
+        let denied = summary
+            .reports
+            .iter()
+            .filter(|r| is_permission_denied(&r.status))
+            .count();
+        if denied > 0 {
+            return Self {
+                title: format!("{APP_NAME} backup incomplete"),
+                body: format!(
+                    "{denied} of {total} history files not readable, check permissions. \
+                     {imported} new visits imported."
+                ),
+                alert: true,
+            };
+        }
+        let failed = summary.failed();
+        if failed > 0 {
+            return Self {
+                title: format!("{APP_NAME} backup failed"),
+                body: format!(
+                    "{failed} of {total} history files failed to back up. \
+                     {imported} new visits imported."
+                ),
+                alert: true,
+            };
+        }
+        let backed_up = total - summary.skipped();
+        Self {
+            title: format!("{APP_NAME} backup finished"),
+            body: format!("{imported} new visits imported from {backed_up} history files."),
+            alert: false,
+        }
+    }
+
+    /// Notice of a backup aborted by `err`.
+    pub fn from_error(err: impl Display) -> Self {
+        Self {
+            title: format!("{APP_NAME} backup failed"),
+            body: format!("{err:#}"),
+            alert: true,
+        }
+    }
+
+    /// Show this notice as desktop notification, XDG notification on Linux,
+    /// Notification Center on macOS and toast on Windows.
+    pub fn send(&self) -> Result<()> {
+        let mut notification = Notification::new();
+        notification
+            .appname(APP_NAME)
+            .summary(&self.title)
+            .body(&self.body);
+        if self.alert {
+            // Stay until dismissed, instead of fading out silently
+            notification.timeout(Timeout::Never);
+            #[cfg(all(unix, not(target_os = "macos")))]
+            notification.urgency(notify_rust::Urgency::Critical);
+            #[cfg(target_os = "macos")]
+            notification.sound_name("Basso");
+        }
+        notification.show().context("show notification")?;
+        Ok(())
+    }
+}
+
+// Reasons of history files not readable by 1History, such as missing Full Disk
+// Access on macOS
+const PERMISSION_REASONS: &[&str] = &[
+    "permission denied",
+    "operation not permitted",
+    "authorization denied",
+];
+
+fn is_permission_denied(status: &BackupStatus) -> bool {
+    match status {
+        BackupStatus::Skipped(reason) | BackupStatus::Failed(reason) => {
+            let reason = reason.to_lowercase();
+            PERMISSION_REASONS.iter().any(|r| reason.contains(r))
+        }
+        BackupStatus::Ok { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::SourceReport;
+    use std::path::PathBuf;
+
+    fn summary(statuses: Vec<BackupStatus>) -> BackupSummary {
+        BackupSummary {
+            reports: statuses
+                .into_iter()
+                .enumerate()
+                .map(|(i, status)| SourceReport {
+                    path: PathBuf::from(format!("History{i}")),
+                    profile: String::new(),
+                    browser: None,
+                    status,
+                })
+                .collect(),
+            pruned: 0,
+            timings: Vec::new(),
+        }
+    }
+
+    fn ok(imported: usize) -> BackupStatus {
+        BackupStatus::Ok {
+            found: imported,
+            imported,
+            duplicated: 0,
+        }
+    }
+
+    #[test]
+    fn test_notice() {
+        let notice = Notice::from_summary(&summary(vec![
+            ok(10),
+            ok(5),
+            BackupStatus::Skipped("not found".to_string()),
+        ]));
+        assert_eq!(
+            notice,
+            Notice {
+                title: "1History backup finished".to_string(),
+                body: "15 new visits imported from 2 history files.".to_string(),
+                alert: false,
+            }
+        );
+
+        let notice = Notice::from_summary(&summary(vec![
+            ok(10),
+            BackupStatus::Failed("persist: database is locked".to_string()),
+        ]));
+        assert_eq!(notice.title, "1History backup failed");
+        assert_eq!(
+            notice.body,
+            "1 of 2 history files failed to back up. 10 new visits imported."
+        );
+        assert!(notice.alert);
+
+        let notice = Notice::from_summary(&summary(vec![
+            BackupStatus::Failed("open: Permission denied (os error 13)".to_string()),
+            BackupStatus::Failed("persist: database is locked".to_string()),
+            ok(0),
+        ]));
+        assert_eq!(notice.title, "1History backup incomplete");
+        assert_eq!(
+            notice.body,
+            "1 of 3 history files not readable, check permissions. 0 new visits imported."
+        );
+        assert!(notice.alert);
+
+        let notice = Notice::from_error("open 1History DB");
+        assert_eq!(notice.body, "open 1History DB");
+        assert!(notice.alert);
+    }
+}
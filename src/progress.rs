@@ -1,28 +1,224 @@
-use indicatif::ProgressBar;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-pub trait ProgressCollector {
+// Minimum interval between two lines of `LogCollector`
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Collect progress of long-running tasks, such as backup, whose threads share it.
+pub trait ProgressCollector: Sync {
+    /// Called when a new task with `total` steps begins.
+    fn start(&self, total: u64);
     fn inc(&self, delta: u64);
     fn finish(&self);
 }
 
+/// Show progress as a progress bar in terminal.
+#[derive(Default)]
 pub struct TUICollector {
-    pb: ProgressBar,
+    pb: Mutex<Option<ProgressBar>>,
 }
 
 impl TUICollector {
-    pub fn new(len: u64) -> Self {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ProgressCollector for TUICollector {
+    fn start(&self, total: u64) {
+        *self.pb.lock().unwrap() = Some(ProgressBar::new(total));
+    }
+
+    fn inc(&self, delta: u64) {
+        if let Some(pb) = self.pb.lock().unwrap().as_ref() {
+            pb.inc(delta);
+        }
+    }
+
+    fn finish(&self) {
+        if let Some(pb) = self.pb.lock().unwrap().take() {
+            pb.finish();
+        }
+    }
+}
+
+/// Like `TUICollector`, but progress is measured in bytes, with throughput shown.
+#[derive(Default)]
+pub struct ByteCollector {
+    pb: Mutex<Option<ProgressBar>>,
+}
+
+impl ByteCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ProgressCollector for ByteCollector {
+    fn start(&self, total: u64) {
+        let pb = ProgressBar::new(total).with_style(ProgressStyle::default_bar().template(
+            "[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        ));
+        *self.pb.lock().unwrap() = Some(pb);
+    }
+
+    fn inc(&self, delta: u64) {
+        if let Some(pb) = self.pb.lock().unwrap().as_ref() {
+            pb.inc(delta);
+        }
+    }
+
+    fn finish(&self) {
+        if let Some(pb) = self.pb.lock().unwrap().take() {
+            pb.finish();
+        }
+    }
+}
+
+/// Log progress periodically, used when progress bar can't be shown, such as stderr
+/// is not a terminal.
+pub struct LogCollector {
+    done: AtomicU64,
+    total: AtomicU64,
+    last_log: Mutex<Instant>,
+}
+
+impl LogCollector {
+    pub fn new() -> Self {
         Self {
-            pb: ProgressBar::new(len),
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            last_log: Mutex::new(Instant::now()),
         }
     }
+
+    fn log(&self) {
+        let done = self.done.load(Ordering::SeqCst);
+        let total = self.total.load(Ordering::SeqCst);
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        info!("Progress: {done}/{total} ({percent}%)");
+    }
 }
 
-impl ProgressCollector for TUICollector {
+impl Default for LogCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressCollector for LogCollector {
+    fn start(&self, total: u64) {
+        self.done.store(0, Ordering::SeqCst);
+        self.total.store(total, Ordering::SeqCst);
+        *self.last_log.lock().unwrap() = Instant::now();
+    }
+
     fn inc(&self, delta: u64) {
-        self.pb.inc(delta);
+        self.done.fetch_add(delta, Ordering::SeqCst);
+        let mut last_log = self.last_log.lock().unwrap();
+        if last_log.elapsed() >= LOG_INTERVAL {
+            *last_log = Instant::now();
+            self.log();
+        }
     }
 
     fn finish(&self) {
-        self.pb.finish();
+        self.log();
+    }
+}
+
+impl<T: ProgressCollector + ?Sized> ProgressCollector for Box<T> {
+    fn start(&self, total: u64) {
+        (**self).start(total)
+    }
+
+    fn inc(&self, delta: u64) {
+        (**self).inc(delta)
+    }
+
+    fn finish(&self) {
+        (**self).finish()
+    }
+}
+
+/// Discard all progress.
+pub struct SilentCollector;
+
+impl ProgressCollector for SilentCollector {
+    fn start(&self, _total: u64) {}
+    fn inc(&self, _delta: u64) {}
+    fn finish(&self) {}
+}
+
+/// Send `(done, total)` over a channel every time progress is made.
+pub struct ChannelCollector {
+    tx: Sender<(u64, u64)>,
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl ChannelCollector {
+    pub fn new(tx: Sender<(u64, u64)>) -> Self {
+        Self {
+            tx,
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    fn send(&self) {
+        let progress = (
+            self.done.load(Ordering::SeqCst),
+            self.total.load(Ordering::SeqCst),
+        );
+        // Receiver may be dropped, progress is not important enough to fail tasks.
+        let _ = self.tx.send(progress);
+    }
+}
+
+impl ProgressCollector for ChannelCollector {
+    fn start(&self, total: u64) {
+        self.done.store(0, Ordering::SeqCst);
+        self.total.store(total, Ordering::SeqCst);
+        self.send();
+    }
+
+    fn inc(&self, delta: u64) {
+        self.done.fetch_add(delta, Ordering::SeqCst);
+        self.send();
+    }
+
+    fn finish(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_channel_collector() {
+        let (tx, rx) = channel();
+        let collector = ChannelCollector::new(tx);
+        collector.start(3);
+        collector.inc(2);
+        collector.inc(1);
+        collector.finish();
+        collector.start(1);
+        collector.inc(1);
+        drop(collector);
+
+        assert_eq!(
+            rx.iter().collect::<Vec<_>>(),
+            vec![(0, 3), (2, 3), (3, 3), (0, 1), (1, 1)]
+        );
     }
 }
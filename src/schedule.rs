@@ -0,0 +1,355 @@
+use crate::{
+    error::{Error, Result},
+    util::porcelain_line,
+};
+use anyhow::{anyhow, Context};
+use home::home_dir;
+use std::{
+    env,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::PathBuf,
+    process::Command,
+    str::FromStr,
+};
+
+const SYSTEMD_UNIT: &str = "onehistory-backup";
+const LAUNCHD_LABEL: &str = "com.github.1history.backup";
+
+/// How often scheduled backup runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleInterval {
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+impl Display for ScheduleInterval {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Local time of day scheduled backup runs at, in `HH:MM` format. Only minute is
+/// used by hourly interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScheduleTime {
+    pub hour: u32,
+    pub minute: u32,
+}
+
+impl FromStr for ScheduleTime {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = || {
+            let (hour, minute) = s.split_once(':')?;
+            let (hour, minute) = (hour.parse().ok()?, minute.parse().ok()?);
+            (hour < 24 && minute < 60).then_some(ScheduleTime { hour, minute })
+        };
+        parse().ok_or_else(|| anyhow!("invalid time {s}, expect HH:MM").into())
+    }
+}
+
+/// Scheduled backup, which runs `exe` with `args` periodically.
+pub struct Schedule {
+    pub exe: PathBuf,
+    pub args: Vec<String>,
+    pub interval: ScheduleInterval,
+    pub time: ScheduleTime,
+}
+
+// Quote one argument of ExecStart
+// https://www.freedesktop.org/software/systemd/man/systemd.service.html#Command%20lines
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+impl Schedule {
+    fn command_line(&self) -> Vec<String> {
+        let mut cmd = vec![self.exe.to_string_lossy().to_string()];
+        cmd.extend(self.args.iter().cloned());
+        cmd
+    }
+
+    /// Content of systemd service unit.
+    pub fn systemd_service(&self) -> String {
+        let exec = self
+            .command_line()
+            .iter()
+            .map(|arg| systemd_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            r#"[Unit]
+Description=Backup browser history with 1History
+
+[Service]
+Type=oneshot
+ExecStart={exec}
+"#
+        )
+    }
+
+    /// Content of systemd timer unit, which triggers the service.
+    pub fn systemd_timer(&self) -> String {
+        let ScheduleTime { hour, minute } = self.time;
+        let calendar = match self.interval {
+            ScheduleInterval::Hourly => format!("*-*-* *:{minute:02}:00"),
+            ScheduleInterval::Daily => format!("*-*-* {hour:02}:{minute:02}:00"),
+            ScheduleInterval::Weekly => format!("Mon *-*-* {hour:02}:{minute:02}:00"),
+        };
+        format!(
+            r#"[Unit]
+Description=Run 1History backup {}
+
+[Timer]
+OnCalendar={calendar}
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+"#,
+            self.interval
+        )
+    }
+
+    /// Content of launchd property list.
+    pub fn launchd_plist(&self) -> String {
+        let args = self
+            .command_line()
+            .iter()
+            .map(|arg| format!("        <string>{}</string>\n", xml_escape(arg)))
+            .collect::<String>();
+        let ScheduleTime { hour, minute } = self.time;
+        let mut calendar = String::new();
+        if self.interval == ScheduleInterval::Weekly {
+            // Monday
+            calendar.push_str("        <key>Weekday</key>\n        <integer>1</integer>\n");
+        }
+        if self.interval != ScheduleInterval::Hourly {
+            calendar.push_str(&format!(
+                "        <key>Hour</key>\n        <integer>{hour}</integer>\n"
+            ));
+        }
+        calendar.push_str(&format!(
+            "        <key>Minute</key>\n        <integer>{minute}</integer>\n"
+        ));
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+{args}    </array>
+    <key>StartCalendarInterval</key>
+    <dict>
+{calendar}    </dict>
+</dict>
+</plist>
+"#
+        )
+    }
+}
+
+fn home() -> Result<PathBuf> {
+    Ok(home_dir().ok_or_else(|| anyhow!("home directory not found"))?)
+}
+
+fn systemd_dir() -> Result<PathBuf> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home()?.join(".config"),
+    };
+    Ok(config.join("systemd").join("user"))
+}
+
+fn launchd_plist_path() -> Result<PathBuf> {
+    Ok(home()?
+        .join("Library")
+        .join("LaunchAgents")
+        .join(format!("{LAUNCHD_LABEL}.plist")))
+}
+
+// Files of scheduled backup on this OS
+fn unit_files() -> Result<Vec<PathBuf>> {
+    match env::consts::OS {
+        "linux" => {
+            let dir = systemd_dir()?;
+            Ok(vec![
+                dir.join(format!("{SYSTEMD_UNIT}.service")),
+                dir.join(format!("{SYSTEMD_UNIT}.timer")),
+            ])
+        }
+        "macos" => Ok(vec![launchd_plist_path()?]),
+        os => Err(anyhow!("scheduled backup is not supported on {os}").into()),
+    }
+}
+
+fn command(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+// Commands to enable scheduled backup once files are written
+fn enable_commands() -> Result<Vec<Vec<String>>> {
+    let timer = format!("{SYSTEMD_UNIT}.timer");
+    Ok(match env::consts::OS {
+        "linux" => vec![
+            command(&["systemctl", "--user", "daemon-reload"]),
+            command(&["systemctl", "--user", "enable", "--now", &timer]),
+        ],
+        _ => vec![command(&[
+            "launchctl",
+            "load",
+            "-w",
+            &launchd_plist_path()?.to_string_lossy(),
+        ])],
+    })
+}
+
+fn run_command(cmd: &[String]) -> Result<String> {
+    let output = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .output()
+        .with_context(|| cmd.join(" "))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed, {}",
+            cmd.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(stdout)
+}
+
+/// Write unit files of `schedule` for this OS, systemd user units on Linux, and
+/// launchd agent on macOS. They are also enabled when `enable` is true, otherwise
+/// commands to enable them are returned.
+///
+/// Return written files and commands to run.
+pub fn install_schedule(schedule: &Schedule, enable: bool) -> Result<(Vec<PathBuf>, Vec<String>)> {
+    let files = unit_files()?;
+    let contents = match env::consts::OS {
+        "linux" => vec![schedule.systemd_service(), schedule.systemd_timer()],
+        _ => vec![schedule.launchd_plist()],
+    };
+    for (file, content) in files.iter().zip(contents) {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+        }
+        fs::write(file, content).with_context(|| file.display().to_string())?;
+    }
+
+    let cmds = enable_commands()?;
+    if enable {
+        for cmd in &cmds {
+            run_command(cmd)?;
+        }
+        return Ok((files, Vec::new()));
+    }
+    Ok((files, cmds.iter().map(|cmd| cmd.join(" ")).collect()))
+}
+
+/// Disable and remove scheduled backup, return removed files.
+pub fn uninstall_schedule() -> Result<Vec<PathBuf>> {
+    let files = unit_files()?
+        .into_iter()
+        .filter(|f| f.exists())
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Ok(files);
+    }
+    let disable = match env::consts::OS {
+        "linux" => command(&[
+            "systemctl",
+            "--user",
+            "disable",
+            "--now",
+            &format!("{SYSTEMD_UNIT}.timer"),
+        ]),
+        _ => command(&["launchctl", "unload", "-w", &files[0].to_string_lossy()]),
+    };
+    // It's fine if it's not enabled before
+    let _ = run_command(&disable);
+    for file in &files {
+        fs::remove_file(file).with_context(|| file.display().to_string())?;
+    }
+    Ok(files)
+}
+
+/// Describe whether scheduled backup is installed, together with status reported
+/// by systemd/launchd.
+pub fn schedule_status() -> Result<String> {
+    let files = unit_files()?;
+    let missing = files.iter().filter(|f| !f.exists()).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Ok(format!(
+            "Not installed, {} not found",
+            missing
+                .iter()
+                .map(|f| f.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    let cmd = match env::consts::OS {
+        "linux" => command(&[
+            "systemctl",
+            "--user",
+            "list-timers",
+            "--all",
+            &format!("{SYSTEMD_UNIT}.timer"),
+        ]),
+        _ => command(&["launchctl", "list", LAUNCHD_LABEL]),
+    };
+    let status = run_command(&cmd).unwrap_or_else(|e| format!("{e:#}"));
+    Ok(format!(
+        "Installed at {}\n{}",
+        files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        status.trim_end()
+    ))
+}
+
+/// Stable lines of `schedule status --porcelain`, `installed\t<1/0>` followed by
+/// `file\t<exists 1/0>\t<path>` of each unit file. Status reported by systemd/launchd
+/// is left out, its format is up to them.
+pub fn schedule_status_porcelain() -> Result<Vec<String>> {
+    let files = unit_files()?;
+    let installed = files.iter().all(|f| f.exists());
+    let mut lines = vec![porcelain_line(&[
+        "installed",
+        if installed { "1" } else { "0" },
+    ])];
+    for f in files {
+        let exists = if f.exists() { "1" } else { "0" };
+        lines.push(porcelain_line(&["file", exists, &f.to_string_lossy()]));
+    }
+    Ok(lines)
+}
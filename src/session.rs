@@ -0,0 +1,180 @@
+use crate::{
+    types::{SessionSpan, SessionStats, VisitDetail},
+    util::{unixepoch_as_ymd, ymd_midnight},
+};
+use serde_derive::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+
+// Visits without long gaps between, `start`/`end` are in unix epoch milliseconds.
+#[derive(Serialize)]
+pub(crate) struct Session {
+    pub start: i64,
+    pub end: i64,
+    pub visits: Vec<VisitDetail>,
+}
+
+// Split ordered visits into sessions, a gap longer than `gap` starts a new one.
+pub(crate) struct Sessionizer {
+    gap: i64,
+    current: Option<Session>,
+}
+
+impl Sessionizer {
+    pub fn new(gap: Duration) -> Self {
+        Self {
+            gap: gap.as_millis() as i64,
+            current: None,
+        }
+    }
+
+    // Return previous session when `visit` starts a new one
+    pub fn push(&mut self, visit: VisitDetail) -> Option<Session> {
+        let visit_time = visit.visit_time;
+        match &mut self.current {
+            Some(session) if visit_time - session.end <= self.gap => {
+                session.end = visit_time;
+                session.visits.push(visit);
+                None
+            }
+            _ => self.current.replace(Session {
+                start: visit_time,
+                end: visit_time,
+                visits: vec![visit],
+            }),
+        }
+    }
+
+    pub fn finish(self) -> Option<Session> {
+        self.current
+    }
+}
+
+// Accumulate `SessionStats` of ordered visits
+pub(crate) struct SessionStatsBuilder {
+    sessionizer: Sessionizer,
+    sessions: usize,
+    visits: usize,
+    // Sessions started on each day, keyed by %Y-%m-%d
+    daily: BTreeMap<String, i64>,
+    longest: Option<SessionSpan>,
+    active_time: i64,
+}
+
+impl SessionStatsBuilder {
+    pub fn new(gap: Duration) -> Self {
+        Self {
+            sessionizer: Sessionizer::new(gap),
+            sessions: 0,
+            visits: 0,
+            daily: BTreeMap::new(),
+            longest: None,
+            active_time: 0,
+        }
+    }
+
+    fn add(&mut self, session: Session) {
+        let span = SessionSpan {
+            start: session.start,
+            end: session.end,
+            visits: session.visits.len(),
+        };
+        self.sessions += 1;
+        self.visits += span.visits;
+        // Gaps between visits of a session add up to its span
+        self.active_time += span.end - span.start;
+        *self.daily.entry(unixepoch_as_ymd(span.start)).or_insert(0) += 1;
+        // Longest in time, the one with more visits wins a tie
+        let longer = self.longest.as_ref().is_none_or(|longest| {
+            (span.end - span.start, span.visits) > (longest.end - longest.start, longest.visits)
+        });
+        if longer {
+            self.longest = Some(span);
+        }
+    }
+
+    pub fn push(&mut self, visit: VisitDetail) {
+        if let Some(session) = self.sessionizer.push(visit) {
+            self.add(session);
+        }
+    }
+
+    pub fn finish(mut self) -> SessionStats {
+        if let Some(session) = self.sessionizer.current.take() {
+            self.add(session);
+        }
+        SessionStats {
+            sessions: self.sessions,
+            visits: self.visits,
+            avg_visits: if self.sessions == 0 {
+                0.0
+            } else {
+                self.visits as f64 / self.sessions as f64
+            },
+            daily_sessions: self
+                .daily
+                .into_iter()
+                .filter_map(|(ymd, cnt)| ymd_midnight(&ymd).ok().map(|ts| (ts, cnt)))
+                .collect(),
+            longest: self.longest,
+            active_time: self.active_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visit(visit_time: i64) -> VisitDetail {
+        VisitDetail {
+            url: "https://github.com/".to_string(),
+            title: "GitHub".to_string(),
+            visit_time,
+            visit_type: 1,
+            source_visit_id: None,
+        }
+    }
+
+    #[test]
+    fn test_session_stats() {
+        let empty = SessionStatsBuilder::new(Duration::from_secs(60)).finish();
+        assert_eq!(empty.sessions, 0);
+        assert_eq!(empty.avg_visits, 0.0);
+        assert!(empty.longest.is_none());
+        assert_eq!(empty.active_time, 0);
+
+        let day1 = ymd_midnight("2022-01-12").unwrap();
+        let day2 = ymd_midnight("2022-01-13").unwrap();
+        let minute = 60_000;
+        let mut builder = SessionStatsBuilder::new(Duration::from_secs(120));
+        for visit_time in [
+            // 2 sessions in day1, the second one is longest
+            day1 + 10 * minute,
+            day1 + 11 * minute,
+            day1 + 60 * minute,
+            day1 + 61 * minute,
+            day1 + 62 * minute,
+            day1 + 63 * minute,
+            // 1 session in day2, as long as the longest one but has fewer visits
+            day2,
+            day2 + 2 * minute,
+            day2 + 3 * minute,
+        ] {
+            builder.push(visit(visit_time));
+        }
+        let stats = builder.finish();
+        assert_eq!(stats.sessions, 3);
+        assert_eq!(stats.visits, 9);
+        assert_eq!(stats.avg_visits, 3.0);
+        assert_eq!(stats.daily_sessions, vec![(day1, 2), (day2, 1)]);
+        assert_eq!(stats.active_time, 7 * minute);
+        assert_eq!(
+            stats.longest,
+            Some(SessionSpan {
+                start: day1 + 60 * minute,
+                end: day1 + 63 * minute,
+                visits: 4,
+            })
+        );
+    }
+}
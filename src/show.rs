@@ -0,0 +1,373 @@
+use crate::{
+    database::Database,
+    default_browser::{default_browser, is_browser_of},
+    error::{Error, Result},
+    source::Source,
+    types::SourceName,
+    util::{
+        default_browser_files, detect_files, porcelain_line, profile_name, unixepoch_as_ymdhms,
+        DEFAULT_PROFILES,
+    },
+};
+use home::home_dir;
+use indicatif::HumanBytes;
+use rusqlite::ErrorCode;
+use serde_derive::Serialize;
+use std::{
+    env,
+    error::Error as StdError,
+    fmt::{self, Display, Formatter},
+    fs, io, iter,
+    path::{Path, PathBuf},
+};
+
+/// Whether a history file is ready to backup.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum FileStatus {
+    Ok,
+    /// Why this file can't be backed up.
+    Error {
+        message: String,
+    },
+}
+
+/// Summary of a history file, used to check whether it's ready to backup.
+#[derive(Debug, Serialize)]
+pub struct HistoryFileInfo {
+    pub path: String,
+    pub browser: Option<SourceName>,
+    pub profile: String,
+    /// Whether it belongs to the system default browser
+    pub default_browser: bool,
+    pub size_bytes: Option<u64>,
+    pub visit_count: Option<i64>,
+    // unix_epoch_ms
+    pub newest_visit_ms: Option<i64>,
+    // visit time of the newest imported visit, unix_epoch_ms
+    pub last_import_ms: Option<i64>,
+    #[serde(flatten)]
+    pub status: FileStatus,
+}
+
+impl HistoryFileInfo {
+    pub fn is_ready(&self) -> bool {
+        matches!(self.status, FileStatus::Ok)
+    }
+
+    /// Stable line of `show --porcelain`, fields are `status`(ok/error), `browser`,
+    /// `profile`, `default_browser`(1/0), `size_bytes`, `visit_count`,
+    /// `newest_visit_ms`, `last_import_ms`, `path` and error `message`. Unknown
+    /// fields are `-`, browser is the `Display` of `SourceName`. See `porcelain_line`
+    /// for escaping.
+    pub fn porcelain(&self) -> String {
+        let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        let (status, message) = match &self.status {
+            FileStatus::Ok => ("ok", "-".to_string()),
+            FileStatus::Error { message } => ("error", message.clone()),
+        };
+        porcelain_line(&[
+            status.to_string(),
+            or_dash(self.browser.map(|b| b.to_string())),
+            or_dash(Some(self.profile.clone()).filter(|p| !p.is_empty())),
+            (self.default_browser as u8).to_string(),
+            or_dash(self.size_bytes.map(|v| v.to_string())),
+            or_dash(self.visit_count.map(|v| v.to_string())),
+            or_dash(self.newest_visit_ms.map(|v| v.to_string())),
+            or_dash(self.last_import_ms.map(|v| v.to_string())),
+            self.path.clone(),
+            message,
+        ])
+    }
+}
+
+impl Display for HistoryFileInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let format_ts = |ts: Option<i64>| ts.map_or_else(|| "-".to_string(), unixepoch_as_ymdhms);
+        if let FileStatus::Error { message } = &self.status {
+            return write!(f, "problematic:{}\n    {message}", self.path);
+        }
+        let default = if self.default_browser {
+            " (default browser)"
+        } else {
+            ""
+        };
+        write!(
+            f,
+            "found:{}{default}\n    browser:{:?}, profile:{}, size:{}, visits:{}, newest visit:{}, last import:{}",
+            self.path,
+            self.browser.unwrap(),
+            self.profile,
+            HumanBytes(self.size_bytes.unwrap_or(0)),
+            self.visit_count.unwrap_or(0),
+            format_ts(self.newest_visit_ms),
+            self.last_import_ms
+                .map_or_else(|| "never".to_string(), |ts| format_ts(Some(ts))),
+        )
+    }
+}
+
+fn failure_kind(e: &Error) -> &'static str {
+    for cause in iter::successors(Some(e as &(dyn StdError + 'static)), |&e| e.source()) {
+        if let Some(Error::UnsupportedSource(_)) = cause.downcast_ref::<Error>() {
+            return "unknown schema";
+        }
+        if let Some(rusqlite::Error::SqliteFailure(ffi_err, _)) =
+            cause.downcast_ref::<rusqlite::Error>()
+        {
+            return match ffi_err.code {
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => "locked",
+                ErrorCode::PermissionDenied
+                | ErrorCode::ReadOnly
+                | ErrorCode::CannotOpen
+                | ErrorCode::AuthorizationForStatementDenied => "permission",
+                ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt => "unknown schema",
+                _ => "sqlite error",
+            };
+        }
+        if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+            if io_err.kind() == io::ErrorKind::PermissionDenied {
+                return "permission";
+            }
+        }
+    }
+    "error"
+}
+
+fn inspect(path: &Path, db: Option<&Database>, default_browser: bool) -> HistoryFileInfo {
+    let mut info = HistoryFileInfo {
+        path: path.to_string_lossy().to_string(),
+        browser: None,
+        profile: profile_name(path),
+        default_browser,
+        size_bytes: None,
+        visit_count: None,
+        newest_visit_ms: None,
+        last_import_ms: None,
+        status: FileStatus::Ok,
+    };
+    let mut fill = || -> Result<()> {
+        info.size_bytes = Some(fs::metadata(path)?.len());
+        let s = Source::open_read_only(path)?;
+        info.browser = Some(s.name());
+        let (visit_count, newest_visit_ms) = s.visit_stats()?;
+        info.visit_count = Some(visit_count);
+        info.newest_visit_ms = newest_visit_ms;
+        if let Some(db) = db {
+            info.last_import_ms = db
+                .select_last_import(&path.to_string_lossy())?
+                .map(|ts| ts / 1_000);
+        }
+        Ok(())
+    };
+    if let Err(e) = fill() {
+        info.status = FileStatus::Error {
+            message: format!("{}: {e:#}", failure_kind(&e)),
+        };
+    }
+
+    info
+}
+
+/// Inspect each of `history_files`, without modifying them. Import records are read
+/// from 1History database at `db_file` if it exists.
+pub fn inspect_history_files<P: AsRef<Path>>(
+    history_files: &[PathBuf],
+    db_file: P,
+) -> Result<Vec<HistoryFileInfo>> {
+    let db = if db_file.as_ref().exists() {
+        Some(Database::open(db_file)?)
+    } else {
+        None
+    };
+
+    let defaults = default_browser_files();
+    Ok(history_files
+        .iter()
+        .map(|f| inspect(f, db.as_ref(), defaults.contains(f)))
+        .collect())
+}
+
+/// History file pattern 1History looks for by default.
+#[derive(Debug, Serialize)]
+pub struct BrowserPattern {
+    pub browser: String,
+    /// Glob pattern, with home directory expanded.
+    pub pattern: String,
+    /// Whether any non-empty file matches `pattern`.
+    pub matched: bool,
+    /// Whether `browser` is the system default browser.
+    pub default_browser: bool,
+}
+
+impl BrowserPattern {
+    /// Stable line of `show --list-browsers --porcelain`, fields are `browser`,
+    /// `matched`(1/0), `default_browser`(1/0) and `pattern`.
+    pub fn porcelain(&self) -> String {
+        porcelain_line(&[
+            self.browser.clone(),
+            (self.matched as u8).to_string(),
+            (self.default_browser as u8).to_string(),
+            self.pattern.clone(),
+        ])
+    }
+}
+
+/// List default history file patterns of browsers on current OS, such as
+/// `firefox-linux`, together with whether they match any file now.
+pub fn list_browsers() -> Vec<BrowserPattern> {
+    match home_dir() {
+        Some(home) => browser_patterns(&home, env::consts::OS, default_browser()),
+        None => Vec::new(),
+    }
+}
+
+fn browser_patterns(home: &Path, os: &str, default: Option<&str>) -> Vec<BrowserPattern> {
+    let suffix = format!("-{os}");
+    let mut patterns = DEFAULT_PROFILES
+        .iter()
+        .filter(|(browser, _)| browser.ends_with(&suffix))
+        .map(|(browser, pattern)| BrowserPattern {
+            browser: browser.to_string(),
+            pattern: home.join(pattern).to_string_lossy().to_string(),
+            matched: !detect_files(home, iter::once(*pattern)).is_empty(),
+            default_browser: default.is_some_and(|default| is_browser_of(browser, default)),
+        })
+        .collect::<Vec<_>>();
+    patterns
+        .sort_by(|a, b| (!a.default_browser, &a.browser).cmp(&(!b.default_browser, &b.browser)));
+
+    patterns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser_patterns() {
+        let home = tempfile::tempdir().unwrap();
+        let profile = home.path().join(".mozilla/firefox/abc.default");
+        fs::create_dir_all(&profile).unwrap();
+        fs::write(profile.join("places.sqlite"), "history").unwrap();
+
+        let patterns = browser_patterns(home.path(), "linux", None);
+        assert!(patterns.iter().all(|p| p.browser.ends_with("-linux")));
+        let firefox = patterns
+            .iter()
+            .find(|p| p.browser == "firefox-linux")
+            .unwrap();
+        assert_eq!(
+            firefox.pattern,
+            home.path()
+                .join(".mozilla/firefox/*/places.sqlite")
+                .to_string_lossy()
+        );
+        assert!(firefox.matched);
+        assert_eq!(patterns.iter().filter(|p| p.matched).count(), 1);
+
+        let patterns = browser_patterns(home.path(), "macos", None);
+        assert!(patterns.iter().any(|p| p.browser == "safari-macos"));
+        assert!(patterns.iter().all(|p| !p.matched));
+
+        // Non-stable channels
+        let profile = home
+            .path()
+            .join(".config/BraveSoftware/Brave-Browser-Nightly/Default");
+        fs::create_dir_all(&profile).unwrap();
+        fs::write(profile.join("History"), "history").unwrap();
+        let patterns = browser_patterns(home.path(), "linux", None);
+        assert!(patterns
+            .iter()
+            .any(|p| p.browser == "brave-nightly-linux" && p.matched));
+        let patterns = browser_patterns(home.path(), "windows", None);
+        assert!(patterns.iter().any(|p| p.browser == "opera-gx-windows"));
+        assert!(patterns.iter().all(|p| !p.default_browser));
+
+        let patterns = browser_patterns(home.path(), "linux", Some("brave-nightly"));
+        assert_eq!(patterns[0].browser, "brave-nightly-linux");
+        assert!(patterns[0].default_browser);
+        assert_eq!(patterns.iter().filter(|p| p.default_browser).count(), 1);
+    }
+
+    #[test]
+    fn test_vivaldi_patterns() {
+        let home = tempfile::tempdir().unwrap();
+        let profile = home.path().join(".config/vivaldi/Default");
+        fs::create_dir_all(&profile).unwrap();
+        fs::write(profile.join("History"), "history").unwrap();
+
+        let patterns = browser_patterns(home.path(), "linux", Some("vivaldi"));
+        assert_eq!(patterns[0].browser, "vivaldi-linux");
+        assert!(patterns[0].matched);
+        assert!(patterns[0].default_browser);
+        for os in ["macos", "windows"] {
+            let patterns = browser_patterns(home.path(), os, None);
+            assert!(patterns
+                .iter()
+                .any(|p| p.browser == format!("vivaldi-{os}") && !p.matched));
+        }
+    }
+
+    #[test]
+    fn test_chromium_fork_patterns() {
+        let home = tempfile::tempdir().unwrap();
+        // Opera keeps history in profile root, without profile directories
+        for history in [
+            ".config/opera/History",
+            "Library/Application Support/com.operasoftware.Opera/History",
+            "AppData/Roaming/Opera Software/Opera Stable/History",
+            "Library/Application Support/Chromium/Default/History",
+            "AppData/Local/Chromium/User Data/Profile 1/History",
+        ] {
+            let history = home.path().join(history);
+            fs::create_dir_all(history.parent().unwrap()).unwrap();
+            fs::write(&history, "history").unwrap();
+        }
+
+        for (os, browsers) in [
+            ("linux", vec!["opera-linux"]),
+            ("macos", vec!["chromium-macos", "opera-macos"]),
+            ("windows", vec!["chromium-windows", "opera-windows"]),
+        ] {
+            let matched = browser_patterns(home.path(), os, None)
+                .into_iter()
+                .filter(|p| p.matched)
+                .map(|p| p.browser)
+                .collect::<Vec<_>>();
+            assert_eq!(matched, browsers);
+        }
+    }
+
+    #[test]
+    fn test_edge_patterns() {
+        let home = tempfile::tempdir().unwrap();
+        for (os, profile) in [
+            ("linux", ".config/microsoft-edge/Default"),
+            (
+                "macos",
+                "Library/Application Support/Microsoft Edge/Profile 1",
+            ),
+            ("windows", "AppData/Local/Microsoft/Edge/User Data/Default"),
+        ] {
+            let profile = home.path().join(profile);
+            fs::create_dir_all(&profile).unwrap();
+            fs::write(profile.join("History"), "history").unwrap();
+
+            let patterns = browser_patterns(home.path(), os, Some("edge"));
+            let edge = patterns
+                .iter()
+                .find(|p| p.browser == format!("edge-{os}"))
+                .unwrap();
+            assert!(edge.matched, "{os}");
+            assert!(edge.default_browser);
+            assert_eq!(
+                detect_files(
+                    home.path(),
+                    iter::once(DEFAULT_PROFILES[edge.browser.as_str()])
+                ),
+                vec![profile.join("History")]
+            );
+        }
+    }
+}
@@ -1,26 +1,185 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::types::{SourceName, VisitDetail};
-use anyhow::{bail, Context, Result};
-use log::debug;
-use rusqlite::{named_params, Connection, OpenFlags, ToSql};
+use crate::{
+    error::{Error, Result},
+    types::{SourceName, VisitDetail},
+    util::is_internal_url,
+};
+use anyhow::Context;
+use log::{debug, warn};
+use rusqlite::{named_params, Connection, OpenFlags, OptionalExtension, ToSql};
 
+/// History file of a browser, opened as a SQLite database.
 pub struct Source {
-    path: String,
+    path: PathBuf,
     name: SourceName,
     conn: Connection,
+    min_dwell: Option<Duration>,
+    flatten_redirects: bool,
+    exclude_internal: bool,
+    // `table.column` of Chrome history file, its schema differs between versions
+    columns: HashSet<String>,
+    schema_version: Option<String>,
 }
 
+// Highest schema versions queries are tested against, by fixtures of
+// `test_source_schema_tested`, newer ones may have columns renamed or dropped
+const TESTED_CHROME_VERSION: i64 = 70;
+const TESTED_FIREFOX_VERSION: i64 = 78;
+
 impl Source {
-    pub fn open(path: &str) -> Result<Source> {
+    /// Open history file at `path`, browser is detected by its schema.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Source> {
+        let path = path.as_ref();
         let flags = OpenFlags::SQLITE_OPEN_READ_WRITE;
-        let conn = Connection::open_with_flags(&path, flags).context(path.to_string())?;
-        let name = Self::detect_name(&conn).context(format!("detect {path}"))?;
-        Ok(Source {
-            path: path.to_string(),
+        let conn =
+            Connection::open_with_flags(path, flags).with_context(|| path.display().to_string())?;
+        // Error code 14: Unable to open the database file
+        // https://github.com/groue/GRDB.swift/issues/415#issuecomment-485220857
+        conn.pragma_update(None, "journal_mode", "DELETE")?;
+        Self::with_conn(path, conn)
+    }
+
+    /// Like `open`, but history file is opened read-only and left untouched.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Source> {
+        let path = path.as_ref();
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY;
+        let conn =
+            Connection::open_with_flags(path, flags).with_context(|| path.display().to_string())?;
+        Self::with_conn(path, conn)
+    }
+
+    fn with_conn(path: &Path, conn: Connection) -> Result<Source> {
+        let name =
+            Self::detect_name(&conn).with_context(|| format!("detect {}", path.display()))?;
+        let name = Self::chromium_name(name, path);
+        let (columns, schema_version) = match name {
+            SourceName::Chrome | SourceName::Edge => (
+                Self::table_columns(&conn, &["visits", "urls"])
+                    .with_context(|| format!("columns of {}", path.display()))?,
+                Self::chrome_version(&conn),
+            ),
+            SourceName::Firefox => (HashSet::new(), Self::firefox_version(&conn)),
+            SourceName::Safari => (HashSet::new(), None),
+        };
+        debug!("{} schema version: {schema_version:?}", path.display());
+        let source = Source {
+            path: path.to_path_buf(),
             name,
             conn,
+            min_dwell: None,
+            flatten_redirects: false,
+            exclude_internal: false,
+            columns,
+            schema_version,
+        };
+        if !source.is_schema_tested() {
+            warn!(
+                "{} has schema version {} newer than tested, visits may be missing or wrong",
+                path.display(),
+                source.schema_version().unwrap_or_default()
+            );
+        }
+        Ok(source)
+    }
+
+    // `table.column` of all columns of `tables`
+    fn table_columns(conn: &Connection, tables: &[&str]) -> Result<HashSet<String>> {
+        let mut columns = HashSet::new();
+        for table in tables {
+            let mut stat = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+            let rows = stat.query_map([], |row| row.get::<_, String>(1))?;
+            for column in rows {
+                columns.insert(format!("{table}.{}", column?));
+            }
+        }
+        Ok(columns)
+    }
+
+    // `meta.version` of Chrome history file, `None` when it's not recorded
+    fn chrome_version(conn: &Connection) -> Option<String> {
+        conn.query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| {
+            row.get::<_, String>(0)
         })
+        .optional()
+        .unwrap_or_else(|e| {
+            debug!("select meta version failed, err: {e}");
+            None
+        })
+    }
+
+    // `user_version` of places.sqlite, `None` when it's not set
+    fn firefox_version(conn: &Connection) -> Option<String> {
+        conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))
+            .map_err(|e| debug!("select user_version failed, err: {e}"))
+            .ok()
+            .filter(|v| *v > 0)
+            .map(|v| v.to_string())
+    }
+
+    /// Whether schema version of history file is not newer than the highest one
+    /// queries are tested against, unknown versions are taken as tested.
+    pub fn is_schema_tested(&self) -> bool {
+        let tested = match self.name {
+            SourceName::Chrome | SourceName::Edge => TESTED_CHROME_VERSION,
+            SourceName::Firefox => TESTED_FIREFOX_VERSION,
+            SourceName::Safari => return true,
+        };
+        self.schema_version()
+            .and_then(|v| v.parse::<i64>().ok())
+            .is_none_or(|v| v <= tested)
+    }
+
+    /// Schema version of history file, `meta.version` for Chrome, `user_version`
+    /// for Firefox, `None` for Safari or when it's not recorded.
+    pub fn schema_version(&self) -> Option<&str> {
+        self.schema_version.as_deref()
+    }
+
+    fn has_column(&self, table: &str, column: &str) -> bool {
+        self.columns.contains(&format!("{table}.{column}"))
+    }
+
+    /// Whether columns read by `Database::persist_chromium` all exist, old or new
+    /// schemas missing some of them are read by `select` instead.
+    pub(crate) fn has_chromium_columns(&self) -> bool {
+        self.has_column("urls", "title") && self.has_column("visits", "transition")
+    }
+
+    /// Skip visits which user stays less than `min_dwell` on, such as quick redirects
+    /// or mis-clicks. Only Chrome records how long a visit lasts, visits of other
+    /// browsers are always kept.
+    pub fn with_min_dwell(mut self, min_dwell: Option<Duration>) -> Self {
+        self.min_dwell = min_dwell;
+        self
+    }
+
+    /// Skip visits redirected to other URLs, so only the final landing URL of a
+    /// redirect chain is kept.
+    pub fn with_flatten_redirects(mut self, flatten_redirects: bool) -> Self {
+        self.flatten_redirects = flatten_redirects;
+        self
+    }
+
+    /// Skip visits of local files and browser internal pages, such as `file:///tmp/a.html`
+    /// or `chrome://settings`.
+    pub fn with_exclude_internal(mut self, exclude_internal: bool) -> Self {
+        self.exclude_internal = exclude_internal;
+        self
+    }
+
+    // Only works when `flatten_redirects` is enabled
+    fn redirect_filter(&self, filter: &str) -> String {
+        if self.flatten_redirects {
+            format!("AND {filter}")
+        } else {
+            String::new()
+        }
     }
 
     // For Safari, seconds since 00:00:00 UTC on 1 January 2001
@@ -37,7 +196,7 @@ impl Source {
 
     // For Chrome, microseconds since January 1, 1601 UTC
     // https://www.systoolsgroup.com/forensics/sqlite/places.html
-    fn unixepoch_ms_to_webkit(ts: i64) -> i64 {
+    pub(crate) fn unixepoch_ms_to_webkit(ts: i64) -> i64 {
         ts * 1_000 + 11644473600 * 1_000_000
     }
 
@@ -50,15 +209,13 @@ impl Source {
         detect_sqls.insert("select 1 from history_items limit 1", SourceName::Safari);
         detect_sqls.insert("select 1 from visits limit 1", SourceName::Chrome);
 
-        // Error code 14: Unable to open the database file
-        // https://github.com/groue/GRDB.swift/issues/415#issuecomment-485220857
-        conn.pragma_update(None, "journal_mode", "DELETE")?;
         for (sql, name) in detect_sqls {
             match conn.query_row(sql, [], |row| {
                 let r: i64 = row.get(0)?;
                 Ok(r)
             }) {
-                Ok(_) => return Ok(name),
+                // Table exists but has no visits yet
+                Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(name),
                 Err(e) if e.to_string().contains("no such table") => {
                     continue;
                 }
@@ -66,43 +223,102 @@ impl Source {
             }
         }
 
-        bail!("No known browser, Only support Safari/Firefox/Chrome");
+        Err(Error::UnsupportedSource(
+            "No known browser, Only support Safari/Firefox/Chrome".to_string(),
+        ))
+    }
+
+    // Edge shares the Chromium schema with Chrome, so it's told by its data directory,
+    // such as `Microsoft Edge`, `microsoft-edge-beta` or `Microsoft/Edge`
+    fn chromium_name(name: SourceName, path: &Path) -> SourceName {
+        if !name.is_chromium() {
+            return name;
+        }
+        let dirs = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+            .collect::<Vec<_>>();
+        let is_edge = dirs.iter().enumerate().any(|(i, dir)| {
+            dir.starts_with("microsoft edge")
+                || dir.starts_with("microsoft-edge")
+                || (dir.starts_with("edge") && i > 0 && dirs[i - 1] == "microsoft")
+        });
+        if is_edge {
+            SourceName::Edge
+        } else {
+            SourceName::Chrome
+        }
+    }
+
+    /// Take browser of temp copy from its `original` history file, which decides
+    /// Chromium based browsers.
+    pub(crate) fn copied_from(mut self, original: &Path) -> Self {
+        self.name = Self::chromium_name(self.name, original);
+        self
     }
 
     pub fn name(&self) -> SourceName {
         self.name
     }
 
-    pub fn path(&self) -> &str {
+    pub fn path(&self) -> &Path {
         &self.path
     }
 
+    /// Return number of visits and time of the newest visit(unix epoch in milliseconds).
+    pub fn visit_stats(&self) -> Result<(i64, Option<i64>)> {
+        let sql = match self.name {
+            SourceName::Firefox => {
+                "SELECT count(1), max(visit_date) / 1000 FROM moz_historyvisits"
+            }
+            SourceName::Safari => {
+                "SELECT count(1), CAST((max(visit_time) + 978307200.0) * 1000 AS integer) FROM history_visits"
+            }
+            SourceName::Chrome | SourceName::Edge => {
+                "SELECT count(1), (max(visit_time) - 11644473600*1000000) / 1000 FROM visits"
+            }
+        };
+        let stats = self
+            .conn
+            .query_row(sql, [], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        Ok(stats)
+    }
+
+    /// Select visits between `[start, end]`(unix epoch in milliseconds), `visit_time`
+    /// of returned visits is in PRTime, and `source_visit_id` is their id in history
+    /// file.
     pub fn select(&self, start: i64, end: i64) -> Result<Box<dyn Iterator<Item = VisitDetail>>> {
         match self.name {
             SourceName::Firefox => self.select_firefox(start, end),
             SourceName::Safari => self.select_safari(start, end),
-            SourceName::Chrome => self.select_chrome(start, end),
+            SourceName::Chrome | SourceName::Edge => self.select_chrome(start, end),
         }
     }
 
     fn select_safari(&self, start: i64, end: i64) -> Result<Box<dyn Iterator<Item = VisitDetail>>> {
-        let sql = r#"
+        let redirect_filter = self.redirect_filter("hv.redirect_destination IS NULL");
+        let sql = format!(
+            r#"
 SELECT
     url,
     title,
     CAST((visit_time + 978307200.0) * 1000000 AS integer) as visit_time,     -- convert to PRTime
-    -1
+    -1,
+    hv.id
 FROM
     history_items AS hi,
     history_visits AS hv ON hi.id = hv.history_item
 WHERE
     visit_time >= :start
     AND visit_time <= :end
+    {redirect_filter}
 ORDER BY
     visit_time
-"#;
+"#
+        );
         self.select_inner(
-            sql,
+            &sql,
             Self::unixepoch_ms_to_nsdate(start),
             Self::unixepoch_ms_to_nsdate(end),
         )
@@ -113,48 +329,105 @@ ORDER BY
         start: i64,
         end: i64,
     ) -> Result<Box<dyn Iterator<Item = VisitDetail>>> {
-        let sql = r#"
+        // Visits of type 5/6 are reached by permanent/temporary redirect from their
+        // from_visit
+        let redirect_filter = self.redirect_filter(
+            "NOT EXISTS (SELECT 1 FROM moz_historyvisits r \
+             WHERE r.from_visit = h.id AND r.visit_type IN (5, 6))",
+        );
+        let sql = format!(
+            r#"
 SELECT
     p.url,
     p.title,
     h.visit_date,
-    h.visit_type
+    h.visit_type,
+    h.id
 FROM
     moz_historyvisits h,
     moz_places p ON h.place_id = p.id
 WHERE
     h.visit_date >= :start
     AND h.visit_date <= :end
+    {redirect_filter}
 ORDER BY
     visit_date
-"#;
+"#
+        );
 
         self.select_inner(
-            sql,
+            &sql,
             Self::unixepoch_ms_to_prtime(start),
             Self::unixepoch_ms_to_prtime(end),
         )
     }
 
+    // Columns missing in old or new schemas are taken as NULL(empty title) or 0
+    // (transition), and filters needing them are skipped
     fn select_chrome(&self, start: i64, end: i64) -> Result<Box<dyn Iterator<Item = VisitDetail>>> {
-        let sql = r#"
+        let title = if self.has_column("urls", "title") {
+            "u.title"
+        } else {
+            "NULL"
+        };
+        let has_transition = self.has_column("visits", "transition");
+        let transition = if has_transition {
+            "v.transition & 0xFF"
+        } else {
+            "0"
+        };
+        // visit_duration is in microseconds
+        let dwell_filter = match self.min_dwell {
+            Some(d) if self.has_column("visits", "visit_duration") => {
+                format!("AND v.visit_duration >= {}", d.as_micros())
+            }
+            Some(_) => {
+                warn!(
+                    "{} has no visit duration, min dwell is ignored",
+                    self.path.display()
+                );
+                String::new()
+            }
+            None => String::new(),
+        };
+        // Visits reached by redirect have CLIENT_REDIRECT or SERVER_REDIRECT qualifier
+        let redirect_filter = if has_transition && self.has_column("visits", "from_visit") {
+            self.redirect_filter(
+                "NOT EXISTS (SELECT 1 FROM visits r \
+                 WHERE r.from_visit = v.id AND r.transition & 0xC0000000 != 0)",
+            )
+        } else {
+            if self.flatten_redirects {
+                warn!(
+                    "{} has no redirect chains, redirects are not flattened",
+                    self.path.display()
+                );
+            }
+            String::new()
+        };
+        let sql = format!(
+            r#"
 SELECT
     u.url,
-    u.title,
+    {title},
     v.visit_time - 11644473600*1000000,
-    v.transition & 0xFF
+    {transition},
+    v.id
 FROM
     visits v,
     urls u ON v.url = u.id
 WHERE
     v.visit_time >= :start
     AND v.visit_time <= :end
+    {dwell_filter}
+    {redirect_filter}
 ORDER BY
     visit_time
-"#;
+"#
+        );
 
         self.select_inner(
-            sql,
+            &sql,
             Self::unixepoch_ms_to_webkit(start),
             Self::unixepoch_ms_to_webkit(end),
         )
@@ -184,6 +457,7 @@ ORDER BY
                     title: row.get(1).unwrap_or_else(|_| "".to_string()),
                     visit_time: row.get(2)?,
                     visit_type: row.get(3)?,
+                    source_visit_id: row.get(4)?,
                 };
                 Ok(detail)
             },
@@ -191,7 +465,11 @@ ORDER BY
 
         let mut res: Vec<VisitDetail> = Vec::new();
         for r in rows {
-            res.push(r?);
+            let detail = r?;
+            if self.exclude_internal && is_internal_url(&detail.url) {
+                continue;
+            }
+            res.push(detail);
         }
 
         Ok(Box::new(res.into_iter()) as Box<dyn Iterator<Item = _>>)
@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Context};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    database::Database, error::Result, progress::ProgressCollector, types::VisitDetail,
+    util::now_ms,
+};
+
+const BUNDLE_FORMAT: &str = "onehistory-sync";
+/// Version of sync bundles written by this version, bundles of newer versions are
+/// rejected.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// First line of a sync bundle, visits follow it one per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncHeader {
+    pub format: String,
+    pub version: u32,
+    /// Name of the exporting machine
+    pub peer: String,
+    /// Visits with id in `(since, watermark]` of the exporting database are included
+    pub since: i64,
+    pub watermark: i64,
+    /// Unix epoch milliseconds
+    pub exported_at: i64,
+}
+
+// One visit in sync bundle, visit_time is PRTime, so deduplication works as backup
+#[derive(Serialize, Deserialize)]
+struct SyncRecord {
+    url: String,
+    title: String,
+    visit_time: i64,
+    visit_type: i64,
+}
+
+/// Result of importing a sync bundle.
+#[derive(Debug)]
+pub struct SyncImport {
+    pub peer: String,
+    /// Number of visits not in database before
+    pub imported: usize,
+    pub duplicated: usize,
+    /// Watermark of `peer` after import, export on it with this as `since` next time
+    pub watermark: i64,
+}
+
+/// Export visits added after `since`(a watermark returned by a previous import on
+/// the other machine, 0 for all) into a gzipped JSON lines bundle at `output`.
+///
+/// Return header of the bundle and number of visits exported.
+pub fn export_sync_bundle<P: AsRef<Path>>(
+    db: &Database,
+    peer: &str,
+    since: i64,
+    output: P,
+) -> Result<(SyncHeader, usize)> {
+    let output = output.as_ref();
+    let header = SyncHeader {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        peer: peer.to_string(),
+        since,
+        watermark: db.max_visit_id()?.max(since),
+        exported_at: now_ms(),
+    };
+    if let Some(dir) = output.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let file = File::create(output).with_context(|| output.display().to_string())?;
+    let mut w = GzEncoder::new(BufWriter::new(file), Compression::default());
+    serde_json::to_writer(&mut w, &header).context("write header")?;
+    w.write_all(b"\n")?;
+
+    let mut count = 0;
+    db.for_each_visit_between_ids(since, header.watermark, |visit| {
+        let record = SyncRecord {
+            url: visit.url,
+            title: visit.title,
+            visit_time: visit.visit_time,
+            visit_type: visit.visit_type,
+        };
+        serde_json::to_writer(&mut w, &record).context("write visit")?;
+        w.write_all(b"\n")?;
+        count += 1;
+        Ok(())
+    })?;
+    w.finish()?.flush()?;
+    debug!("export {count} visits into {}", output.display());
+
+    Ok((header, count))
+}
+
+fn read_header(line: Option<std::io::Result<String>>) -> Result<SyncHeader> {
+    let line = line.ok_or_else(|| anyhow!("empty sync bundle"))??;
+    let header: SyncHeader = serde_json::from_str(&line).context("not a sync bundle")?;
+    if header.format != BUNDLE_FORMAT {
+        return Err(anyhow!("not a sync bundle, format is {}", header.format).into());
+    }
+    if header.version > BUNDLE_VERSION {
+        return Err(anyhow!(
+            "sync bundle version {} is not supported, upgrade onehistory to import it",
+            header.version
+        )
+        .into());
+    }
+    Ok(header)
+}
+
+/// Import sync bundle at `bundle` into `db`, visits already in database are skipped,
+/// so importing a bundle twice is harmless. Watermark of the exporting peer is
+/// advanced after all visits are persisted.
+pub fn import_sync_bundle<P: AsRef<Path>>(
+    db: &Database,
+    bundle: P,
+    collector: &impl ProgressCollector,
+) -> Result<SyncImport> {
+    let bundle = bundle.as_ref();
+    let file = File::open(bundle).with_context(|| bundle.display().to_string())?;
+    let mut lines = BufReader::new(GzDecoder::new(file)).lines();
+    let header = read_header(lines.next())?;
+
+    let mut details = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record: SyncRecord = serde_json::from_str(&line)
+            .with_context(|| format!("invalid visit at line {}", i + 2))?;
+        details.push(VisitDetail {
+            url: record.url,
+            title: record.title,
+            visit_time: record.visit_time,
+            visit_type: record.visit_type,
+            source_visit_id: None,
+        });
+    }
+
+    let (imported, duplicated) = if details.is_empty() {
+        (0, 0)
+    } else {
+        db.persist(&format!("sync:{}", header.peer), details, collector)?
+    };
+    let watermark = db.update_sync_watermark(&header.peer, header.watermark)?;
+
+    Ok(SyncImport {
+        peer: header.peer,
+        imported,
+        duplicated,
+        watermark,
+    })
+}
@@ -0,0 +1,622 @@
+//! Tabs synced from other devices, which Chrome keeps in the LevelDB at
+//! `<profile>/Sync Data/LevelDB`.
+//!
+//! Only the bits of LevelDB, snappy and protobuf formats required to read
+//! `SessionSpecifics` entities are implemented, since the schema is not stable,
+//! anything unrecognized is skipped instead of failing the backup.
+
+use crate::{error::Result, types::VisitDetail};
+use anyhow::{anyhow, bail, Context};
+use log::debug;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// `visit_type` of visits synced from other devices, far beyond transitions of
+/// Chrome(masked by 0xFF) and Firefox, and unlike -1 used by Safari.
+pub const SYNCED_VISIT_TYPE: i64 = 1_000_000;
+
+// Prefix of keys holding sessions data in Chrome's sync store
+const SESSIONS_DATA_PREFIX: &[u8] = b"sessions-dt-";
+const LOG_BLOCK_SIZE: usize = 32 * 1024;
+const LOG_HEADER_SIZE: usize = 7;
+const TABLE_FOOTER_SIZE: usize = 48;
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+
+/// Tabs of one device, as visits sorted by `visit_time`.
+#[derive(Default)]
+pub struct SyncedSession {
+    /// Client name of the device, or its session tag when unknown.
+    pub device: String,
+    pub visits: Vec<VisitDetail>,
+}
+
+/// Return `Sync Data/LevelDB` directories next to Chrome-like `history_files`.
+pub fn synced_session_dirs(history_files: &[PathBuf]) -> Vec<PathBuf> {
+    history_files
+        .iter()
+        .filter(|f| f.file_name().is_some_and(|name| name == "History"))
+        .filter_map(|f| f.parent())
+        .map(|profile| profile.join("Sync Data").join("LevelDB"))
+        .filter(|dir| dir.is_dir())
+        .collect()
+}
+
+/// Read sessions of all devices from the LevelDB at `dir`, files or entries which
+/// can't be decoded are skipped.
+pub fn read_synced_sessions(dir: &Path) -> Result<Vec<SyncedSession>> {
+    let mut files = fs::read_dir(dir)
+        .with_context(|| dir.display().to_string())?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect::<Vec<_>>();
+    files.sort();
+
+    let mut entries = Entries::default();
+    for file in files {
+        let read: fn(&[u8], &mut Entries) -> anyhow::Result<()> =
+            match file.extension().and_then(|ext| ext.to_str()) {
+                Some("log") => read_log,
+                Some("ldb") | Some("sst") => read_table,
+                _ => continue,
+            };
+        let res = fs::read(&file)
+            .map_err(Into::into)
+            .and_then(|buf| read(&buf, &mut entries));
+        if let Err(e) = res {
+            debug!("skip {}, err:{:#}", file.display(), e);
+        }
+    }
+
+    let mut sessions: BTreeMap<String, SyncedSession> = BTreeMap::new();
+    for (key, value) in entries.into_values() {
+        let specifics = match decode_specifics(&value) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("skip {}, err:{:#}", String::from_utf8_lossy(&key), e);
+                continue;
+            }
+        };
+        let session = sessions.entry(specifics.session_tag).or_default();
+        if let Some(name) = specifics.client_name {
+            session.device = name;
+        }
+        session.visits.extend(specifics.visits);
+    }
+
+    Ok(sessions
+        .into_iter()
+        .filter(|(_, session)| !session.visits.is_empty())
+        .map(|(tag, mut session)| {
+            if session.device.is_empty() {
+                session.device = tag;
+            }
+            session.visits.sort_by_key(|v| v.visit_time);
+            session
+        })
+        .collect())
+}
+
+/// Sessions entries of LevelDB, only the newest operation of each key is kept.
+#[derive(Default)]
+struct Entries(HashMap<Vec<u8>, (u64, Option<Vec<u8>>)>);
+
+impl Entries {
+    // `value` is None for deletion
+    fn apply(&mut self, key: &[u8], seq: u64, value: Option<&[u8]>) {
+        if !key.starts_with(SESSIONS_DATA_PREFIX) {
+            return;
+        }
+        match self.0.get(key) {
+            Some((newest, _)) if *newest > seq => {}
+            _ => {
+                self.0
+                    .insert(key.to_vec(), (seq, value.map(|v| v.to_vec())));
+            }
+        }
+    }
+
+    fn into_values(self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut values = self
+            .0
+            .into_iter()
+            .filter_map(|(key, (_, value))| value.map(|v| (key, v)))
+            .collect::<Vec<_>>();
+        values.sort();
+        values
+    }
+}
+
+// https://github.com/google/leveldb/blob/main/doc/log_format.md
+fn read_log(buf: &[u8], entries: &mut Entries) -> anyhow::Result<()> {
+    let mut record = Vec::new();
+    let mut pos = 0;
+    while pos + LOG_HEADER_SIZE <= buf.len() {
+        let left = LOG_BLOCK_SIZE - pos % LOG_BLOCK_SIZE;
+        if left < LOG_HEADER_SIZE {
+            // trailer of a block
+            pos += left;
+            continue;
+        }
+        let len = u16::from_le_bytes([buf[pos + 4], buf[pos + 5]]) as usize;
+        let kind = buf[pos + 6];
+        let start = pos + LOG_HEADER_SIZE;
+        // The last record may be partially written
+        let data = match buf.get(start..start + len) {
+            Some(data) => data,
+            None => break,
+        };
+        pos = start + len;
+        match kind {
+            // preallocated space
+            0 => {}
+            1 => read_batch(data, entries)?,
+            2 => record = data.to_vec(),
+            3 => record.extend_from_slice(data),
+            4 => {
+                record.extend_from_slice(data);
+                read_batch(&record, entries)?;
+                record.clear();
+            }
+            _ => bail!("unknown log record type {kind}"),
+        }
+    }
+
+    Ok(())
+}
+
+// https://github.com/google/leveldb/blob/main/db/write_batch.cc
+fn read_batch(mut buf: &[u8], entries: &mut Entries) -> anyhow::Result<()> {
+    let seq = le_uint(take(&mut buf, 8)?);
+    let count = le_uint(take(&mut buf, 4)?);
+    for i in 0..count {
+        let kind = take(&mut buf, 1)?[0];
+        let key = take_prefixed(&mut buf)?;
+        let value = match kind {
+            0 => None,
+            1 => Some(take_prefixed(&mut buf)?),
+            _ => bail!("unknown batch entry type {kind}"),
+        };
+        entries.apply(key, seq + i, value);
+    }
+
+    Ok(())
+}
+
+// https://github.com/google/leveldb/blob/main/doc/table_format.md
+fn read_table(buf: &[u8], entries: &mut Entries) -> anyhow::Result<()> {
+    let mut footer = buf
+        .len()
+        .checked_sub(TABLE_FOOTER_SIZE)
+        .map(|start| &buf[start..])
+        .ok_or_else(|| anyhow!("too short for a table"))?;
+    if le_uint(&footer[TABLE_FOOTER_SIZE - 8..]) != TABLE_MAGIC {
+        bail!("bad table magic");
+    }
+    // metaindex handle is not used
+    read_varint(&mut footer)?;
+    read_varint(&mut footer)?;
+    let index = read_block(buf, &mut footer)?;
+    for (_, handle) in block_entries(&index)? {
+        let block = read_block(buf, &mut handle.as_slice())?;
+        for (internal_key, value) in block_entries(&block)? {
+            // internal key is user key followed by (seq << 8 | type)
+            let split = internal_key
+                .len()
+                .checked_sub(8)
+                .ok_or_else(|| anyhow!("bad internal key"))?;
+            let (key, trailer) = internal_key.split_at(split);
+            let trailer = le_uint(trailer);
+            let value = (trailer & 0xff == 1).then_some(value.as_slice());
+            entries.apply(key, trailer >> 8, value);
+        }
+    }
+
+    Ok(())
+}
+
+// Read block pointed by `handle`, uncompressed when necessary
+fn read_block(buf: &[u8], handle: &mut &[u8]) -> anyhow::Result<Vec<u8>> {
+    let offset = read_varint(handle)? as usize;
+    let size = read_varint(handle)? as usize;
+    let content = buf
+        .get(offset..offset.saturating_add(size))
+        .ok_or_else(|| anyhow!("block out of range"))?;
+    match buf.get(offset.saturating_add(size)) {
+        Some(0) => Ok(content.to_vec()),
+        Some(1) => snappy_decompress(content),
+        other => bail!("unknown block compression {other:?}"),
+    }
+}
+
+fn block_entries(block: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let restarts = block
+        .len()
+        .checked_sub(4)
+        .map(|start| le_uint(&block[start..]) as usize)
+        .ok_or_else(|| anyhow!("too short for a block"))?;
+    let end = restarts
+        .checked_mul(4)
+        .and_then(|size| block.len().checked_sub(size + 4))
+        .ok_or_else(|| anyhow!("bad restart points"))?;
+
+    let mut buf = &block[..end];
+    let mut key = Vec::new();
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        let shared = read_varint(&mut buf)? as usize;
+        let non_shared = read_varint(&mut buf)? as usize;
+        let value_len = read_varint(&mut buf)? as usize;
+        if shared > key.len() {
+            bail!("bad shared key length");
+        }
+        key.truncate(shared);
+        key.extend_from_slice(take(&mut buf, non_shared)?);
+        let value = take(&mut buf, value_len)?;
+        entries.push((key.clone(), value.to_vec()));
+    }
+
+    Ok(entries)
+}
+
+// https://github.com/google/snappy/blob/main/format_description.txt
+fn snappy_decompress(mut buf: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let expected = read_varint(&mut buf)? as usize;
+    let mut out = Vec::with_capacity(expected.min(buf.len() * 8));
+    while !buf.is_empty() {
+        let tag = take(&mut buf, 1)?[0];
+        let (len, offset) = match tag & 3 {
+            0 => {
+                let mut len = (tag >> 2) as usize;
+                if len >= 60 {
+                    len = le_uint(take(&mut buf, len - 59)?) as usize;
+                }
+                out.extend_from_slice(take(&mut buf, len + 1)?);
+                continue;
+            }
+            1 => (
+                4 + ((tag >> 2) & 7) as usize,
+                ((tag as usize >> 5) << 8) | take(&mut buf, 1)?[0] as usize,
+            ),
+            2 => (
+                (tag >> 2) as usize + 1,
+                le_uint(take(&mut buf, 2)?) as usize,
+            ),
+            _ => (
+                (tag >> 2) as usize + 1,
+                le_uint(take(&mut buf, 4)?) as usize,
+            ),
+        };
+        if offset == 0 || offset > out.len() {
+            bail!("bad copy offset {offset}");
+        }
+        // source and destination may overlap, so copy byte by byte
+        let start = out.len() - offset;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+    if out.len() != expected {
+        bail!("expect {expected} bytes, got {}", out.len());
+    }
+
+    Ok(out)
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+// https://developers.google.com/protocol-buffers/docs/encoding
+fn proto_fields(mut buf: &[u8]) -> anyhow::Result<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = read_varint(&mut buf)?;
+        let field = match key & 7 {
+            0 => Field::Varint(read_varint(&mut buf)?),
+            1 => take(&mut buf, 8).map(|_| Field::Fixed)?,
+            2 => Field::Bytes(take_prefixed(&mut buf)?),
+            5 => take(&mut buf, 4).map(|_| Field::Fixed)?,
+            wire_type => bail!("unsupported wire type {wire_type}"),
+        };
+        fields.push((key >> 3, field));
+    }
+
+    Ok(fields)
+}
+
+#[derive(Default)]
+struct Specifics {
+    session_tag: String,
+    client_name: Option<String>,
+    visits: Vec<VisitDetail>,
+}
+
+// https://source.chromium.org/chromium/chromium/src/+/main:components/sync/protocol/session_specifics.proto
+fn decode_specifics(buf: &[u8]) -> anyhow::Result<Specifics> {
+    let mut specifics = Specifics::default();
+    for (number, field) in proto_fields(buf)? {
+        match (number, field) {
+            (1, Field::Bytes(tag)) => {
+                specifics.session_tag = String::from_utf8_lossy(tag).to_string()
+            }
+            // SessionHeader
+            (2, Field::Bytes(header)) => {
+                for (number, field) in proto_fields(header)? {
+                    if let (3, Field::Bytes(name)) = (number, field) {
+                        specifics.client_name = Some(String::from_utf8_lossy(name).to_string());
+                    }
+                }
+            }
+            // SessionTab
+            (3, Field::Bytes(tab)) => {
+                for (number, field) in proto_fields(tab)? {
+                    if let (7, Field::Bytes(navigation)) = (number, field) {
+                        specifics.visits.extend(decode_navigation(navigation)?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if specifics.session_tag.is_empty() {
+        bail!("no session tag");
+    }
+
+    Ok(specifics)
+}
+
+fn decode_navigation(buf: &[u8]) -> anyhow::Result<Option<VisitDetail>> {
+    let (mut url, mut title, mut timestamp_msec) = (String::new(), String::new(), 0);
+    for (number, field) in proto_fields(buf)? {
+        match (number, field) {
+            (2, Field::Bytes(v)) => url = String::from_utf8_lossy(v).to_string(),
+            (4, Field::Bytes(v)) => title = String::from_utf8_lossy(v).to_string(),
+            (9, Field::Varint(v)) => timestamp_msec = v as i64,
+            _ => {}
+        }
+    }
+    let visit_time = match timestamp_msec.checked_mul(1_000) {
+        Some(ts) if ts > 0 && !url.is_empty() => ts, // PRTime
+        _ => return Ok(None),
+    };
+
+    Ok(Some(VisitDetail {
+        url,
+        title,
+        visit_time,
+        visit_type: SYNCED_VISIT_TYPE,
+        source_visit_id: None,
+    }))
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> anyhow::Result<&'a [u8]> {
+    if buf.len() < n {
+        bail!("unexpected end of data");
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+fn take_prefixed<'a>(buf: &mut &'a [u8]) -> anyhow::Result<&'a [u8]> {
+    let len = read_varint(buf)? as usize;
+    take(buf, len)
+}
+
+fn read_varint(buf: &mut &[u8]) -> anyhow::Result<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let b = take(buf, 1)?[0];
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    bail!("varint too long")
+}
+
+fn le_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut v: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while v >= 0x80 {
+            buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        buf.push(v as u8);
+        buf
+    }
+
+    fn prefixed(bytes: &[u8]) -> Vec<u8> {
+        [varint(bytes.len() as u64), bytes.to_vec()].concat()
+    }
+
+    fn bytes_field(number: u64, bytes: &[u8]) -> Vec<u8> {
+        [varint(number << 3 | 2), prefixed(bytes)].concat()
+    }
+
+    fn specifics(tag: &str, client_name: Option<&str>, urls: &[(&str, u64)]) -> Vec<u8> {
+        let mut buf = bytes_field(1, tag.as_bytes());
+        if let Some(name) = client_name {
+            buf.extend(bytes_field(2, &bytes_field(3, name.as_bytes())));
+        }
+        let mut tab = Vec::new();
+        for (url, ts) in urls {
+            let nav = [
+                bytes_field(2, url.as_bytes()),
+                bytes_field(4, b"title"),
+                varint(9 << 3),
+                varint(*ts),
+            ]
+            .concat();
+            tab.extend(bytes_field(7, &nav));
+        }
+        buf.extend(bytes_field(3, &tab));
+        buf
+    }
+
+    fn batch(seq: u64, ops: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let mut buf = [
+            seq.to_le_bytes().to_vec(),
+            (ops.len() as u32).to_le_bytes().to_vec(),
+        ]
+        .concat();
+        for (key, value) in ops {
+            buf.push(value.is_some() as u8);
+            buf.extend(prefixed(key));
+            if let Some(value) = value {
+                buf.extend(prefixed(value));
+            }
+        }
+        buf
+    }
+
+    fn log_record(kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; 4]; // checksum is not verified
+        buf.extend((data.len() as u16).to_le_bytes());
+        buf.push(kind);
+        buf.extend(data);
+        buf
+    }
+
+    #[test]
+    fn test_read_synced_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let phone = specifics(
+            "session_sync1",
+            Some("Pixel"),
+            &[
+                ("https://b.com/", 1_642_000_001_000),
+                ("https://a.com/", 1_642_000_000_000),
+            ],
+        );
+        let header = specifics("session_sync1", Some("Pixel"), &[]);
+        let removed = specifics("session_sync2", Some("Laptop"), &[("https://c.com/", 1)]);
+        let batch1 = batch(
+            1,
+            &[
+                (b"sessions-dt-phone-1", Some(&phone)),
+                (b"sessions-dt-laptop-1", Some(&removed)),
+                (b"sessions-md-phone-1", Some(b"metadata")),
+            ],
+        );
+        let batch2 = batch(
+            3,
+            &[
+                (b"sessions-dt-laptop-1", None),
+                (b"sessions-dt-phone-0", Some(&header)),
+                (b"sessions-dt-bad", Some(b"\xff")),
+            ],
+        );
+        // batch2 is split into two records
+        let (first, last) = batch2.split_at(10);
+        let log = [
+            log_record(1, &batch1),
+            log_record(2, first),
+            log_record(4, last),
+            vec![1, 2, 3], // partially written
+        ]
+        .concat();
+        fs::write(dir.path().join("000003.log"), log).unwrap();
+        fs::write(dir.path().join("LOCK"), "").unwrap();
+
+        let sessions = read_synced_sessions(dir.path()).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].device, "Pixel");
+        let visits = sessions[0]
+            .visits
+            .iter()
+            .map(|v| (v.url.as_str(), v.visit_time, v.visit_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            visits,
+            vec![
+                ("https://a.com/", 1_642_000_000_000_000, SYNCED_VISIT_TYPE),
+                ("https://b.com/", 1_642_000_001_000_000, SYNCED_VISIT_TYPE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_table() {
+        // One data block holding two keys, the second shares prefix with the first
+        let value = specifics("tag", None, &[("https://a.com/", 1_642_000_000_000)]);
+        let key1 = [b"sessions-dt-a".as_slice(), &(2u64 << 8 | 1).to_le_bytes()].concat();
+        let key2 = [b"sessions-dt-b".as_slice(), &(1u64 << 8 | 1).to_le_bytes()].concat();
+        let mut data = [
+            varint(0),
+            varint(key1.len() as u64),
+            varint(value.len() as u64),
+        ]
+        .concat();
+        data.extend(&key1);
+        data.extend(&value);
+        data.extend([varint(12), varint(key2.len() as u64 - 12), varint(1)].concat());
+        data.extend(&key2[12..]);
+        data.push(0xff);
+        data.extend([0u8; 4]); // restart at 0
+        data.extend(1u32.to_le_bytes());
+        let handle = [varint(0), varint(data.len() as u64)].concat();
+        let mut index = [
+            varint(0),
+            varint(key2.len() as u64),
+            varint(handle.len() as u64),
+        ]
+        .concat();
+        index.extend(&key2);
+        index.extend(&handle);
+        index.extend([0u8; 4]);
+        index.extend(1u32.to_le_bytes());
+
+        let mut table = data.clone();
+        table.extend([0u8; 5]); // uncompressed, checksum is not verified
+        let index_offset = table.len() as u64;
+        table.extend(&index);
+        table.extend([0u8; 5]);
+        let mut footer = [
+            varint(0),
+            varint(0),
+            varint(index_offset),
+            varint(index.len() as u64),
+        ]
+        .concat();
+        footer.resize(TABLE_FOOTER_SIZE - 8, 0);
+        footer.extend(TABLE_MAGIC.to_le_bytes());
+        table.extend(footer);
+
+        let mut entries = Entries::default();
+        read_table(&table, &mut entries).unwrap();
+        assert_eq!(
+            entries.into_values(),
+            vec![
+                (b"sessions-dt-a".to_vec(), value),
+                (b"sessions-dt-b".to_vec(), vec![0xff])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snappy_decompress() {
+        // literal "abcd", then copy 8 bytes from offset 4 (overlapping)
+        let buf = [
+            vec![12],
+            vec![3 << 2],
+            b"abcd".to_vec(),
+            vec![1 | (8 - 4) << 2, 4],
+        ]
+        .concat();
+        assert_eq!(snappy_decompress(&buf).unwrap(), b"abcdabcdabcd");
+        assert!(snappy_decompress(&[4, 1 | 4 << 2, 1]).is_err());
+    }
+}
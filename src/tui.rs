@@ -0,0 +1,472 @@
+use crate::{
+    database::Database,
+    lock::lock_db,
+    types::VisitDetail,
+    util::{full_timerange, open_url, unixepoch_as_ymdhms, ymd_midnight},
+};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use std::{
+    io::{self, IsTerminal, Write},
+    path::Path,
+    time::Duration,
+};
+
+// Number of visits loaded from database at a time
+const PAGE_SIZE: usize = 200;
+const ONE_DAY: i64 = 3_600_000 * 24;
+
+// A window over visits matching current filters, only the page containing
+// selected visit is kept in memory.
+struct VisitList {
+    page_size: usize,
+    total: usize,
+    page_start: usize,
+    page: Vec<VisitDetail>,
+    selected: usize,
+}
+
+impl VisitList {
+    fn new(page_size: usize) -> Self {
+        Self {
+            page_size,
+            total: 0,
+            page_start: 0,
+            page: Vec::new(),
+            selected: 0,
+        }
+    }
+
+    // `fetch(offset, limit)` returns visits of one page
+    fn reset<F>(&mut self, total: usize, fetch: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) -> Result<Vec<VisitDetail>>,
+    {
+        self.total = total;
+        self.selected = 0;
+        self.load(fetch)
+    }
+
+    fn select<F>(&mut self, index: usize, fetch: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) -> Result<Vec<VisitDetail>>,
+    {
+        if self.total == 0 {
+            return Ok(());
+        }
+        self.selected = index.min(self.total - 1);
+        if !(self.page_start..self.page_start + self.page.len()).contains(&self.selected) {
+            self.load(fetch)?;
+        }
+        Ok(())
+    }
+
+    fn move_by<F>(&mut self, delta: isize, fetch: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) -> Result<Vec<VisitDetail>>,
+    {
+        self.select(self.selected.saturating_add_signed(delta), fetch)
+    }
+
+    fn select_last<F>(&mut self, fetch: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) -> Result<Vec<VisitDetail>>,
+    {
+        self.select(self.total.saturating_sub(1), fetch)
+    }
+
+    // Called after selected visit is deleted from database
+    fn remove_selected<F>(&mut self, fetch: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) -> Result<Vec<VisitDetail>>,
+    {
+        self.total = self.total.saturating_sub(1);
+        self.selected = self.selected.min(self.total.saturating_sub(1));
+        self.load(fetch)
+    }
+
+    fn load<F>(&mut self, mut fetch: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) -> Result<Vec<VisitDetail>>,
+    {
+        self.page_start = self.selected / self.page_size * self.page_size;
+        self.page = if self.total == 0 {
+            Vec::new()
+        } else {
+            fetch(self.page_start, self.page_size)?
+        };
+        Ok(())
+    }
+
+    fn selected(&self) -> Option<&VisitDetail> {
+        self.page.get(self.selected.checked_sub(self.page_start)?)
+    }
+}
+
+enum Mode {
+    Normal,
+    Search(String),
+    Date(String),
+    ConfirmDelete,
+}
+
+struct App<'a> {
+    db: &'a Database,
+    // Locked while deleting, so it doesn't race a running backup
+    db_file: &'a Path,
+    lock_wait: Duration,
+    keyword: Option<String>,
+    // Only show visits of this day(YYYY-MM-DD) when set
+    day: Option<String>,
+    list: VisitList,
+    list_state: ListState,
+    rendered_page: usize,
+    list_height: usize,
+    // Visit count of selected url
+    url_visits: Option<(String, u64)>,
+    mode: Mode,
+    message: String,
+    quit: bool,
+}
+
+impl<'a> App<'a> {
+    fn new(db: &'a Database, db_file: &'a Path, lock_wait: Duration) -> Self {
+        Self {
+            db,
+            db_file,
+            lock_wait,
+            keyword: None,
+            day: None,
+            list: VisitList::new(PAGE_SIZE),
+            list_state: ListState::default(),
+            rendered_page: 0,
+            list_height: 1,
+            url_visits: None,
+            mode: Mode::Normal,
+            message: String::new(),
+            quit: false,
+        }
+    }
+
+    fn time_range(day: Option<&str>) -> Result<(i64, i64)> {
+        match day {
+            Some(day) => {
+                let start = ymd_midnight(day)?;
+                Ok((start, start + ONE_DAY - 1))
+            }
+            None => Ok(full_timerange()),
+        }
+    }
+
+    // Newest visits first
+    fn fetcher(&self) -> Result<impl FnMut(usize, usize) -> Result<Vec<VisitDetail>> + 'a> {
+        let db = self.db;
+        let (start, end) = Self::time_range(self.day.as_deref())?;
+        let keyword = self.keyword.clone();
+        Ok(move |offset, limit| {
+            Ok(db.select_visits_page(start, end, keyword.clone(), true, offset, limit)?)
+        })
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let (start, end) = Self::time_range(self.day.as_deref())?;
+        let total = self.db.count_visits(start, end, self.keyword.clone())?;
+        let fetch = self.fetcher()?;
+        self.list.reset(total as usize, fetch)
+    }
+
+    fn update_url_visits(&mut self) -> Result<()> {
+        let url = match self.list.selected() {
+            Some(visit) => visit.url.clone(),
+            None => {
+                self.url_visits = None;
+                return Ok(());
+            }
+        };
+        if !matches!(&self.url_visits, Some((cached, _)) if *cached == url) {
+            let cnt = self.db.count_url_visits(&url)?;
+            self.url_visits = Some((url, cnt));
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.quit {
+            if let Err(e) = self.update_url_visits() {
+                self.message = format!("Error: {e:#}");
+            }
+            terminal.draw(|frame| self.render(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                self.message.clear();
+                if let Err(e) = self.handle_key(key) {
+                    self.message = format!("Error: {e:#}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Result<()> {
+        let page = self.list_height.max(1) as isize;
+        match &mut self.mode {
+            Mode::Normal => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    self.quit = true
+                }
+                KeyCode::Down | KeyCode::Char('j') => self.list.move_by(1, self.fetcher()?)?,
+                KeyCode::Up | KeyCode::Char('k') => self.list.move_by(-1, self.fetcher()?)?,
+                KeyCode::PageDown => self.list.move_by(page, self.fetcher()?)?,
+                KeyCode::PageUp => self.list.move_by(-page, self.fetcher()?)?,
+                KeyCode::Home | KeyCode::Char('g') => self.list.select(0, self.fetcher()?)?,
+                KeyCode::End | KeyCode::Char('G') => self.list.select_last(self.fetcher()?)?,
+                KeyCode::Char('/') => {
+                    self.mode = Mode::Search(self.keyword.clone().unwrap_or_default())
+                }
+                KeyCode::Char('d') => self.mode = Mode::Date(self.day.clone().unwrap_or_default()),
+                KeyCode::Char('y') => {
+                    if let Some(visit) = self.list.selected() {
+                        copy_to_clipboard(&visit.url)?;
+                        self.message = "URL copied".to_string();
+                    }
+                }
+                KeyCode::Char('o') => {
+                    if let Some(visit) = self.list.selected() {
+                        open_url(&visit.url).context("open url")?;
+                    }
+                }
+                KeyCode::Char('x') | KeyCode::Delete if self.list.selected().is_some() => {
+                    self.mode = Mode::ConfirmDelete
+                }
+                _ => {}
+            },
+            Mode::Search(input) | Mode::Date(input) => match key.code {
+                KeyCode::Char(c) => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Enter => {
+                    let input = Some(input.trim().to_string()).filter(|s| !s.is_empty());
+                    if let Mode::Search(_) = self.mode {
+                        self.keyword = input;
+                    } else {
+                        Self::time_range(input.as_deref()).context("invalid date")?;
+                        self.day = input;
+                    }
+                    self.mode = Mode::Normal;
+                    self.reload()?;
+                }
+                _ => {}
+            },
+            Mode::ConfirmDelete => {
+                self.mode = Mode::Normal;
+                if let (KeyCode::Char('y'), Some(visit)) = (key.code, self.list.selected()) {
+                    let lock = lock_db(self.db_file, self.lock_wait)?;
+                    self.db.delete_visit(&visit.url, visit.visit_time)?;
+                    drop(lock);
+                    self.url_visits = None;
+                    self.list.remove_selected(self.fetcher()?)?;
+                    self.message = "Visit deleted".to_string();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [header, main, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [list_area, detail_area] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main);
+
+        frame.render_widget(
+            Line::from(format!(
+                "1History  keyword: {}  date: {}",
+                self.keyword.as_deref().unwrap_or("-"),
+                self.day.as_deref().unwrap_or("all")
+            ))
+            .bold(),
+            header,
+        );
+
+        let items = self
+            .list
+            .page
+            .iter()
+            .map(|v| {
+                ListItem::new(format!(
+                    "{} {}",
+                    unixepoch_as_ymdhms(v.visit_time),
+                    if v.title.is_empty() { &v.url } else { &v.title }
+                ))
+            })
+            .collect::<Vec<_>>();
+        let position = if self.list.total == 0 {
+            0
+        } else {
+            self.list.selected + 1
+        };
+        let list = List::new(items)
+            .block(Block::bordered().title(format!(" Visits {position}/{} ", self.list.total)))
+            .highlight_style(Style::new().reversed());
+        // Borders take two lines
+        self.list_height = list_area.height.saturating_sub(2) as usize;
+        if self.rendered_page != self.list.page_start {
+            *self.list_state.offset_mut() = 0;
+            self.rendered_page = self.list.page_start;
+        }
+        self.list_state
+            .select(self.list.selected.checked_sub(self.list.page_start));
+        frame.render_stateful_widget(list, list_area, &mut self.list_state);
+
+        let details = match self.list.selected() {
+            Some(visit) => vec![
+                Line::from(format!("Title: {}", visit.title)),
+                Line::from(format!("URL: {}", visit.url)),
+                Line::from(format!("Time: {}", unixepoch_as_ymdhms(visit.visit_time))),
+                Line::from(format!(
+                    "Visits: {}",
+                    self.url_visits
+                        .as_ref()
+                        .map_or_else(|| "-".to_string(), |(_, cnt)| cnt.to_string())
+                )),
+            ],
+            None => vec![Line::from("No visits")],
+        };
+        frame.render_widget(
+            Paragraph::new(details)
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(" Details ")),
+            detail_area,
+        );
+
+        let status = match &self.mode {
+            Mode::Normal if !self.message.is_empty() => self.message.clone(),
+            Mode::Normal => {
+                "q quit  j/k move  / search  d date  y copy url  o open  x delete".to_string()
+            }
+            Mode::Search(input) => format!("Search: {input}"),
+            Mode::Date(input) => format!("Date(YYYY-MM-DD, empty for all): {input}"),
+            Mode::ConfirmDelete => "Delete this visit? (y/n)".to_string(),
+        };
+        frame.render_widget(Line::from(status), footer);
+    }
+}
+
+// Use OSC 52 escape sequence, which also works over SSH
+fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()
+}
+
+/// Browse visits in 1History database at `db_file` interactively in terminal, newest
+/// first. Both stdin and stdout must be a terminal. Deleting a visit locks the
+/// database, waiting at most `lock_wait` for other processes, see `lock_db`.
+pub fn browse<P: AsRef<Path>>(db_file: P, lock_wait: Duration) -> crate::error::Result<()> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return Err(anyhow!("tui requires an interactive terminal").into());
+    }
+    let db_file = db_file.as_ref();
+    let db = Database::open(db_file).context("open 1History DB")?;
+    let mut app = App::new(&db, db_file, lock_wait);
+    app.reload()?;
+
+    // Terminal is also restored on panic
+    let mut terminal = ratatui::try_init()?;
+    let res = app.run(&mut terminal);
+    ratatui::restore();
+
+    Ok(res?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn visits(n: usize) -> Vec<VisitDetail> {
+        (0..n)
+            .map(|i| VisitDetail {
+                url: format!("https://example.com/{i}"),
+                title: String::new(),
+                visit_time: i as i64,
+                visit_type: 0,
+                source_visit_id: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_visit_list_pagination() {
+        let all = visits(25);
+        let fetches = Cell::new(0);
+        let fetch = |offset: usize, limit: usize| {
+            fetches.set(fetches.get() + 1);
+            Ok(all.iter().skip(offset).take(limit).cloned().collect())
+        };
+        let selected_url = |list: &VisitList| list.selected().map(|v| v.url.clone());
+
+        let mut list = VisitList::new(10);
+        list.reset(all.len(), fetch).unwrap();
+        assert_eq!(fetches.get(), 1);
+        assert_eq!((list.page_start, list.page.len()), (0, 10));
+        assert_eq!(selected_url(&list).unwrap(), "https://example.com/0");
+
+        // Within current page, no fetch
+        list.move_by(9, fetch).unwrap();
+        assert_eq!(fetches.get(), 1);
+        list.move_by(1, fetch).unwrap();
+        assert_eq!(fetches.get(), 2);
+        assert_eq!((list.page_start, list.selected), (10, 10));
+
+        list.move_by(-100, fetch).unwrap();
+        assert_eq!((list.page_start, list.selected), (0, 0));
+        list.select_last(fetch).unwrap();
+        assert_eq!((list.page_start, list.page.len()), (20, 5));
+        assert_eq!(selected_url(&list).unwrap(), "https://example.com/24");
+        list.move_by(100, fetch).unwrap();
+        assert_eq!(list.selected, 24);
+    }
+
+    #[test]
+    fn test_visit_list_remove() {
+        let mut all = visits(11);
+        let mut list = VisitList::new(10);
+        list.reset(all.len(), |o, l| {
+            Ok(all.iter().skip(o).take(l).cloned().collect())
+        })
+        .unwrap();
+        list.select_last(|o, l| Ok(all.iter().skip(o).take(l).cloned().collect()))
+            .unwrap();
+
+        all.pop();
+        list.remove_selected(|o, l| Ok(all.iter().skip(o).take(l).cloned().collect()))
+            .unwrap();
+        assert_eq!((list.total, list.selected, list.page_start), (10, 9, 0));
+        assert_eq!(list.selected().unwrap().url, "https://example.com/9");
+
+        list.reset(0, |_, _| unreachable!()).unwrap();
+        assert!(list.selected().is_none());
+        list.move_by(1, |_, _| unreachable!()).unwrap();
+        list.remove_selected(|_, _| unreachable!()).unwrap();
+        assert_eq!(list.total, 0);
+    }
+}
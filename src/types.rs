@@ -1,26 +1,554 @@
-use anyhow::Error;
+use crate::{
+    fuzzy::DEFAULT_FUZZY_THRESHOLD,
+    util::{canonical_url, domain_from, unixepoch_as_ymdhms},
+};
+use anyhow::{anyhow, Error};
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, Weekday};
+use serde::{de, Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    time::Duration,
+};
 use warp::reject::Reject;
 
-#[derive(Debug, Clone, Copy)]
+/// Browsers supported by 1History.
+#[derive(Debug, Clone, Copy, Serialize)]
 pub enum SourceName {
     Safari,
     Firefox,
     Chrome,
+    /// Microsoft Edge, told from Chrome by path of its history file since they
+    /// share the Chromium schema
+    Edge,
 }
 
-#[derive(Serialize)]
+impl SourceName {
+    /// Whether history file is of Chromium schema.
+    pub fn is_chromium(&self) -> bool {
+        matches!(self, SourceName::Chrome | SourceName::Edge)
+    }
+}
+
+impl Display for SourceName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SourceName::Safari => "safari",
+            SourceName::Firefox => "firefox",
+            SourceName::Chrome => "chrome",
+            SourceName::Edge => "edge",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// One visit of an URL.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct VisitDetail {
     pub url: String,
     pub title: String,
-    // unix_epoch_ms
+    /// unix_epoch_ms when selected from `Database`, PRTime when selected from `Source`
     pub visit_time: i64,
     pub visit_type: i64,
+    /// Id of the visit in history file it's read from, only selected by `Source`
+    /// and `Database::select_visit_traces`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_visit_id: Option<i64>,
+}
+
+/// Where a visit in `Database` comes from, see `Database::select_visit_trace`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VisitTrace {
+    /// Id of the visit in `Database`
+    pub id: i64,
+    /// `source_visit_id` is `None` for visits not read from history files, such as
+    /// synced tabs, or imported by older versions
+    #[serde(flatten)]
+    pub visit: VisitDetail,
+    /// History file the visit is imported from, `None` when imported by older versions
+    pub source: Option<String>,
+    /// Whether the visit is hidden, see `Database::set_visit_hidden`
+    pub hidden: bool,
+}
+
+impl Display for VisitTrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let unknown = || "unknown".to_string();
+        writeln!(f, "id:              {}", self.id)?;
+        writeln!(f, "url:             {}", self.visit.url)?;
+        writeln!(f, "title:           {}", self.visit.title)?;
+        writeln!(
+            f,
+            "time:            {}",
+            unixepoch_as_ymdhms(self.visit.visit_time)
+        )?;
+        writeln!(f, "visit type:      {}", self.visit.visit_type)?;
+        writeln!(
+            f,
+            "source:          {}",
+            self.source.clone().unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            f,
+            "source visit id: {}",
+            self.visit
+                .source_visit_id
+                .map_or_else(unknown, |id| id.to_string())
+        )?;
+        writeln!(
+            f,
+            "hidden:          {}",
+            if self.hidden { "yes" } else { "no" }
+        )
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DetailsQueryParams {
     pub keyword: Option<String>,
+    /// List most recent visits first
+    #[serde(default)]
+    pub reverse: bool,
+    /// Fold consecutive visits of the same url into one row, `collapse=0` disables it
+    #[serde(default = "enabled", deserialize_with = "deserialize_flag")]
+    pub collapse: bool,
+    /// Only list visits of this exact domain, see `util::domain_from`
+    pub domain: Option<String>,
+    /// Locale of page, such as `zh-CN`, overrides `Accept-Language`
+    pub lang: Option<String>,
+    /// How `keyword` matches titles
+    #[serde(default, rename = "match")]
+    pub match_mode: MatchMode,
+    /// How terms of `keyword` combine in substring match
+    #[serde(default, rename = "op")]
+    pub keyword_op: KeywordOp,
+    /// Minimum similarity of fuzzy match, `DEFAULT_FUZZY_THRESHOLD` by default
+    pub threshold: Option<f64>,
+    /// Show where each visit comes from, see `VisitTrace`
+    #[serde(default)]
+    pub debug: bool,
+    /// Only include visits of this source, see `SourceFilter`
+    pub source: Option<String>,
+    /// Include hidden visits, see `Database::with_include_hidden`
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub include_hidden: bool,
+    /// Group variants of urls in top lists and stats, see `Database::with_canonical`
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub canonical: bool,
+    /// Also show how long ago each visit was, such as "2 hours ago", handy for
+    /// recent days
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub relative: bool,
+}
+
+/// Body of `PATCH /api/visits/{id}`.
+#[derive(Debug, Deserialize)]
+pub struct VisitPatch {
+    /// Hide the visit, or show it again when false
+    pub hidden: bool,
+}
+
+impl DetailsQueryParams {
+    /// Keyword and threshold of fuzzy match, `None` when `keyword` should be matched
+    /// as substring, or there is no keyword.
+    pub fn fuzzy(&self) -> Result<Option<(&str, f64)>, Error> {
+        let keyword = self.keyword.as_deref().filter(|kw| !kw.trim().is_empty());
+        match (self.match_mode, keyword) {
+            (MatchMode::Fuzzy, Some(keyword)) => {
+                let threshold = self.threshold.unwrap_or(DEFAULT_FUZZY_THRESHOLD);
+                if !(0.0..=1.0).contains(&threshold) {
+                    return Err(anyhow!(
+                        "threshold should be between 0 and 1, got {threshold}"
+                    ));
+                }
+                Ok(Some((keyword, threshold)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn scope(&self) -> QueryScope<'_> {
+        QueryScope {
+            source: self.source.as_deref(),
+            include_hidden: self.include_hidden,
+            canonical: self.canonical,
+            keyword_op: self.keyword_op,
+        }
+    }
+}
+
+impl Default for DetailsQueryParams {
+    fn default() -> Self {
+        Self {
+            keyword: None,
+            reverse: false,
+            collapse: true,
+            domain: None,
+            lang: None,
+            match_mode: MatchMode::default(),
+            keyword_op: KeywordOp::default(),
+            threshold: None,
+            debug: false,
+            source: None,
+            include_hidden: false,
+            canonical: false,
+            relative: false,
+        }
+    }
+}
+
+/// How keyword of search matches visits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Url or title contains keyword
+    #[default]
+    Substring,
+    /// Title is similar to keyword, ranked by similarity, see
+    /// `Database::select_fuzzy_visits`
+    Fuzzy,
+}
+
+/// How terms of keyword, separated by whitespace or quoted as phrases, combine,
+/// see `util::split_keyword`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeywordOp {
+    /// Url or title contains every term
+    #[default]
+    And,
+    /// Url or title contains any term
+    Or,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+// Accept `0`/`1` besides `true`/`false`
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        other => Err(de::Error::invalid_value(
+            de::Unexpected::Str(other),
+            &"0, 1, true or false",
+        )),
+    }
+}
+
+/// How visits are counted in daily counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CountMode {
+    /// Every visit counts
+    #[default]
+    Raw,
+    /// Visits of the same url within one hour count once
+    UrlHour,
+    /// Visits of the same url within this interval after its previous visit don't count
+    MinInterval(Duration),
+}
+
+/// Hours of day in local time, from `start` to `end` exclusive, such as `9-18` for
+/// work hours. It wraps around midnight when `start` is after `end`, such as `22-6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HourRange {
+    start: u32,
+    end: u32,
+}
+
+impl HourRange {
+    /// Hours from `start`(0-23) to `end`(0-24) exclusive, which must differ.
+    pub fn new(start: u32, end: u32) -> Result<Self, Error> {
+        if start > 23 || end > 24 || start == end {
+            anyhow::bail!(
+                "invalid hours {start}-{end}, expect START in 0-23, END in 0-24 and not START"
+            );
+        }
+        Ok(Self { start, end })
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    /// SQL condition matching hours of `column`, which is PRTime.
+    pub(crate) fn to_sql(self, column: &str) -> String {
+        let hour = format!(
+            "CAST(strftime('%H', {column} / 1000000, 'unixepoch', 'localtime') AS integer)"
+        );
+        if self.start < self.end {
+            format!("{hour} BETWEEN {} AND {}", self.start, self.end - 1)
+        } else {
+            format!("{hour} NOT BETWEEN {} AND {}", self.end, self.start - 1)
+        }
+    }
+}
+
+impl FromStr for HourRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((start, end)) = s.split_once('-') else {
+            anyhow::bail!("invalid hours {s}, expect START-END such as 9-18");
+        };
+        Self::new(start.trim().parse()?, end.trim().parse()?)
+    }
+}
+
+impl Display for HourRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// One ISO 8601 week, such as `2024-W21`, which starts on Monday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsoWeek {
+    pub year: i32,
+    pub week: u32,
+}
+
+impl IsoWeek {
+    /// Week `date` falls in.
+    pub fn of(date: NaiveDate) -> Self {
+        let week = date.iso_week();
+        Self {
+            year: week.year(),
+            week: week.week(),
+        }
+    }
+
+    /// Monday of the week.
+    pub fn monday(&self) -> NaiveDate {
+        NaiveDate::from_isoywd(self.year, self.week, Weekday::Mon)
+    }
+
+    /// The week before this one.
+    pub fn previous(&self) -> Self {
+        Self::of(self.monday() - ChronoDuration::weeks(1))
+    }
+}
+
+impl FromStr for IsoWeek {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("invalid week {s}, expect YYYY-Www such as 2024-W21");
+        let (year, week) = s.trim().split_once(['W', 'w']).ok_or_else(invalid)?;
+        let year = year.strip_suffix('-').unwrap_or(year);
+        let (year, week) = (
+            year.parse().map_err(|_| invalid())?,
+            week.parse().map_err(|_| invalid())?,
+        );
+        NaiveDate::from_isoywd_opt(year, week, Weekday::Mon).ok_or_else(invalid)?;
+        Ok(Self { year, week })
+    }
+}
+
+impl Display for IsoWeek {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-W{:02}", self.year, self.week)
+    }
+}
+
+/// How trailing slashes of url paths are canonicalized, see
+/// `util::normalize_trailing_slash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// `https://example.com/page/` becomes `https://example.com/page`
+    Strip,
+    /// `https://example.com/page` becomes `https://example.com/page/`
+    Keep,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "strip" => Ok(Self::Strip),
+            "keep" => Ok(Self::Keep),
+            _ => anyhow::bail!("invalid trailing slash mode {s}, expect strip or keep"),
+        }
+    }
+}
+
+impl Display for TrailingSlash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TrailingSlash::Strip => write!(f, "strip"),
+            TrailingSlash::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+/// Which history file visits come from, by id of its import record, or browser
+/// name such as `chrome`. `unknown` matches visits imported before sources are
+/// recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceFilter {
+    Id(i64),
+    Browser(String),
+    Unknown,
+}
+
+impl SourceFilter {
+    /// SQL condition matching visits whose import record id is in `column`, browser
+    /// name is bound to named parameter `param`, whose value is returned together.
+    pub(crate) fn to_sql(&self, column: &str, param: &str) -> (String, Option<String>) {
+        match self {
+            SourceFilter::Id(id) => (format!("{column} = {id}"), None),
+            SourceFilter::Browser(name) => (
+                format!("{column} IN (SELECT id FROM import_records WHERE browser = {param})"),
+                Some(name.clone()),
+            ),
+            SourceFilter::Unknown => (format!("{column} IS NULL"), None),
+        }
+    }
+}
+
+impl FromStr for SourceFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("unknown") {
+            return Ok(Self::Unknown);
+        }
+        if let Ok(id) = s.parse() {
+            return Ok(Self::Id(id));
+        }
+        if s.is_empty() {
+            anyhow::bail!("invalid source {s}, expect id of source, browser name or unknown");
+        }
+        Ok(Self::Browser(s.to_ascii_lowercase()))
+    }
+}
+
+impl Display for SourceFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceFilter::Id(id) => write!(f, "{id}"),
+            SourceFilter::Browser(name) => write!(f, "{name}"),
+            SourceFilter::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// History file visits come from, see `Database::select_sources`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SourceSummary {
+    /// Id of its import record, `None` for visits imported before sources are
+    /// recorded
+    pub id: Option<i64>,
+    pub data_path: Option<String>,
+    /// Browser detected when it's backed up, such as `chrome`
+    pub browser: Option<String>,
+    pub visits: u64,
+    /// Schema version of history file when it's last backed up, such as Chrome's
+    /// `meta.version`
+    pub schema_version: Option<String>,
+}
+
+/// Consecutive visits of the same url, folded into one row of details page.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct VisitGroup {
+    /// The first visit in listed order
+    #[serde(flatten)]
+    pub visit: VisitDetail,
+    pub count: usize,
+    /// unix_epoch_ms of the earliest visit
+    pub start: i64,
+    /// unix_epoch_ms of the latest visit
+    pub end: i64,
+    /// Where the first visit comes from, only filled in debug mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<VisitTrace>,
+}
+
+/// Headline numbers of visits in one day, see `Database::select_day_stats`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DayStats {
+    pub visits: u64,
+    /// Number of distinct urls
+    pub urls: u64,
+    /// Number of distinct domains
+    pub domains: u64,
+    /// Top 10 domains by visits, ordered by count desc
+    pub top_domains: Vec<(String, i64)>,
+    /// Number of hidden visits, which are not counted in others
+    pub hidden: u64,
+}
+
+impl DayStats {
+    /// Stats of visits counted by distinct url.
+    pub(crate) fn from_url_counts(url_counts: Vec<(String, i64)>) -> Self {
+        let mut stats = DayStats {
+            urls: url_counts.len() as u64,
+            ..Default::default()
+        };
+        let mut domain_counts = HashMap::new();
+        for (url, cnt) in url_counts {
+            stats.visits += cnt as u64;
+            *domain_counts.entry(domain_from(url)).or_insert(0) += cnt;
+        }
+        stats.domains = domain_counts.len() as u64;
+        let mut domains = domain_counts.into_iter().collect::<Vec<(String, i64)>>();
+        domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        domains.truncate(10);
+        stats.top_domains = domains;
+        stats
+    }
+
+    /// Stats of `visits`, urls are grouped by `util::canonical_url` when `canonical`.
+    pub(crate) fn from_visits(visits: &[VisitDetail], canonical: bool) -> Self {
+        let mut url_counts = HashMap::new();
+        for visit in visits {
+            let url = if canonical {
+                canonical_url(&visit.url)
+            } else {
+                visit.url.clone()
+            };
+            *url_counts.entry(url).or_insert(0) += 1;
+        }
+        Self::from_url_counts(url_counts.into_iter().collect())
+    }
+}
+
+/// Visits of one domain in two halves of a time range, see
+/// `Database::select_domain_trends`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DomainTrend {
+    pub domain: String,
+    /// Visits in the first half
+    pub before: i64,
+    /// Visits in the second half
+    pub after: i64,
+}
+
+impl DomainTrend {
+    /// Visits gained in the second half, negative when it loses visits.
+    pub fn change(&self) -> i64 {
+        self.after - self.before
+    }
+}
+
+/// Domains whose visits change most between two halves of a time range.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DomainTrends {
+    /// unix_epoch_ms where the second half starts
+    pub split_at: i64,
+    /// Ordered by visits gained desc
+    pub rising: Vec<DomainTrend>,
+    /// Ordered by visits lost desc
+    pub falling: Vec<DomainTrend>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,6 +556,344 @@ pub struct IndexQueryParams {
     pub start: Option<String>, // Y-m-d
     pub end: Option<String>,   // Y-m-d
     pub keyword: Option<String>,
+    /// How terms of `keyword` combine
+    #[serde(default, rename = "op")]
+    pub keyword_op: KeywordOp,
+    /// Locale of page, such as `zh-CN`, overrides `Accept-Language`
+    pub lang: Option<String>,
+    /// Only include visits of this source, see `SourceFilter`
+    pub source: Option<String>,
+    /// Include hidden visits, see `Database::with_include_hidden`
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub include_hidden: bool,
+    /// Group variants of urls in top lists and stats, see `Database::with_canonical`
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub canonical: bool,
+}
+
+impl IndexQueryParams {
+    pub fn scope(&self) -> QueryScope<'_> {
+        QueryScope {
+            source: self.source.as_deref(),
+            include_hidden: self.include_hidden,
+            canonical: self.canonical,
+            keyword_op: self.keyword_op,
+        }
+    }
+}
+
+/// Which visits a query of web pages covers, shared by `IndexQueryParams` and
+/// `DetailsQueryParams`.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryScope<'a> {
+    /// Only include visits of this source, see `SourceFilter`
+    pub source: Option<&'a str>,
+    /// Include hidden visits, see `Database::with_include_hidden`
+    pub include_hidden: bool,
+    /// Group variants of urls, see `Database::with_canonical`
+    pub canonical: bool,
+    /// How terms of keyword combine
+    pub keyword_op: KeywordOp,
+}
+
+/// One browsing session, visits without long gaps between.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SessionSpan {
+    /// unix_epoch_ms of the first visit
+    pub start: i64,
+    /// unix_epoch_ms of the last visit
+    pub end: i64,
+    pub visits: usize,
+}
+
+/// Statistics of browsing sessions, see `Database::select_session_stats`.
+#[derive(Debug, Serialize)]
+pub struct SessionStats {
+    pub sessions: usize,
+    pub visits: usize,
+    /// Average visits per session
+    pub avg_visits: f64,
+    /// Sessions started on each day, as (unix_epoch_ms of midnight, count)
+    pub daily_sessions: Vec<(i64, i64)>,
+    /// Session lasting longest
+    pub longest: Option<SessionSpan>,
+    /// Estimated active browsing time in milliseconds, which is the sum of gaps
+    /// between visits within sessions. Time spent on the last page of a session
+    /// isn't known, so it's an estimate.
+    pub active_time: i64,
+}
+
+impl SessionStats {
+    /// Average sessions of days with visits.
+    pub fn sessions_per_day(&self) -> f64 {
+        if self.daily_sessions.is_empty() {
+            return 0.0;
+        }
+        self.sessions as f64 / self.daily_sessions.len() as f64
+    }
+}
+
+/// Visits imported by the most recent backup, see `Database::select_whatsnew`.
+#[derive(Debug, Serialize)]
+pub struct WhatsNew {
+    /// unix_epoch_ms when the backup started
+    pub backup_at: i64,
+    pub visits: u64,
+    /// Visit counts grouped by domain, ordered by count desc
+    pub domains: Vec<(String, i64)>,
+}
+
+impl Display for WhatsNew {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} new visits in backup at {}",
+            self.visits,
+            unixepoch_as_ymdhms(self.backup_at)
+        )?;
+        if self.domains.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "{:>8}  DOMAIN", "VISITS")?;
+        for (domain, cnt) in &self.domains {
+            writeln!(f, "{cnt:>8}  {domain}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Visits only in one of two databases compared by `Database::diff`.
+#[derive(Debug, Default, Serialize)]
+pub struct DiffSide {
+    pub visits: u64,
+    /// Visit counts grouped by month(%Y-%m) in local time, ordered by month
+    pub by_month: Vec<(String, u64)>,
+    /// Visit counts grouped by history file imported from, ordered by count desc,
+    /// `None` for visits imported by older versions
+    pub by_source: Vec<(Option<String>, u64)>,
+    /// Earliest visits, at most the number of samples requested
+    pub samples: Vec<VisitDetail>,
+}
+
+/// URL whose title differs between two databases.
+#[derive(Debug, Serialize)]
+pub struct TitleDiff {
+    pub url: String,
+    pub title: Option<String>,
+    pub other_title: Option<String>,
+}
+
+/// Differences between two databases, see `Database::diff`.
+#[derive(Debug, Serialize)]
+pub struct DbDiff {
+    /// Visits only in the database compared
+    pub only_here: DiffSide,
+    /// Visits only in the other database
+    pub only_other: DiffSide,
+    /// Number of URLs in both databases but with different titles
+    pub title_diffs: u64,
+    /// URLs with different titles, at most the number of samples requested
+    pub title_samples: Vec<TitleDiff>,
+}
+
+impl DbDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_here.visits == 0 && self.only_other.visits == 0 && self.title_diffs == 0
+    }
+}
+
+impl Display for DiffSide {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.visits == 0 {
+            return Ok(());
+        }
+        writeln!(f, "{:>8}  MONTH", "VISITS")?;
+        for (month, cnt) in &self.by_month {
+            writeln!(f, "{cnt:>8}  {month}")?;
+        }
+        writeln!(f, "{:>8}  SOURCE", "VISITS")?;
+        for (source, cnt) in &self.by_source {
+            writeln!(f, "{cnt:>8}  {}", source.as_deref().unwrap_or("unknown"))?;
+        }
+        for visit in &self.samples {
+            writeln!(
+                f,
+                "{}  {}  {}",
+                unixepoch_as_ymdhms(visit.visit_time),
+                visit.url,
+                visit.title
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for DbDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} visits only in this database", self.only_here.visits)?;
+        write!(f, "{}", self.only_here)?;
+        writeln!(
+            f,
+            "{} visits only in the other database",
+            self.only_other.visits
+        )?;
+        write!(f, "{}", self.only_other)?;
+        writeln!(f, "{} urls with different titles", self.title_diffs)?;
+        for diff in &self.title_samples {
+            writeln!(
+                f,
+                "{}  {:?} -> {:?}",
+                diff.url,
+                diff.title.as_deref().unwrap_or_default(),
+                diff.other_title.as_deref().unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// One invocation of backup, recorded even when it fails partway.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackupRun {
+    // unix_epoch_ms
+    pub started_at: i64,
+    pub finished_at: i64,
+    /// Number of history files attempted
+    pub sources: usize,
+    pub imported: usize,
+    pub duplicated: usize,
+    pub skipped: usize,
+    /// Visits pruned by retention, see `BackupOptions::retention_days`
+    pub pruned: usize,
+    /// Retention of this run in days, `None` when disabled
+    pub retention_days: Option<u64>,
+    /// Reasons of failed history files, or of the whole run
+    pub errors: Vec<String>,
+    /// Time spent in each history file, only when timing is profiled
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub timings: Vec<BackupTiming>,
+}
+
+/// Time spent in each phase of backing up one history file, collected when
+/// `BackupOptions::profile_timing` is set. Durations are in milliseconds in JSON.
+///
+/// Chromium history files imported by set-based SQL(see
+/// `Database::persist_chromium`) have no separate phases, all their time is in
+/// `visits`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTimings {
+    /// Copying a locked or read-only history file into temp directory
+    #[serde(rename = "copy_ms", serialize_with = "serialize_ms")]
+    #[serde(deserialize_with = "deserialize_ms")]
+    pub copy: Duration,
+    /// Opening history file and reading its visits
+    #[serde(rename = "select_ms", serialize_with = "serialize_ms")]
+    #[serde(deserialize_with = "deserialize_ms")]
+    pub select: Duration,
+    /// Looking up urls of visits, inserting new ones
+    #[serde(rename = "urls_ms", serialize_with = "serialize_ms")]
+    #[serde(deserialize_with = "deserialize_ms")]
+    pub urls: Duration,
+    /// Inserting visits and updating daily summary
+    #[serde(rename = "visits_ms", serialize_with = "serialize_ms")]
+    #[serde(deserialize_with = "deserialize_ms")]
+    pub visits: Duration,
+    /// Committing transactions of batches
+    #[serde(rename = "commit_ms", serialize_with = "serialize_ms")]
+    #[serde(deserialize_with = "deserialize_ms")]
+    pub commit: Duration,
+    /// Wall time of reading and persisting the file, excluding time waiting in
+    /// queue between them
+    #[serde(rename = "total_ms", serialize_with = "serialize_ms")]
+    #[serde(deserialize_with = "deserialize_ms")]
+    pub total: Duration,
+    /// Visits read from the file
+    pub rows: usize,
+}
+
+impl PhaseTimings {
+    /// Sum of all phases, the rest of `total` is spent elsewhere, such as filtering
+    /// visits.
+    pub fn phases(&self) -> Duration {
+        self.copy + self.select + self.urls + self.visits + self.commit
+    }
+
+    /// Visits read per second of `total`.
+    pub fn rows_per_sec(&self) -> f64 {
+        let secs = self.total.as_secs_f64();
+        if secs > 0.0 {
+            self.rows as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// `PhaseTimings` of one history file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackupTiming {
+    pub path: String,
+    #[serde(flatten)]
+    pub phases: PhaseTimings,
+}
+
+fn serialize_ms<S: serde::Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(d.as_secs_f64() * 1000.0)
+}
+
+fn deserialize_ms<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let ms = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(ms / 1000.0).map_err(de::Error::custom)
+}
+
+impl BackupRun {
+    /// Run failed before any history file is backed up.
+    pub fn failed(started_at: i64, finished_at: i64, err: impl Display) -> Self {
+        Self {
+            started_at,
+            finished_at,
+            errors: vec![err.to_string()],
+            ..Default::default()
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Imports from one history file, as an audit trail of the database.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportRecord {
+    pub data_path: String,
+    // unix_epoch_ms, visit time of the newest imported visit
+    pub last_import: Option<i64>,
+    /// Cumulative visits imported from `data_path`
+    pub imported: usize,
+    pub duplicated: usize,
+}
+
+/// Rows of one table salvaged by `recover_db`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecoveredTable {
+    pub table: String,
+    pub recovered: usize,
+    /// Rows before corruption, `None` when they can't be counted
+    pub total: Option<usize>,
+}
+
+impl RecoveredTable {
+    /// Rows lost in corruption, `None` when it's unknown.
+    pub fn lost(&self) -> Option<usize> {
+        self.total.map(|total| total.saturating_sub(self.recovered))
+    }
+}
+
+#[derive(Serialize)]
+pub struct TimeRange {
+    // unix_epoch_ms
+    pub min_time: i64,
+    pub max_time: i64,
 }
 
 #[derive(Debug)]
@@ -37,6 +903,17 @@ pub struct ServerError {
 
 impl From<Error> for ServerError {
     fn from(err: Error) -> Self {
+        Self::from(crate::error::Error::from(err))
+    }
+}
+
+impl From<crate::error::Error> for ServerError {
+    fn from(err: crate::error::Error) -> Self {
+        if err.is_interrupted() {
+            return Self {
+                e: "query timed out".to_string(),
+            };
+        }
         Self { e: err.to_string() }
     }
 }
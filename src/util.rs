@@ -1,23 +1,54 @@
 use anyhow::Context;
-use chrono::{Date, DateTime, FixedOffset, Local, NaiveDate, TimeZone, Utc};
+use chrono::{Duration, Local, LocalResult, NaiveDate, TimeZone, Utc};
+use directories::ProjectDirs;
 use home::home_dir;
 use lazy_static::lazy_static;
 use log::debug;
-use minijinja::State;
+use minijinja::{Environment, State};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env::temp_dir;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use url::{Host, Url};
+
+use crate::default_browser::{default_browser, is_browser_of};
+use crate::i18n::{add_i18n_functions, lang_of, translate};
+use crate::progress::ProgressCollector;
+use crate::types::TrailingSlash;
+
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+// Titles are short, so `whatlang::Info::is_reliable` rejects most of them
+#[cfg(feature = "detect-language")]
+const MIN_LANGUAGE_CONFIDENCE: f64 = 0.3;
+
+// Set by `request_stop`, checked by persist after each committed batch
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Number of persists running, see `Persisting`
+static PERSISTING: AtomicUsize = AtomicUsize::new(0);
 
 lazy_static! {
-    pub static ref DEFAULT_DB_FILE: String = default_location("onehistory.db");
-    pub static ref DEFAULT_CSV_FILE: String = default_location(&format!(
+    /// Path of 1History database used before, `onehistory.db` under home directory.
+    pub static ref LEGACY_DB_FILE: PathBuf = legacy_location("onehistory.db");
+    /// Path of 1History database under platform data directory, such as
+    /// `~/.local/share/onehistory/onehistory.db` on Linux.
+    pub static ref DATA_DB_FILE: PathBuf = default_location("onehistory.db");
+    /// Default path of 1History database, `DATA_DB_FILE` unless only `LEGACY_DB_FILE`
+    /// exists, which can be moved by `migrate_db`.
+    pub static ref DEFAULT_DB_FILE: PathBuf = default_db_file(&LEGACY_DB_FILE, &DATA_DB_FILE);
+    pub static ref DEFAULT_CSV_FILE: PathBuf = default_location(&format!(
         "onehistory-{}.csv",
         unixepoch_as_ymd(tomorrow_midnight() - 1)
     ));
-    static ref DEFAULT_PROFILES: HashMap<&'static str, String> = {
-        let mut m = HashMap::new();
-        if let Some(home) = home_dir() {
+    // Temp copies and files not removed yet, see `remove_temp_copies`
+    static ref TEMP_COPIES: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    // Glob patterns relative to home directory
+    pub(crate) static ref DEFAULT_PROFILES: HashMap<&'static str, &'static str> = {
             let dirs = vec![
                 // Chrome
                 ("chrome-linux", ".config/google-chrome/*/History"),
@@ -39,12 +70,22 @@ lazy_static! {
                     "chromium-flatpak-linux",
                     ".var/app/org.chromium.Chromium/config/chromium/*/History",
                 ),
+                ("chromium-macos", "Library/Application Support/Chromium/*/History"),
+                ("chromium-windows", "AppData/Local/Chromium/User Data/*/History"),
                 // Edge
                 ("edge-linux", ".config/microsoft-edge/*/History"),
                 (
                     "edge-flatpak-linux",
                     ".var/app/com.microsoft.Edge/config/microsoft-edge/*/History",
                 ),
+                (
+                    "edge-macos",
+                    "Library/Application Support/Microsoft Edge/*/History",
+                ),
+                (
+                    "edge-windows",
+                    "AppData/Local/Microsoft/Edge/User Data/*/History",
+                ),
                 // Firefox
                 ("firefox-linux", ".mozilla/firefox/*/places.sqlite"),
                 (
@@ -68,87 +109,710 @@ lazy_static! {
                     "brave-flatpak-linux",
                     ".var/app/com.brave.Browser/config/BraveSoftware/Brave-Browser/*/History",
                 ),
-                ("brave-windows", "AppData/Local/BraveSoftware/Brave-Browser/*/History"),
+                ("brave-windows", "AppData/Local/BraveSoftware/Brave-Browser/User Data/*/History"),
+                // Brave Beta/Nightly
+                (
+                    "brave-beta-macos",
+                    "Library/Application Support/BraveSoftware/Brave-Browser-Beta/*/History",
+                ),
+                ("brave-beta-linux", ".config/BraveSoftware/Brave-Browser-Beta/*/History"),
+                (
+                    "brave-beta-windows",
+                    "AppData/Local/BraveSoftware/Brave-Browser-Beta/User Data/*/History",
+                ),
+                (
+                    "brave-nightly-macos",
+                    "Library/Application Support/BraveSoftware/Brave-Browser-Nightly/*/History",
+                ),
+                ("brave-nightly-linux", ".config/BraveSoftware/Brave-Browser-Nightly/*/History"),
+                (
+                    "brave-nightly-windows",
+                    "AppData/Local/BraveSoftware/Brave-Browser-Nightly/User Data/*/History",
+                ),
+                // Opera and Opera GX keep history in their profile directory directly
+                ("opera-linux", ".config/opera/History"),
+                (
+                    "opera-macos",
+                    "Library/Application Support/com.operasoftware.Opera/History",
+                ),
+                (
+                    "opera-windows",
+                    "AppData/Roaming/Opera Software/Opera Stable/History",
+                ),
+                (
+                    "opera-gx-macos",
+                    "Library/Application Support/com.operasoftware.OperaGX/History",
+                ),
+                (
+                    "opera-gx-windows",
+                    "AppData/Roaming/Opera Software/Opera GX Stable/History",
+                ),
+                // Vivaldi
+                ("vivaldi-linux", ".config/vivaldi/*/History"),
+                ("vivaldi-macos", "Library/Application Support/Vivaldi/*/History"),
+                ("vivaldi-windows", "AppData/Local/Vivaldi/User Data/*/History"),
             ];
 
-            for (browser, dir) in dirs {
-                m.insert(browser, join_path(home.clone(), dir));
-            }
-        }
-        m
+            dirs.into_iter().collect()
     };
 }
 
-pub fn detect_history_files() -> Vec<String> {
+/// Detect history files of all supported browsers in their default locations, files
+/// of the system default browser come first.
+pub fn detect_history_files() -> Vec<PathBuf> {
+    match home_dir() {
+        Some(home) => detect_files(
+            &home,
+            profiles_by_default(default_browser())
+                .into_iter()
+                .map(|(browser, pattern)| {
+                    debug!("detect {}...", browser);
+                    pattern
+                }),
+        ),
+        None => Vec::new(),
+    }
+}
+
+/// History files of the system default browser in its default locations, empty when
+/// default browser can't be detected.
+pub(crate) fn default_browser_files() -> Vec<PathBuf> {
+    match (home_dir(), default_browser()) {
+        (Some(home), Some(default)) => detect_files(
+            &home,
+            DEFAULT_PROFILES
+                .iter()
+                .filter(|(browser, _)| is_browser_of(browser, default))
+                .map(|(_, pattern)| *pattern),
+        ),
+        _ => Vec::new(),
+    }
+}
+
+// `DEFAULT_PROFILES` sorted by name, profiles of `default` browser first
+fn profiles_by_default(default: Option<&str>) -> Vec<(&'static str, &'static str)> {
+    let mut profiles = DEFAULT_PROFILES
+        .iter()
+        .map(|(browser, pattern)| (*browser, *pattern))
+        .collect::<Vec<_>>();
+    profiles.sort_by_key(|(browser, _)| {
+        let is_default = default.is_some_and(|default| is_browser_of(browser, default));
+        (!is_default, *browser)
+    });
+    profiles
+}
+
+/// Return files matched by glob `patterns` relative to `base`. Files are deduplicated by
+/// their canonical path(and inode on unix, to catch hard links), directories and empty
+/// files are skipped.
+pub(crate) fn detect_files<'a>(
+    base: &Path,
+    patterns: impl Iterator<Item = &'a str>,
+) -> Vec<PathBuf> {
     let mut files = Vec::new();
-    for (browser, pattern) in DEFAULT_PROFILES.iter() {
-        debug!("detect {}...", browser);
-        if let Ok(entries) = glob::glob(pattern) {
-            for e in entries {
-                match e {
-                    Ok(file) => files.push(file.into_os_string().into_string().unwrap()),
-                    Err(e) => debug!("glob err:{:?}", e),
+    let mut seen = HashSet::new();
+    for pattern in patterns {
+        for file in expand_glob(base, pattern) {
+            let (canonical, meta) = match fs::canonicalize(&file)
+                .and_then(|canonical| fs::metadata(&canonical).map(|meta| (canonical, meta)))
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("skip {:?}, err:{:?}", file, e);
+                    continue;
                 }
+            };
+            if meta.is_dir() || meta.len() == 0 {
+                debug!("skip {:?}, directory or empty file", file);
+                continue;
+            }
+            if !seen.insert(file_key(canonical, &meta)) {
+                debug!("skip {:?}, duplicated", file);
+                continue;
             }
+            files.push(file);
         }
     }
 
     files
 }
 
-fn join_path(mut base: PathBuf, rest: &str) -> String {
+/// Expand `path` passed as history file when it's a glob pattern such as
+/// `~/.config/chromium/*/History`, for shells not expanding it(cmd, PowerShell,
+/// systemd units). A leading `~` is taken as home directory. `None` when `path` is
+/// neither a pattern nor starts with `~`, or exists as it is, such as
+/// `Profile [work]/History`. Matched files are deduplicated like `detect_files`.
+pub(crate) fn expand_history_pattern(path: &Path) -> Option<Vec<PathBuf>> {
+    if path.exists() {
+        return None;
+    }
+    let mut components = path.components().peekable();
+    let mut base = PathBuf::new();
+    let mut tilde = false;
+    if components.peek().and_then(|c| c.as_os_str().to_str()) == Some("~") {
+        components.next();
+        base = home_dir()?;
+        tilde = true;
+    }
+    // Components before the first one with glob metacharacters are literal
+    let mut pattern = Vec::new();
+    for c in components {
+        match c.as_os_str().to_str() {
+            Some(s) if !pattern.is_empty() || glob::Pattern::escape(s) != s => pattern.push(s),
+            _ if pattern.is_empty() => base.push(c),
+            // Non-UTF-8 components after a pattern can't be matched
+            _ => return Some(Vec::new()),
+        }
+    }
+    if pattern.is_empty() {
+        return tilde.then(|| vec![base]);
+    }
+    if base.as_os_str().is_empty() {
+        base.push(".");
+    }
+    debug!("expand {} in {}", pattern.join("/"), base.display());
+    Some(detect_files(
+        &base,
+        std::iter::once(pattern.join("/").as_str()),
+    ))
+}
+
+/// Expand glob `pattern` relative to `base`. Unlike `glob::glob`, `base` can be any path,
+/// including non-UTF-8 ones, since only components of `pattern` are matched as globs.
+fn expand_glob(base: &Path, pattern: &str) -> Vec<PathBuf> {
+    let mut paths = vec![base.to_path_buf()];
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        let matcher = match glob::Pattern::new(component) {
+            Ok(m) => m,
+            Err(e) => {
+                debug!("glob err:{:?}", e);
+                return Vec::new();
+            }
+        };
+        if glob::Pattern::escape(component) == component {
+            paths.iter_mut().for_each(|p| p.push(component));
+            continue;
+        }
+        paths = paths
+            .into_iter()
+            .flat_map(|dir| {
+                let mut matched = fs::read_dir(dir)
+                    .map(|entries| {
+                        entries
+                            .filter_map(|e| e.ok())
+                            .filter(|e| matcher.matches(&e.file_name().to_string_lossy()))
+                            .map(|e| e.path())
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                matched.sort();
+                matched
+            })
+            .collect();
+    }
+
+    paths
+}
+
+// Hard links are different paths of the same inode, so files are identified by inode on unix.
+#[cfg(unix)]
+fn file_key(_canonical: PathBuf, meta: &fs::Metadata) -> (PathBuf, u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (PathBuf::new(), meta.dev(), meta.ino())
+}
+
+#[cfg(not(unix))]
+fn file_key(canonical: PathBuf, _meta: &fs::Metadata) -> (PathBuf, u64, u64) {
+    (canonical, 0, 0)
+}
+
+/// Name of the profile a history file belongs to, such as `Work (alice@corp.com)`
+/// read from `Preferences` of Chromium based browsers, or name in `profiles.ini` of
+/// Firefox. Fallback to its parent directory when neither is readable.
+pub fn profile_name(history_file: &Path) -> String {
+    let dir = match history_file.parent() {
+        Some(dir) => dir,
+        None => return String::new(),
+    };
+    chromium_profile_name(dir)
+        .or_else(|| firefox_profile_name(dir))
+        .unwrap_or_else(|| {
+            dir.file_name()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default()
+        })
+}
+
+fn chromium_profile_name(profile_dir: &Path) -> Option<String> {
+    let prefs: serde_json::Value =
+        serde_json::from_slice(&fs::read(profile_dir.join("Preferences")).ok()?).ok()?;
+    let name = prefs.pointer("/profile/name")?.as_str()?.trim().to_string();
+    if name.is_empty() {
+        return None;
+    }
+    match prefs
+        .pointer("/account_info/0/email")
+        .and_then(|email| email.as_str())
+        .filter(|email| !email.is_empty())
+    {
+        Some(email) => Some(format!("{name} ({email})")),
+        None => Some(name),
+    }
+}
+
+fn firefox_profile_name(profile_dir: &Path) -> Option<String> {
+    // profiles.ini is next to profile directories on Linux, and one level up on
+    // macOS/Windows, where they are under `Profiles`
+    for base in profile_dir.ancestors().skip(1).take(2) {
+        let ini = match fs::read_to_string(base.join("profiles.ini")) {
+            Ok(ini) => ini,
+            Err(_) => continue,
+        };
+        let mut name = None;
+        let mut path = None;
+        let mut relative = true;
+        // A trailing section header to check the last section
+        for line in ini.lines().map(str::trim).chain(std::iter::once("[]")) {
+            if line.starts_with('[') {
+                if let (Some(name), Some(path)) = (name.take(), path.take()) {
+                    let dir = if relative {
+                        base.join(path)
+                    } else {
+                        PathBuf::from(path)
+                    };
+                    if dir == profile_dir {
+                        return Some(name);
+                    }
+                }
+                relative = true;
+            } else if let Some((key, value)) = line.split_once('=') {
+                match key.trim() {
+                    "Name" => name = Some(value.trim().to_string()),
+                    "Path" => path = Some(value.trim().to_string()),
+                    "IsRelative" => relative = value.trim() != "0",
+                    _ => {}
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Copy of a SQLite file in temp directory, together with its `-wal` file if any.
+/// Copied files are removed when dropped.
+pub(crate) struct TempCopy {
+    path: PathBuf,
+}
+
+impl TempCopy {
+    /// Copy `src` in chunks, progress is reported to `collector` in bytes.
+    pub(crate) fn new(src: &Path, collector: &impl ProgressCollector) -> io::Result<TempCopy> {
+        static SEQ: AtomicUsize = AtomicUsize::new(0);
+        let path = temp_dir().join(format!(
+            "onehistory-{}-{}.sqlite",
+            process::id(),
+            SEQ.fetch_add(1, Ordering::SeqCst)
+        ));
+        let copy = TempCopy { path };
+        let files = [
+            (src.to_path_buf(), copy.path.clone()),
+            (wal_path(src), wal_path(&copy.path)),
+        ]
+        .into_iter()
+        .filter(|(from, _)| from.exists())
+        .collect::<Vec<_>>();
+        let total = files.iter().try_fold(0, |acc, (from, _)| {
+            fs::metadata(from).map(|meta| acc + meta.len())
+        })?;
+
+        collector.start(total);
+        for (from, to) in files {
+            TEMP_COPIES.lock().unwrap().insert(to.clone());
+            copy_file(&from, &to, collector)?;
+        }
+        collector.finish();
+
+        Ok(copy)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempCopy {
+    fn drop(&mut self) {
+        let mut copies = TEMP_COPIES.lock().unwrap();
+        for path in [self.path.clone(), wal_path(&self.path)] {
+            if copies.remove(&path) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn wal_path(path: &Path) -> PathBuf {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    PathBuf::from(wal)
+}
+
+// Attempts of opening a locked history file to copy, and delay before the first retry,
+// doubled after each one
+const COPY_OPEN_ATTEMPTS: u32 = 5;
+const COPY_OPEN_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Call `f` up to `attempts` times until it succeeds, `sleep` between attempts starts
+/// with `delay` and doubles each time. Only errors of files in use are retried, see
+/// `is_in_use`.
+pub(crate) fn retry_with_backoff<T>(
+    attempts: u32,
+    delay: std::time::Duration,
+    mut sleep: impl FnMut(std::time::Duration),
+    mut f: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= attempts || !is_in_use(&e) => return Err(e),
+            Err(e) => {
+                debug!("attempt {attempt} failed, retry in {delay:?}, err:{e}");
+                sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Whether `e` is caused by others having the file open, which may go away by
+// waiting, ERROR_SHARING_VIOLATION(32) or ERROR_LOCK_VIOLATION(33) on Windows
+fn is_in_use(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+        || (cfg!(windows) && matches!(e.raw_os_error(), Some(32 | 33)))
+}
+
+// Open `path` to copy, retried when it's in use, such as by a sharing violation of
+// running Chrome on Windows
+fn open_locked(path: &Path) -> io::Result<File> {
+    retry_with_backoff(COPY_OPEN_ATTEMPTS, COPY_OPEN_DELAY, thread::sleep, || {
+        File::open(path)
+    })
+    .map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => e,
+        kind => io::Error::new(
+            kind,
+            format!(
+                "{e}, close the browser or grant access to {}",
+                path.display()
+            ),
+        ),
+    })
+}
+
+fn copy_file(from: &Path, to: &Path, collector: &impl ProgressCollector) -> io::Result<()> {
+    let mut reader = open_locked(from)?;
+    let mut writer = File::create(to)?;
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n])?;
+        collector.inc(n as u64);
+    }
+    // Make sure SQLite sees the whole copy
+    writer.sync_all()
+}
+
+/// Track temp file at `path`, so it's removed by `remove_temp_copies` when process
+/// is interrupted.
+pub(crate) fn track_temp_file(path: &Path) {
+    TEMP_COPIES.lock().unwrap().insert(path.to_path_buf());
+}
+
+/// Remove temp file at `path` tracked by `track_temp_file`.
+pub(crate) fn remove_temp_file(path: &Path) {
+    if TEMP_COPIES.lock().unwrap().remove(path) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Remove temp copies of locked history files made by backup, and CSV generated for
+/// downloads by serve, intended to be called when process is interrupted, such as
+/// by Ctrl-C.
+pub fn remove_temp_copies() {
+    for path in TEMP_COPIES.lock().unwrap().drain() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Ask running persist to stop after committing its in-flight batch, so the
+/// interrupted backup resumes from it next time, intended to be called when process
+/// is interrupted, such as by Ctrl-C.
+///
+/// Return `false` when nothing is being persisted, or stop is already requested,
+/// then caller should exit directly.
+pub fn request_stop() -> bool {
+    let persisting = PERSISTING.load(Ordering::SeqCst) > 0;
+    !STOP_REQUESTED.swap(true, Ordering::SeqCst) && persisting
+}
+
+/// Whether stop is requested by `request_stop`, the request is consumed.
+pub(crate) fn take_stop_request() -> bool {
+    STOP_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Marks a persist running while alive, see `request_stop`.
+pub(crate) struct Persisting;
+
+impl Persisting {
+    pub(crate) fn new() -> Self {
+        PERSISTING.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for Persisting {
+    fn drop(&mut self) {
+        PERSISTING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn join_path(mut base: PathBuf, rest: &str) -> PathBuf {
     base.push(rest);
-    base.into_os_string().into_string().unwrap()
+    base
 }
 
-fn default_location(filename: &str) -> String {
+fn legacy_location(filename: &str) -> PathBuf {
     let base = home_dir().unwrap_or_else(temp_dir);
     join_path(base, filename)
 }
 
+// Under data directory of this platform, `~/.local/share/onehistory` on Linux,
+// `~/Library/Application Support/onehistory` on macOS and `%APPDATA%` on Windows.
+fn default_location(filename: &str) -> PathBuf {
+    match ProjectDirs::from("", "", "onehistory") {
+        Some(dirs) => dirs.data_dir().join(filename),
+        None => legacy_location(filename),
+    }
+}
+
+/// Cache directory of this platform, `~/.cache/onehistory` on Linux.
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "onehistory").map(|dirs| dirs.cache_dir().to_path_buf())
+}
+
+// Keep using database at `legacy` until it's migrated to `data`
+fn default_db_file(legacy: &Path, data: &Path) -> PathBuf {
+    if legacy.exists() && !data.exists() {
+        legacy.to_path_buf()
+    } else {
+        data.to_path_buf()
+    }
+}
+
+/// Unix epoch milliseconds of the start of `date` in `tz`.
+///
+/// When midnight is ambiguous, the earliest instant is used. When it doesn't exist,
+/// such as DST starting at midnight, the first valid local time after it is used.
+pub(crate) fn midnight_in<Tz: TimeZone>(tz: &Tz, date: NaiveDate) -> i64 {
+    let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is valid");
+    // DST gaps are far shorter than one day
+    let candidates =
+        (0..24 * 60).map_while(|minutes| midnight.checked_add_signed(Duration::minutes(minutes)));
+    for local in candidates {
+        if let Some(dt) = tz.from_local_datetime(&local).earliest() {
+            return dt.timestamp_millis();
+        }
+    }
+    Utc.from_utc_datetime(&midnight).timestamp_millis()
+}
+
 pub fn tomorrow_midnight() -> i64 {
-    let now = Local::today();
-    let dt: DateTime<Local> = now.and_hms(0, 0, 0);
-    dt.timestamp_millis() + 24 * 3_600_000
+    let today = Local::now().naive_local().date();
+    midnight_in(&Local, today.succ_opt().unwrap_or(today))
 }
 
 pub fn ymd_midnight(ymd: &str) -> anyhow::Result<i64> {
     let nd = NaiveDate::parse_from_str(ymd, "%Y-%m-%d").context("not %Y-%m-%d date")?;
+    Ok(midnight_in(&Local, nd))
+}
 
-    lazy_static! {
-        static ref LOCAL_OFFSET: FixedOffset = *Local::now().offset();
+/// Local days wholly within a time range, see `whole_days`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct WholeDays {
+    pub first: NaiveDate,
+    pub last: NaiveDate,
+    /// Unix epoch milliseconds of the start of `first`
+    pub start: i64,
+    /// Unix epoch milliseconds of the start of the day after `last`
+    pub end: i64,
+}
+
+/// Local days wholly within `[start, end]`(unix epoch milliseconds), `None` when no
+/// day is covered wholly.
+pub(crate) fn whole_days(start: i64, end: i64) -> Option<WholeDays> {
+    let date_of = |ts| match Local.timestamp_millis_opt(ts) {
+        LocalResult::Single(dt) => Some(dt.naive_local().date()),
+        _ => None,
+    };
+    let mut first = date_of(start)?;
+    if midnight_in(&Local, first) < start {
+        first = first.succ_opt()?;
+    }
+    let mut last = date_of(end)?;
+    if midnight_in(&Local, last.succ_opt()?) - 1 > end {
+        last = last.pred_opt()?;
+    }
+    if first > last {
+        return None;
     }
+    Some(WholeDays {
+        first,
+        last,
+        start: midnight_in(&Local, first),
+        end: midnight_in(&Local, last.succ_opt()?),
+    })
+}
 
-    let dc: Date<Local> = Date::from_utc(nd, *LOCAL_OFFSET);
-    Ok(dc.and_hms(0, 0, 0).timestamp_millis())
+// Format unix epoch milliseconds `ts` in local time, `invalid(<ts>)` when it's out of range.
+fn format_unixepoch(ts: i64, fmt: &str) -> String {
+    match Local.timestamp_millis_opt(ts) {
+        LocalResult::Single(dt) => dt.format(fmt).to_string(),
+        _ => format!("invalid({ts})"),
+    }
 }
 
 pub fn unixepoch_as_ymd(ts: i64) -> String {
-    let utc = Utc.timestamp(ts / 1000, 0);
-    let dt: DateTime<Local> = DateTime::from(utc);
-    dt.format("%Y-%m-%d").to_string()
+    format_unixepoch(ts, "%Y-%m-%d")
 }
 
 pub fn unixepoch_as_hms(ts: i64) -> String {
-    let utc = Utc.timestamp(ts / 1000, 0);
-    let dt: DateTime<Local> = DateTime::from(utc);
-    dt.format("%H:%M:%S").to_string()
+    format_unixepoch(ts, "%H:%M:%S")
 }
 
 pub fn unixepoch_as_ymdhms(ts: i64) -> String {
-    let utc = Utc.timestamp(ts / 1000, 0);
-    let dt: DateTime<Local> = DateTime::from(utc);
-    dt.format("%Y-%m-%d %H:%M:%S").to_string()
+    format_unixepoch(ts, "%Y-%m-%d %H:%M:%S")
+}
+
+/// Format milliseconds as hours and minutes, such as `4h 20m`.
+pub fn format_duration(ms: i64) -> String {
+    let minutes = ms / 60_000;
+    match (minutes / 60, minutes % 60) {
+        (0, m) => format!("{m}m"),
+        (h, 0) => format!("{h}h"),
+        (h, m) => format!("{h}h {m}m"),
+    }
 }
 
+#[allow(clippy::result_large_err)] // signature required by minijinja
 pub fn minijinja_format_as_ymd(_state: &State, ts: i64) -> Result<String, minijinja::Error> {
     Ok(unixepoch_as_ymd(ts))
 }
 
+#[allow(clippy::result_large_err)]
 pub fn minijinja_format_as_hms(_state: &State, ts: i64) -> Result<String, minijinja::Error> {
     Ok(unixepoch_as_hms(ts))
 }
 
+// Only this format is localized, ymd is also used in URLs of pages
+#[allow(clippy::result_large_err)]
+pub fn minijinja_format_as_ymdhms(state: &State, ts: i64) -> Result<String, minijinja::Error> {
+    Ok(format_unixepoch(
+        ts,
+        &translate(&lang_of(state), "date.ymdhms"),
+    ))
+}
+
+/// Open `url` in default browser, without waiting for it.
+pub fn open_url(url: &str) -> io::Result<()> {
+    let program = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(windows) {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+    process::Command::new(program)
+        .arg(url)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+/// Add time elapsed since `begin` to `phase`, nothing when it's `None`.
+pub(crate) fn add_elapsed(phase: Option<&mut std::time::Duration>, begin: std::time::Instant) {
+    if let Some(phase) = phase {
+        *phase += begin.elapsed();
+    }
+}
+
+/// Terms of search `keyword`, separated by whitespace, text in double quotes is one
+/// term with its spaces kept, such as `rust "async trait"`. An unclosed quote runs
+/// to the end.
+pub(crate) fn split_keyword(keyword: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut quoted = false;
+    for c in keyword.chars() {
+        match c {
+            '"' => {
+                if !term.is_empty() {
+                    terms.push(std::mem::take(&mut term));
+                }
+                quoted = !quoted;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !term.is_empty() {
+                    terms.push(std::mem::take(&mut term));
+                }
+            }
+            c => term.push(c),
+        }
+    }
+    if !term.is_empty() {
+        terms.push(term);
+    }
+    terms
+}
+
+/// How long `ts` is before `now`, both unix_epoch_ms, such as "2 hours ago" in
+/// `lang`. Timestamps within a minute, or later than `now`, are "just now".
+pub fn format_relative(now: i64, ts: i64, lang: &str) -> String {
+    let secs = now.saturating_sub(ts) / 1000;
+    let (n, unit) = if secs < 60 {
+        return translate(lang, "relative.just_now");
+    } else if secs < 3600 {
+        (secs / 60, "minute")
+    } else if secs < 86400 {
+        (secs / 3600, "hour")
+    } else {
+        (secs / 86400, "day")
+    };
+    let key = if n == 1 {
+        format!("relative.{unit}")
+    } else {
+        format!("relative.{unit}s")
+    };
+    translate(lang, &key).replace("{n}", &n.to_string())
+}
+
+#[allow(clippy::result_large_err)]
+pub fn minijinja_format_relative(state: &State, ts: i64) -> Result<String, minijinja::Error> {
+    Ok(format_relative(
+        Local::now().timestamp_millis(),
+        ts,
+        &lang_of(state),
+    ))
+}
+
+#[allow(clippy::result_large_err)]
 pub fn minijinja_format_title(
     _state: &State,
     title: String,
@@ -161,11 +825,173 @@ pub fn minijinja_format_title(
     }
 }
 
+/// Register functions shared by all templates.
+pub fn add_template_functions(env: &mut Environment) {
+    env.add_function("format_as_ymd", minijinja_format_as_ymd);
+    env.add_function("format_as_hms", minijinja_format_as_hms);
+    env.add_function("format_as_ymdhms", minijinja_format_as_ymdhms);
+    env.add_function("format_relative", minijinja_format_relative);
+    env.add_function("format_title", minijinja_format_title);
+    add_i18n_functions(env);
+}
+
+// Schemes of pages shown by browsers themselves, rather than web browsing
+const BROWSER_INTERNAL_SCHEMES: [&str; 13] = [
+    "about:",
+    "chrome:",
+    "chrome-extension:",
+    "chrome-search:",
+    "chrome-untrusted:",
+    "devtools:",
+    "edge:",
+    "brave:",
+    "opera:",
+    "vivaldi:",
+    "moz-extension:",
+    "resource:",
+    "view-source:",
+];
+const LOCAL_FILE_SCHEME: &str = "file:";
+
+/// Synthetic domain of `file://` URLs.
+pub const LOCAL_FILE_DOMAIN: &str = "[local file]";
+/// Synthetic domain of browser internal pages, such as `about:blank` or `chrome://settings`.
+pub const BROWSER_INTERNAL_DOMAIN: &str = "[browser internal]";
+
+/// Synthetic domain `url` is grouped under when it's a local file or browser internal
+/// page, `None` for others.
+pub fn internal_domain(url: &str) -> Option<&'static str> {
+    let has_scheme = |scheme: &str| {
+        url.get(..scheme.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(scheme))
+    };
+    if has_scheme(LOCAL_FILE_SCHEME) {
+        Some(LOCAL_FILE_DOMAIN)
+    } else if BROWSER_INTERNAL_SCHEMES.iter().any(|s| has_scheme(s)) {
+        Some(BROWSER_INTERNAL_DOMAIN)
+    } else {
+        None
+    }
+}
+
+/// Whether `url` is a local file or browser internal page, see `internal_domain`.
+pub fn is_internal_url(url: &str) -> bool {
+    internal_domain(url).is_some()
+}
+
+// SQL condition matching internal urls in `column`, LIKE is case-insensitive as
+// `internal_domain`
+pub(crate) fn internal_url_sql(column: &str) -> String {
+    let conditions = std::iter::once(LOCAL_FILE_SCHEME)
+        .chain(BROWSER_INTERNAL_SCHEMES)
+        .map(|scheme| format!("{column} LIKE '{scheme}%'"))
+        .collect::<Vec<_>>();
+    format!("({})", conditions.join(" OR "))
+}
+
+/// Domains whose fragments are kept by default when stripping fragments, where they
+/// identify content rather than routes of single-page apps.
+pub const DEFAULT_KEEP_FRAGMENT_DOMAINS: &str = "groups.google.com";
+
+/// `url` without its fragment, such as `#/inbox` of single-page apps, unless its host
+/// is one of `keep_domains` or their subdomains.
+pub(crate) fn strip_fragment<'a>(url: &'a str, keep_domains: &[String]) -> &'a str {
+    let Some((base, _)) = url.split_once('#') else {
+        return url;
+    };
+    let keep = Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+        .is_some_and(|host| {
+            keep_domains.iter().any(|d| {
+                let d = d.trim().to_lowercase();
+                !d.is_empty() && (host == d || host.ends_with(&format!(".{d}")))
+            })
+        });
+    if keep {
+        url
+    } else {
+        base
+    }
+}
+
+/// `url` with trailing slash of its path stripped or added by `mode`, so urls only
+/// differing by it are one url. Root paths always keep their slash, and urls without
+/// a host, such as `data:`, are returned as they are. Other parts of `url` are left
+/// untouched, rather than serialized by `Url`.
+pub fn normalize_trailing_slash(url: &str, mode: TrailingSlash) -> String {
+    let path = match Url::parse(url) {
+        Ok(u) if u.has_host() && !u.cannot_be_a_base() => u.path().to_string(),
+        _ => return url.to_string(),
+    };
+    if path == "/" {
+        return url.to_string();
+    }
+    // Path ends before query or fragment, which come after `://host`
+    let host_start = url.find("://").map_or(0, |i| i + 3);
+    let path_end = url[host_start..]
+        .find(['?', '#'])
+        .map_or(url.len(), |i| host_start + i);
+    let (base, rest) = url.split_at(path_end);
+    match mode {
+        TrailingSlash::Strip if base.ends_with('/') => {
+            format!("{}{rest}", &base[..base.len() - 1])
+        }
+        TrailingSlash::Keep if !base.ends_with('/') => format!("{base}/{rest}"),
+        _ => url.to_string(),
+    }
+}
+
+/// Canonical form of `url`, so its variants are grouped in statistics: `http` is
+/// upgraded to `https`, `www.` is stripped from host, and trailing slash of path is
+/// stripped like `TrailingSlash::Strip`. Urls without a host, such as `about:`, are
+/// returned as they are.
+pub fn canonical_url(url: &str) -> String {
+    let mut parsed = match Url::parse(url) {
+        Ok(u) if u.has_host() && !u.cannot_be_a_base() => u,
+        _ => return url.to_string(),
+    };
+    if parsed.scheme() == "http" {
+        // Always allowed between special schemes
+        let _ = parsed.set_scheme("https");
+    }
+    if let Some(host) = parsed.host_str().map(canonical_domain) {
+        if Some(host) != parsed.host_str() {
+            let host = host.to_string();
+            let _ = parsed.set_host(Some(&host));
+        }
+    }
+    normalize_trailing_slash(parsed.as_str(), TrailingSlash::Strip)
+}
+
+/// Domain without leading `www.`, unless what's left isn't a domain, such as `www.com`.
+pub fn canonical_domain(domain: &str) -> &str {
+    domain
+        .strip_prefix("www.")
+        .filter(|d| d.contains('.'))
+        .unwrap_or(domain)
+}
+
+/// Merge counts of urls with the same `canonical_url`, ordered by count desc.
+pub(crate) fn canonical_counts(url_counts: Vec<(String, i64)>) -> Vec<(String, i64)> {
+    let mut merged = HashMap::new();
+    for (url, cnt) in url_counts {
+        *merged.entry(canonical_url(&url)).or_insert(0) += cnt;
+    }
+    let mut counts = merged.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
 pub fn domain_from(url: String) -> String {
     lazy_static! {
         static ref RE: Regex = Regex::new("://(.+?)/").unwrap();
     }
 
+    if let Some(domain) = internal_domain(&url) {
+        return domain.to_string();
+    }
+
     if let Some(cap) = RE.captures_iter(&url).next() {
         return cap[1].to_string();
     }
@@ -173,6 +999,53 @@ pub fn domain_from(url: String) -> String {
     url
 }
 
+// Top-level domain of `url`'s host, such as `.com`, `None` for URLs without a
+// domain host, such as `data:`, `file:` or IP addresses.
+pub fn tld_from(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    match url.host()? {
+        Host::Domain(domain) => domain
+            .trim_end_matches('.')
+            .rsplit_once('.')
+            .map(|(_, tld)| format!(".{}", tld.to_lowercase())),
+        Host::Ipv4(_) | Host::Ipv6(_) => None,
+    }
+}
+
+/// Language of `title` as ISO 639-3 code, such as `eng` or `cmn`, detected by
+/// statistics of its characters. `None` when detection isn't confident enough,
+/// which is common for short titles, brand names, or titles mixing languages.
+#[cfg(feature = "detect-language")]
+pub(crate) fn detect_language(title: &str) -> Option<&'static str> {
+    whatlang::detect(title)
+        .filter(|info| info.confidence() >= MIN_LANGUAGE_CONFIDENCE)
+        .map(|info| info.lang().code())
+}
+
+/// One record of `--porcelain` output, `fields` are separated by tab. Backslash, tab
+/// and newline in fields are escaped as `\\`, `\t` and `\n`, so each record is
+/// always one line.
+pub fn porcelain_line<S: AsRef<str>>(fields: &[S]) -> String {
+    fields
+        .iter()
+        .map(|f| {
+            f.as_ref()
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// Current time in unix epoch milliseconds, 0 when system clock is before 1970.
+pub fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Return `(start, end)` in unix epoch milliseconds covering all histories.
 pub fn full_timerange() -> (i64, i64) {
     let start = 0;
     let end = tomorrow_midnight();
@@ -183,13 +1056,34 @@ pub fn full_timerange() -> (i64, i64) {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_default_db_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("onehistory.db");
+        let data = dir.path().join("data").join("onehistory.db");
+        assert_eq!(default_db_file(&legacy, &data), data);
+
+        fs::write(&legacy, "").unwrap();
+        assert_eq!(default_db_file(&legacy, &data), legacy);
+
+        fs::create_dir_all(data.parent().unwrap()).unwrap();
+        fs::write(&data, "").unwrap();
+        assert_eq!(default_db_file(&legacy, &data), data);
+    }
+
     #[test]
     fn test_join_path() {
         let mut base = PathBuf::new();
         base.push("/tmp");
 
-        assert_eq!("/tmp/abc.txt", join_path(base.clone(), "abc.txt"));
-        assert_eq!("/tmp/history.txt", join_path(base.clone(), "history.txt"));
+        assert_eq!(
+            PathBuf::from("/tmp/abc.txt"),
+            join_path(base.clone(), "abc.txt")
+        );
+        assert_eq!(
+            PathBuf::from("/tmp/history.txt"),
+            join_path(base.clone(), "history.txt")
+        );
     }
 
     #[test]
@@ -198,6 +1092,11 @@ mod tests {
             ("https://emacs-china.org/", "emacs-china.org"),
             ("https://github.com/notifications", "github.com"),
             ("data:text/html", "data:text/html"),
+            ("file:///Users/x", LOCAL_FILE_DOMAIN),
+            ("about:blank", BROWSER_INTERNAL_DOMAIN),
+            ("chrome://settings", BROWSER_INTERNAL_DOMAIN),
+            ("Chrome://settings/passwords", BROWSER_INTERNAL_DOMAIN),
+            ("chromium.org/about:blank", "chromium.org/about:blank"),
         ];
 
         for (url, expected) in cases {
@@ -205,6 +1104,492 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_strip_fragment() {
+        let keep = vec!["groups.google.com".to_string()];
+        let cases = [
+            (
+                "https://mail.google.com/mail/u/0/#inbox",
+                "https://mail.google.com/mail/u/0/",
+            ),
+            (
+                "https://app.example.com/#/settings?a=1",
+                "https://app.example.com/",
+            ),
+            ("https://github.com/", "https://github.com/"),
+            (
+                "https://groups.google.com/g/rust#!topic/123",
+                "https://groups.google.com/g/rust#!topic/123",
+            ),
+            (
+                "https://a.groups.google.com/#x",
+                "https://a.groups.google.com/#x",
+            ),
+            (
+                "https://notgroups.google.com/#x",
+                "https://notgroups.google.com/",
+            ),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(strip_fragment(url, &keep), expected, "{url}");
+        }
+        assert_eq!(
+            strip_fragment("https://groups.google.com/#x", &[]),
+            "https://groups.google.com/"
+        );
+    }
+
+    #[test]
+    fn test_normalize_trailing_slash() {
+        let cases = [
+            (
+                "https://example.com/page/",
+                "https://example.com/page",
+                "https://example.com/page/",
+            ),
+            (
+                "https://example.com/page",
+                "https://example.com/page",
+                "https://example.com/page/",
+            ),
+            (
+                "https://example.com/a/b/?q=1#top",
+                "https://example.com/a/b?q=1#top",
+                "https://example.com/a/b/?q=1#top",
+            ),
+            (
+                "https://example.com/search?q=a/",
+                "https://example.com/search?q=a/",
+                "https://example.com/search/?q=a/",
+            ),
+            // Root paths keep their slash
+            (
+                "https://example.com/",
+                "https://example.com/",
+                "https://example.com/",
+            ),
+            (
+                "https://example.com",
+                "https://example.com",
+                "https://example.com",
+            ),
+            (
+                "https://example.com?q=1",
+                "https://example.com?q=1",
+                "https://example.com?q=1",
+            ),
+            (
+                "data:text/html,a/",
+                "data:text/html,a/",
+                "data:text/html,a/",
+            ),
+            ("about:blank", "about:blank", "about:blank"),
+        ];
+        for (url, stripped, kept) in cases {
+            assert_eq!(
+                normalize_trailing_slash(url, TrailingSlash::Strip),
+                stripped,
+                "{url}"
+            );
+            assert_eq!(
+                normalize_trailing_slash(url, TrailingSlash::Keep),
+                kept,
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_canonical_url() {
+        let cases = [
+            ("http://example.com/post", "https://example.com/post"),
+            ("https://www.example.com/post/", "https://example.com/post"),
+            ("http://www.example.com/", "https://example.com/"),
+            ("http://WWW.Example.com", "https://example.com/"),
+            // Only the leading www is stripped, and only from domains
+            ("https://www.com/", "https://www.com/"),
+            (
+                "https://shop.www.example.com/",
+                "https://shop.www.example.com/",
+            ),
+            ("https://wwwexample.com/", "https://wwwexample.com/"),
+            // Explicit ports are kept, default ones are dropped
+            ("http://example.com:8080/a/", "https://example.com:8080/a"),
+            ("http://example.com:443/", "https://example.com/"),
+            (
+                "https://example.com/a/?q=1#top",
+                "https://example.com/a?q=1#top",
+            ),
+            // Other schemes are kept
+            ("ftp://www.example.com/pub/", "ftp://example.com/pub"),
+            ("file:///tmp/a.html", "file:///tmp/a.html"),
+            ("about:blank", "about:blank"),
+            ("not a url", "not a url"),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(canonical_url(url), expected, "{url}");
+        }
+
+        assert_eq!(canonical_domain("www.example.com"), "example.com");
+        assert_eq!(canonical_domain("www.com"), "www.com");
+        assert_eq!(canonical_domain("example.com"), "example.com");
+        assert_eq!(
+            canonical_counts(vec![
+                ("https://example.com/post".to_string(), 1),
+                ("http://www.example.com/post/".to_string(), 2),
+                ("https://github.com/".to_string(), 2),
+            ]),
+            vec![
+                ("https://example.com/post".to_string(), 3),
+                ("https://github.com/".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_internal_url_sql() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let is_internal = |url: &str| -> bool {
+            conn.query_row(
+                &format!("SELECT {}", internal_url_sql("?1")),
+                [url],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        for url in [
+            "file:///Users/x",
+            "about:blank",
+            "chrome://settings",
+            "https://github.com",
+            "data:text/html",
+        ] {
+            assert_eq!(is_internal(url), is_internal_url(url), "{url}");
+        }
+    }
+
+    #[test]
+    fn test_format_duration() {
+        for (ms, expected) in [
+            (0, "0m"),
+            (59_999, "0m"),
+            (20 * 60_000, "20m"),
+            (2 * 3_600_000, "2h"),
+            (4 * 3_600_000 + 20 * 60_000 + 59_999, "4h 20m"),
+        ] {
+            assert_eq!(format_duration(ms), expected, "{ms}");
+        }
+    }
+
+    #[test]
+    fn test_porcelain_line() {
+        assert_eq!(porcelain_line(&["ok", "1", "-"]), "ok\t1\t-");
+        assert_eq!(
+            porcelain_line(&["a\tb", "c\nd", "C:\\History"]),
+            "a\\tb\tc\\nd\tC:\\\\History"
+        );
+        assert_eq!(porcelain_line::<&str>(&[]), "");
+    }
+
+    #[test]
+    fn test_tld_from() {
+        let cases = vec![
+            ("https://emacs-china.org/", Some(".org")),
+            ("https://github.com/notifications", Some(".com")),
+            ("https://www.bbc.CO.UK./news", Some(".uk")),
+            ("http://127.0.0.1:9960/", None),
+            ("http://localhost:8080/", None),
+            ("data:text/html", None),
+            ("file:///tmp/a.html", None),
+            ("not a url", None),
+        ];
+
+        for (url, expected) in cases {
+            assert_eq!(tld_from(url).as_deref(), expected, "{}", url);
+        }
+    }
+
+    #[cfg(feature = "detect-language")]
+    #[test]
+    fn test_detect_language() {
+        let cases = vec![
+            (
+                "The Rust Programming Language - Learn how to write reliable software",
+                Some("eng"),
+            ),
+            (
+                "Rust 程序设计语言 - 学习如何编写可靠而高效的软件",
+                Some("cmn"),
+            ),
+            (
+                "How to write reliable and efficient software in Rust",
+                Some("eng"),
+            ),
+            ("GitHub", None),
+            ("YouTube", None),
+            ("", None),
+        ];
+
+        for (title, expected) in cases {
+            assert_eq!(detect_language(title), expected, "{}", title);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_detect_files() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let profile = dir.path().join("abc.default");
+        fs::create_dir(&profile).unwrap();
+        fs::write(profile.join("places.sqlite"), "history").unwrap();
+        // symlink created by sync tools
+        symlink(&profile, dir.path().join("synced")).unwrap();
+        // hard linked backup
+        fs::create_dir(dir.path().join("backup")).unwrap();
+        fs::hard_link(
+            profile.join("places.sqlite"),
+            dir.path().join("backup/places.sqlite"),
+        )
+        .unwrap();
+        fs::create_dir_all(dir.path().join("empty")).unwrap();
+        fs::write(dir.path().join("empty/places.sqlite"), "").unwrap();
+        fs::create_dir_all(dir.path().join("dir/places.sqlite")).unwrap();
+
+        let pattern = "*/places.sqlite";
+        let files = detect_files(dir.path(), vec![pattern, pattern].into_iter());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with("places.sqlite"));
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let mut delays = Vec::new();
+        let mut calls = 0;
+        let res: io::Result<()> = retry_with_backoff(
+            4,
+            std::time::Duration::from_millis(100),
+            |d| delays.push(d.as_millis()),
+            || {
+                calls += 1;
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "in use"))
+            },
+        );
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(calls, 4);
+        assert_eq!(delays, vec![100, 200, 400]);
+
+        let mut calls = 0;
+        let res = retry_with_backoff(
+            4,
+            std::time::Duration::ZERO,
+            |_| {},
+            || {
+                calls += 1;
+                if calls < 3 {
+                    Err(io::Error::new(io::ErrorKind::WouldBlock, "in use"))
+                } else {
+                    Ok(calls)
+                }
+            },
+        );
+        assert_eq!(res.unwrap(), 3);
+
+        // Missing or forbidden files won't change by waiting
+        for kind in [io::ErrorKind::NotFound, io::ErrorKind::PermissionDenied] {
+            let mut calls = 0;
+            let res: io::Result<()> = retry_with_backoff(
+                4,
+                std::time::Duration::ZERO,
+                |_| {},
+                || {
+                    calls += 1;
+                    Err(kind.into())
+                },
+            );
+            assert!(res.is_err());
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_copy_shared_file() {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let history = dir.path().join("History");
+        fs::write(&history, "history").unwrap();
+        // Opened for writing like a running browser does, std opens it with all
+        // sharing modes
+        let writer = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .share_mode(0x1 | 0x2)
+            .open(&history)
+            .unwrap();
+        let copy = dir.path().join("copy");
+        copy_file(&history, &copy, &crate::progress::SilentCollector).unwrap();
+        assert_eq!(fs::read(&copy).unwrap(), b"history");
+
+        // Exclusively opened files fail with a hint after retries
+        drop(writer);
+        let _exclusive = fs::OpenOptions::new()
+            .read(true)
+            .share_mode(0)
+            .open(&history)
+            .unwrap();
+        let err = copy_file(&history, &copy, &crate::progress::SilentCollector).unwrap_err();
+        assert!(err.to_string().contains("close the browser"), "{err}");
+    }
+
+    #[test]
+    fn test_expand_history_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        for profile in ["Default", "Profile 1"] {
+            fs::create_dir(dir.path().join(profile)).unwrap();
+            fs::write(dir.path().join(profile).join("History"), "history").unwrap();
+        }
+
+        assert_eq!(
+            expand_history_pattern(&dir.path().join("Default/History")),
+            None
+        );
+        assert_eq!(
+            expand_history_pattern(&dir.path().join("*/History")),
+            Some(vec![
+                dir.path().join("Default/History"),
+                dir.path().join("Profile 1/History"),
+            ])
+        );
+        assert_eq!(
+            expand_history_pattern(&dir.path().join("Profile [0-9]/History")),
+            Some(vec![dir.path().join("Profile 1/History")])
+        );
+        assert_eq!(
+            expand_history_pattern(&dir.path().join("*/places.sqlite")),
+            Some(Vec::new())
+        );
+        if let Some(home) = home_dir() {
+            assert_eq!(
+                expand_history_pattern(Path::new("~/History")),
+                Some(vec![home.join("History")])
+            );
+        }
+
+        // Existing files with glob metacharacters are taken literally
+        let literal = dir.path().join("Profile [work]/History");
+        fs::create_dir(literal.parent().unwrap()).unwrap();
+        fs::write(&literal, "history").unwrap();
+        assert_eq!(expand_history_pattern(&literal), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_detect_non_utf8_files() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join(OsStr::from_bytes(b"j\xf6rg"));
+        let profile = home.join(OsStr::from_bytes(b".mozilla/firefox/ab\xffc.default"));
+        fs::create_dir_all(&profile).unwrap();
+        fs::write(profile.join("places.sqlite"), "history").unwrap();
+
+        let files = detect_files(&home, vec![".mozilla/firefox/*/places.sqlite"].into_iter());
+        assert_eq!(files, vec![profile.join("places.sqlite")]);
+        assert_eq!(profile_name(&files[0]), "ab\u{FFFD}c.default");
+    }
+
+    #[test]
+    fn test_whole_days() {
+        let jan10 = ymd_midnight("2022-01-10").unwrap();
+        let jan11 = ymd_midnight("2022-01-11").unwrap();
+        let jan13 = ymd_midnight("2022-01-13").unwrap();
+        let days = whole_days(jan10, jan13 - 1).unwrap();
+        assert_eq!(days.first.to_string(), "2022-01-10");
+        assert_eq!(days.last.to_string(), "2022-01-12");
+        assert_eq!((days.start, days.end), (jan10, jan13));
+
+        // Partial days at both sides are excluded
+        let days = whole_days(jan10 + 1, jan13).unwrap();
+        assert_eq!(days.first.to_string(), "2022-01-11");
+        assert_eq!(days.last.to_string(), "2022-01-12");
+        assert_eq!((days.start, days.end), (jan11, jan13));
+
+        assert_eq!(whole_days(jan10 + 1, jan11 + 1), None);
+        assert_eq!(whole_days(jan10, jan11 - 2), None);
+    }
+
+    #[test]
+    fn test_profiles_by_default() {
+        let os = std::env::consts::OS;
+        let profiles = profiles_by_default(Some("firefox"));
+        assert_eq!(profiles.len(), DEFAULT_PROFILES.len());
+        let defaults = profiles
+            .iter()
+            .take_while(|(browser, _)| is_browser_of(browser, "firefox"))
+            .count();
+        assert!(profiles[..defaults]
+            .iter()
+            .any(|(browser, _)| *browser == format!("firefox-{os}")));
+        assert!(profiles[defaults..]
+            .iter()
+            .all(|(browser, _)| !is_browser_of(browser, "firefox")));
+
+        let profiles = profiles_by_default(None);
+        assert!(profiles.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_profile_name() {
+        let cases = vec![
+            ("/home/a/.config/google-chrome/Default/History", "Default"),
+            (
+                "/home/a/.mozilla/firefox/x.default/places.sqlite",
+                "x.default",
+            ),
+            ("History", ""),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(profile_name(Path::new(path)), expected);
+        }
+
+        let home = tempfile::tempdir().unwrap();
+        let chrome = home.path().join("google-chrome");
+        for (dir, prefs) in [
+            (
+                "Profile 1",
+                r#"{"profile":{"name":"Work"},"account_info":[{"email":"alice@corp.com"}]}"#,
+            ),
+            ("Profile 2", r#"{"profile":{"name":"Personal"}}"#),
+            ("Profile 3", "{not json"),
+        ] {
+            fs::create_dir_all(chrome.join(dir)).unwrap();
+            fs::write(chrome.join(dir).join("Preferences"), prefs).unwrap();
+        }
+        let chrome_profile = |dir: &str| profile_name(&chrome.join(dir).join("History"));
+        assert_eq!(chrome_profile("Profile 1"), "Work (alice@corp.com)");
+        assert_eq!(chrome_profile("Profile 2"), "Personal");
+        assert_eq!(chrome_profile("Profile 3"), "Profile 3");
+        assert_eq!(chrome_profile("Default"), "Default");
+
+        let firefox = home.path().join("Firefox");
+        fs::create_dir_all(&firefox).unwrap();
+        fs::write(
+            firefox.join("profiles.ini"),
+            "[Profile1]\nName=work\nIsRelative=1\nPath=Profiles/abc.work\n\n\
+             [Profile0]\nName=default-release\nIsRelative=1\nPath=Profiles/xyz.default-release\nDefault=1\n",
+        )
+        .unwrap();
+        let firefox_profile =
+            |dir: &str| profile_name(&firefox.join("Profiles").join(dir).join("places.sqlite"));
+        assert_eq!(firefox_profile("abc.work"), "work");
+        assert_eq!(firefox_profile("xyz.default-release"), "default-release");
+        assert_eq!(firefox_profile("other"), "other");
+    }
+
     #[test]
     fn test_ymd_midnight() {
         let cases = vec!["2022-01-15"];
@@ -212,4 +1597,103 @@ mod tests {
             assert_eq!(unixepoch_as_ymd(ymd_midnight(ymd).unwrap()), ymd);
         }
     }
+
+    #[test]
+    fn test_split_keyword() {
+        let cases = [
+            ("rust", vec!["rust"]),
+            ("  rust \t async ", vec!["rust", "async"]),
+            (r#"rust "async trait""#, vec!["rust", "async trait"]),
+            (
+                r#"tokio"async  trait"runtime"#,
+                vec!["tokio", "async  trait", "runtime"],
+            ),
+            (r#""unclosed quote"#, vec!["unclosed quote"]),
+            (r#""" ""#, vec![]),
+            ("", vec![]),
+        ];
+        for (keyword, expected) in cases {
+            assert_eq!(split_keyword(keyword), expected, "{keyword}");
+        }
+    }
+
+    #[test]
+    fn test_format_relative() {
+        let now = 1_650_000_000_000;
+        let cases = [
+            (now + 5_000, "en", "just now"),
+            (now - 59_000, "en", "just now"),
+            (now - 60_000, "en", "1 minute ago"),
+            (now - 59 * 60_000, "en", "59 minutes ago"),
+            (now - 2 * 3_600_000 - 1, "en", "2 hours ago"),
+            (now - 24 * 3_600_000, "en", "1 day ago"),
+            (now - 3 * 24 * 3_600_000, "zh-CN", "3 天前"),
+            (i64::MIN, "en", "106751991167 days ago"),
+        ];
+        for (ts, lang, expected) in cases {
+            assert_eq!(format_relative(now, ts, lang), expected, "{ts}");
+        }
+    }
+
+    #[test]
+    fn test_format_invalid_unixepoch() {
+        for ts in [i64::MAX, i64::MIN] {
+            assert_eq!(unixepoch_as_ymd(ts), format!("invalid({ts})"));
+            assert_eq!(unixepoch_as_hms(ts), format!("invalid({ts})"));
+            assert_eq!(unixepoch_as_ymdhms(ts), format!("invalid({ts})"));
+        }
+        // Before unix epoch is fine
+        assert_eq!(unixepoch_as_ymd(-86_400_000 * 365), "1969-01-01");
+
+        let mut env = Environment::new();
+        add_template_functions(&mut env);
+        env.add_template(
+            "t",
+            "{{ format_as_ymd(ts) }} {{ format_as_hms(ts) }} {{ format_as_ymdhms(ts) }}",
+        )
+        .unwrap();
+        let out = env
+            .get_template("t")
+            .unwrap()
+            .render(minijinja::context!(ts => i64::MAX))
+            .unwrap();
+        assert_eq!(
+            out,
+            format!("invalid({0}) invalid({0}) invalid({0})", i64::MAX)
+        );
+
+        for ymd in ["+262143-12-31", "-262144-01-01", "0000-01-01"] {
+            let _ = ymd_midnight(ymd);
+        }
+    }
+
+    #[test]
+    fn test_midnight_in_dst() {
+        use chrono_tz::{America, Europe};
+
+        let midnight =
+            |tz, ymd| midnight_in(&tz, NaiveDate::parse_from_str(ymd, "%Y-%m-%d").unwrap());
+        let utc = |ymdhms| {
+            Utc.datetime_from_str(ymdhms, "%Y-%m-%d %H:%M:%S")
+                .unwrap()
+                .timestamp_millis()
+        };
+        let cases = vec![
+            // Winter and summer time
+            (Europe::Berlin, "2022-01-15", "2022-01-14 23:00:00"),
+            (Europe::Berlin, "2022-07-15", "2022-07-14 22:00:00"),
+            // Days when DST starts and ends
+            (Europe::Berlin, "2022-03-27", "2022-03-26 23:00:00"),
+            (Europe::Berlin, "2022-03-28", "2022-03-27 22:00:00"),
+            (Europe::Berlin, "2022-10-30", "2022-10-29 22:00:00"),
+            (Europe::Berlin, "2022-10-31", "2022-10-30 23:00:00"),
+            // Midnight doesn't exist, clocks jump to 01:00 -03
+            (America::Santiago, "2023-09-03", "2023-09-03 04:00:00"),
+            // Midnight is ambiguous, clocks go back from 01:00 -04 to 00:00 -05
+            (America::Havana, "2022-11-06", "2022-11-06 04:00:00"),
+        ];
+        for (tz, ymd, expected) in cases {
+            assert_eq!(midnight(tz, ymd), utc(expected), "{tz} {ymd}");
+        }
+    }
 }
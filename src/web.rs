@@ -1,20 +1,58 @@
 use crate::{
     database::Database,
-    types::{ClientError, DetailsQueryParams, ErrorMessage, IndexQueryParams, ServerError},
+    export::{write_csv_to, CsvDialect},
+    i18n::{messages_json, negotiate},
+    progress::SilentCollector,
+    types::{
+        ClientError, CountMode, DayStats, DetailsQueryParams, ErrorMessage, HourRange,
+        IndexQueryParams, KeywordOp, MatchMode, QueryScope, ServerError, TimeRange, VisitDetail,
+        VisitGroup, VisitPatch, VisitTrace,
+    },
     util::{
-        minijinja_format_as_hms, minijinja_format_as_ymd, minijinja_format_title,
-        tomorrow_midnight, ymd_midnight,
+        add_template_functions, canonical_domain, domain_from, format_duration, remove_temp_file,
+        tomorrow_midnight, track_temp_file, ymd_midnight,
     },
 };
 use anyhow::{Context, Error, Result};
-use log::error;
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use log::{error, info};
 use minijinja::{context, Environment};
 use rust_embed::RustEmbed;
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
-use tokio::runtime::Runtime;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    env::temp_dir,
+    fs::{self, File},
+    io::{self, BufWriter, SeekFrom, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    runtime::Runtime,
+    task,
+};
 use warp::{
-    http::HeaderValue,
-    hyper::StatusCode,
+    http::{
+        header::{
+            ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH,
+            CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY,
+        },
+        HeaderMap, HeaderValue,
+    },
+    hyper::{
+        body::{self, Bytes, Sender},
+        Body, StatusCode,
+    },
     path::Tail,
     reject,
     reply::{self, Response},
@@ -22,12 +60,42 @@ use warp::{
 };
 
 const DEFAULT_SEARCH_INTERVAL: i64 = 3_600_000 * 24 * 30; // 30 days
+
+// Responses smaller than this are not worth compressing
+const MIN_COMPRESS_SIZE: usize = 1024;
+// Domains listed in each direction of trends
+const TRENDS_LIMIT: usize = 10;
 #[derive(RustEmbed)]
 #[folder = "static"]
-struct Asset;
+pub(crate) struct Asset;
+
+// Fold consecutive visits of the same url into groups, `visits` keep their order.
+fn collapse_repeats(visits: Vec<VisitDetail>) -> Vec<VisitGroup> {
+    let mut groups: Vec<VisitGroup> = Vec::new();
+    for visit in visits {
+        match groups.last_mut() {
+            Some(group) if group.visit.url == visit.url => {
+                group.count += 1;
+                group.start = group.start.min(visit.visit_time);
+                group.end = group.end.max(visit.visit_time);
+            }
+            _ => groups.push(VisitGroup {
+                count: 1,
+                start: visit.visit_time,
+                end: visit.visit_time,
+                visit,
+                trace: None,
+            }),
+        }
+    }
+    groups
+}
 
 async fn serve_file(path: Tail) -> Result<impl Reply, Rejection> {
-    let path = path.as_str();
+    serve_asset(path.as_str())
+}
+
+fn serve_asset(path: &str) -> Result<Response, Rejection> {
     let asset = Asset::get(path).ok_or_else(reject::not_found)?;
     let mut res = Response::new(asset.data.into());
 
@@ -39,16 +107,266 @@ async fn serve_file(path: Tail) -> Result<impl Reply, Rejection> {
     Ok(res)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Pick encoding accepted by client according to `Accept-Encoding` header, gzip is preferred.
+    /// `*` only applies to encodings not listed explicitly, so `gzip;q=0, *` refuses gzip.
+    fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let codings = accept_encoding
+            .split(',')
+            .map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let coding = parts.next().unwrap_or_default();
+                let q = parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .map_or(1.0, |q| q.parse::<f32>().unwrap_or(0.0));
+                (coding, q)
+            })
+            .collect::<Vec<_>>();
+        let q_of = |name: &str| {
+            codings
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(name))
+                .map(|(_, q)| *q)
+        };
+        let accepted = |name: &str| q_of(name).or_else(|| q_of("*")).unwrap_or(0.0) > 0.0;
+        [Encoding::Gzip, Encoding::Deflate]
+            .into_iter()
+            .find(|e| accepted(e.name()))
+    }
+}
+
+/// Compress `reply` when client accepts it and it's large enough.
+async fn compress(headers: HeaderMap, reply: impl Reply) -> Result<Response, Infallible> {
+    let mut res = reply.into_response();
+    res.headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let encoding = match headers
+        .get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Encoding::negotiate)
+    {
+        // Byte ranges refer to uncompressed body, and downloads are streamed
+        Some(e)
+            if !res.headers().contains_key(CONTENT_ENCODING)
+                && !res.headers().contains_key(ACCEPT_RANGES) =>
+        {
+            e
+        }
+        _ => return Ok(res),
+    };
+
+    let (mut parts, body) = res.into_parts();
+    let data = match body::to_bytes(body).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("read response body failed, err:{:?}", e);
+            let mut res = Response::default();
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return Ok(res);
+        }
+    };
+    if data.len() < MIN_COMPRESS_SIZE {
+        return Ok(Response::from_parts(parts, data.into()));
+    }
+    match encoding.encode(&data) {
+        Ok(compressed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            Ok(Response::from_parts(parts, compressed.into()))
+        }
+        Err(e) => {
+            error!("compress response failed, err:{:?}", e);
+            Ok(Response::from_parts(parts, data.into()))
+        }
+    }
+}
+
+// Size of chunks `/download.csv` is streamed in
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// CSV of all visits served by `/download.csv`, it's kept until visits change, so
+/// interrupted downloads can be resumed by range requests over the same bytes.
+struct CsvSnapshot {
+    etag: String,
+    path: PathBuf,
+    len: u64,
+}
+
+impl Drop for CsvSnapshot {
+    fn drop(&mut self) {
+        remove_temp_file(&self.path);
+    }
+}
+
+/// Latest `CsvSnapshot` of database at `db_path`, which is exported on its own
+/// connection, so it neither waits for queries of server nor is aborted by their
+/// timeout.
+#[derive(Clone)]
+struct CsvCache {
+    db_path: PathBuf,
+    snapshot: Arc<Mutex<Option<Arc<CsvSnapshot>>>>,
+}
+
+impl CsvCache {
+    fn new(db_path: PathBuf) -> Self {
+        Self {
+            db_path,
+            snapshot: Arc::default(),
+        }
+    }
+}
+
+/// Parse `Range` header of a body of `len` bytes into inclusive `(first, last)`
+/// byte positions. `None` when the whole body should be sent, such as for invalid
+/// or multiple ranges, and `Some(Err(()))` when range is unsatisfiable.
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let range = if first.is_empty() {
+        // Suffix range, the last `n` bytes
+        match last.parse::<u64>().ok()? {
+            0 => Err(()),
+            _ if len == 0 => Err(()),
+            n => Ok((len.saturating_sub(n), len - 1)),
+        }
+    } else {
+        let first = first.parse::<u64>().ok()?;
+        let last = match last {
+            "" => u64::MAX,
+            last => last.parse::<u64>().ok().filter(|&last| last >= first)?,
+        };
+        if first >= len {
+            Err(())
+        } else {
+            Ok((first, last.min(len - 1)))
+        }
+    };
+    Some(range)
+}
+
+// Send `len` bytes of file at `path` from `start` to `sender`
+async fn send_file(sender: &mut Sender, path: &PathBuf, start: u64, len: u64) -> io::Result<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut file = file.take(len);
+    let mut buf = vec![0; DOWNLOAD_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        if sender
+            .send_data(Bytes::copy_from_slice(&buf[..n]))
+            .await
+            .is_err()
+        {
+            // Client is gone, it may resume later
+            return Ok(());
+        }
+    }
+}
+
+/// Options to customize the HTTP server.
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// Show url as title for visits without title, otherwise a "(no title)" label.
+    pub title_fallback: bool,
+    /// Reload pages in browser periodically, so new visits show up without manual
+    /// refresh. `None` or zero disables it.
+    pub refresh: Option<Duration>,
+    /// Abort queries running longer than this, so one pathological keyword can't
+    /// hang the server. `None` or zero disables it.
+    pub query_timeout: Option<Duration>,
+    /// Gap between two visits that starts a new browsing session.
+    pub session_gap: Duration,
+    /// How visits are counted in daily counts chart.
+    pub daily_count_mode: CountMode,
+    /// Leave out local files and browser internal pages, see
+    /// `Database::with_exclude_internal`.
+    pub exclude_internal: bool,
+    /// Only include visits in these hours of day, see `Database::with_hours`.
+    pub hours: Option<HourRange>,
+}
+
+impl ServeOptions {
+    // Seconds used by meta refresh, 0 means disabled
+    fn refresh_secs(&self) -> u64 {
+        self.refresh.map_or(0, |d| d.as_secs())
+    }
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            title_fallback: true,
+            refresh: None,
+            query_timeout: None,
+            session_gap: Duration::from_secs(30 * 60),
+            daily_count_mode: CountMode::Raw,
+            exclude_internal: false,
+            hours: None,
+        }
+    }
+}
+
 struct Server {
     db: Arc<Database>,
-    addr: SocketAddr,
+    addrs: Vec<SocketAddr>,
+    options: Arc<ServeOptions>,
+    csv: CsvCache,
 }
 
 impl Server {
-    fn try_new(addr: String, db_filepath: String) -> Result<Self> {
+    fn try_new(
+        addrs: Vec<SocketAddr>,
+        db_filepath: PathBuf,
+        options: ServeOptions,
+    ) -> Result<Self> {
         Ok(Self {
-            db: Arc::new(Database::open(db_filepath).context("open db")?),
-            addr: addr.parse()?,
+            csv: CsvCache::new(db_filepath.clone()),
+            db: Arc::new(
+                Database::open(db_filepath)
+                    .context("open db")?
+                    .with_query_timeout(options.query_timeout)
+                    .with_exclude_internal(options.exclude_internal)
+                    .with_hours(options.hours),
+            ),
+            addrs,
+            options: Arc::new(options),
         })
     }
 
@@ -58,17 +376,203 @@ impl Server {
         warp::any().map(move || db.clone())
     }
 
+    fn with_options(
+        options: Arc<ServeOptions>,
+    ) -> impl Filter<Extract = (Arc<ServeOptions>,), Error = Infallible> + Clone {
+        warp::any().map(move || options.clone())
+    }
+
+    fn with_csv(csv: CsvCache) -> impl Filter<Extract = (CsvCache,), Error = Infallible> + Clone {
+        warp::any().map(move || csv.clone())
+    }
+
+    // CSV of current visits, generated again only when database changes. It's
+    // blocking, so it's run by `spawn_blocking`.
+    fn csv_snapshot(
+        db: &Database,
+        options: &ServeOptions,
+        csv: &CsvCache,
+    ) -> Result<Arc<CsvSnapshot>> {
+        static SEQ: AtomicUsize = AtomicUsize::new(0);
+        // Filters of server change exported visits too
+        let etag = format!(
+            "\"{}-{}-{}\"",
+            db.select_data_version()?,
+            options.exclude_internal as u8,
+            options.hours.map_or("all".to_string(), |h| h.to_string())
+        );
+        // Held while exporting, so concurrent downloads wait for the same snapshot
+        let mut cached = csv.snapshot.lock().unwrap();
+        if let Some(snapshot) = cached.as_ref().filter(|s| s.etag == etag) {
+            return Ok(snapshot.clone());
+        }
+        let export = Database::open(&csv.db_path)
+            .context("open db")?
+            .with_exclude_internal(options.exclude_internal)
+            .with_hours(options.hours);
+
+        let path = temp_dir().join(format!(
+            "onehistory-{}-{}.csv",
+            process::id(),
+            SEQ.fetch_add(1, Ordering::SeqCst)
+        ));
+        // Created before writing, so partial file is removed on failure
+        track_temp_file(&path);
+        let mut snapshot = CsvSnapshot { etag, path, len: 0 };
+        let f = File::create(&snapshot.path).context("create csv")?;
+        write_csv_to(
+            &export,
+            BufWriter::new(f),
+            false,
+            false,
+            CsvDialect::default(),
+            &SilentCollector,
+        )?;
+        snapshot.len = fs::metadata(&snapshot.path)?.len();
+        let snapshot = Arc::new(snapshot);
+        *cached = Some(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    async fn download_csv(
+        db: Arc<Database>,
+        options: Arc<ServeOptions>,
+        csv: CsvCache,
+        range: Option<String>,
+        if_range: Option<String>,
+    ) -> Result<Response, Rejection> {
+        let snapshot = task::spawn_blocking(move || Self::csv_snapshot(&db, &options, &csv))
+            .await
+            .context("join csv snapshot")
+            .map_err(ServerError::from)?
+            .context("csv snapshot")
+            .map_err(ServerError::from)?;
+        let len = snapshot.len;
+        // Range of another version would mix bytes of two exports
+        let range = match if_range {
+            Some(tag) if tag != snapshot.etag => None,
+            _ => range,
+        };
+        let (status, first, last) = match range.and_then(|r| parse_range(&r, len)) {
+            Some(Ok((first, last))) => (StatusCode::PARTIAL_CONTENT, first, last),
+            Some(Err(())) => {
+                let mut res = Response::default();
+                *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                if let Ok(v) = HeaderValue::from_str(&format!("bytes */{len}")) {
+                    res.headers_mut().insert(CONTENT_RANGE, v);
+                }
+                return Ok(res);
+            }
+            None => (StatusCode::OK, 0, len.saturating_sub(1)),
+        };
+        let body_len = if len == 0 { 0 } else { last - first + 1 };
+
+        let (mut sender, body) = Body::channel();
+        let streamed = snapshot.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_file(&mut sender, &streamed.path, first, body_len).await {
+                error!("send {} failed, err:{:?}", streamed.path.display(), e);
+                sender.abort();
+            }
+        });
+
+        let mut res = Response::new(body);
+        *res.status_mut() = status;
+        let headers = res.headers_mut();
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/csv; charset=utf-8"),
+        );
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_static("attachment; filename=\"onehistory.csv\""),
+        );
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body_len));
+        if let Ok(v) = HeaderValue::from_str(&snapshot.etag) {
+            headers.insert(ETAG, v);
+        }
+        if status == StatusCode::PARTIAL_CONTENT {
+            if let Ok(v) = HeaderValue::from_str(&format!("bytes {first}-{last}/{len}")) {
+                headers.insert(CONTENT_RANGE, v);
+            }
+        }
+        Ok(res)
+    }
+
     async fn details(
         db: Arc<Database>,
+        options: Arc<ServeOptions>,
         ymd: String,
         query_params: DetailsQueryParams,
+        accept_language: Option<String>,
     ) -> Result<impl Reply, Rejection> {
-        let start = ymd_midnight(&ymd).map_err(ClientError::from)?;
-        let end = start + 3_600_000 * 24;
-        let keyword = query_params.keyword;
-        let visit_details = db
-            .select_visits(start, end, keyword.clone())
-            .map_err(ServerError::from)?;
+        let lang = negotiate(query_params.lang.as_deref(), accept_language.as_deref());
+        let (db, start, end) = Self::scoped_day(&db, &ymd, &query_params)?;
+        let keyword = query_params.keyword.clone();
+        let reverse = query_params.reverse;
+        let collapse = query_params.collapse;
+        let fuzzy = query_params.fuzzy().map_err(ClientError::from)?;
+        let (day_stats, mut visit_details) = match fuzzy {
+            Some((keyword, threshold)) => {
+                let visits = Self::fuzzy_visits(&db, start, end, keyword, threshold, reverse)?;
+                (
+                    DayStats::from_visits(&visits, query_params.canonical),
+                    visits,
+                )
+            }
+            None => {
+                let day_stats = db
+                    .select_day_stats(start, end, keyword.clone())
+                    .context("day_stats")
+                    .map_err(ServerError::from)?;
+                let visits = db
+                    .select_visits(start, end, keyword.clone(), reverse)
+                    .map_err(ServerError::from)?;
+                (day_stats, visits)
+            }
+        };
+        // Kept by links of the page
+        let fuzzy_query = match (query_params.match_mode, query_params.threshold) {
+            (MatchMode::Fuzzy, Some(threshold)) => format!("&match=fuzzy&threshold={threshold}"),
+            (MatchMode::Fuzzy, None) => "&match=fuzzy".to_string(),
+            (MatchMode::Substring, _) => String::new(),
+        };
+        let domain = query_params.domain.filter(|d| !d.is_empty());
+        if let Some(domain) = &domain {
+            // Drilling down a canonical domain also lists visits of its variants
+            let domain_of = |url: &str| {
+                let domain = domain_from(url.to_string());
+                if query_params.canonical {
+                    canonical_domain(&domain).to_string()
+                } else {
+                    domain
+                }
+            };
+            visit_details.retain(|v| &domain_of(&v.url) == domain);
+        }
+        let visit_count = visit_details.len();
+        // Visits of the whole day are selected, so groups are never split by pages
+        let mut visit_details = if collapse {
+            collapse_repeats(visit_details)
+        } else {
+            visit_details
+                .into_iter()
+                .map(|visit| VisitGroup {
+                    count: 1,
+                    start: visit.visit_time,
+                    end: visit.visit_time,
+                    visit,
+                    trace: None,
+                })
+                .collect()
+        };
+        if query_params.debug {
+            let mut traces = Self::traces_by_visit(&db, start, end)?;
+            for group in &mut visit_details {
+                group.trace = traces.remove(&(group.visit.url.clone(), group.visit.visit_time));
+            }
+        }
 
         let asset = Asset::get("details.html").unwrap();
         let index_tmpl: &str =
@@ -77,48 +581,130 @@ impl Server {
         env.add_template("details", index_tmpl)
             .map_err(|e| ServerError::from(Error::from(e)))?;
 
-        env.add_function("format_as_ymd", minijinja_format_as_ymd);
-        env.add_function("format_as_hms", minijinja_format_as_hms);
-        env.add_function("format_title", minijinja_format_title);
+        add_template_functions(&mut env);
         let tmpl = env.get_template("details").unwrap();
         let body = tmpl
             .render(context!(
                 ymd => ymd,
                 ymd_ts => start,
                 visit_details => visit_details,
+                visit_count => visit_count,
+                title_fallback => options.title_fallback,
+                refresh => options.refresh_secs(),
                 version => clap::crate_version!(),
-                keyword => keyword.unwrap_or_else(|| "".to_string()),
+                keyword => keyword.unwrap_or_default(),
+                fuzzy => query_params.match_mode == MatchMode::Fuzzy,
+                fuzzy_query => fuzzy_query,
+                reverse => reverse,
+                collapse => collapse,
+                domain => domain.unwrap_or_default(),
+                debug => query_params.debug,
+                source => query_params.source.unwrap_or_default(),
+                include_hidden => query_params.include_hidden,
+                canonical => query_params.canonical,
+                op => query_params.keyword_op,
+                relative => query_params.relative,
+                day_stats => day_stats,
+                lang => lang,
             ))
             .map_err(|e| ServerError::from(Error::from(e)))?;
 
         Ok(reply::html(body))
     }
 
-    async fn index(
-        db: Arc<Database>,
-        query_params: IndexQueryParams,
-    ) -> Result<impl Reply, Rejection> {
+    // Database only including visits of `scope`, whose source is parsed as
+    // `SourceFilter`
+    fn scoped_db(db: &Arc<Database>, scope: QueryScope) -> Result<Arc<Database>, Rejection> {
+        let QueryScope {
+            source,
+            include_hidden,
+            canonical,
+            keyword_op,
+        } = scope;
+        let source = source.filter(|s| !s.is_empty());
+        if source.is_none() && !include_hidden && !canonical && keyword_op == KeywordOp::And {
+            return Ok(db.clone());
+        }
+        let source = source
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(ClientError::from)?;
+        Ok(Arc::new(
+            db.view()
+                .with_source(source)
+                .with_include_hidden(include_hidden)
+                .with_canonical(canonical)
+                .with_keyword_op(keyword_op),
+        ))
+    }
+
+    // Scoped database and time range of dashboards and their APIs
+    fn scoped_range(
+        db: &Arc<Database>,
+        query_params: &IndexQueryParams,
+    ) -> Result<(Arc<Database>, i64, i64), Rejection> {
+        let (start, end) = Self::time_range(query_params)?;
+        Ok((Self::scoped_db(db, query_params.scope())?, start, end))
+    }
+
+    // Scoped database and time range of the day `ymd` of details page and its API
+    fn scoped_day(
+        db: &Arc<Database>,
+        ymd: &str,
+        query_params: &DetailsQueryParams,
+    ) -> Result<(Arc<Database>, i64, i64), Rejection> {
+        let start = ymd_midnight(ymd).map_err(ClientError::from)?;
+        let end = start + 3_600_000 * 24;
+        Ok((Self::scoped_db(db, query_params.scope())?, start, end))
+    }
+
+    fn time_range(query_params: &IndexQueryParams) -> Result<(i64, i64), Rejection> {
         let end = query_params
             .end
-            .map_or_else(|| Ok(tomorrow_midnight() - 1), |ymd| ymd_midnight(&ymd))
+            .as_ref()
+            .map_or_else(|| Ok(tomorrow_midnight() - 1), |ymd| ymd_midnight(ymd))
             .map_err(ClientError::from)?;
         let start = query_params
             .start
+            .as_ref()
             .map_or_else(
                 || Ok(tomorrow_midnight() - DEFAULT_SEARCH_INTERVAL),
-                |ymd| ymd_midnight(&ymd),
+                |ymd| ymd_midnight(ymd),
             )
             .map_err(ClientError::from)?;
+
+        Ok((start, end))
+    }
+
+    async fn index(
+        db: Arc<Database>,
+        options: Arc<ServeOptions>,
+        query_params: IndexQueryParams,
+        accept_language: Option<String>,
+    ) -> Result<impl Reply, Rejection> {
+        let lang = negotiate(query_params.lang.as_deref(), accept_language.as_deref());
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
         let keyword = query_params.keyword;
+        let sources = db
+            .select_sources()
+            .context("sources")
+            .map_err(ServerError::from)?;
 
         let daily_counts = db
-            .select_daily_count(start, end, keyword.clone())
+            .select_daily_count(start, end, keyword.clone(), options.daily_count_mode)
             .context("daily_count")
             .map_err(ServerError::from)?;
         let (min_time, max_time) = db
             .select_min_max_time()
             .context("min_max_time")
             .map_err(ServerError::from)?;
+        // Retention is set by backup, the most recent run tells the active one
+        let retention_days = db
+            .select_backup_runs(1)
+            .context("backup_runs")
+            .map_err(ServerError::from)?
+            .first()
+            .and_then(|run| run.retention_days);
         let title_top100 = db
             .select_title_top100(start, end, keyword.clone())
             .context("title_top100")
@@ -127,6 +713,26 @@ impl Server {
             .select_domain_top100(start, end, keyword.clone())
             .context("domain_top100")
             .map_err(ServerError::from)?;
+        let url_top100 = db
+            .select_url_top100(start, end, keyword.clone())
+            .context("url_top100")
+            .map_err(ServerError::from)?;
+        let tld_breakdown = db
+            .select_tld_breakdown(start, end, keyword.clone())
+            .context("tld_breakdown")
+            .map_err(ServerError::from)?;
+        let domain_trends = db
+            .select_domain_trends(start, end, keyword.clone(), TRENDS_LIMIT)
+            .context("domain_trends")
+            .map_err(ServerError::from)?;
+        let session_stats = db
+            .select_session_stats(start, end, keyword.clone(), options.session_gap)
+            .context("session_stats")
+            .map_err(ServerError::from)?;
+        let hour_weekday_counts = db
+            .select_hour_weekday_count(start, end, keyword.clone())
+            .context("hour_weekday_count")
+            .map_err(ServerError::from)?;
 
         let asset = Asset::get("index.html").unwrap();
         let index_tmpl: &str =
@@ -135,50 +741,383 @@ impl Server {
         env.add_template("index", index_tmpl)
             .map_err(|e| ServerError::from(Error::from(e)))?;
 
+        add_template_functions(&mut env);
         let tmpl = env.get_template("index").unwrap();
         let body = tmpl
             .render(context!(
                 min_time => min_time,
                 max_time => max_time,
+                retention_days => retention_days,
                 start => start,
                 end => end,
                 daily_counts => daily_counts,
                 title_top100 => title_top100,
                 domain_top100 => domain_top100,
-                keyword => keyword.unwrap_or_else(|| "".to_string()),
+                url_top100 => url_top100,
+                tld_breakdown => tld_breakdown,
+                domain_trends => domain_trends,
+                sessions_per_day => format!("{:.1}", session_stats.sessions_per_day()),
+                visits_per_session => format!("{:.1}", session_stats.avg_visits),
+                active_time => format_duration(session_stats.active_time),
+                session_stats => session_stats,
+                hour_weekday_counts => hour_weekday_counts,
+                refresh => options.refresh_secs(),
+                keyword => keyword.unwrap_or_default(),
+                sources => sources,
+                source => query_params.source.unwrap_or_default(),
+                include_hidden => query_params.include_hidden,
+                canonical => query_params.canonical,
+                op => query_params.keyword_op,
                 version => clap::crate_version!(),
+                lang => lang,
+                messages => messages_json(lang),
             ))
             .map_err(|e| ServerError::from(Error::from(e)))?;
 
         Ok(reply::html(body))
     }
 
+    async fn api_daily_counts(
+        db: Arc<Database>,
+        options: Arc<ServeOptions>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let daily_counts = db
+            .select_daily_count(start, end, query_params.keyword, options.daily_count_mode)
+            .context("daily_count")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&daily_counts))
+    }
+
+    async fn api_details(
+        db: Arc<Database>,
+        ymd: String,
+        query_params: DetailsQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_day(&db, &ymd, &query_params)?;
+        let visit_details = match query_params.fuzzy().map_err(ClientError::from)? {
+            Some((keyword, threshold)) => {
+                Self::fuzzy_visits(&db, start, end, keyword, threshold, query_params.reverse)?
+            }
+            None => db
+                .select_visits(start, end, query_params.keyword, query_params.reverse)
+                .map_err(ServerError::from)?,
+        };
+        if query_params.debug {
+            let mut traces = Self::traces_by_visit(&db, start, end)?;
+            let traces = visit_details
+                .into_iter()
+                .filter_map(|v| traces.remove(&(v.url, v.visit_time)))
+                .collect::<Vec<_>>();
+            return Ok(reply::json(&traces));
+        }
+
+        Ok(reply::json(&visit_details))
+    }
+
+    async fn api_visit(db: Arc<Database>, id: i64) -> Result<impl Reply, Rejection> {
+        let trace = db
+            .select_visit_trace(id)
+            .map_err(ServerError::from)?
+            .ok_or_else(reject::not_found)?;
+
+        Ok(reply::json(&trace))
+    }
+
+    async fn api_patch_visit(
+        db: Arc<Database>,
+        id: i64,
+        patch: VisitPatch,
+    ) -> Result<impl Reply, Rejection> {
+        // Replied directly, since rejections of not found would give way to the
+        // method not allowed of `api_visit`
+        if !db
+            .set_visit_hidden(id, patch.hidden)
+            .map_err(ServerError::from)?
+        {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+        Ok(Self::api_visit(db, id).await?.into_response())
+    }
+
+    // Traces of visits between `[start, end]`, keyed by url and visit time
+    fn traces_by_visit(
+        db: &Database,
+        start: i64,
+        end: i64,
+    ) -> Result<HashMap<(String, i64), VisitTrace>, Rejection> {
+        let traces = db
+            .select_visit_traces(start, end)
+            .map_err(ServerError::from)?;
+        Ok(traces
+            .into_iter()
+            .map(|t| ((t.visit.url.clone(), t.visit.visit_time), t))
+            .collect())
+    }
+
+    // Visits whose title is similar to `keyword`, most similar first
+    fn fuzzy_visits(
+        db: &Database,
+        start: i64,
+        end: i64,
+        keyword: &str,
+        threshold: f64,
+        reverse: bool,
+    ) -> Result<Vec<VisitDetail>, Rejection> {
+        let visits = db
+            .select_fuzzy_visits(start, end, keyword, threshold, reverse)
+            .map_err(ServerError::from)?;
+        Ok(visits.into_iter().map(|(visit, _)| visit).collect())
+    }
+
+    async fn api_titles(
+        db: Arc<Database>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let title_top100 = db
+            .select_title_top100(start, end, query_params.keyword)
+            .context("title_top100")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&title_top100))
+    }
+
+    async fn api_urls(
+        db: Arc<Database>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let url_top100 = db
+            .select_url_top100(start, end, query_params.keyword)
+            .context("url_top100")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&url_top100))
+    }
+
+    async fn api_domains(
+        db: Arc<Database>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let domain_top100 = db
+            .select_domain_top100(start, end, query_params.keyword)
+            .context("domain_top100")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&domain_top100))
+    }
+
+    async fn api_tlds(
+        db: Arc<Database>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let tld_breakdown = db
+            .select_tld_breakdown(start, end, query_params.keyword)
+            .context("tld_breakdown")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&tld_breakdown))
+    }
+
+    async fn api_domain_trends(
+        db: Arc<Database>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let domain_trends = db
+            .select_domain_trends(start, end, query_params.keyword, TRENDS_LIMIT)
+            .context("domain_trends")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&domain_trends))
+    }
+
+    async fn api_hour_weekday_counts(
+        db: Arc<Database>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let hour_weekday_counts = db
+            .select_hour_weekday_count(start, end, query_params.keyword)
+            .context("hour_weekday_count")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&hour_weekday_counts))
+    }
+
+    async fn api_sessions(
+        db: Arc<Database>,
+        options: Arc<ServeOptions>,
+        query_params: IndexQueryParams,
+    ) -> Result<impl Reply, Rejection> {
+        let (db, start, end) = Self::scoped_range(&db, &query_params)?;
+        let session_stats = db
+            .select_session_stats(start, end, query_params.keyword, options.session_gap)
+            .context("session_stats")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&session_stats))
+    }
+
+    async fn api_sources(db: Arc<Database>) -> Result<impl Reply, Rejection> {
+        let sources = db
+            .select_sources()
+            .context("sources")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&sources))
+    }
+
+    async fn api_range(db: Arc<Database>) -> Result<impl Reply, Rejection> {
+        let (min_time, max_time) = db
+            .select_min_max_time()
+            .context("min_max_time")
+            .map_err(ServerError::from)?;
+
+        Ok(reply::json(&TimeRange { min_time, max_time }))
+    }
+
+    fn api_routes(
+        &self,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static
+    {
+        let daily_counts = Self::with_db(self.db.clone())
+            .and(Self::with_options(self.options.clone()))
+            .and(warp::path!("api" / "daily_counts"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_daily_counts);
+        let details = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "details" / String))
+            .and(warp::query::<DetailsQueryParams>())
+            .and_then(Self::api_details);
+        let titles = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "titles"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_titles);
+        let urls = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "urls"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_urls);
+        let domains = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "domains"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_domains);
+        let tlds = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "tlds"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_tlds);
+        let visit = Self::with_db(self.db.clone())
+            .and(warp::get())
+            .and(warp::path!("api" / "visits" / i64))
+            .and_then(Self::api_visit);
+        let patch_visit = Self::with_db(self.db.clone())
+            .and(warp::patch())
+            .and(warp::path!("api" / "visits" / i64))
+            .and(warp::body::json())
+            .and_then(Self::api_patch_visit);
+        let domain_trends = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "domain_trends"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_domain_trends);
+        let hour_weekday_counts = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "hour_weekday_counts"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_hour_weekday_counts);
+        let sessions = Self::with_db(self.db.clone())
+            .and(Self::with_options(self.options.clone()))
+            .and(warp::path!("api" / "sessions"))
+            .and(warp::query::<IndexQueryParams>())
+            .and_then(Self::api_sessions);
+        let sources = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "sources"))
+            .and_then(Self::api_sources);
+        let range = Self::with_db(self.db.clone())
+            .and(warp::path!("api" / "range"))
+            .and_then(Self::api_range);
+        let openapi =
+            warp::path!("api" / "openapi.json").and_then(|| async { serve_asset("openapi.json") });
+
+        daily_counts
+            .or(details)
+            .or(titles)
+            .or(urls)
+            .or(domains)
+            .or(tlds)
+            .or(patch_visit)
+            .or(visit)
+            .or(domain_trends)
+            .or(hour_weekday_counts)
+            .or(sessions)
+            .or(sources)
+            .or(range)
+            .or(openapi)
+    }
+
     // https://github.com/ItsNothingPersonal/warp-postgres-example/blob/main/src/main.rs#L63
     fn serve(&self) -> Result<()> {
         let index = warp::path::end()
             .and(Self::with_db(self.db.clone()))
+            .and(Self::with_options(self.options.clone()))
             .and(warp::query::<IndexQueryParams>())
+            .and(warp::header::optional::<String>("accept-language"))
             .and_then(Self::index);
 
         let detail = Self::with_db(self.db.clone())
+            .and(Self::with_options(self.options.clone()))
             .and(warp::path!("details" / String))
             .and(warp::query::<DetailsQueryParams>())
+            .and(warp::header::optional::<String>("accept-language"))
             .and_then(Self::details);
 
+        let download = warp::path!("download.csv")
+            .and(Self::with_db(self.db.clone()))
+            .and(Self::with_options(self.options.clone()))
+            .and(Self::with_csv(self.csv.clone()))
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::header::optional::<String>("if-range"))
+            .and_then(Self::download_csv);
+
         let static_route = warp::path("static")
             .and(warp::path::tail())
             .and_then(serve_file);
 
         let routes = detail
+            .or(self.api_routes())
             .or(index)
+            .or(download)
             .or(static_route)
             .recover(Self::handle_rejection);
+        let routes = warp::header::headers_cloned()
+            .and(routes)
+            .and_then(compress);
 
         let rt = Runtime::new().context("tokio runtime build")?;
         rt.block_on(async {
-            warp::serve(routes).run(self.addr).await;
-        });
-        Ok(())
+            // Bind all addresses before serving any, so a busy one fails the whole server
+            let mut servers = Vec::with_capacity(self.addrs.len());
+            for addr in &self.addrs {
+                let (bound, server) = warp::serve(routes.clone())
+                    .try_bind_ephemeral(*addr)
+                    .with_context(|| format!("listen on {addr}"))?;
+                servers.push((bound, server));
+            }
+            let servers = servers
+                .into_iter()
+                .map(|(bound, server)| {
+                    info!("Listening on http://{bound}");
+                    tokio::spawn(server)
+                })
+                .collect::<Vec<_>>();
+            for server in servers {
+                server.await.context("server task")?;
+            }
+            Ok(())
+        })
     }
 
     async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
@@ -209,7 +1148,508 @@ impl Server {
     }
 }
 
-pub fn serve(addr: String, db_filepath: String) -> Result<()> {
-    let server = Server::try_new(addr, db_filepath)?;
-    server.serve()
+/// Start HTTP server listening on every one of `addrs` to visualize 1History database
+/// at `db_filepath`, failing when any of them can't be bound.
+pub fn serve(
+    addrs: Vec<SocketAddr>,
+    db_filepath: PathBuf,
+    options: ServeOptions,
+) -> crate::error::Result<()> {
+    let server = Server::try_new(addrs, db_filepath, options)?;
+    Ok(server.serve()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{progress::SilentCollector, types::SourceName};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let cases = vec![
+            ("gzip, deflate, br", Some(Encoding::Gzip)),
+            ("deflate", Some(Encoding::Deflate)),
+            ("gzip;q=0, deflate;q=0.5", Some(Encoding::Deflate)),
+            ("GZIP", Some(Encoding::Gzip)),
+            ("*", Some(Encoding::Gzip)),
+            // Explicit q=0 wins over wildcard
+            ("gzip;q=0, *", Some(Encoding::Deflate)),
+            ("*, gzip;q=0, deflate;q=0", None),
+            ("*;q=0, deflate", Some(Encoding::Deflate)),
+            ("br", None),
+            ("identity", None),
+            ("", None),
+        ];
+        for (accept_encoding, expected) in cases {
+            assert_eq!(
+                Encoding::negotiate(accept_encoding),
+                expected,
+                "{accept_encoding}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compress() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let html = "<tr><td>1History</td></tr>".repeat(100);
+
+        let res = compress(headers.clone(), reply::html(html.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        let data = body::to_bytes(res.into_body()).await.unwrap();
+        assert!(data.len() < html.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&data[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, html);
+
+        // Small responses are kept as is
+        let res = compress(headers, reply::html("ok")).await.unwrap();
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(res.headers()[VARY], "accept-encoding");
+
+        // Client doesn't accept compression
+        let res = compress(HeaderMap::new(), reply::html(html)).await.unwrap();
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let cases = vec![
+            ("bytes=0-99", Some(Ok((0, 99)))),
+            ("bytes=100-", Some(Ok((100, 999)))),
+            ("bytes=900-2000", Some(Ok((900, 999)))),
+            ("bytes=-100", Some(Ok((900, 999)))),
+            ("bytes=-2000", Some(Ok((0, 999)))),
+            ("bytes=1000-", Some(Err(()))),
+            ("bytes=-0", Some(Err(()))),
+            ("bytes=10-5", None),
+            ("bytes=0-1,5-9", None),
+            ("items=0-1", None),
+            ("bytes=a-", None),
+        ];
+        for (range, expected) in cases {
+            assert_eq!(parse_range(range, 1000), expected, "{range}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(Database::open(dir.path().join("onehistory.db")).unwrap());
+        let visits = (0..100)
+            .map(|i| VisitDetail {
+                url: format!("https://github.com/{i}"),
+                title: "GitHub".to_string(),
+                visit_time: (1_642_000_000_000 + i * 1_000) * 1_000,
+                visit_type: 1,
+                source_visit_id: None,
+            })
+            .collect();
+        db.persist("History", visits, &SilentCollector).unwrap();
+        let options = Arc::new(ServeOptions::default());
+        let csv = CsvCache::new(dir.path().join("onehistory.db"));
+        let download = |range: Option<&str>, if_range: Option<&str>| {
+            let (db, options, csv) = (db.clone(), options.clone(), csv.clone());
+            let (range, if_range) = (range.map(String::from), if_range.map(String::from));
+            async move {
+                let res = Server::download_csv(db, options, csv, range, if_range)
+                    .await
+                    .unwrap();
+                let (parts, body) = res.into_parts();
+                (parts, body::to_bytes(body).await.unwrap())
+            }
+        };
+
+        let (parts, full) = download(None, None).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(parts.headers[ACCEPT_RANGES], "bytes");
+        assert_eq!(parts.headers[CONTENT_LENGTH], full.len().to_string());
+        assert!(full.starts_with(b"time,title,url,visit_type\n"));
+        assert_eq!(full.split(|&b| b == b'\n').count(), 102);
+        let etag = parts.headers[ETAG].to_str().unwrap().to_string();
+
+        // Resume from byte 100
+        let (parts, partial) = download(Some("bytes=100-"), Some(&etag)).await;
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            parts.headers[CONTENT_RANGE],
+            format!("bytes 100-{}/{}", full.len() - 1, full.len())
+        );
+        assert_eq!(partial, full[100..]);
+        let range = format!("bytes={}-", full.len());
+        let (parts, _) = download(Some(&range), None).await;
+        assert_eq!(parts.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            parts.headers[CONTENT_RANGE],
+            format!("bytes */{}", full.len())
+        );
+
+        // Visits changed since download began, so the whole CSV is sent again
+        db.persist(
+            "History",
+            vec![VisitDetail {
+                url: "https://rust-lang.org/".to_string(),
+                title: "Rust".to_string(),
+                visit_time: 1_642_000_200_000_000,
+                visit_type: 1,
+                source_visit_id: None,
+            }],
+            &SilentCollector,
+        )
+        .unwrap();
+        let (parts, body) = download(Some("bytes=100-"), Some(&etag)).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_ne!(parts.headers[ETAG], etag.as_str());
+        assert!(body.len() > full.len());
+        let etag = parts.headers[ETAG].to_str().unwrap().to_string();
+
+        // Hidden by another process, without adding or removing visits
+        Database::open(dir.path().join("onehistory.db"))
+            .unwrap()
+            .hide_visits("https://rust-lang.org/%", true)
+            .unwrap();
+        let (parts, _) = download(None, None).await;
+        assert_ne!(parts.headers[ETAG], etag.as_str());
+        let etag = parts.headers[ETAG].to_str().unwrap().to_string();
+        // And by server itself
+        db.hide_visits("https://rust-lang.org/%", false).unwrap();
+        let (parts, _) = download(None, None).await;
+        assert_ne!(parts.headers[ETAG], etag.as_str());
+    }
+
+    #[test]
+    fn test_collapse_repeats() {
+        let visit = |url: &str, visit_time| VisitDetail {
+            url: url.to_string(),
+            title: String::new(),
+            visit_time,
+            visit_type: 1,
+            source_visit_id: None,
+        };
+        let groups = collapse_repeats(vec![
+            visit("https://mail.google.com/", 300),
+            visit("https://mail.google.com/", 200),
+            visit("https://mail.google.com/", 100),
+            visit("https://github.com/", 50),
+            visit("https://mail.google.com/", 10),
+        ]);
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].visit, visit("https://mail.google.com/", 300));
+        assert_eq!(
+            (groups[0].count, groups[0].start, groups[0].end),
+            (3, 100, 300)
+        );
+        assert_eq!(
+            (groups[1].count, groups[1].start, groups[1].end),
+            (1, 50, 50)
+        );
+        assert_eq!(groups[2].count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_collapse_param() {
+        let filter = warp::query::<DetailsQueryParams>();
+        for (query, collapse) in [("", true), ("collapse=0", false), ("collapse=true", true)] {
+            let params = warp::test::request()
+                .path(&format!("/?{query}"))
+                .filter(&filter)
+                .await
+                .unwrap();
+            assert_eq!(params.collapse, collapse, "{query}");
+        }
+        assert!(warp::test::request()
+            .path("/?collapse=yes")
+            .filter(&filter)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_serve_busy_addr() {
+        let dir = tempfile::tempdir().unwrap();
+        let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy_addr = busy.local_addr().unwrap();
+        let server = Server::try_new(
+            vec!["127.0.0.1:0".parse().unwrap(), busy_addr],
+            dir.path().join("onehistory.db"),
+            ServeOptions::default(),
+        )
+        .unwrap();
+        let err = server.serve().unwrap_err();
+        assert!(
+            format!("{err:#}").contains(&format!("listen on {busy_addr}")),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(Database::open(dir.path().join("onehistory.db")).unwrap());
+        let render = |refresh| {
+            let options = Arc::new(ServeOptions {
+                refresh,
+                ..Default::default()
+            });
+            let db = db.clone();
+            async move {
+                let res = Server::details(
+                    db,
+                    options,
+                    "2022-01-12".to_string(),
+                    DetailsQueryParams::default(),
+                    None,
+                )
+                .await
+                .unwrap()
+                .into_response();
+                let body = body::to_bytes(res.into_body()).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let html = render(Some(Duration::from_secs(30))).await;
+        assert!(html.contains(r#"<meta http-equiv="refresh" content="30">"#));
+        for disabled in [None, Some(Duration::ZERO)] {
+            assert!(!render(disabled).await.contains("http-equiv"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_details_domain() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+        let start = ymd_midnight("2022-01-12").unwrap();
+        let visits = [
+            ("https://github.com/", "GitHub"),
+            ("https://emacs-china.org/", "Emacs China"),
+            ("https://github.com/rust-lang", "Rust"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (url, title))| VisitDetail {
+            url: url.to_string(),
+            title: title.to_string(),
+            visit_time: (start + i as i64 * 60_000) * 1_000,
+            visit_type: 1,
+            source_visit_id: None,
+        })
+        .collect();
+        db.persist("History", visits, &SilentCollector).unwrap();
+        let db = Arc::new(db);
+
+        let render = |domain: Option<&str>| {
+            let db = db.clone();
+            let query_params = DetailsQueryParams {
+                domain: domain.map(|s| s.to_string()),
+                ..Default::default()
+            };
+            async move {
+                let res = Server::details(
+                    db,
+                    Default::default(),
+                    "2022-01-12".to_string(),
+                    query_params,
+                    None,
+                )
+                .await
+                .unwrap()
+                .into_response();
+                let body = body::to_bytes(res.into_body()).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let html = render(None).await;
+        assert!(html.contains("<strong>3</strong> visits"));
+        assert!(html.contains("<strong>2</strong> distinct domains"));
+        assert!(html.contains(r#"domain=github%2Ecom"#));
+        assert!(html.contains(r#"<a href="https://emacs-china.org/">"#));
+
+        // Summary still covers the whole day
+        let html = render(Some("github.com")).await;
+        assert!(html.contains("<strong>3</strong> visits"));
+        assert!(html.contains("2 results"));
+        assert!(!html.contains(r#"<a href="https://emacs-china.org/">"#));
+        assert!(!html.contains("#1 · History"));
+
+        let query_params = DetailsQueryParams {
+            debug: true,
+            ..Default::default()
+        };
+        let res = Server::details(
+            db,
+            Default::default(),
+            "2022-01-12".to_string(),
+            query_params,
+            None,
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("#1 · History"), "{html}");
+        assert!(html.contains("Hide sources"));
+    }
+
+    #[tokio::test]
+    async fn test_index_locales() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+        let visit = VisitDetail {
+            url: "https://github.com".to_string(),
+            title: "GitHub".to_string(),
+            visit_time: 1_642_000_000_000_000,
+            visit_type: 1,
+            source_visit_id: None,
+        };
+        db.persist("History", vec![visit], &SilentCollector)
+            .unwrap();
+        let db = Arc::new(db);
+        let render = |lang: Option<&str>, accept_language: Option<&str>| {
+            let db = db.clone();
+            let query_params = IndexQueryParams {
+                start: Some("2022-01-01".to_string()),
+                end: Some("2022-01-31".to_string()),
+                keyword: None,
+                keyword_op: KeywordOp::And,
+                lang: lang.map(|s| s.to_string()),
+                source: None,
+                include_hidden: false,
+                canonical: false,
+            };
+            let accept_language = accept_language.map(|s| s.to_string());
+            async move {
+                let res = Server::index(db, Default::default(), query_params, accept_language)
+                    .await
+                    .unwrap()
+                    .into_response();
+                let body = body::to_bytes(res.into_body()).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let html = render(None, None).await;
+        assert!(html.contains(r#"<html lang="en">"#));
+        assert!(html.contains("Browsing sessions"));
+        assert!(html.contains(r#""chart.daily":"Daily PV""#));
+
+        let html = render(Some("zh-CN"), Some("en")).await;
+        assert!(html.contains(r#"<html lang="zh-CN">"#));
+        assert!(html.contains("浏览会话"));
+        assert!(!html.contains("Browsing sessions"));
+
+        let html = render(None, Some("zh-CN,zh;q=0.9,en;q=0.8")).await;
+        assert!(html.contains("浏览会话"));
+    }
+
+    #[tokio::test]
+    async fn test_index_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+        let visit = VisitDetail {
+            url: "https://github.com".to_string(),
+            title: "GitHub".to_string(),
+            visit_time: 1_642_000_000_000_000,
+            visit_type: 1,
+            source_visit_id: None,
+        };
+        db.set_source_browser("History", SourceName::Chrome)
+            .unwrap();
+        db.persist("History", vec![visit], &SilentCollector)
+            .unwrap();
+        let db = Arc::new(db);
+        let render = |source: &str| {
+            let db = db.clone();
+            let query_params = IndexQueryParams {
+                start: Some("2022-01-01".to_string()),
+                end: Some("2022-01-31".to_string()),
+                keyword: None,
+                keyword_op: KeywordOp::And,
+                lang: None,
+                source: Some(source.to_string()),
+                include_hidden: false,
+                canonical: false,
+            };
+            async move {
+                Server::index(db, Default::default(), query_params, None)
+                    .await
+                    .map(|reply| reply.into_response())
+            }
+        };
+
+        let res = render("chrome").await.unwrap();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains(r#"<option value="1">#1 chrome · History (1)</option>"#));
+        assert!(html.contains("<td>https://github.com</td>"));
+        let res = render("unknown").await.unwrap();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!html.contains("<td>https://github.com</td>"));
+        // Bound as parameter, so it's a browser matching nothing
+        let res = render("'; DROP TABLE x").await.unwrap();
+        let body = body::to_bytes(res.into_body()).await.unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!html.contains("<td>https://github.com</td>"));
+    }
+
+    #[tokio::test]
+    async fn test_patch_visit() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("onehistory.db");
+        let db = Database::open(&db_file).unwrap();
+        let visit = VisitDetail {
+            url: "https://github.com".to_string(),
+            title: "GitHub".to_string(),
+            visit_time: 1_642_000_000_000_000,
+            visit_type: 1,
+            source_visit_id: None,
+        };
+        db.persist("History", vec![visit], &SilentCollector)
+            .unwrap();
+        let id = db
+            .select_visit_traces(1_642_000_000_000, 1_642_000_000_000)
+            .unwrap()[0]
+            .id;
+        let server = Server::try_new(
+            vec!["127.0.0.1:0".parse().unwrap()],
+            db_file,
+            Default::default(),
+        )
+        .unwrap();
+        let routes = server.api_routes();
+        let patch = |id: i64, body: &'static str| {
+            warp::test::request()
+                .method("PATCH")
+                .path(&format!("/api/visits/{id}"))
+                .body(body)
+                .reply(&routes)
+        };
+
+        let res = patch(id, r#"{"hidden":true}"#).await;
+        assert_eq!(res.status(), 200);
+        let trace: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(trace["hidden"], true);
+        assert!(db
+            .select_visits(1_642_000_000_000, 1_642_000_000_000, None, false)
+            .unwrap()
+            .is_empty());
+        assert_eq!(patch(id + 1, r#"{"hidden":true}"#).await.status(), 404);
+        assert_eq!(patch(id, r#"{"hidden":1}"#).await.status(), 400);
+        // Reading is still GET
+        let res = warp::test::request()
+            .path(&format!("/api/visits/{id}"))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+    }
 }
@@ -1,6 +1,31 @@
 const SHOW_FORMAT = "YYYY-MM-DD";
 
-function configChart(dailyVisits, titleTop100, domainTop100, keyword) {
+// Messages of current locale, set by pages before this script
+function t(key) {
+  return (typeof I18N !== 'undefined' && I18N[key]) || key;
+}
+
+function langParam() {
+  return typeof LANG !== 'undefined' ? `&lang=${LANG}` : '';
+}
+
+// Source selected in index page, kept by links to details page
+function sourceParam() {
+  let source = $('#source').val();
+  return source ? `&source=${encodeURIComponent(source)}` : '';
+}
+
+// Whether variants of urls are grouped, kept by links to details page
+function canonicalParam() {
+  return $('#canonical').is(':checked') ? '&canonical=1' : '';
+}
+
+// Whether any term of keyword matches, rather than all
+function opParam() {
+  return $('#any').is(':checked') ? '&op=or' : '';
+}
+
+function configChart(dailyVisits, titleTop100, domainTop100, tldBreakdown, keyword) {
   require.config({
     paths: {
       echarts: '/static/js'
@@ -14,15 +39,16 @@ function configChart(dailyVisits, titleTop100, domainTop100, keyword) {
       'echarts/chart/bar',
       'echarts/chart/funnel'
     ],
-    initCharts(dailyVisits, titleTop100, domainTop100, keyword)
+    initCharts(dailyVisits, titleTop100, domainTop100, tldBreakdown, keyword)
   );
 }
 
-function initCharts(dailyVisits, titleTop, domainTop, keyword) {
+function initCharts(dailyVisits, titleTop, domainTop, tldBreakdown, keyword) {
   return function(ec) {
     initDailyVisits(ec, dailyVisits, keyword);
-    initTop10(ec, titleTop, 'titleTop10', 'TOP10 sites(by title)');
-    initTop10(ec, domainTop, 'domainTop10', 'TOP10 sites(by domain)');
+    initTop10(ec, titleTop, 'titleTop10', t('chart.top_titles'));
+    initTop10(ec, domainTop, 'domainTop10', t('chart.top_domains'));
+    initTop10(ec, tldBreakdown, 'tldBreakdown', t('chart.top_tlds'));
   };
 }
 
@@ -33,13 +59,13 @@ function initDailyVisits(ec, dailyVisits, keyword) {
   dailyVisitsChart.setOption({
     color: ['#23B7E5'],
     title : {
-      text : 'Daily PV',
-      subtext : 'Click any node to view details'
+      text : t('chart.daily'),
+      subtext : t('chart.daily_hint')
     },
     tooltip : {
       trigger: 'item',
       formatter : function (params) {
-        return `${moment(params.value[0]).format(SHOW_FORMAT)} <br/> PV: ${params.value[1]}`;
+        return `${moment(params.value[0]).format(SHOW_FORMAT)} <br/> ${t('chart.pv')}: ${params.value[1]}`;
       }
     },
     toolbox: {
@@ -57,7 +83,7 @@ function initDailyVisits(ec, dailyVisits, keyword) {
       start : 0
     },
     legend : {
-      data : ['Page View']
+      data : [t('chart.page_view')]
     },
     grid: {
       y2: 100
@@ -70,13 +96,13 @@ function initDailyVisits(ec, dailyVisits, keyword) {
     ],
     yAxis : [
       {
-        name: 'PV',
+        name: t('chart.pv'),
         type : 'value'
       }
     ],
     series : [
       {
-        name: 'Page View',
+        name: t('chart.page_view'),
         type: 'line',
         showAllSymbol: true,
         symbolSize: function (value){
@@ -91,7 +117,7 @@ function initDailyVisits(ec, dailyVisits, keyword) {
     ]
   });
   dailyVisitsChart.on(ecConfig.EVENT.CLICK, function(params) {
-    let url = `/details/${moment(params.value[0]).format(SHOW_FORMAT)}?keyword=${keyword}`;
+    let url = `/details/${moment(params.value[0]).format(SHOW_FORMAT)}?keyword=${keyword}${opParam()}${sourceParam()}${canonicalParam()}${langParam()}`;
     window.open(url, '_blank');
   });
 
@@ -146,7 +172,7 @@ function initTop10(ec, topItems, eleId, title) {
     calculable : true,
     series : [
       {
-        name:'Title: ',
+        name: t('chart.title'),
         type:'pie',
         radius : '75%',
         center: ['50%', '60%'],
@@ -156,6 +182,29 @@ function initTop10(ec, topItems, eleId, title) {
   });
 }
 
+// Punch card of visits, rows are weekdays starting from Sunday, columns are hours
+function initHourWeekday(eleId, counts) {
+  var weekdays = t('chart.weekdays').split(',');
+  var max = _.max(_.map(counts, function(row) { return _.max(row); }));
+  var table = $('#' + eleId);
+  var header = $('<tr>').append($('<th>'));
+  for (var hour = 0; hour < 24; hour++) {
+    header.append($('<th class="text-center">').text(hour));
+  }
+  table.append(header);
+  _.each(counts, function(row, weekday) {
+    var tr = $('<tr>').append($('<th>').text(weekdays[weekday]));
+    _.each(row, function(cnt, hour) {
+      var alpha = max > 0 ? cnt / max : 0;
+      tr.append($('<td>')
+                .attr('title', `${weekdays[weekday]} ${hour}:00 - ${hour + 1}:00, ${t('chart.pv')}: ${cnt}`)
+                .css('background-color', `rgba(35, 183, 229, ${alpha.toFixed(2)})`)
+                .html('&nbsp;'));
+    });
+    table.append(tr);
+  });
+}
+
 function chooseDaterangeCB(start, end) {
   $('#browse_range span').html(`${start.format(SHOW_FORMAT)} - ${end.format(SHOW_FORMAT)}`);
 }
@@ -164,5 +213,5 @@ function ohsearchIndex() {
   let kw = $('#keyword').val();
   let range = $('#browse_range').data('daterangepicker');
 
-  window.location = `/?start=${range.startDate.format(SHOW_FORMAT)}&end=${range.endDate.format(SHOW_FORMAT)}&keyword=${encodeURIComponent(kw)}`;
+  window.location = `/?start=${range.startDate.format(SHOW_FORMAT)}&end=${range.endDate.format(SHOW_FORMAT)}&keyword=${encodeURIComponent(kw)}${opParam()}${sourceParam()}${canonicalParam()}${langParam()}`;
 }
@@ -0,0 +1,1263 @@
+mod common;
+
+use common::{create_chrome_history, create_firefox_history, create_safari_history};
+use onehistory::{
+    backup, full_timerange, BackupOptions, BackupRun, BackupStatus, BackupTiming, ChannelCollector,
+    Database, SilentCollector, Source, SourceName,
+};
+use rusqlite::Connection;
+use std::{
+    sync::mpsc::channel,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn test_backup_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &history_file,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000),
+            ("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+            ("https://github.com/", "GitHub", 1_642_000_002_000),
+        ],
+    );
+    let db_file = dir.path().join("onehistory.db");
+
+    let (tx, rx) = channel();
+    backup(
+        vec![history_file],
+        &db_file,
+        &BackupOptions::default(),
+        &ChannelCollector::new(tx),
+        &SilentCollector,
+    )
+    .unwrap();
+
+    let events = rx.iter().collect::<Vec<_>>();
+    assert_eq!(events, vec![(0, 3), (3, 3)]);
+
+    let (start, end) = full_timerange();
+    let db = Database::open(&db_file).unwrap();
+    assert_eq!(db.select_visits(start, end, None, false).unwrap().len(), 3);
+}
+
+#[test]
+fn test_backup_exclude_internal() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &history_file,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000),
+            ("file:///Users/x/a.html", "a.html", 1_642_000_001_000),
+            ("about:blank", "", 1_642_000_002_000),
+            ("chrome://settings", "Settings", 1_642_000_003_000),
+        ],
+    );
+    let db_file = dir.path().join("onehistory.db");
+
+    let summary = backup(
+        vec![history_file],
+        &db_file,
+        &BackupOptions {
+            exclude_internal: true,
+            ..Default::default()
+        },
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { found: 1, .. }
+    ));
+
+    let (start, end) = full_timerange();
+    let db = Database::open(&db_file).unwrap();
+    let urls = db
+        .select_visits(start, end, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| v.url)
+        .collect::<Vec<_>>();
+    assert_eq!(urls, vec!["https://github.com/"]);
+}
+
+#[test]
+fn test_backup_min_dwell() {
+    let dir = tempfile::tempdir().unwrap();
+    let chrome = dir.path().join("History");
+    create_chrome_history(
+        &chrome,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000, 0),
+            (
+                "https://emacs-china.org/",
+                "Emacs China",
+                1_642_000_001_000,
+                500,
+            ),
+            ("https://github.com/", "GitHub", 1_642_000_002_000, 60_000),
+        ],
+    );
+    let firefox = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &firefox,
+        &[("https://emacs-china.org/", "Emacs China", 1_642_000_003_000)],
+    );
+    let db_file = dir.path().join("onehistory.db");
+
+    let summary = backup(
+        vec![chrome, firefox],
+        &db_file,
+        &BackupOptions {
+            min_dwell: Some(Duration::from_secs(1)),
+            ..Default::default()
+        },
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { found: 1, .. }
+    ));
+    // Firefox has no visit duration, all visits are kept
+    assert!(matches!(
+        summary.reports[1].status,
+        BackupStatus::Ok { found: 1, .. }
+    ));
+
+    let (start, end) = full_timerange();
+    let db = Database::open(&db_file).unwrap();
+    let visits = db
+        .select_visits(start, end, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| v.visit_time)
+        .collect::<Vec<_>>();
+    assert_eq!(visits, vec![1_642_000_002_000, 1_642_000_003_000]);
+}
+
+#[test]
+fn test_backup_flatten_redirects() {
+    let dir = tempfile::tempdir().unwrap();
+    let chrome = dir.path().join("History");
+    create_chrome_history(
+        &chrome,
+        &[
+            ("http://github.com/", "", 1_642_000_000_000, 0),
+            ("https://github.com/", "GitHub", 1_642_000_000_001, 0),
+            ("https://t.co/abc", "", 1_642_000_001_000, 0),
+            (
+                "https://emacs-china.org/",
+                "Emacs China",
+                1_642_000_001_001,
+                0,
+            ),
+        ],
+    );
+    // Server redirect and client redirect
+    let conn = Connection::open(&chrome).unwrap();
+    conn.execute_batch(
+        "UPDATE visits SET from_visit = 1, transition = -2147483647 WHERE id = 2;
+         UPDATE visits SET from_visit = 3, transition = 1073741825 WHERE id = 4;",
+    )
+    .unwrap();
+    let firefox = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &firefox,
+        &[
+            ("http://rust-lang.org/", "", 1_642_000_002_000),
+            ("https://rust-lang.org/", "Rust", 1_642_000_002_001),
+        ],
+    );
+    Connection::open(&firefox)
+        .unwrap()
+        .execute(
+            "UPDATE moz_historyvisits SET from_visit = 1, visit_type = 5 WHERE id = 2",
+            [],
+        )
+        .unwrap();
+
+    let found = |flatten_redirects| {
+        let summary = backup(
+            vec![chrome.clone(), firefox.clone()],
+            dir.path().join("onehistory.db"),
+            &BackupOptions {
+                dry_run: true,
+                flatten_redirects,
+                ..Default::default()
+            },
+            &SilentCollector,
+            &SilentCollector,
+        )
+        .unwrap();
+        (summary.found(), summary.reports.len())
+    };
+    assert_eq!(found(false), (6, 2));
+    assert_eq!(found(true), (3, 2));
+
+    let db_file = dir.path().join("onehistory.db");
+    backup(
+        vec![chrome, firefox],
+        &db_file,
+        &BackupOptions {
+            flatten_redirects: true,
+            ..Default::default()
+        },
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    let (start, end) = full_timerange();
+    let urls = Database::open(&db_file)
+        .unwrap()
+        .select_visits(start, end, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| v.url)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        urls,
+        vec![
+            "https://github.com/",
+            "https://emacs-china.org/",
+            "https://rust-lang.org/"
+        ]
+    );
+}
+
+#[test]
+fn test_backup_partial_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let good = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &good,
+        &[("https://github.com/", "GitHub", 1_642_000_000_000)],
+    );
+    let unknown = dir.path().join("unknown.sqlite");
+    Connection::open(&unknown)
+        .unwrap()
+        .execute_batch("CREATE TABLE foo (id integer)")
+        .unwrap();
+    let missing = dir.path().join("missing.sqlite");
+    let db_file = dir.path().join("onehistory.db");
+
+    let summary = backup(
+        vec![good, unknown, missing],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok {
+            found: 1,
+            imported: 1,
+            duplicated: 0
+        }
+    ));
+    assert!(matches!(summary.reports[1].status, BackupStatus::Failed(_)));
+    assert!(matches!(
+        summary.reports[2].status,
+        BackupStatus::Skipped(_)
+    ));
+    assert_eq!(summary.failed(), 1);
+    assert_eq!(summary.imported(), 1);
+
+    // Runs are listed most recent first, with errors of failed files
+    let db = Database::open(&db_file).unwrap();
+    db.record_backup_run(&BackupRun::failed(1, 2, "disk full"))
+        .unwrap();
+    db.record_backup_run(&summary.to_run(3, 4)).unwrap();
+    let runs = db.select_backup_runs(20).unwrap();
+    assert_eq!(runs.len(), 2);
+    let run = &runs[0];
+    assert_eq!(
+        (
+            run.started_at,
+            run.finished_at,
+            run.sources,
+            run.imported,
+            run.duplicated,
+            run.skipped
+        ),
+        (3, 4, 3, 1, 0, 1)
+    );
+    assert_eq!(run.errors.len(), 1);
+    assert!(run.errors[0].contains("unknown.sqlite"));
+    assert_eq!(runs[1].errors, vec!["disk full".to_string()]);
+    assert_eq!(db.select_backup_runs(1).unwrap().len(), 1);
+}
+
+#[test]
+fn test_backup_jobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut history_files = (0..4)
+        .map(|i| {
+            let path = dir.path().join(format!("places-{i}.sqlite"));
+            let visits = (0..=i)
+                .map(|j| {
+                    (
+                        "https://github.com/",
+                        "GitHub",
+                        1_642_000_000_000 + j * 1_000,
+                    )
+                })
+                .collect::<Vec<_>>();
+            create_firefox_history(&path, &visits);
+            path
+        })
+        .collect::<Vec<_>>();
+    history_files.insert(2, dir.path().join("missing.sqlite"));
+    let db_file = dir.path().join("onehistory.db");
+
+    // More readers than the queue holds, so they wait for the writer
+    let summary = backup(
+        history_files.clone(),
+        &db_file,
+        &BackupOptions {
+            jobs: 3,
+            queue_size: 1,
+            ..Default::default()
+        },
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+
+    // Reports are in the order passed, however files are read
+    let paths = summary
+        .reports
+        .iter()
+        .map(|r| r.path.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, history_files);
+    let found = summary
+        .reports
+        .iter()
+        .map(|r| match r.status {
+            BackupStatus::Ok { found, .. } => found,
+            _ => 0,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![1, 2, 0, 3, 4]);
+    assert!(matches!(
+        summary.reports[2].status,
+        BackupStatus::Skipped(_)
+    ));
+    assert_eq!(summary.found(), 10);
+    // Files are written in the order passed, so visits are imported by the first one
+    let imported = summary
+        .reports
+        .iter()
+        .map(|r| match r.status {
+            BackupStatus::Ok {
+                imported,
+                duplicated,
+                ..
+            } => (imported, duplicated),
+            _ => (0, 0),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(imported, vec![(1, 0), (1, 1), (0, 0), (1, 2), (1, 3)]);
+
+    let (start, end) = full_timerange();
+    let db = Database::open(&db_file).unwrap();
+    assert_eq!(db.select_visits(start, end, None, false).unwrap().len(), 4);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_backup_chromium_fast_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let chrome = dir.path().join("History");
+    create_chrome_history(
+        &chrome,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000, 0),
+            ("https://t.co/abc", "", 1_642_000_001_000, 0),
+            (
+                "https://emacs-china.org/",
+                "Emacs China",
+                1_642_000_002_000,
+                0,
+            ),
+            ("https://github.com/", "GitHub", 1_642_000_003_000, 0),
+            ("https://rust-lang.org/", "Rust", 1_642_000_004_000, 0),
+        ],
+    );
+    // Same visit twice in history file
+    Connection::open(&chrome)
+        .unwrap()
+        .execute(
+            "INSERT INTO visits (url, visit_time, transition) SELECT url, visit_time, 1 FROM visits WHERE id = 1",
+            [],
+        )
+        .unwrap();
+    let firefox = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &firefox,
+        &[
+            ("https://rust-lang.org/", "Rust Language", 1_642_000_004_000),
+            ("https://docs.rs/", "Docs.rs", 1_642_000_005_000),
+        ],
+    );
+
+    // Import firefox first, so some urls and visits already exist
+    let prepare = |name: &str| {
+        let db_file = dir.path().join(name);
+        backup(
+            vec![firefox.clone()],
+            &db_file,
+            &BackupOptions::default(),
+            &SilentCollector,
+            &SilentCollector,
+        )
+        .unwrap();
+        let db = Database::open(&db_file).unwrap();
+        db.rebuild_fuzzy_index().unwrap();
+        (db_file, db)
+    };
+    let (fast_file, _) = prepare("fast.db");
+    let (generic_file, generic) = prepare("generic.db");
+
+    for round in 0..2 {
+        let summary = backup(
+            vec![chrome.clone()],
+            &fast_file,
+            &BackupOptions::default(),
+            &SilentCollector,
+            &SilentCollector,
+        )
+        .unwrap();
+        let (start, end) = full_timerange();
+        let rows = Source::open(&chrome)
+            .unwrap()
+            .select(start, end)
+            .unwrap()
+            .collect::<Vec<_>>();
+        let (imported, duplicated) = generic
+            .persist(&chrome.to_string_lossy(), rows, &SilentCollector)
+            .unwrap();
+        assert!(matches!(
+            summary.reports[0].status,
+            BackupStatus::Ok { found: 6, imported: i, duplicated: d } if i == imported && d == duplicated
+        ));
+        assert_eq!(
+            (imported, duplicated),
+            if round == 0 { (4, 2) } else { (0, 6) }
+        );
+    }
+
+    let dump = |db_file| {
+        let conn = Connection::open(db_file).unwrap();
+        [
+            "SELECT id, url, title FROM onehistory_urls",
+            "SELECT id, item_id, visit_time, visit_type, source_id, source_visit_id FROM onehistory_visits",
+            "SELECT day, host, visits FROM onehistory_daily_summary",
+            "SELECT data_path, last_import, imported, duplicated, in_progress FROM import_records",
+            "SELECT trigram, url_id FROM onehistory_title_trigrams",
+            "SELECT rowid, url, title FROM onehistory_urls_fts WHERE onehistory_urls_fts MATCH 'https'",
+        ]
+        .iter()
+        .map(|sql| {
+            let mut stat = conn.prepare(&format!("{sql} ORDER BY 1, 2")).unwrap();
+            let columns = stat.column_count();
+            stat.query_map([], |row| {
+                (0..columns)
+                    .map(|i| row.get::<_, rusqlite::types::Value>(i))
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap()
+        })
+        .collect::<Vec<_>>()
+    };
+    let fast = dump(&fast_file);
+    assert_eq!(fast, dump(&generic_file));
+    // Existing url keeps its title
+    assert!(fast[0].contains(&vec![
+        1.into(),
+        "https://rust-lang.org/".to_string().into(),
+        "Rust Language".to_string().into(),
+    ]));
+    assert!(!Database::open(&fast_file).unwrap().reindex().unwrap());
+}
+
+// Compare set-based import of Chromium history with the batch path, on a fixture of
+// `ONEHISTORY_BENCH_VISITS`(1M by default) visits over a fifth as many urls:
+// cargo test --release --test backup -- --ignored bench_chromium_import --nocapture
+#[test]
+#[ignore]
+fn bench_chromium_import() {
+    let visits = std::env::var("ONEHISTORY_BENCH_VISITS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(1_000_000);
+    let dir = tempfile::tempdir().unwrap();
+    let chrome = dir.path().join("History");
+    create_chrome_history(&chrome, &[]);
+    Connection::open(&chrome)
+        .unwrap()
+        .execute_batch(&format!(
+            r#"
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {urls})
+INSERT INTO urls (id, url, title) SELECT i, 'https://example.com/' || i, 'Page ' || i FROM n;
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < {visits})
+INSERT INTO visits (url, visit_time, transition)
+    SELECT (i - 1) % {urls} + 1, (1642000000000 + i * 1000 + 11644473600000) * 1000, 1 FROM n;
+"#,
+            urls = (visits / 5).max(1)
+        ))
+        .unwrap();
+
+    let fast_file = dir.path().join("fast.db");
+    let begin = Instant::now();
+    let summary = backup(
+        vec![chrome.clone()],
+        &fast_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    let fast = begin.elapsed();
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { imported: i, .. } if i == visits as usize
+    ));
+
+    let begin = Instant::now();
+    let (start, end) = full_timerange();
+    let rows = Source::open(&chrome)
+        .unwrap()
+        .select(start, end)
+        .unwrap()
+        .collect::<Vec<_>>();
+    let (imported, _) = Database::open(dir.path().join("batch.db"))
+        .unwrap()
+        .persist(&chrome.to_string_lossy(), rows, &SilentCollector)
+        .unwrap();
+    let batch = begin.elapsed();
+    assert_eq!(imported, visits as usize);
+    println!("{visits} visits, set-based: {fast:?}, batch: {batch:?}");
+}
+
+#[test]
+fn test_backup_safari_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    // Such as a copy in Time Machine backup, detected by schema rather than name
+    let snapshot = dir
+        .path()
+        .join("Backups.backupdb")
+        .join("Latest")
+        .join("History.db.snapshot");
+    std::fs::create_dir_all(snapshot.parent().unwrap()).unwrap();
+    create_safari_history(
+        &snapshot,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000),
+            ("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+        ],
+    );
+    assert!(matches!(
+        Source::open_read_only(&snapshot).unwrap().name(),
+        SourceName::Safari
+    ));
+
+    let db_file = dir.path().join("onehistory.db");
+    let summary = backup(
+        vec![snapshot],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(summary.imported(), 2);
+    let (start, end) = full_timerange();
+    let visits = Database::open(&db_file)
+        .unwrap()
+        .select_visits(start, end, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| (v.title, v.visit_time))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        visits,
+        vec![
+            ("GitHub".to_string(), 1_642_000_000_000),
+            ("Emacs China".to_string(), 1_642_000_001_000)
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_backup_duplicated_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &history_file,
+        &[("https://github.com/", "GitHub", 1_642_000_000_000)],
+    );
+    let link = dir.path().join("link.sqlite");
+    std::os::unix::fs::symlink(&history_file, &link).unwrap();
+
+    let summary = backup(
+        vec![
+            history_file.clone(),
+            dir.path().join(".").join("places.sqlite"),
+            link,
+            dir.path().join("missing.sqlite"),
+        ],
+        dir.path().join("onehistory.db"),
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    let paths = summary
+        .reports
+        .iter()
+        .map(|r| r.path.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, vec![history_file, dir.path().join("missing.sqlite")]);
+    assert_eq!((summary.imported(), summary.duplicated()), (1, 0));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_backup_non_utf8_path() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join(OsStr::from_bytes(b"pl\xffaces.sqlite"));
+    create_firefox_history(
+        &history_file,
+        &[("https://github.com/", "GitHub", 1_642_000_000_000)],
+    );
+
+    let summary = backup(
+        vec![history_file],
+        dir.path().join("onehistory.db"),
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(summary.imported(), 1);
+    assert!(summary.to_string().contains("pl\u{FFFD}aces.sqlite"));
+}
+
+#[test]
+fn test_backup_locked_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &history_file,
+        &[("https://github.com/", "GitHub", 1_642_000_000_000)],
+    );
+    // Lock it like a running browser
+    let conn = Connection::open(&history_file).unwrap();
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE;")
+        .unwrap();
+
+    let db_file = dir.path().join("onehistory.db");
+    let (tx, rx) = channel();
+    let summary = backup(
+        vec![history_file.clone()],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &ChannelCollector::new(tx),
+    )
+    .unwrap();
+    drop(conn);
+
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { imported: 1, .. }
+    ));
+    let size = std::fs::metadata(&history_file).unwrap().len();
+    let events = rx.iter().collect::<Vec<_>>();
+    assert_eq!(events.first(), Some(&(0, size)));
+    assert_eq!(events.last(), Some(&(size, size)));
+    // Import record belongs to the original file, not the copy
+    let db = Database::open(&db_file).unwrap();
+    assert!(db
+        .select_last_import(&history_file.to_string_lossy())
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn test_whatsnew() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    let db_file = dir.path().join("onehistory.db");
+    let backup_once = |visits: &[(&str, &str, i64)]| {
+        create_firefox_history(&history_file, visits);
+        backup(
+            vec![history_file.clone()],
+            &db_file,
+            &BackupOptions::default(),
+            &SilentCollector,
+            &SilentCollector,
+        )
+        .unwrap();
+    };
+
+    assert!(Database::open(&db_file)
+        .unwrap()
+        .select_whatsnew()
+        .unwrap()
+        .is_none());
+    backup_once(&[("https://github.com/", "GitHub", 1_642_000_000_000)]);
+    std::fs::remove_file(&history_file).unwrap();
+    backup_once(&[
+        ("https://github.com/", "GitHub", 1_642_000_000_000),
+        ("https://github.com/pulls", "Pulls", 1_642_000_001_000),
+        ("https://emacs-china.org/", "Emacs China", 1_642_000_002_000),
+        ("https://github.com/", "GitHub", 1_642_000_003_000),
+    ]);
+
+    let db = Database::open(&db_file).unwrap();
+    let whatsnew = db.select_whatsnew().unwrap().unwrap();
+    assert_eq!(whatsnew.visits, 3);
+    assert_eq!(
+        whatsnew.domains,
+        vec![
+            ("github.com".to_string(), 2),
+            ("emacs-china.org".to_string(), 1)
+        ]
+    );
+}
+
+#[test]
+fn test_repair_dangling_visits() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &history_file,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000),
+            ("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+            ("https://github.com/", "GitHub", 1_642_000_002_000),
+        ],
+    );
+    let db_file = dir.path().join("onehistory.db");
+    backup(
+        vec![history_file],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+
+    // Url of github is lost, and one visit is written without url nor source
+    let conn = Connection::open(&db_file).unwrap();
+    conn.execute_batch(
+        r#"
+DELETE FROM onehistory_urls WHERE url = 'https://github.com/';
+INSERT INTO onehistory_visits (item_id, visit_time) VALUES (999, 1642000003000000);
+"#,
+    )
+    .unwrap();
+    let github_id: i64 = conn
+        .query_row(
+            "SELECT item_id FROM onehistory_visits ORDER BY id LIMIT 1",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+
+    let db = Database::open(&db_file).unwrap();
+    assert_eq!(db.foreign_key_check().unwrap(), 0);
+    assert_eq!(
+        db.select_dangling_items().unwrap(),
+        vec![(github_id, 2), (999, 1)]
+    );
+    assert_eq!(db.restore_dangling_urls().unwrap(), 1);
+    assert_eq!(db.select_dangling_items().unwrap(), vec![(999, 1)]);
+    let (start, end) = full_timerange();
+    let visits = db.select_visits(start, end, Some("github".to_string()), false);
+    assert_eq!(visits.unwrap().len(), 2);
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+
+    assert_eq!(db.delete_dangling_visits().unwrap(), 1);
+    assert!(db.select_dangling_items().unwrap().is_empty());
+    assert_eq!(db.restore_dangling_urls().unwrap(), 0);
+}
+
+#[test]
+fn test_backup_retention() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    let now = chrono::Local::now().timestamp_millis();
+    let day = 86_400_000;
+    create_firefox_history(
+        &history_file,
+        &[
+            ("https://github.com/", "GitHub", now - 40 * day),
+            ("https://emacs-china.org/", "Emacs China", now - 10 * day),
+            ("https://emacs-china.org/", "Emacs China", now - day),
+        ],
+    );
+    let db_file = dir.path().join("onehistory.db");
+    let backup_with = |retention_days| {
+        backup(
+            vec![history_file.clone()],
+            &db_file,
+            &BackupOptions {
+                retention_days,
+                ..Default::default()
+            },
+            &SilentCollector,
+            &SilentCollector,
+        )
+        .unwrap()
+    };
+    assert_eq!(backup_with(None).pruned, 0);
+    // 0 days would prune everything, so it's disabled
+    let summary = backup_with(Some(0));
+    assert_eq!(summary.pruned, 0);
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { found: 3, .. }
+    ));
+
+    // Old visit is neither imported again nor kept
+    let summary = backup_with(Some(30));
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { found: 2, .. }
+    ));
+    assert_eq!(summary.pruned, 1);
+    let db = Database::open(&db_file).unwrap();
+    let (start, end) = full_timerange();
+    assert_eq!(db.select_visits(start, end, None, false).unwrap().len(), 2);
+    assert_eq!(db.count_orphan_urls().unwrap(), 0);
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+    assert_eq!(backup_with(Some(30)).pruned, 0);
+
+    let mut run = summary.to_run(1, 2);
+    run.retention_days = Some(30);
+    db.record_backup_run(&run).unwrap();
+    let runs = db.select_backup_runs(1).unwrap();
+    assert_eq!((runs[0].pruned, runs[0].retention_days), (1, Some(30)));
+}
+
+#[test]
+fn test_backup_profile_timing() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    let urls = (0..500)
+        .map(|i| format!("https://example.com/{i}"))
+        .collect::<Vec<_>>();
+    let visits = urls
+        .iter()
+        .enumerate()
+        .map(|(i, url)| {
+            (
+                url.as_str(),
+                "Example",
+                1_642_000_000_000 + i as i64 * 1_000,
+            )
+        })
+        .collect::<Vec<_>>();
+    create_firefox_history(&history_file, &visits);
+    let db_file = dir.path().join("onehistory.db");
+
+    let summary = backup(
+        vec![history_file.clone()],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert!(summary.timings.is_empty());
+
+    let db_file = dir.path().join("profiled.db");
+    let begin = Instant::now();
+    let summary = backup(
+        vec![history_file.clone()],
+        &db_file,
+        &BackupOptions {
+            profile_timing: true,
+            ..Default::default()
+        },
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    let wall = begin.elapsed();
+    assert_eq!(summary.timings.len(), 1);
+    let BackupTiming { path, phases } = &summary.timings[0];
+    assert_eq!(path, &history_file.display().to_string());
+    assert_eq!(phases.rows, 500);
+    assert_eq!(phases.copy, Duration::ZERO);
+    assert!(phases.select > Duration::ZERO && phases.urls > Duration::ZERO);
+    assert!(phases.visits > Duration::ZERO && phases.commit > Duration::ZERO);
+    // Phases cover most of the time of the file, which is most of the backup
+    assert!(phases.total <= wall, "{phases:?} {wall:?}");
+    assert!(phases.phases() <= phases.total, "{phases:?}");
+    assert!(phases.phases() * 2 >= phases.total, "{phases:?}");
+    assert!(phases.rows_per_sec() > 0.0);
+    assert!(summary.timing_table().contains("places.sqlite"));
+
+    // Kept in backup runs
+    let db = Database::open(&db_file).unwrap();
+    db.record_backup_run(&summary.to_run(0, 1)).unwrap();
+    let runs = db.select_backup_runs(1).unwrap();
+    assert_eq!(runs[0].timings.len(), 1);
+    assert_eq!(runs[0].timings[0].phases.rows, 500);
+    let json = serde_json::to_value(&runs[0]).unwrap();
+    assert!(json["timings"][0]["commit_ms"].as_f64().unwrap() > 0.0);
+}
+
+#[test]
+fn test_backup_chrome_schema_versions() {
+    let dir = tempfile::tempdir().unwrap();
+    // Old schema without titles, transitions, durations and meta
+    let old = dir.path().join("old").join("History");
+    std::fs::create_dir_all(old.parent().unwrap()).unwrap();
+    let conn = Connection::open(&old).unwrap();
+    conn.execute_batch(
+        r#"
+CREATE TABLE urls (id INTEGER PRIMARY KEY, url LONGVARCHAR UNIQUE);
+CREATE TABLE visits (id INTEGER PRIMARY KEY, url INTEGER NOT NULL, visit_time INTEGER NOT NULL);
+INSERT INTO urls (id, url) VALUES (1, 'https://github.com/');
+INSERT INTO visits (url, visit_time) VALUES (1, 13286473600000000);
+"#,
+    )
+    .unwrap();
+    drop(conn);
+    // New schema with columns unknown yet
+    let new = dir.path().join("new").join("History");
+    std::fs::create_dir_all(new.parent().unwrap()).unwrap();
+    create_chrome_history(
+        &new,
+        &[(
+            "https://emacs-china.org/",
+            "Emacs China",
+            1_642_000_001_000,
+            0,
+        )],
+    );
+    let conn = Connection::open(&new).unwrap();
+    conn.execute_batch(
+        r#"
+ALTER TABLE visits ADD COLUMN is_known_to_sync BOOLEAN DEFAULT FALSE NOT NULL;
+ALTER TABLE urls ADD COLUMN visited_link_id INTEGER DEFAULT 0 NOT NULL;
+CREATE TABLE meta (key LONGVARCHAR NOT NULL UNIQUE PRIMARY KEY, value LONGVARCHAR);
+INSERT INTO meta (key, value) VALUES ('version', '69');
+"#,
+    )
+    .unwrap();
+    drop(conn);
+    let db_file = dir.path().join("onehistory.db");
+
+    for options in [
+        BackupOptions::default(),
+        BackupOptions {
+            min_dwell: Some(Duration::from_secs(1)),
+            flatten_redirects: true,
+            ..Default::default()
+        },
+    ] {
+        let summary = backup(
+            vec![old.clone(), new.clone()],
+            &db_file,
+            &options,
+            &SilentCollector,
+            &SilentCollector,
+        )
+        .unwrap();
+        for report in &summary.reports {
+            assert!(
+                matches!(report.status, BackupStatus::Ok { .. }),
+                "{report:?}"
+            );
+        }
+    }
+
+    let (start, end) = full_timerange();
+    let db = Database::open(&db_file).unwrap();
+    let visits = db
+        .select_visits(start, end, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| (v.title, v.visit_time))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        visits,
+        vec![
+            ("".to_string(), 1_642_000_000_000),
+            ("Emacs China".to_string(), 1_642_000_001_000),
+        ]
+    );
+    let mut versions = db
+        .select_sources()
+        .unwrap()
+        .into_iter()
+        .map(|s| s.schema_version)
+        .collect::<Vec<_>>();
+    versions.sort();
+    assert_eq!(versions, vec![None, Some("69".to_string())]);
+}
+
+#[test]
+fn test_source_schema_tested() {
+    let dir = tempfile::tempdir().unwrap();
+    let chrome = dir.path().join("History");
+    create_chrome_history(
+        &chrome,
+        &[("https://github.com/", "GitHub", 1_642_000_000_000, 0)],
+    );
+    let firefox = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &firefox,
+        &[("https://github.com/", "GitHub", 1_642_000_000_000)],
+    );
+
+    // Versions not recorded are taken as tested
+    for path in [&chrome, &firefox] {
+        let s = Source::open(path).unwrap();
+        assert_eq!(s.schema_version(), None);
+        assert!(s.is_schema_tested());
+    }
+
+    // Fixtures at the highest tested versions, Chrome 70 and Firefox 78
+    let conn = Connection::open(&chrome).unwrap();
+    conn.execute_batch(
+        r#"
+CREATE TABLE meta (key LONGVARCHAR NOT NULL UNIQUE PRIMARY KEY, value LONGVARCHAR);
+INSERT INTO meta (key, value) VALUES ('version', '70');
+"#,
+    )
+    .unwrap();
+    let s = Source::open(&chrome).unwrap();
+    assert_eq!(s.schema_version(), Some("70"));
+    assert!(s.is_schema_tested());
+    Connection::open(&firefox)
+        .unwrap()
+        .pragma_update(None, "user_version", 78)
+        .unwrap();
+    let s = Source::open(&firefox).unwrap();
+    assert_eq!(s.schema_version(), Some("78"));
+    assert!(s.is_schema_tested());
+    let summary = backup(
+        vec![chrome.clone(), firefox.clone()],
+        dir.path().join("tested.db"),
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    for report in &summary.reports {
+        assert!(
+            matches!(report.status, BackupStatus::Ok { found: 1, .. }),
+            "{report:?}"
+        );
+    }
+
+    conn.execute("UPDATE meta SET value = '999' WHERE key = 'version'", [])
+        .unwrap();
+    drop(conn);
+    assert!(!Source::open(&chrome).unwrap().is_schema_tested());
+
+    Connection::open(&firefox)
+        .unwrap()
+        .pragma_update(None, "user_version", 999)
+        .unwrap();
+    let s = Source::open(&firefox).unwrap();
+    assert_eq!(s.schema_version(), Some("999"));
+    assert!(!s.is_schema_tested());
+    // Untested versions are still backed up
+    let summary = backup(
+        vec![chrome, firefox],
+        dir.path().join("onehistory.db"),
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    for report in &summary.reports {
+        assert!(matches!(report.status, BackupStatus::Ok { found: 1, .. }));
+    }
+}
+
+#[test]
+fn test_backup_glob_history_files() {
+    let dir = tempfile::tempdir().unwrap();
+    for (profile, ts) in [
+        ("Default", 1_642_000_000_000),
+        ("Profile 1", 1_642_000_001_000),
+    ] {
+        let profile = dir.path().join(profile);
+        std::fs::create_dir(&profile).unwrap();
+        create_chrome_history(
+            &profile.join("History"),
+            &[("https://github.com/", "GitHub", ts, 0)],
+        );
+    }
+
+    let summary = backup(
+        vec![
+            dir.path().join("Default").join("History"),
+            dir.path().join("*").join("History"),
+            dir.path().join("*").join("places.sqlite"),
+        ],
+        dir.path().join("onehistory.db"),
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    let paths = summary
+        .reports
+        .iter()
+        .map(|r| r.path.clone())
+        .collect::<Vec<_>>();
+    // Files passed explicitly are not backed up again
+    assert_eq!(
+        paths,
+        vec![
+            dir.path().join("Default").join("History"),
+            dir.path().join("Profile 1").join("History"),
+            dir.path().join("*").join("places.sqlite"),
+        ]
+    );
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { imported: 1, .. }
+    ));
+    assert!(matches!(
+        summary.reports[1].status,
+        BackupStatus::Ok { imported: 1, .. }
+    ));
+    assert!(
+        matches!(&summary.reports[2].status, BackupStatus::Skipped(reason) if reason == "no files matched")
+    );
+}
+
+#[test]
+fn test_backup_edge() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = [
+        "Microsoft Edge/Default/History",
+        "microsoft-edge-beta/Default/History",
+        "Microsoft/Edge/User Data/Default/History",
+        "google-chrome/Default/History",
+    ]
+    .map(|f| dir.path().join(f));
+    for (i, file) in files.iter().enumerate() {
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        create_chrome_history(
+            file,
+            &[(
+                "https://github.com/",
+                "GitHub",
+                1_642_000_000_000 + i as i64,
+                0,
+            )],
+        );
+    }
+
+    let summary = backup(
+        files.to_vec(),
+        dir.path().join("onehistory.db"),
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    let browsers = summary
+        .reports
+        .iter()
+        .map(|r| r.browser.map(|b| b.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        browsers,
+        ["edge", "edge", "edge", "chrome"].map(|b| Some(b.to_string()))
+    );
+    assert!(summary
+        .to_string()
+        .contains("Default/History [edge: Default]"));
+
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let mut browsers = db
+        .select_sources()
+        .unwrap()
+        .into_iter()
+        .filter_map(|s| s.browser)
+        .collect::<Vec<_>>();
+    browsers.sort();
+    assert_eq!(browsers, vec!["chrome", "edge", "edge", "edge"]);
+}
+
+#[test]
+fn test_backup_vivaldi() {
+    let dir = tempfile::tempdir().unwrap();
+    let history = dir.path().join(".config/vivaldi/Default/History");
+    std::fs::create_dir_all(history.parent().unwrap()).unwrap();
+    // Vivaldi keeps the Chromium schema, with its own internal pages
+    create_chrome_history(
+        &history,
+        &[
+            (
+                "https://vivaldi.com/",
+                "Vivaldi Browser",
+                1_642_000_000_000,
+                0,
+            ),
+            ("vivaldi://settings/", "Settings", 1_642_000_001_000, 0),
+        ],
+    );
+    assert!(matches!(
+        Source::open(&history).unwrap().name(),
+        SourceName::Chrome
+    ));
+
+    let db_file = dir.path().join("onehistory.db");
+    let summary = backup(
+        vec![history],
+        &db_file,
+        &BackupOptions {
+            exclude_internal: true,
+            ..Default::default()
+        },
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok { imported: 1, .. }
+    ));
+
+    let (start, end) = full_timerange();
+    let visits = Database::open(&db_file)
+        .unwrap()
+        .select_visits(start, end, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| (v.url, v.visit_time))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        visits,
+        vec![("https://vivaldi.com/".to_string(), 1_642_000_000_000)]
+    );
+}
@@ -0,0 +1,113 @@
+#![allow(dead_code)]
+
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+/// Create a Firefox `places.sqlite` with `visits` of `(url, title, unix_epoch_ms)`.
+pub fn create_firefox_history(path: &Path, visits: &[(&str, &str, i64)]) {
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch(
+        r#"
+CREATE TABLE moz_places (
+    id INTEGER PRIMARY KEY,
+    url LONGVARCHAR UNIQUE,
+    title LONGVARCHAR
+);
+CREATE TABLE moz_historyvisits (
+    id INTEGER PRIMARY KEY,
+    place_id INTEGER,
+    visit_date INTEGER,
+    visit_type INTEGER,
+    from_visit INTEGER DEFAULT 0
+);
+"#,
+    )
+    .unwrap();
+    for (url, title, ts) in visits {
+        conn.execute(
+            "INSERT OR IGNORE INTO moz_places (url, title) VALUES (?1, ?2)",
+            params![url, title],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO moz_historyvisits (place_id, visit_date, visit_type)
+                 SELECT id, ?2, 1 FROM moz_places WHERE url = ?1",
+            params![url, ts * 1_000],
+        )
+        .unwrap();
+    }
+}
+
+/// Create a Chrome `History` with `visits` of `(url, title, unix_epoch_ms, visit_duration_ms)`.
+pub fn create_chrome_history(path: &Path, visits: &[(&str, &str, i64, i64)]) {
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch(
+        r#"
+CREATE TABLE urls (
+    id INTEGER PRIMARY KEY,
+    url LONGVARCHAR UNIQUE,
+    title LONGVARCHAR
+);
+CREATE TABLE visits (
+    id INTEGER PRIMARY KEY,
+    url INTEGER NOT NULL,
+    visit_time INTEGER NOT NULL,
+    transition INTEGER DEFAULT 0 NOT NULL,
+    visit_duration INTEGER DEFAULT 0 NOT NULL,
+    from_visit INTEGER DEFAULT 0 NOT NULL
+);
+"#,
+    )
+    .unwrap();
+    for (url, title, ts, duration) in visits {
+        conn.execute(
+            "INSERT OR IGNORE INTO urls (url, title) VALUES (?1, ?2)",
+            params![url, title],
+        )
+        .unwrap();
+        // visit_time is microseconds since 1601-01-01
+        conn.execute(
+            "INSERT INTO visits (url, visit_time, transition, visit_duration)
+                 SELECT id, ?2, 1, ?3 FROM urls WHERE url = ?1",
+            params![url, (ts + 11_644_473_600_000) * 1_000, duration * 1_000],
+        )
+        .unwrap();
+    }
+}
+
+/// Create a Safari `History.db` with `visits` of `(url, title, unix_epoch_ms)`, in WAL
+/// mode like Safari does.
+pub fn create_safari_history(path: &Path, visits: &[(&str, &str, i64)]) {
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch(
+        r#"
+PRAGMA journal_mode = WAL;
+CREATE TABLE history_items (
+    id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL UNIQUE
+);
+CREATE TABLE history_visits (
+    id INTEGER PRIMARY KEY,
+    history_item INTEGER NOT NULL,
+    visit_time REAL NOT NULL,
+    title TEXT,
+    redirect_destination INTEGER
+);
+"#,
+    )
+    .unwrap();
+    for (url, title, ts) in visits {
+        conn.execute(
+            "INSERT OR IGNORE INTO history_items (url) VALUES (?1)",
+            params![url],
+        )
+        .unwrap();
+        // visit_time is seconds since 2001-01-01
+        conn.execute(
+            "INSERT INTO history_visits (history_item, visit_time, title)
+                 SELECT id, ?2, ?3 FROM history_items WHERE url = ?1",
+            params![url, *ts as f64 / 1000.0 - 978_307_200.0, title],
+        )
+        .unwrap();
+    }
+}
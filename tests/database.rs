@@ -0,0 +1,1574 @@
+use chrono::{Local, TimeZone};
+use onehistory::{
+    check_db_integrity, migrate_db, recover_db, ChannelCollector, CountMode, Database, DayStats,
+    DomainTrend, HourRange, KeywordOp, SilentCollector, SourceFilter, SourceName, TrailingSlash,
+    VisitDetail, BROWSER_INTERNAL_DOMAIN, LOCAL_FILE_DOMAIN,
+};
+use std::{
+    fs,
+    io::{Seek, SeekFrom, Write},
+    sync::mpsc::channel,
+    time::Duration,
+};
+
+fn visit(url: &str, title: &str, visit_time_ms: i64) -> VisitDetail {
+    VisitDetail {
+        url: url.to_string(),
+        title: title.to_string(),
+        visit_time: visit_time_ms * 1_000, // PRTime
+        visit_type: 1,
+        source_visit_id: None,
+    }
+}
+
+#[test]
+fn test_persist_select_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+
+    let details = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_000_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+        visit("https://github.com/", "GitHub", 1_642_000_002_000),
+    ];
+    let (affected, duplicated) = db.persist("History", details, &SilentCollector).unwrap();
+    assert_eq!((affected, duplicated), (3, 0));
+
+    // persist same visits again should be deduplicated
+    let details = vec![visit("https://github.com/", "GitHub", 1_642_000_000_000)];
+    let (affected, duplicated) = db.persist("History", details, &SilentCollector).unwrap();
+    assert_eq!((affected, duplicated), (0, 1));
+
+    let visits = db
+        .select_visits(1_642_000_000_000, 1_642_000_002_000, None, false)
+        .unwrap();
+    let actual = visits
+        .iter()
+        .map(|v| (v.url.as_str(), v.visit_time))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        actual,
+        vec![
+            ("https://github.com/", 1_642_000_000_000),
+            ("https://emacs-china.org/", 1_642_000_001_000),
+            ("https://github.com/", 1_642_000_002_000),
+        ]
+    );
+
+    let visits = db
+        .select_visits(0, 1_642_000_002_000, Some("emacs".to_string()), false)
+        .unwrap();
+    assert_eq!(visits.len(), 1);
+    assert_eq!(visits[0].title, "Emacs China");
+
+    assert_eq!(
+        db.count_visits(1_642_000_000_000, 1_642_000_002_000, None)
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        db.count_visits(0, 1_642_000_002_000, Some("emacs".to_string()))
+            .unwrap(),
+        1
+    );
+
+    let visits = db
+        .select_visits(1_642_000_000_000, 1_642_000_002_000, None, true)
+        .unwrap();
+    let actual = visits.iter().map(|v| v.visit_time).collect::<Vec<_>>();
+    assert_eq!(
+        actual,
+        vec![1_642_000_002_000, 1_642_000_001_000, 1_642_000_000_000]
+    );
+}
+
+#[test]
+fn test_keyword_terms() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let details = vec![
+        visit(
+            "https://docs.rs/tokio",
+            "Rust async runtime",
+            1_642_000_000_000,
+        ),
+        visit("https://go.dev/", "Go async patterns", 1_642_000_001_000),
+        visit("https://www.rust-lang.org/", "Rust", 1_642_000_002_000),
+        visit(
+            "https://blog.rs/",
+            "Writing async trait in Rust",
+            1_642_000_003_000,
+        ),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let titles = |db: &Database, kw: &str| {
+        db.select_visits(0, 1_642_000_003_000, Some(kw.to_string()), false)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.title)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        titles(&db, "rust async"),
+        vec!["Rust async runtime", "Writing async trait in Rust"]
+    );
+    // Short terms are matched by LIKE, long ones by full text search
+    assert_eq!(titles(&db, "go  async"), vec!["Go async patterns"]);
+    assert_eq!(
+        titles(&db, r#""async trait""#),
+        vec!["Writing async trait in Rust"]
+    );
+    assert_eq!(titles(&db, r#""rust async""#), vec!["Rust async runtime"]);
+    assert_eq!(titles(&db, "  ").len(), 4);
+    assert_eq!(
+        db.count_visits(0, 1_642_000_003_000, Some("rust async".into()))
+            .unwrap(),
+        2
+    );
+
+    let any = db.view().with_keyword_op(KeywordOp::Or);
+    assert_eq!(
+        titles(&any, "go.dev rust-lang"),
+        vec!["Go async patterns", "Rust"]
+    );
+    assert_eq!(titles(&any, r#""async trait" tokio"#).len(), 2);
+}
+
+#[test]
+fn test_keyword_special_characters() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let details = vec![
+        visit("https://a.com/", "100% done", 1_642_000_000_000),
+        visit("https://b.com/", "1000 done", 1_642_000_001_000),
+        visit("https://c.com/", "a_b", 1_642_000_002_000),
+        visit("https://d.com/", "axb", 1_642_000_003_000),
+        visit("https://e.com/", "it's", 1_642_000_004_000),
+        visit("https://f.com/", r"C:\temp", 1_642_000_005_000),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let titles = |kw: &str| {
+        db.select_visits(0, 1_642_000_005_000, Some(kw.to_string()), false)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.title)
+            .collect::<Vec<_>>()
+    };
+    // `%`, `_` and `\` are matched literally, not as wildcards of LIKE
+    assert_eq!(titles("%"), vec!["100% done"]);
+    assert_eq!(titles("0%"), vec!["100% done"]);
+    assert_eq!(titles("_"), vec!["a_b"]);
+    assert_eq!(titles(r"\t"), vec![r"C:\temp"]);
+    assert_eq!(titles("'s"), vec!["it's"]);
+    assert_eq!(titles("it's"), vec!["it's"]);
+    assert_eq!(titles(r#""0% done""#), vec!["100% done"]);
+    assert_eq!(titles("a_b"), vec!["a_b"]);
+}
+
+#[test]
+fn test_select_recent_urls() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let details = vec![
+        visit(
+            "https://grafana.example.com/d/1",
+            "Latency",
+            1_642_000_000_000,
+        ),
+        visit(
+            "https://grafana.example.com/d/2",
+            "Errors",
+            1_642_000_001_000,
+        ),
+        visit("https://github.com/", "GitHub", 1_642_000_002_000),
+        visit(
+            "https://grafana.example.com/d/1",
+            "Latency",
+            1_642_000_003_000,
+        ),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let recent = |kw: &str, limit| {
+        db.select_recent_urls(Some(kw.to_string()), limit)
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.url, v.visit_time))
+            .collect::<Vec<_>>()
+    };
+    // Each url once, by its last visit
+    assert_eq!(
+        recent("grafana", 10),
+        vec![
+            (
+                "https://grafana.example.com/d/1".to_string(),
+                1_642_000_003_000
+            ),
+            (
+                "https://grafana.example.com/d/2".to_string(),
+                1_642_000_001_000
+            ),
+        ]
+    );
+    assert_eq!(recent("grafana", 1).len(), 1);
+    assert_eq!(recent("grafana errors", 10).len(), 1);
+    assert!(recent("gitlab", 10).is_empty());
+}
+
+#[test]
+fn test_persist_batch_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db"))
+        .unwrap()
+        .with_persist_batch(3, 64);
+    let giant = format!("data:text/plain,{}", "x".repeat(100));
+    let details = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_000_000),
+        visit(&giant, "", 1_642_000_001_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_002_000),
+        visit("https://github.com/", "GitHub", 1_642_000_003_000),
+    ];
+    let (tx, rx) = channel();
+    db.persist("History", details, &ChannelCollector::new(tx))
+        .unwrap();
+
+    // The giant url fills the first batch before it has 3 visits
+    let events = rx.iter().collect::<Vec<_>>();
+    assert_eq!(events, vec![(0, 4), (2, 4), (4, 4)]);
+    let visits = db
+        .select_visits(1_642_000_000_000, 1_642_000_003_000, None, false)
+        .unwrap();
+    assert_eq!(visits.len(), 4);
+}
+
+#[test]
+fn test_select_page_and_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let details = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_000_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+        visit("https://github.com/", "GitHub", 1_642_000_002_000),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let page = |offset, limit| {
+        db.select_visits_page(0, 1_642_000_002_000, None, true, offset, limit)
+            .unwrap()
+            .iter()
+            .map(|v| v.visit_time)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(page(0, 2), vec![1_642_000_002_000, 1_642_000_001_000]);
+    assert_eq!(page(2, 2), vec![1_642_000_000_000]);
+    assert!(page(3, 2).is_empty());
+
+    assert_eq!(db.count_url_visits("https://github.com/").unwrap(), 2);
+    assert_eq!(
+        db.delete_visit("https://github.com/", 1_642_000_002_000)
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        db.delete_visit("https://github.com/", 1_642_000_002_000)
+            .unwrap(),
+        0
+    );
+    assert_eq!(db.count_url_visits("https://github.com/").unwrap(), 1);
+    assert_eq!(page(0, 10), vec![1_642_000_001_000, 1_642_000_000_000]);
+}
+
+#[test]
+fn test_delete_cleanup_orphan_urls() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let details = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_000_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+        visit("https://github.com/", "GitHub", 1_642_000_002_000),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let url_exists = |url: &str| {
+        rusqlite::Connection::open(&db_file)
+            .unwrap()
+            .query_row(
+                "SELECT count(1) FROM onehistory_urls WHERE url = ?",
+                [url],
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+            == 1
+    };
+    let search = |kw: &str| {
+        db.select_visits(0, 1_642_000_002_000, Some(kw.to_string()), false)
+            .unwrap()
+            .len()
+    };
+
+    assert_eq!(
+        db.delete_visit("https://emacs-china.org/", 1_642_000_001_000)
+            .unwrap(),
+        1
+    );
+    assert!(!url_exists("https://emacs-china.org/"));
+    assert_eq!(search("china"), 0);
+
+    // Shared url survives until its last visit is deleted
+    db.delete_visit("https://github.com/", 1_642_000_000_000)
+        .unwrap();
+    assert!(url_exists("https://github.com/"));
+    assert_eq!(search("github"), 1);
+    assert_eq!(db.count_orphan_urls().unwrap(), 0);
+
+    // Visits deleted by others are cleaned up standalone
+    rusqlite::Connection::open(&db_file)
+        .unwrap()
+        .execute("DELETE FROM onehistory_visits", [])
+        .unwrap();
+    assert_eq!(db.count_orphan_urls().unwrap(), 1);
+    assert_eq!(db.cleanup_orphan_urls().unwrap(), 1);
+    assert!(!url_exists("https://github.com/"));
+    assert_eq!(db.cleanup_orphan_urls().unwrap(), 0);
+}
+
+#[test]
+fn test_tld_breakdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let details = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_000_000),
+        visit("https://gitlab.com/", "GitLab", 1_642_000_001_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_002_000),
+        visit("data:text/html,hello", "", 1_642_000_003_000),
+        visit("file:///tmp/a.html", "", 1_642_000_004_000),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let breakdown = db
+        .select_tld_breakdown(1_642_000_000_000, 1_642_000_004_000, None)
+        .unwrap();
+    assert_eq!(
+        breakdown,
+        vec![
+            (".com".to_string(), 2),
+            ("other".to_string(), 2),
+            (".org".to_string(), 1)
+        ]
+    );
+}
+
+#[test]
+fn test_domain_trends() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let start = 1_642_000_000_000;
+    let hour = 3_600_000;
+    let details = vec![
+        // First half
+        visit("https://github.com/", "GitHub", start),
+        visit("https://github.com/", "GitHub", start + hour),
+        visit("https://github.com/", "GitHub", start + 2 * hour),
+        visit("https://emacs-china.org/", "Emacs China", start + 3 * hour),
+        visit("https://gitlab.com/", "GitLab", start + 4 * hour),
+        // Second half
+        visit("https://github.com/", "GitHub", start + 6 * hour),
+        visit("https://rust-lang.org/", "Rust", start + 7 * hour),
+        visit("https://rust-lang.org/", "Rust", start + 8 * hour),
+        visit("https://emacs-china.org/", "Emacs China", start + 9 * hour),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let trend = |domain: &str, before, after| DomainTrend {
+        domain: domain.to_string(),
+        before,
+        after,
+    };
+    let end = start + 10 * hour - 1;
+    let trends = db.select_domain_trends(start, end, None, 10).unwrap();
+    assert_eq!(trends.split_at, start + 5 * hour);
+    assert_eq!(trends.rising, vec![trend("rust-lang.org", 0, 2)]);
+    assert_eq!(
+        trends.falling,
+        vec![trend("github.com", 3, 1), trend("gitlab.com", 1, 0)]
+    );
+
+    let trends = db.select_domain_trends(start, end, None, 1).unwrap();
+    assert_eq!(trends.falling, vec![trend("github.com", 3, 1)]);
+}
+
+#[test]
+fn test_fuzzy_visits() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let start = 1_642_000_000_000;
+    let details = vec![
+        visit("https://go.dev/", "The Go Programming Language", start),
+        visit(
+            "https://kubernetes.io/docs/",
+            "Overview | Kubernetes",
+            start + 1_000,
+        ),
+        visit(
+            "https://kubernetes.io/blog/",
+            "Kubernetes Blog | Kubernetes",
+            start + 2_000,
+        ),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let end = start + 10_000;
+    let err = db
+        .select_fuzzy_visits(start, end, "kubernets", 0.3, false)
+        .unwrap_err();
+    assert!(err.to_string().contains("reindex --fuzzy"), "{err}");
+
+    assert_eq!(db.rebuild_fuzzy_index().unwrap(), 3);
+    let visits = db
+        .select_fuzzy_visits(start, end, "kubernets", 0.3, false)
+        .unwrap();
+    let urls = visits
+        .iter()
+        .map(|(v, _)| v.url.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        urls,
+        vec!["https://kubernetes.io/docs/", "https://kubernetes.io/blog/"]
+    );
+    assert!(visits[0].1 > 0.5);
+    assert!(db
+        .select_fuzzy_visits(start, end, "kubernets", 1.0, false)
+        .unwrap()
+        .is_empty());
+
+    // Urls persisted after index is built are indexed too
+    let details = vec![visit(
+        "https://rust-lang.org/",
+        "Rust Programming",
+        start + 3_000,
+    )];
+    db.persist("History", details, &SilentCollector).unwrap();
+    let visits = db
+        .select_fuzzy_visits(start, end, "programing", 0.3, true)
+        .unwrap();
+    let urls = visits
+        .iter()
+        .map(|(v, _)| v.url.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(urls, vec!["https://rust-lang.org/", "https://go.dev/"]);
+}
+
+#[test]
+fn test_visit_trace() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    // Visits imported by older versions have no source
+    let conn = rusqlite::Connection::open(&db_file).unwrap();
+    conn.execute_batch(
+        r#"
+CREATE TABLE onehistory_urls (id integer PRIMARY KEY AUTOINCREMENT, url text NOT NULL UNIQUE, title text);
+CREATE TABLE onehistory_visits (id integer PRIMARY KEY AUTOINCREMENT, item_id integer, visit_time integer, visit_type integer NOT NULL DEFAULT 0, UNIQUE(item_id, visit_time));
+INSERT INTO onehistory_urls (url, title) VALUES ('https://github.com/', 'GitHub');
+INSERT INTO onehistory_visits (item_id, visit_time, visit_type) VALUES (1, 1642000000000000, 1);
+"#,
+    )
+    .unwrap();
+    drop(conn);
+
+    let db = Database::open(&db_file).unwrap();
+    let mut details = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_001_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_002_000),
+    ];
+    details[0].source_visit_id = Some(42);
+    db.persist("/tmp/History", details, &SilentCollector)
+        .unwrap();
+
+    let old = db.select_visit_trace(1).unwrap().unwrap();
+    assert_eq!(old.visit.visit_time, 1_642_000_000_000);
+    assert_eq!((old.source, old.visit.source_visit_id), (None, None));
+    let new = db.select_visit_trace(2).unwrap().unwrap();
+    assert_eq!(new.visit.url, "https://github.com/");
+    assert_eq!(new.source.as_deref(), Some("/tmp/History"));
+    assert_eq!(new.visit.source_visit_id, Some(42));
+    assert!(new.to_string().contains("source visit id: 42"));
+    assert!(db.select_visit_trace(4).unwrap().is_none());
+
+    let traces = db
+        .select_visit_traces(1_642_000_001_000, 1_642_000_002_000)
+        .unwrap();
+    let ids = traces.iter().map(|t| t.id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![2, 3]);
+    // Source ids don't take part in deduplication
+    let mut details = vec![visit("https://github.com/", "GitHub", 1_642_000_001_000)];
+    details[0].source_visit_id = Some(43);
+    assert_eq!(
+        db.persist("/tmp/History", details, &SilentCollector)
+            .unwrap(),
+        (0, 1)
+    );
+}
+
+#[test]
+fn test_source_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let conn = rusqlite::Connection::open(&db_file).unwrap();
+    conn.execute_batch(
+        r#"
+CREATE TABLE onehistory_urls (id integer PRIMARY KEY AUTOINCREMENT, url text NOT NULL UNIQUE, title text);
+CREATE TABLE onehistory_visits (id integer PRIMARY KEY AUTOINCREMENT, item_id integer, visit_time integer, visit_type integer NOT NULL DEFAULT 0, UNIQUE(item_id, visit_time));
+INSERT INTO onehistory_urls (url, title) VALUES ('https://github.com/', 'GitHub');
+INSERT INTO onehistory_visits (item_id, visit_time, visit_type) VALUES (1, 1642000000000000, 1);
+"#,
+    )
+    .unwrap();
+    drop(conn);
+
+    let db = Database::open(&db_file).unwrap();
+    db.set_source_browser("/tmp/places.sqlite", SourceName::Firefox)
+        .unwrap();
+    db.persist(
+        "/tmp/places.sqlite",
+        vec![visit("https://github.com/", "GitHub", 1_642_000_001_000)],
+        &SilentCollector,
+    )
+    .unwrap();
+    db.persist(
+        "/tmp/History",
+        vec![
+            visit("https://emacs-china.org/", "Emacs China", 1_642_000_002_000),
+            visit("https://www.rust-lang.org/", "Rust", 1_642_000_003_000),
+        ],
+        &SilentCollector,
+    )
+    .unwrap();
+
+    let sources = db.select_sources().unwrap();
+    let summary = sources
+        .iter()
+        .map(|s| (s.data_path.as_deref(), s.browser.as_deref(), s.visits))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            (Some("/tmp/History"), None, 2),
+            (None, None, 1),
+            (Some("/tmp/places.sqlite"), Some("firefox"), 1)
+        ]
+    );
+    assert_eq!(sources[1].id, None);
+    // Counted from the index of source_id instead of scanning visits
+    let plan: String = rusqlite::Connection::open(&db_file)
+        .unwrap()
+        .query_row(
+            "EXPLAIN QUERY PLAN SELECT source_id, count(1) FROM onehistory_visits GROUP BY source_id",
+            [],
+            |row| row.get(3),
+        )
+        .unwrap();
+    assert!(
+        plan.contains("COVERING INDEX onehistory_visits_source_id"),
+        "{plan}"
+    );
+
+    let urls_of = |source: &str| {
+        db.view()
+            .with_source(Some(source.parse().unwrap()))
+            .select_visits(1_642_000_000_000, 1_642_000_003_000, None, false)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.url)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(urls_of(&sources[0].id.unwrap().to_string()).len(), 2);
+    assert_eq!(urls_of("firefox"), vec!["https://github.com/"]);
+    assert_eq!(urls_of("Unknown"), vec!["https://github.com/"]);
+    assert!(urls_of("safari").is_empty());
+    // Statistics don't use daily summary when filtered by source
+    let counts = db
+        .view()
+        .with_source(Some(SourceFilter::Unknown))
+        .select_domain_top100(1_642_000_000_000, 1_642_000_003_000, None)
+        .unwrap();
+    assert_eq!(counts, vec![("github.com".to_string(), 1)]);
+    let first = db.view().with_source(Some(SourceFilter::Id(1)));
+    assert_eq!(
+        first
+            .count_first_visits(1_642_000_000_000, 1_642_000_003_000)
+            .unwrap(),
+        0
+    );
+    // Browser names are bound as parameters, so quotes only match nothing
+    assert!(urls_of("firefox' OR 1=1 --").is_empty());
+    assert!(" ".parse::<SourceFilter>().is_err());
+}
+
+#[test]
+fn test_hour_weekday_count() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let local_ms = |d, h, m| Local.ymd(2022, 1, d).and_hms(h, m, 0).timestamp_millis();
+    // 2022-01-02 is a Sunday, 2022-01-05 is a Wednesday
+    let details = vec![
+        visit("https://github.com/", "GitHub", local_ms(2, 9, 0)),
+        visit("https://github.com/", "GitHub", local_ms(2, 9, 30)),
+        visit(
+            "https://emacs-china.org/",
+            "Emacs China",
+            local_ms(5, 23, 10),
+        ),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+
+    let counts = db
+        .select_hour_weekday_count(local_ms(1, 0, 0), local_ms(6, 0, 0), None)
+        .unwrap();
+    assert_eq!(counts[0][9], 2);
+    assert_eq!(counts[3][23], 1);
+    assert_eq!(counts.iter().flatten().sum::<i64>(), 3);
+
+    let counts = db
+        .select_hour_weekday_count(local_ms(1, 0, 0), local_ms(6, 0, 0), Some("china".into()))
+        .unwrap();
+    assert_eq!(counts.iter().flatten().sum::<i64>(), 1);
+}
+
+#[test]
+fn test_reindex() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let details = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_000_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+    ];
+    db.persist("History", details, &SilentCollector).unwrap();
+    assert!(!db.reindex().unwrap());
+
+    let search = |kw: &str| {
+        db.select_visits(0, 1_642_000_002_000, Some(kw.to_string()), false)
+            .unwrap()
+            .len()
+    };
+    assert_eq!(search("HUB"), 1);
+    assert_eq!(search("gi"), 1);
+    assert_eq!(search("china.org"), 1);
+    assert_eq!(search("no such\""), 0);
+
+    // Modify urls without updating index
+    rusqlite::Connection::open(&db_file)
+        .unwrap()
+        .execute(
+            "UPDATE onehistory_urls SET title = 'Rust' WHERE url = 'https://github.com/'",
+            [],
+        )
+        .unwrap();
+    assert!(db.reindex().unwrap());
+    assert!(!db.reindex().unwrap());
+    assert_eq!(search("rust"), 1);
+}
+
+#[test]
+fn test_migrate_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let legacy = dir.path().join("onehistory.db");
+    let data = dir
+        .path()
+        .join("share")
+        .join("onehistory")
+        .join("onehistory.db");
+    {
+        let db = Database::open(&legacy).unwrap();
+        db.persist(
+            "History",
+            vec![visit("https://github.com/", "GitHub", 1_642_000_000_000)],
+            &SilentCollector,
+        )
+        .unwrap();
+    }
+
+    migrate_db(&legacy, &data).unwrap();
+    assert!(!legacy.exists());
+    let db = Database::open(&data).unwrap();
+    assert_eq!(db.count_url_visits("https://github.com/").unwrap(), 1);
+
+    // Never overwrite
+    std::fs::write(&legacy, "").unwrap();
+    assert!(migrate_db(&legacy, &data).is_err());
+    assert!(legacy.exists());
+    assert_eq!(db.count_url_visits("https://github.com/").unwrap(), 1);
+
+    // Corrupted database is kept where it is
+    std::fs::write(&legacy, "not a sqlite database").unwrap();
+    let other = dir.path().join("other.db");
+    assert!(migrate_db(&legacy, &other).is_err());
+    assert!(legacy.exists());
+    assert!(!other.exists());
+}
+
+#[test]
+fn test_import_records_of_older_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    rusqlite::Connection::open(&db_file)
+        .unwrap()
+        .execute_batch(
+            r#"
+CREATE TABLE import_records (
+    id integer PRIMARY KEY AUTOINCREMENT,
+    last_import integer,
+    data_path text NOT NULL UNIQUE);
+INSERT INTO import_records (last_import, data_path) VALUES (1642000000000000, 'History');
+"#,
+        )
+        .unwrap();
+
+    let db = Database::open(&db_file).unwrap();
+    db.persist(
+        "History",
+        vec![visit("https://github.com/", "GitHub", 1_642_000_001_000)],
+        &SilentCollector,
+    )
+    .unwrap();
+    let records = db.select_import_records().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].last_import, Some(1_642_000_001_000));
+    assert_eq!((records[0].imported, records[0].duplicated), (1, 0));
+}
+
+#[test]
+fn test_daily_summary() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let jan10 = Local.ymd(2022, 1, 10).and_hms(0, 0, 0).timestamp_millis();
+    let hour = 3_600_000;
+    db.persist(
+        "History",
+        vec![
+            visit("https://github.com/a", "GitHub", jan10 + hour),
+            visit("https://github.com/b", "GitHub", jan10 + 2 * hour),
+            visit("https://github.com/a", "GitHub", jan10 + 25 * hour),
+            visit("https://emacs-china.org/", "", jan10 + 26 * hour),
+            visit("https://emacs-china.org/", "Emacs", jan10 + 50 * hour),
+            visit("https://rust-lang.org/", "Rust", jan10 + 73 * hour),
+        ],
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+
+    // Whole days are read from summary, others from raw visits
+    let day = 24 * hour;
+    let cases = [
+        (jan10, jan10 + 3 * day - 1),
+        (jan10 + 2 * hour, jan10 + 3 * day + hour),
+        (jan10 + hour + 1, jan10 + 2 * day),
+    ];
+    for (start, end) in cases {
+        // Keyword matching everything forces raw visits
+        let all = Some("h".to_string());
+        assert_eq!(
+            db.select_daily_count(start, end, None, CountMode::Raw)
+                .unwrap(),
+            db.select_daily_count(start, end, all.clone(), CountMode::Raw)
+                .unwrap(),
+        );
+        let mut summary = db.select_domain_top100(start, end, None).unwrap();
+        let mut raw = db.select_domain_top100(start, end, all).unwrap();
+        summary.sort();
+        raw.sort();
+        assert_eq!(summary, raw);
+    }
+    assert_eq!(
+        db.select_daily_count(jan10, jan10 + 3 * day - 1, None, CountMode::Raw)
+            .unwrap(),
+        vec![(jan10, 2), (jan10 + day, 2), (jan10 + 2 * day, 1)]
+    );
+    // emacs-china.org is persisted without title first
+    let mut domains = db
+        .select_domain_top100(jan10, jan10 + 4 * day - 1, None)
+        .unwrap();
+    domains.sort();
+    assert_eq!(
+        domains,
+        vec![
+            ("github.com".to_string(), 3),
+            ("rust-lang.org".to_string(), 1)
+        ]
+    );
+
+    assert_eq!(
+        db.delete_visit("https://github.com/a", jan10 + hour)
+            .unwrap(),
+        1
+    );
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+    assert_eq!(
+        db.select_daily_count(jan10, jan10 + day - 1, None, CountMode::Raw)
+            .unwrap(),
+        vec![(jan10, 1)]
+    );
+
+    rusqlite::Connection::open(&db_file)
+        .unwrap()
+        .execute("UPDATE onehistory_daily_summary SET visits = 10", [])
+        .unwrap();
+    assert!(db.verify_daily_summary().unwrap() > 0);
+    db.rebuild_summary().unwrap();
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+}
+
+#[test]
+fn test_daily_count_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let jan10 = Local.ymd(2022, 1, 10).and_hms(0, 0, 0).timestamp_millis();
+    let minute = 60_000;
+    // A page reloaded every minute, together with another page
+    let mut visits = (0..50)
+        .map(|m| {
+            visit(
+                "https://mail.google.com/",
+                "Mail",
+                jan10 + 9 * 60 * minute + m * minute,
+            )
+        })
+        .collect::<Vec<_>>();
+    visits.push(visit(
+        "https://github.com/",
+        "GitHub",
+        jan10 + 9 * 60 * minute,
+    ));
+    db.persist("History", visits, &SilentCollector).unwrap();
+
+    let count = |mode| {
+        db.select_daily_count(jan10, jan10 + 24 * 60 * minute - 1, None, mode)
+            .unwrap()
+    };
+    assert_eq!(count(CountMode::Raw), vec![(jan10, 51)]);
+    // 09:00-09:49 is in one hour
+    assert_eq!(count(CountMode::UrlHour), vec![(jan10, 2)]);
+    assert_eq!(
+        count(CountMode::MinInterval(Duration::from_secs(60))),
+        vec![(jan10, 51)]
+    );
+    assert_eq!(
+        count(CountMode::MinInterval(Duration::from_secs(120))),
+        vec![(jan10, 2)]
+    );
+}
+
+#[test]
+fn test_internal_urls() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let jan10 = Local.ymd(2022, 1, 10).and_hms(9, 0, 0).timestamp_millis();
+    let visits = vec![
+        visit("https://github.com/", "GitHub", jan10),
+        visit("file:///Users/x/a.html", "a.html", jan10 + 1_000),
+        visit("file:///Users/x/b.html", "b.html", jan10 + 2_000),
+        visit("about:blank", "Blank", jan10 + 3_000),
+        visit("chrome://settings", "Settings", jan10 + 4_000),
+    ];
+    db.persist("History", visits, &SilentCollector).unwrap();
+
+    // Whole days are read from daily summary
+    let (start, end) = (jan10 - 86_400_000, jan10 + 86_400_000);
+    let mut domains = db.select_domain_top100(start, end, None).unwrap();
+    domains.sort();
+    assert_eq!(
+        domains,
+        vec![
+            (BROWSER_INTERNAL_DOMAIN.to_string(), 2),
+            (LOCAL_FILE_DOMAIN.to_string(), 2),
+            ("github.com".to_string(), 1),
+        ]
+    );
+
+    let db = Database::open(&db_file)
+        .unwrap()
+        .with_exclude_internal(true);
+    assert_eq!(
+        db.select_domain_top100(start, end, None).unwrap(),
+        vec![("github.com".to_string(), 1)]
+    );
+    let day = Local.ymd(2022, 1, 10).and_hms(0, 0, 0).timestamp_millis();
+    assert_eq!(
+        db.select_daily_count(start, end, None, CountMode::Raw)
+            .unwrap(),
+        vec![(day, 1)]
+    );
+    assert_eq!(db.count_visits(start, end, None).unwrap(), 1);
+    assert_eq!(db.count_first_visits(start, end).unwrap(), 1);
+}
+
+#[test]
+fn test_day_stats() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let jan10 = Local.ymd(2022, 1, 10).and_hms(0, 0, 0).timestamp_millis();
+    let hour = 3_600_000;
+    let visits = vec![
+        visit("https://github.com/", "GitHub", jan10 + hour),
+        visit("https://github.com/rust-lang", "Rust", jan10 + 2 * hour),
+        visit("https://github.com/", "GitHub", jan10 + 3 * hour),
+        visit("https://emacs-china.org/", "", jan10 + 4 * hour),
+        // Next day
+        visit("https://rust-lang.org/", "Rust", jan10 + 25 * hour),
+    ];
+    db.persist("History", visits, &SilentCollector).unwrap();
+
+    let end = jan10 + 24 * hour - 1;
+    assert_eq!(
+        db.select_day_stats(jan10, end, None).unwrap(),
+        DayStats {
+            visits: 4,
+            urls: 3,
+            domains: 2,
+            top_domains: vec![
+                ("github.com".to_string(), 3),
+                ("emacs-china.org".to_string(), 1)
+            ],
+            hidden: 0,
+        }
+    );
+    assert_eq!(
+        db.select_day_stats(jan10, end, Some("rust".to_string()))
+            .unwrap(),
+        DayStats {
+            visits: 1,
+            urls: 1,
+            domains: 1,
+            top_domains: vec![("github.com".to_string(), 1)],
+            hidden: 0,
+        }
+    );
+    assert_eq!(
+        db.select_day_stats(jan10 - 24 * hour, jan10 - 1, None)
+            .unwrap(),
+        DayStats::default()
+    );
+}
+
+#[test]
+fn test_strip_fragments() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let keep = vec!["groups.google.com".to_string()];
+    let t = 1_642_000_000_000;
+    let urls_of = |db: &Database| {
+        db.select_visits(t, t + 10_000, None, false)
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.url, v.title, v.visit_time))
+            .collect::<Vec<_>>()
+    };
+
+    let db = Database::open(&db_file).unwrap();
+    db.persist(
+        "History",
+        vec![
+            visit("https://mail.google.com/#inbox", "", t),
+            visit("https://mail.google.com/#sent", "Sent", t + 1_000),
+            visit("https://mail.google.com/#inbox", "Inbox", t + 2_000),
+            visit("https://mail.google.com/", "Gmail", t + 2_000),
+            visit("https://app.example.com/#/a", "App", t + 3_000),
+            visit("https://groups.google.com/#!topic/1", "Topic", t + 4_000),
+        ],
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(db.strip_url_fragments(&keep).unwrap(), (3, 1));
+    assert_eq!(
+        urls_of(&db),
+        vec![
+            (
+                "https://mail.google.com/".to_string(),
+                "Gmail".to_string(),
+                t
+            ),
+            (
+                "https://mail.google.com/".to_string(),
+                "Gmail".to_string(),
+                t + 1_000
+            ),
+            (
+                "https://mail.google.com/".to_string(),
+                "Gmail".to_string(),
+                t + 2_000
+            ),
+            (
+                "https://app.example.com/".to_string(),
+                "App".to_string(),
+                t + 3_000
+            ),
+            (
+                "https://groups.google.com/#!topic/1".to_string(),
+                "Topic".to_string(),
+                t + 4_000
+            ),
+        ]
+    );
+    assert_eq!(db.strip_url_fragments(&keep).unwrap(), (0, 0));
+    // Urls as visited are kept
+    let raw_urls = urls_of(&db.view().with_raw_urls(true))
+        .into_iter()
+        .map(|(url, _, _)| url)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        raw_urls,
+        vec![
+            "https://mail.google.com/#inbox",
+            "https://mail.google.com/#sent",
+            "https://mail.google.com/",
+            "https://app.example.com/#/a",
+            "https://groups.google.com/#!topic/1",
+        ]
+    );
+    let visits = db
+        .select_visits(t, t + 10_000, Some("inbox".to_string()), false)
+        .unwrap();
+    assert!(visits.is_empty());
+
+    // Stripped when persisted
+    let db = Database::open(dir.path().join("stripped.db"))
+        .unwrap()
+        .with_strip_fragments(Some(keep));
+    let (affected, duplicated) = db
+        .persist(
+            "History",
+            vec![
+                visit("https://app.example.com/#/b", "App", t + 5_000),
+                visit("https://app.example.com/#/c", "App", t + 5_000),
+                visit("https://groups.google.com/#!topic/2", "Topic", t + 6_000),
+            ],
+            &SilentCollector,
+        )
+        .unwrap();
+    assert_eq!((affected, duplicated), (2, 1));
+    let urls = urls_of(&db)
+        .into_iter()
+        .map(|(url, _, _)| url)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        urls,
+        vec![
+            "https://app.example.com/",
+            "https://groups.google.com/#!topic/2"
+        ]
+    );
+    let raw_urls = urls_of(&db.view().with_raw_urls(true))
+        .into_iter()
+        .map(|(url, _, _)| url)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        raw_urls,
+        vec![
+            "https://app.example.com/#/b",
+            "https://groups.google.com/#!topic/2"
+        ]
+    );
+}
+
+#[test]
+fn test_normalize_trailing_slash() {
+    let dir = tempfile::tempdir().unwrap();
+    let t = 1_642_000_000_000;
+    let visits = || {
+        vec![
+            visit("https://example.com/page", "Page", t),
+            visit("https://example.com/page/", "Page", t + 1_000),
+            visit("https://example.com/", "Home", t + 2_000),
+            visit("https://example.com/docs/?q=1", "Docs", t + 3_000),
+        ]
+    };
+    let urls_of = |db: &Database| {
+        db.select_visits(t, t + 10_000, None, false)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.url)
+            .collect::<Vec<_>>()
+    };
+    let raw_urls = visits().into_iter().map(|v| v.url).collect::<Vec<_>>();
+
+    for (mode, normalized, expected) in [
+        (
+            TrailingSlash::Strip,
+            2,
+            [
+                "https://example.com/page",
+                "https://example.com/page",
+                "https://example.com/",
+                "https://example.com/docs?q=1",
+            ],
+        ),
+        (
+            TrailingSlash::Keep,
+            1,
+            [
+                "https://example.com/page/",
+                "https://example.com/page/",
+                "https://example.com/",
+                "https://example.com/docs/?q=1",
+            ],
+        ),
+    ] {
+        // Normalized when persisted
+        let db = Database::open(dir.path().join(format!("{mode}.db")))
+            .unwrap()
+            .with_trailing_slash(Some(mode));
+        db.persist("History", visits(), &SilentCollector).unwrap();
+        assert_eq!(urls_of(&db), expected, "{mode}");
+        assert_eq!(db.count_url_visits(expected[0]).unwrap(), 2);
+        assert_eq!(urls_of(&db.view().with_raw_urls(true)), raw_urls, "{mode}");
+
+        // Urls persisted before are merged
+        let db = Database::open(dir.path().join(format!("{mode}-later.db"))).unwrap();
+        db.persist("History", visits(), &SilentCollector).unwrap();
+        assert_eq!(
+            db.normalize_trailing_slashes(mode).unwrap(),
+            (normalized, 0)
+        );
+        assert_eq!(urls_of(&db), expected, "{mode}");
+        assert_eq!(db.normalize_trailing_slashes(mode).unwrap(), (0, 0));
+        assert_eq!(urls_of(&db.view().with_raw_urls(true)), raw_urls, "{mode}");
+    }
+}
+
+#[test]
+fn test_normalize_urls_search() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let t = 1_642_000_000_000;
+    db.persist(
+        "History",
+        vec![
+            visit("https://kubernetes.io/docs", "", t),
+            visit(
+                "https://kubernetes.io/docs/",
+                "Kubernetes Documentation",
+                t + 1_000,
+            ),
+        ],
+        &SilentCollector,
+    )
+    .unwrap();
+    db.rebuild_fuzzy_index().unwrap();
+    assert_eq!(
+        db.normalize_trailing_slashes(TrailingSlash::Strip).unwrap(),
+        (1, 0)
+    );
+
+    // Title of merged url is searched under the url it's merged into
+    let fuzzy = db
+        .select_fuzzy_visits(t, t + 1_000, "kubernets", 0.3, false)
+        .unwrap()
+        .into_iter()
+        .map(|(v, _)| (v.url, v.title))
+        .collect::<Vec<_>>();
+    let expected = (
+        "https://kubernetes.io/docs".to_string(),
+        "Kubernetes Documentation".to_string(),
+    );
+    assert_eq!(fuzzy, vec![expected.clone(), expected.clone()]);
+    let found = db
+        .select_visits(t, t + 1_000, Some("documentation".to_string()), false)
+        .unwrap()
+        .into_iter()
+        .map(|v| (v.url, v.title))
+        .collect::<Vec<_>>();
+    assert_eq!(found, vec![expected.clone(), expected]);
+    assert!(db
+        .select_visits(t, t + 1_000, Some("docs/".to_string()), false)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_hide_visits() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let t = 1_642_000_000_000;
+    let visits = || {
+        vec![
+            visit("https://github.com/", "GitHub", t),
+            visit("https://github.com/1History", "1History", t + 1_000),
+            visit("https://emacs-china.org/", "Emacs China", t + 2_000),
+        ]
+    };
+    db.persist("History", visits(), &SilentCollector).unwrap();
+    let urls_of = |db: &Database| {
+        db.select_visits(t, t + 10_000, None, false)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.url)
+            .collect::<Vec<_>>()
+    };
+    let count_of = |db: &Database| {
+        db.select_daily_count(t, t + 10_000, None, CountMode::Raw)
+            .unwrap()
+            .iter()
+            .map(|(_, c)| c)
+            .sum::<i64>()
+    };
+
+    assert_eq!(db.hide_visits("%github.com%", true).unwrap(), 2);
+    assert_eq!(urls_of(&db), vec!["https://emacs-china.org/"]);
+    assert_eq!(count_of(&db), 1);
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+    let stats = db.select_day_stats(t, t + 10_000, None).unwrap();
+    assert_eq!((stats.visits, stats.hidden), (1, 2));
+    // Hidden visits follow the same filters as visible ones
+    let stats = db
+        .select_day_stats(t, t + 10_000, Some("1History".to_string()))
+        .unwrap();
+    assert_eq!((stats.visits, stats.hidden), (0, 1));
+    let all = db.view().with_include_hidden(true);
+    assert_eq!(urls_of(&all).len(), 3);
+    assert_eq!(count_of(&all), 3);
+    // Hiding again changes nothing
+    assert_eq!(db.hide_visits("%github.com%", true).unwrap(), 0);
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+
+    // Hidden visits still count as duplicates when imported again
+    assert_eq!(
+        db.persist("History", visits(), &SilentCollector).unwrap(),
+        (0, 3)
+    );
+    assert_eq!(urls_of(&db), vec!["https://emacs-china.org/"]);
+
+    let id = all.select_visit_traces(t, t + 10_000).unwrap()[0].id;
+    assert!(db.select_visit_trace(id).unwrap().unwrap().hidden);
+    assert!(db.set_visit_hidden(id, false).unwrap());
+    assert!(!db.select_visit_trace(id).unwrap().unwrap().hidden);
+    assert_eq!(urls_of(&db).len(), 2);
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+    assert!(!db.set_visit_hidden(42, true).unwrap());
+    assert_eq!(db.hide_visits("%github.com%", false).unwrap(), 1);
+    assert_eq!(count_of(&db), 3);
+    assert_eq!(db.verify_daily_summary().unwrap(), 0);
+}
+
+#[test]
+fn test_diff() {
+    let dir = tempfile::tempdir().unwrap();
+    let t = 1_642_000_000_000;
+    let laptop = Database::open(dir.path().join("laptop.db")).unwrap();
+    laptop
+        .persist(
+            "/laptop/History",
+            vec![
+                visit("https://github.com/", "GitHub", t),
+                visit("https://emacs-china.org/", "Emacs China", t + 1_000),
+            ],
+            &SilentCollector,
+        )
+        .unwrap();
+    let desktop_file = dir.path().join("desktop.db");
+    let desktop = Database::open(&desktop_file).unwrap();
+    desktop
+        .persist(
+            "/desktop/places.sqlite",
+            vec![
+                visit("https://github.com/", "GitHub - Where software is built", t),
+                visit("https://www.rust-lang.org/", "Rust", t + 2_000),
+                visit("https://www.rust-lang.org/", "Rust", t + 3_000),
+            ],
+            &SilentCollector,
+        )
+        .unwrap();
+
+    let diff = laptop.diff(&desktop_file, 1).unwrap();
+    assert!(!diff.is_empty());
+    assert_eq!(diff.only_here.visits, 1);
+    assert_eq!(
+        diff.only_here.by_source,
+        vec![(Some("/laptop/History".to_string()), 1)]
+    );
+    assert_eq!(diff.only_here.samples[0].url, "https://emacs-china.org/");
+    assert_eq!(diff.only_other.visits, 2);
+    assert_eq!(diff.only_other.by_month.len(), 1);
+    assert_eq!(diff.only_other.samples.len(), 1);
+    assert_eq!(diff.only_other.samples[0].visit_time, t + 2_000);
+    assert_eq!(diff.title_diffs, 1);
+    assert_eq!(
+        diff.title_samples[0].other_title.as_deref(),
+        Some("GitHub - Where software is built")
+    );
+
+    let diff = desktop.diff(&desktop_file, 0).unwrap();
+    assert!(diff.is_empty(), "{diff}");
+    assert!(diff.only_here.samples.is_empty());
+    // Other database is detached afterwards
+    assert!(laptop.diff(&desktop_file, 0).is_ok());
+}
+
+#[test]
+fn test_diff_exit_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let here = dir.path().join("here.db");
+    let other = dir.path().join("other.db");
+    Database::open(&here).unwrap();
+    let db = Database::open(&other).unwrap();
+    let diff = |other: &std::path::Path| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_onehistory"))
+            .arg("--db-file")
+            .arg(&here)
+            .arg("diff")
+            .arg(other)
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+
+    // Like diff(1), 0 when same, 1 when different and 2 on errors
+    assert_eq!(diff(&other), Some(0));
+    db.persist(
+        "History",
+        vec![visit("https://github.com/", "GitHub", 1_642_000_000_000)],
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(diff(&other), Some(1));
+    assert_eq!(diff(&dir.path().join("missing.db")), Some(2));
+
+    // Database itself is only read, never created
+    fs::remove_file(&here).unwrap();
+    assert_eq!(diff(&other), Some(2));
+    assert!(!here.exists());
+}
+
+#[test]
+fn test_canonical() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let t = Local.ymd(2022, 1, 12).and_hms(10, 0, 0).timestamp_millis();
+    db.persist(
+        "History",
+        vec![
+            visit("http://example.com/post", "Post", t),
+            visit("https://example.com/post", "Post", t + 1_000),
+            visit("https://www.example.com/post/", "Post", t + 2_000),
+            visit("https://github.com/", "GitHub", t + 3_000),
+        ],
+        &SilentCollector,
+    )
+    .unwrap();
+    // Whole days are counted by daily summary, others by visits
+    let day_start = Local.ymd(2022, 1, 12).and_hms(0, 0, 0).timestamp_millis();
+    let day_end = day_start + 24 * 3_600_000 - 1;
+    let canonical = db.view().with_canonical(true);
+
+    assert_eq!(db.select_url_top100(t, t + 3_000, None).unwrap().len(), 4);
+    assert_eq!(
+        canonical.select_url_top100(t, t + 3_000, None).unwrap(),
+        vec![
+            ("https://example.com/post".to_string(), 3),
+            ("https://github.com/".to_string(), 1)
+        ]
+    );
+    for (start, end) in [(t, t + 3_000), (day_start, day_end)] {
+        assert_eq!(db.select_domain_top100(start, end, None).unwrap().len(), 3);
+        assert_eq!(
+            canonical.select_domain_top100(start, end, None).unwrap(),
+            vec![
+                ("example.com".to_string(), 3),
+                ("github.com".to_string(), 1)
+            ]
+        );
+    }
+    let stats = canonical.select_day_stats(t, t + 3_000, None).unwrap();
+    assert_eq!((stats.visits, stats.urls, stats.domains), (4, 2, 2));
+    assert_eq!(stats.top_domains[0], ("example.com".to_string(), 3));
+    // Visits are still listed as visited
+    let urls = canonical
+        .select_visits(t, t + 3_000, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| v.url)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        urls,
+        vec![
+            "http://example.com/post",
+            "https://example.com/post",
+            "https://www.example.com/post/",
+            "https://github.com/"
+        ]
+    );
+}
+
+#[test]
+fn test_hours() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let at = |d, h| Local.ymd(2022, 1, d).and_hms(h, 30, 0).timestamp_millis();
+    let visits = vec![
+        visit("https://github.com/", "GitHub", at(10, 1)),
+        visit("https://github.com/", "GitHub", at(10, 10)),
+        visit("https://rust-lang.org/", "Rust", at(10, 17)),
+        visit("https://emacs-china.org/", "Emacs", at(10, 18)),
+        visit("https://news.ycombinator.com/", "HN", at(10, 23)),
+    ];
+    db.persist("History", visits, &SilentCollector).unwrap();
+    // Whole days, which are read from daily summary without filters
+    let (start, end) = (at(9, 0), at(11, 0));
+
+    let work = Database::open(&db_file)
+        .unwrap()
+        .with_hours(Some("9-18".parse().unwrap()));
+    assert_eq!(work.count_visits(start, end, None).unwrap(), 2);
+    let mut domains = work.select_domain_top100(start, end, None).unwrap();
+    domains.sort();
+    assert_eq!(
+        domains,
+        vec![
+            ("github.com".to_string(), 1),
+            ("rust-lang.org".to_string(), 1)
+        ]
+    );
+    let day = Local.ymd(2022, 1, 10).and_hms(0, 0, 0).timestamp_millis();
+    assert_eq!(
+        work.select_daily_count(start, end, None, CountMode::Raw)
+            .unwrap(),
+        vec![(day, 2)]
+    );
+
+    let night = Database::open(&db_file)
+        .unwrap()
+        .with_hours(Some("22-6".parse().unwrap()));
+    let urls = night
+        .select_visits(start, end, None, false)
+        .unwrap()
+        .into_iter()
+        .map(|v| v.url)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        urls,
+        vec!["https://github.com/", "https://news.ycombinator.com/"]
+    );
+    // github.com is first visited at 1 o'clock
+    assert_eq!(night.count_first_visits(start, end).unwrap(), 2);
+}
+
+#[test]
+fn test_parse_hour_range() {
+    assert_eq!(
+        "9-18".parse::<HourRange>().unwrap(),
+        HourRange::new(9, 18).unwrap()
+    );
+    assert_eq!(
+        " 22 - 06 ".parse::<HourRange>().unwrap(),
+        HourRange::new(22, 6).unwrap()
+    );
+    assert_eq!(
+        "0-24".parse::<HourRange>().unwrap(),
+        HourRange::new(0, 24).unwrap()
+    );
+    for invalid in ["9", "9-9", "24-1", "1-25", "a-b", ""] {
+        assert!(invalid.parse::<HourRange>().is_err(), "{invalid}");
+    }
+    assert!(HourRange::new(0, 0).is_err());
+}
+
+#[test]
+fn test_recover_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let visits = (0..5_000)
+        .map(|i| {
+            visit(
+                &format!("https://github.com/{}", i % 50),
+                "GitHub",
+                1_642_000_000_000 + i * 1_000,
+            )
+        })
+        .collect();
+    db.persist("History", visits, &SilentCollector).unwrap();
+    assert!(check_db_integrity(&db_file).unwrap().is_empty());
+    drop(db);
+
+    // Overwrite one leaf page of visits in the middle
+    let conn = rusqlite::Connection::open(&db_file).unwrap();
+    let leaves = conn
+        .prepare("SELECT pageno FROM dbstat WHERE name = 'onehistory_visits' AND pagetype = 'leaf' ORDER BY pageno")
+        .unwrap()
+        .query_map([], |row| row.get::<_, u64>(0))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .unwrap();
+    let page_size: u64 = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .unwrap();
+    drop(conn);
+    let mut file = fs::OpenOptions::new().write(true).open(&db_file).unwrap();
+    file.seek(SeekFrom::Start((leaves[leaves.len() / 2] - 1) * page_size))
+        .unwrap();
+    file.write_all(&vec![0xff; page_size as usize]).unwrap();
+    drop(file);
+    let corrupted = fs::read(&db_file).unwrap();
+
+    assert!(!check_db_integrity(&db_file).unwrap().is_empty());
+    let err = Database::open(&db_file)
+        .and_then(|db| db.count_visits(0, i64::MAX / 1_000, None))
+        .unwrap_err();
+    assert!(err.is_corrupt());
+
+    let output = dir.path().join("onehistory.db.recovered");
+    let tables = recover_db(&db_file, &output).unwrap();
+    assert_eq!(fs::read(&db_file).unwrap(), corrupted);
+    assert_eq!(tables.len(), 3);
+    assert_eq!((tables[0].recovered, tables[0].lost()), (50, Some(0)));
+    let visits = &tables[1];
+    assert!(visits.recovered > 4_000 && visits.recovered < 5_000);
+    assert_eq!(visits.lost(), Some(5_000 - visits.recovered));
+
+    let db = Database::open(&output).unwrap();
+    db.integrity_check().unwrap();
+    assert_eq!(
+        db.count_visits(0, i64::MAX / 1_000, None).unwrap(),
+        visits.recovered as u64
+    );
+    assert!(recover_db(&db_file, &output).is_err());
+}
+
+#[test]
+fn test_select_schema() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    db.persist(
+        "History",
+        vec![visit("https://github.com/", "GitHub", 1_642_000_000_000)],
+        &SilentCollector,
+    )
+    .unwrap();
+
+    let (user_version, ddl) = db.select_schema().unwrap();
+    assert_eq!(user_version, 0);
+    assert!(ddl[0].starts_with("CREATE TABLE onehistory_urls"));
+    // Columns added to older databases are included
+    let visits = ddl
+        .iter()
+        .find(|sql| sql.starts_with("CREATE TABLE onehistory_visits"))
+        .unwrap();
+    assert!(visits.contains("hidden integer"));
+    assert!(ddl.iter().any(|sql| sql.contains("USING fts5")));
+    // Shadow tables are created by their virtual table
+    assert!(!ddl.iter().any(|sql| sql.contains("onehistory_urls_fts_")));
+    assert!(ddl.iter().any(|sql| sql.starts_with("CREATE INDEX")));
+    assert!(!ddl.iter().any(|sql| sql.contains("sqlite_sequence")));
+    // Replayed into an empty database
+    let conn = rusqlite::Connection::open_in_memory().unwrap();
+    for sql in &ddl {
+        conn.execute_batch(sql).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+use chrono::{Local, TimeZone};
+use onehistory::{
+    full_timerange, generate_demo, CountMode, Database, DemoOptions, SilentCollector,
+};
+
+#[test]
+fn test_generate_demo() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("demo.db");
+    let options = DemoOptions {
+        days: 30,
+        visits_per_day: 20,
+        seed: 1,
+        until: Some("2022-01-14".to_string()),
+    };
+    let len = generate_demo(&db_file, &options, &SilentCollector).unwrap();
+    assert!(len > 0);
+    // Won't touch existing database
+    assert!(generate_demo(&db_file, &options, &SilentCollector).is_err());
+
+    let (start, end) = full_timerange();
+    let db = Database::open(&db_file).unwrap();
+    assert_eq!(db.count_visits(start, end, None).unwrap(), len as u64);
+    let daily = db
+        .select_daily_count(start, end, None, CountMode::Raw)
+        .unwrap();
+    assert_eq!(daily.len(), 30);
+    assert_eq!(
+        daily[29].0,
+        Local.ymd(2022, 1, 14).and_hms(0, 0, 0).timestamp_millis()
+    );
+    assert!(!db.select_title_top100(start, end, None).unwrap().is_empty());
+    assert!(!db
+        .select_domain_top100(start, end, None)
+        .unwrap()
+        .is_empty());
+    assert!(!db
+        .select_visits(start, end, Some("github".to_string()), false)
+        .unwrap()
+        .is_empty());
+
+    // Deterministic under same seed
+    let other = dir.path().join("other.db");
+    assert_eq!(
+        generate_demo(&other, &options, &SilentCollector).unwrap(),
+        len
+    );
+}
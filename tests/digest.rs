@@ -0,0 +1,99 @@
+use chrono::{Local, NaiveDate, TimeZone};
+use onehistory::{
+    render_digest, weekly_digest, Database, DigestFormat, IsoWeek, SilentCollector, VisitDetail,
+};
+
+fn visit(url: &str, y: i32, m: u32, d: u32, hour: u32) -> VisitDetail {
+    VisitDetail {
+        url: url.to_string(),
+        title: url.to_string(),
+        visit_time: Local.ymd(y, m, d).and_hms(hour, 0, 0).timestamp_millis() * 1_000,
+        visit_type: 1,
+        source_visit_id: None,
+    }
+}
+
+#[test]
+fn test_parse_iso_week() {
+    let week: IsoWeek = "2022-W02".parse().unwrap();
+    assert_eq!(
+        week,
+        IsoWeek {
+            year: 2022,
+            week: 2
+        }
+    );
+    assert_eq!(week.to_string(), "2022-W02");
+    assert_eq!(week.monday(), NaiveDate::from_ymd(2022, 1, 10));
+    assert_eq!(week.previous().to_string(), "2022-W01");
+    // The first week of 2021 starts on 2021-01-04, days before are in 2020-W53
+    assert_eq!(
+        IsoWeek::of(NaiveDate::from_ymd(2021, 1, 3)),
+        IsoWeek {
+            year: 2020,
+            week: 53
+        }
+    );
+    assert_eq!("2021W01".parse::<IsoWeek>().unwrap().previous().week, 53);
+    for invalid in ["2022", "2022-W54", "2021-W53", "W02", "2022-Wx"] {
+        assert!(invalid.parse::<IsoWeek>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_weekly_digest() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let visits = vec![
+        // 2022-W01
+        visit("https://github.com/", 2022, 1, 4, 10),
+        visit("https://github.com/a", 2022, 1, 5, 10),
+        visit("https://emacs-china.org/", 2022, 1, 6, 10),
+        // 2022-W02
+        visit("https://github.com/", 2022, 1, 11, 10),
+        visit("https://docs.rs/", 2022, 1, 12, 9),
+        visit("https://docs.rs/tokio", 2022, 1, 12, 10),
+        visit("https://docs.rs/serde", 2022, 1, 12, 11),
+        visit("https://lobste.rs/", 2022, 1, 16, 23),
+        // 2022-W03
+        visit("https://github.com/", 2022, 1, 17, 0),
+    ];
+    db.persist("History", visits, &SilentCollector).unwrap();
+
+    let digest = weekly_digest(&db, "2022-W02".parse().unwrap()).unwrap();
+    assert_eq!(digest.week, "2022-W02");
+    assert_eq!((digest.visits, digest.previous_visits), (5, 3));
+    assert_eq!(digest.busiest_day, Some(("2022-01-12".to_string(), 3)));
+    let top = digest
+        .top_domains
+        .iter()
+        .map(|d| (d.domain.as_str(), d.after, d.before))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        top,
+        vec![("docs.rs", 3, 0), ("github.com", 1, 2), ("lobste.rs", 1, 0)]
+    );
+    assert_eq!(
+        digest.new_domains,
+        vec![("docs.rs".to_string(), 3), ("lobste.rs".to_string(), 1)]
+    );
+    assert_eq!(digest.new_domain_count, 2);
+
+    let markdown = render_digest(&digest, DigestFormat::Markdown).unwrap();
+    assert!(markdown.starts_with("# 1History digest of 2022-W02\n"));
+    assert!(markdown.contains("- Visits: 5 (+2 vs. 3 the week before)"));
+    assert!(markdown.contains("| github.com | 1 | -1 |"));
+    assert!(markdown.contains("- lobste.rs: 1"));
+    let html = render_digest(&digest, DigestFormat::Html).unwrap();
+    assert!(html.contains("<title>1History digest of 2022-W02</title>"));
+    // Self-contained, so it can be mailed as it is
+    assert!(!html.contains("src=") && !html.contains("href="));
+    let json: serde_json::Value =
+        serde_json::from_str(&render_digest(&digest, DigestFormat::Json).unwrap()).unwrap();
+    assert_eq!(json["top_domains"][0]["domain"], "docs.rs");
+    assert_eq!(json["new_domain_count"], 2);
+
+    // Nothing visited
+    let digest = weekly_digest(&db, "2021-W30".parse().unwrap()).unwrap();
+    assert_eq!((digest.visits, digest.busiest_day), (0, None));
+}
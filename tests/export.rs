@@ -0,0 +1,527 @@
+mod common;
+
+use chrono::TimeZone;
+use common::create_firefox_history;
+use onehistory::{
+    backup, convert_csv, convert_json, export_audit, export_csv, export_new_urls_csv,
+    export_template, export_timeline, manifest_file_of, search_visits, verify_manifest,
+    BackupOptions, ChannelCollector, CsvDialect, Database, ExportOptions, Manifest,
+    SilentCollector, VisitDetail,
+};
+use std::{fs, sync::mpsc::channel, time::Duration};
+
+#[test]
+fn test_export_template() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &history_file,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000),
+            ("https://emacs-china.org/", "", 1_642_000_001_000),
+        ],
+    );
+    let db_file = dir.path().join("onehistory.db");
+    backup(
+        vec![history_file],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+
+    let template = dir.path().join("report.org.j2");
+    fs::write(
+        &template,
+        "{% for v in visits %}- [[{{ v.url }}][{{ format_title(v.title, v.url) }}]]\n{% endfor %}\
+         {% for (domain, cnt) in domain_top100 %}{{ domain }}\n{% endfor %}\
+         favicons:{{ favicons | length }}",
+    )
+    .unwrap();
+    let output = dir.path().join("report.org");
+    export_template(
+        &template,
+        Some(output.clone()),
+        &db_file,
+        false,
+        &ExportOptions::default(),
+        &SilentCollector,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(output).unwrap(),
+        "- [[https://github.com/][GitHub]]\n\
+         - [[https://emacs-china.org/][https://emacs-china.org/]]\n\
+         github.com\n\
+         favicons:0"
+    );
+}
+
+#[test]
+fn test_export_invalid_timestamps() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    // 2022-01-12 12:00:00 UTC, same day in most timezones
+    let visits = [i64::MIN, -1, 1_641_988_800_000_000, i64::MAX]
+        .into_iter()
+        .map(|visit_time| VisitDetail {
+            url: "https://github.com/".to_string(),
+            title: "GitHub".to_string(),
+            visit_time,
+            visit_type: 1,
+            source_visit_id: None,
+        })
+        .collect();
+    db.persist("places.sqlite", visits, &SilentCollector)
+        .unwrap();
+
+    let template = dir.path().join("report.j2");
+    fs::write(
+        &template,
+        "{% for v in visits %}{{ format_as_ymd(v.visit_time) }}\n{% endfor %}\
+         {{ format_as_ymdhms(9223372036854775807) }} {{ format_as_hms(-9223372036854775807) }}",
+    )
+    .unwrap();
+    let output = dir.path().join("report");
+    export_template(
+        &template,
+        Some(output.clone()),
+        &db_file,
+        false,
+        &ExportOptions::default(),
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_to_string(output).unwrap(),
+        "2022-01-12\ninvalid(9223372036854775807) invalid(-9223372036854775807)"
+    );
+
+    let (tx, rx) = channel();
+    export_csv(
+        dir.path().join("export.csv"),
+        &db_file,
+        &ExportOptions::default(),
+        &ChannelCollector::new(tx),
+    )
+    .unwrap();
+    // Only the valid visit is in full timerange
+    assert_eq!(rx.iter().collect::<Vec<_>>(), vec![(0, 1), (1, 1)]);
+}
+
+#[test]
+fn test_export_new_urls() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let visit = |url: &str, visit_time_ms: i64, visit_type| VisitDetail {
+        url: url.to_string(),
+        title: "".to_string(),
+        visit_time: visit_time_ms * 1_000,
+        visit_type,
+        source_visit_id: None,
+    };
+    let visits = vec![
+        visit("https://github.com/", 1_642_000_000_000, 2),
+        visit("https://emacs-china.org/", 1_642_000_001_000, 1),
+        visit("https://github.com/", 1_642_000_002_000, 1),
+        visit("https://rust-lang.org/", 1_642_000_003_000, 3),
+    ];
+    db.persist("places.sqlite", visits, &SilentCollector)
+        .unwrap();
+
+    let csv_file = dir.path().join("new_urls.csv");
+    let (tx, rx) = channel();
+    export_new_urls_csv(
+        csv_file.clone(),
+        &db_file,
+        &ExportOptions::default(),
+        &ChannelCollector::new(tx),
+    )
+    .unwrap();
+    assert_eq!(rx.iter().collect::<Vec<_>>(), vec![(0, 3), (3, 3)]);
+    let rows = fs::read_to_string(csv_file)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').skip(2).collect::<Vec<_>>().join(","))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        vec![
+            "https://github.com/,2",
+            "https://emacs-china.org/,1",
+            "https://rust-lang.org/,3"
+        ]
+    );
+
+    // Urls first visited before the window are not new
+    let mut urls = Vec::new();
+    db.for_each_first_visit(1_642_000_001_000, 1_642_000_003_000, |v| {
+        urls.push((v.url, v.visit_time));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(
+        urls,
+        vec![
+            ("https://emacs-china.org/".to_string(), 1_642_000_001_000),
+            ("https://rust-lang.org/".to_string(), 1_642_000_003_000)
+        ]
+    );
+}
+
+#[test]
+fn test_export_timeline() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let output = dir.path().join("timeline.json");
+    let gap = Duration::from_secs(30 * 60);
+    export_timeline(
+        Some(output.clone()),
+        &db_file,
+        gap,
+        &ExportOptions::default(),
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(fs::read_to_string(&output).unwrap(), "[]\n");
+
+    let db = Database::open(&db_file).unwrap();
+    let minute = 60_000;
+    let visits = [0, 1, 31, 120, 125]
+        .into_iter()
+        .map(|m| VisitDetail {
+            url: format!("https://github.com/{m}"),
+            title: "GitHub".to_string(),
+            visit_time: (1_642_000_000_000 + m * minute) * 1_000,
+            visit_type: 1,
+            source_visit_id: None,
+        })
+        .collect();
+    db.persist("places.sqlite", visits, &SilentCollector)
+        .unwrap();
+
+    let (tx, rx) = channel();
+    let sessions = export_timeline(
+        Some(output.clone()),
+        &db_file,
+        gap,
+        &ExportOptions::default(),
+        &ChannelCollector::new(tx),
+    )
+    .unwrap();
+    assert_eq!(sessions, 2);
+    assert_eq!(rx.iter().last(), Some((5, 5)));
+
+    let timeline: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+    let sessions = timeline.as_array().unwrap();
+    assert_eq!(sessions[0]["start"], 1_642_000_000_000i64);
+    assert_eq!(sessions[0]["end"], 1_642_000_000_000i64 + 31 * minute);
+    assert_eq!(sessions[0]["visits"].as_array().unwrap().len(), 3);
+    assert_eq!(sessions[1]["start"], 1_642_000_000_000i64 + 120 * minute);
+    assert_eq!(sessions[1]["visits"][1]["url"], "https://github.com/125");
+}
+
+#[test]
+fn test_export_audit() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let visits = |n: i64| {
+        (0..n)
+            .map(|i| VisitDetail {
+                url: format!("https://github.com/{i}"),
+                title: "GitHub".to_string(),
+                visit_time: (1_642_000_000_000 + i * 1_000) * 1_000,
+                visit_type: 1,
+                source_visit_id: None,
+            })
+            .collect::<Vec<_>>()
+    };
+    db.persist("places.sqlite", visits(2), &SilentCollector)
+        .unwrap();
+    db.persist("places.sqlite", visits(3), &SilentCollector)
+        .unwrap();
+    db.persist("Work, Inc/History", visits(1), &SilentCollector)
+        .unwrap();
+
+    let output = dir.path().join("audit.csv");
+    assert_eq!(
+        export_audit(
+            Some(output.clone()),
+            &db_file,
+            false,
+            &ExportOptions::default()
+        )
+        .unwrap(),
+        2
+    );
+    let csv = fs::read_to_string(&output).unwrap();
+    let lines = csv.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "data_path,last_import,imported,duplicated");
+    assert!(lines[1].starts_with("\"Work, Inc/History\","));
+    assert!(lines[1].ends_with(",0,1"));
+    assert!(lines[2].starts_with("places.sqlite,2022-01-"));
+    assert!(lines[2].ends_with(",3,2"));
+
+    let output = dir.path().join("audit.json");
+    export_audit(
+        Some(output.clone()),
+        &db_file,
+        true,
+        &ExportOptions::default(),
+    )
+    .unwrap();
+    let records: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+    assert_eq!(records[1]["data_path"], "places.sqlite");
+    assert_eq!(records[1]["last_import"], 1_642_000_002_000i64);
+    assert_eq!(records[1]["imported"], 3);
+    assert_eq!(records[1]["duplicated"], 2);
+}
+
+#[test]
+fn test_convert() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &history_file,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000),
+            (
+                "https://emacs-china.org/",
+                "Emacs, China",
+                1_642_000_001_000,
+            ),
+        ],
+    );
+
+    let csv = dir.path().join("history.csv");
+    assert_eq!(convert_csv(&history_file, Some(csv.clone())).unwrap(), 2);
+    let lines = fs::read_to_string(csv).unwrap();
+    let lines = lines.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "time,title,url,visit_type");
+    assert!(lines[2].ends_with(",Emacs China,https://emacs-china.org/,1"));
+
+    let json = dir.path().join("history.json");
+    assert_eq!(convert_json(&history_file, Some(json.clone())).unwrap(), 2);
+    let visits: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(json).unwrap()).unwrap();
+    assert_eq!(visits[0]["url"], "https://github.com/");
+    assert_eq!(visits[0]["visit_time"], 1_642_000_000_000i64);
+    assert_eq!(visits[1]["title"], "Emacs, China");
+}
+
+#[test]
+fn test_export_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let visits = vec![
+        VisitDetail {
+            url: "https://github.com/".to_string(),
+            title: "GitHub".to_string(),
+            visit_time: 1_642_000_000_000_000,
+            visit_type: 1,
+            source_visit_id: None,
+        },
+        VisitDetail {
+            url: "about:blank".to_string(),
+            title: "".to_string(),
+            visit_time: 1_642_000_001_000_000,
+            visit_type: 1,
+            source_visit_id: None,
+        },
+    ];
+    db.persist("places.sqlite", visits, &SilentCollector)
+        .unwrap();
+
+    let output = dir.path().join("export.csv");
+    let options = ExportOptions {
+        exclude_internal: true,
+        manifest: true,
+        ..Default::default()
+    };
+    export_csv(output.clone(), &db_file, &options, &SilentCollector).unwrap();
+
+    let manifest_file = manifest_file_of(&output);
+    assert_eq!(manifest_file, dir.path().join("export.csv.manifest.json"));
+    let manifest: Manifest =
+        serde_json::from_str(&fs::read_to_string(&manifest_file).unwrap()).unwrap();
+    assert_eq!(manifest.format, "csv");
+    assert_eq!(manifest.filters["exclude_internal"], true);
+    assert_eq!(manifest.parts.len(), 1);
+    let part = &manifest.parts[0];
+    assert_eq!(part.file, "export.csv");
+    assert_eq!(part.rows, 1);
+    assert_eq!(part.bytes, fs::metadata(&output).unwrap().len());
+
+    let checks = verify_manifest(&manifest_file).unwrap();
+    assert!(checks.iter().all(|c| c.is_ok()), "{checks:?}");
+
+    // Bit rot is detected
+    let mut content = fs::read(&output).unwrap();
+    content[0] ^= 1;
+    fs::write(&output, content).unwrap();
+    let checks = verify_manifest(&manifest_file).unwrap();
+    assert!(!checks[0].is_ok());
+    assert!(checks[0].actual.is_some());
+
+    fs::remove_file(&output).unwrap();
+    let checks = verify_manifest(&manifest_file).unwrap();
+    assert_eq!(checks[0].actual, None);
+
+    // Nowhere to write manifest of stdout
+    let gap = Duration::from_secs(30 * 60);
+    assert!(export_timeline(None, &db_file, gap, &options, &SilentCollector).is_err());
+}
+
+#[cfg(feature = "detect-language")]
+#[test]
+fn test_export_detect_language() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let visit = |title: &str, visit_time_ms: i64| VisitDetail {
+        url: format!("https://example.com/{visit_time_ms}"),
+        title: title.to_string(),
+        visit_time: visit_time_ms * 1_000,
+        visit_type: 1,
+        source_visit_id: None,
+    };
+    let visits = vec![
+        visit(
+            "The Rust Programming Language - Learn how to write reliable software",
+            1_642_000_000_000,
+        ),
+        visit("GitHub", 1_642_000_001_000),
+    ];
+    db.persist("places.sqlite", visits, &SilentCollector)
+        .unwrap();
+
+    let output = dir.path().join("export.csv");
+    let options = ExportOptions {
+        detect_language: true,
+        ..Default::default()
+    };
+    export_csv(output.clone(), &db_file, &options, &SilentCollector).unwrap();
+    let content = fs::read_to_string(&output).unwrap();
+    let rows = content.lines().collect::<Vec<_>>();
+    assert_eq!(rows[0], "time,title,url,visit_type,lang");
+    assert!(rows[1].ends_with(",eng"), "{}", rows[1]);
+    // Too short to detect reliably
+    assert!(rows[2].ends_with(","), "{}", rows[2]);
+}
+
+#[test]
+fn test_export_csv_dialect() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let db = Database::open(&db_file).unwrap();
+    let visits = vec![VisitDetail {
+        url: "https://example.com/a;b".to_string(),
+        title: "Rust; \"book\", 2nd".to_string(),
+        visit_time: 1_642_000_000_000_000,
+        visit_type: 1,
+        source_visit_id: None,
+    }];
+    db.persist("places.sqlite", visits, &SilentCollector)
+        .unwrap();
+    let time = chrono::Local
+        .timestamp_millis(1_642_000_000_000)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+
+    let output = dir.path().join("export.csv");
+    export_csv(
+        output.clone(),
+        &db_file,
+        &ExportOptions::default(),
+        &SilentCollector,
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read(&output).unwrap(),
+        format!("time,title,url,visit_type\n{time},\"Rust; \"\"book\"\" 2nd\",https://example.com/a;b,0\n")
+            .into_bytes()
+    );
+
+    let options = ExportOptions {
+        csv_dialect: CsvDialect {
+            bom: true,
+            crlf: true,
+            delimiter: ';',
+        },
+        ..Default::default()
+    };
+    export_csv(output.clone(), &db_file, &options, &SilentCollector).unwrap();
+    let content = fs::read(&output).unwrap();
+    assert_eq!(&content[..3], b"\xEF\xBB\xBF");
+    assert_eq!(
+        String::from_utf8(content[3..].to_vec()).unwrap(),
+        format!("time;title;url;visit_type\r\n{time};\"Rust; \"\"book\"\" 2nd\";\"https://example.com/a;b\";0\r\n")
+    );
+}
+
+#[test]
+fn test_search_visits() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let visit = |url: &str, title: &str, visit_time_ms: i64| VisitDetail {
+        url: url.to_string(),
+        title: title.to_string(),
+        visit_time: visit_time_ms * 1_000,
+        visit_type: 1,
+        source_visit_id: None,
+    };
+    let visits = vec![
+        visit("https://github.com/", "GitHub", 1_642_000_000_000),
+        visit("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+        visit("https://github.com/rust-lang", "Rust", 1_642_000_002_000),
+        visit(
+            "https://tabs.example/",
+            "Tabs\tand\nlines",
+            1_642_000_003_000,
+        ),
+    ];
+    db.persist("places.sqlite", visits, &SilentCollector)
+        .unwrap();
+
+    let mut out = Vec::new();
+    assert_eq!(
+        search_visits(&db, "github", true, true, &mut out).unwrap(),
+        2
+    );
+    let rows = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(rows[0]["url"], "https://github.com/rust-lang");
+    assert_eq!(rows[0]["visit_time"], 1_642_000_002_000i64);
+    assert_eq!(rows[1]["title"], "GitHub");
+
+    let mut out = Vec::new();
+    search_visits(&db, "emacs", false, false, &mut out).unwrap();
+    let content = String::from_utf8(out).unwrap();
+    assert!(
+        content.ends_with("\tEmacs China\thttps://emacs-china.org/\n"),
+        "{content}"
+    );
+
+    // One visit is always one line
+    let mut out = Vec::new();
+    search_visits(&db, "tabs", false, false, &mut out).unwrap();
+    let content = String::from_utf8(out).unwrap();
+    assert_eq!(content.lines().count(), 1);
+    assert!(
+        content.ends_with("\tTabs\\tand\\nlines\thttps://tabs.example/\n"),
+        "{content}"
+    );
+}
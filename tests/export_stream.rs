@@ -0,0 +1,82 @@
+use onehistory::{export_csv, Database, ExportOptions, SilentCollector, VisitDetail};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Tracks live and peak heap usage, so we can check export doesn't materialize
+// all visits in memory. Kept in its own test binary to avoid noise from other tests.
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const VISITS: usize = 100_000;
+
+#[test]
+fn test_export_large_csv() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    {
+        let db = Database::open(&db_file).unwrap();
+        // 2022-01-12 12:00:00 UTC, one visit per second
+        let visits = (0..VISITS)
+            .map(|i| VisitDetail {
+                url: format!("https://example.com/{}/{}", i % 1000, "x".repeat(64)),
+                title: format!("Page {}, {}", i % 1000, "y".repeat(64)),
+                visit_time: (1_641_988_800 + i as i64) * 1_000_000,
+                visit_type: 1,
+                source_visit_id: None,
+            })
+            .collect();
+        db.persist("places.sqlite", visits, &SilentCollector)
+            .unwrap();
+    }
+
+    let csv_file = dir.path().join("export.csv");
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    export_csv(
+        csv_file.clone(),
+        &db_file,
+        &ExportOptions::default(),
+        &SilentCollector,
+    )
+    .unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    // Materialized visits would take more than 20MB
+    assert!(peak < 1024 * 1024, "peak allocation {peak} bytes");
+
+    let content = fs::read_to_string(csv_file).unwrap();
+    let lines = content.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), VISITS + 1);
+    assert_eq!(lines[0], "time,title,url,visit_type");
+    for (i, line) in lines[1..].iter().enumerate() {
+        let cols = line.split(',').collect::<Vec<_>>();
+        assert_eq!(cols[1], format!("Page {} {}", i % 1000, "y".repeat(64)));
+        assert_eq!(
+            cols[2],
+            format!("https://example.com/{}/{}", i % 1000, "x".repeat(64))
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use onehistory::{lock_db, lock_file_of, Error};
+use std::{
+    process,
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+#[test]
+fn test_lock_contention() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let lock = lock_db(&db_file, Duration::ZERO).unwrap();
+    assert_eq!(lock.path(), dir.path().join("onehistory.db.lock"));
+
+    match lock_db(&db_file, Duration::ZERO) {
+        Err(Error::AlreadyRunning { pid, .. }) => assert_eq!(pid, process::id()),
+        other => panic!("expect AlreadyRunning, got {other:?}"),
+    }
+    let begin = Instant::now();
+    assert!(lock_db(&db_file, Duration::from_millis(300)).is_err());
+    assert!(begin.elapsed() >= Duration::from_millis(300));
+
+    // Acquired once the holder releases it
+    let holder = thread::spawn(move || {
+        sleep(Duration::from_millis(200));
+        drop(lock);
+    });
+    lock_db(&db_file, Duration::from_secs(10)).unwrap();
+    holder.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_lock_stale() {
+    use fs2::FileExt;
+    use std::{fs, process::Command};
+
+    let dir = tempfile::tempdir().unwrap();
+    let db_file = dir.path().join("onehistory.db");
+    let lock_file = lock_file_of(&db_file);
+
+    // Lock file left without lock
+    fs::write(&lock_file, "1\n0\n").unwrap();
+    drop(lock_db(&db_file, Duration::ZERO).unwrap());
+
+    // Lock held by a dead process, such as inherited by its children
+    let mut child = Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    fs::write(&lock_file, format!("{}\n0\n", child.id())).unwrap();
+    let inherited = fs::File::open(&lock_file).unwrap();
+    inherited.lock_exclusive().unwrap();
+
+    let lock = lock_db(&db_file, Duration::ZERO).unwrap();
+    let content = fs::read_to_string(lock.path()).unwrap();
+    assert_eq!(
+        content.lines().next(),
+        Some(process::id().to_string().as_str())
+    );
+}
@@ -0,0 +1,109 @@
+// Stop requests are global to the process, so this runs in its own test binary
+mod common;
+
+use common::create_firefox_history;
+use onehistory::{
+    backup, full_timerange, request_stop, BackupOptions, BackupStatus, Database, ProgressCollector,
+    SilentCollector,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Request stop like Ctrl-C, after `batches` batches are persisted
+struct StopCollector {
+    batches: u64,
+    persisted: AtomicU64,
+}
+
+impl ProgressCollector for StopCollector {
+    fn start(&self, _total: u64) {}
+
+    fn inc(&self, _delta: u64) {
+        if self.persisted.fetch_add(1, Ordering::SeqCst) + 1 == self.batches {
+            assert!(request_stop());
+        }
+    }
+
+    fn finish(&self) {}
+}
+
+#[test]
+fn test_resume_interrupted_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = dir.path().join("places.sqlite");
+    let urls = (0..50)
+        .map(|i| format!("https://github.com/{i}"))
+        .collect::<Vec<_>>();
+    let visits = (0..350)
+        .map(|i| {
+            // Visits of the same time across a batch boundary are committed together
+            let ts = if i == 100 { 99 } else { i };
+            (
+                urls[i % 50].as_str(),
+                "GitHub",
+                1_642_000_000_000 + ts as i64,
+            )
+        })
+        .collect::<Vec<_>>();
+    create_firefox_history(&history_file, &visits);
+    let db_file = dir.path().join("onehistory.db");
+
+    let collector = StopCollector {
+        batches: 2,
+        persisted: AtomicU64::new(0),
+    };
+    let err = backup(
+        vec![history_file.clone()],
+        &db_file,
+        &BackupOptions::default(),
+        &collector,
+        &SilentCollector,
+    )
+    .unwrap_err();
+    assert!(err.is_stopped());
+
+    let db = Database::open(&db_file).unwrap();
+    let (start, end) = full_timerange();
+    assert_eq!(db.count_visits(start, end, None).unwrap(), 201);
+    let src_path = history_file.to_string_lossy();
+    assert_eq!(
+        db.select_resume_point(&src_path).unwrap(),
+        Some((1_642_000_000_000 + 200) * 1_000)
+    );
+
+    let summary = backup(
+        vec![history_file.clone()],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok {
+            found: 149,
+            imported: 149,
+            duplicated: 0
+        }
+    ));
+    assert_eq!(db.count_visits(start, end, None).unwrap(), 350);
+    assert_eq!(db.select_resume_point(&src_path).unwrap(), None);
+
+    // Finished backup reads the whole file again
+    let summary = backup(
+        vec![history_file],
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    assert!(matches!(
+        summary.reports[0].status,
+        BackupStatus::Ok {
+            found: 350,
+            imported: 0,
+            duplicated: 350
+        }
+    ));
+}
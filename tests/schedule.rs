@@ -0,0 +1,63 @@
+use onehistory::{Schedule, ScheduleInterval, ScheduleTime};
+use std::path::PathBuf;
+
+fn schedule(interval: ScheduleInterval) -> Schedule {
+    Schedule {
+        exe: PathBuf::from("/usr/local/bin/onehistory"),
+        args: vec![
+            "-d".to_string(),
+            "/home/alice/R&D 100%/onehistory.db".to_string(),
+            "backup".to_string(),
+            "--include-synced".to_string(),
+        ],
+        interval,
+        time: "03:30".parse().unwrap(),
+    }
+}
+
+#[test]
+fn test_schedule_time() {
+    assert_eq!(
+        "23:05".parse::<ScheduleTime>().unwrap(),
+        ScheduleTime {
+            hour: 23,
+            minute: 5
+        }
+    );
+    for invalid in ["24:00", "12:60", "12", "ab:cd", ""] {
+        assert!(invalid.parse::<ScheduleTime>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_systemd_units() {
+    let daily = schedule(ScheduleInterval::Daily);
+    assert_eq!(
+        daily.systemd_service(),
+        include_str!("golden/onehistory-backup.service")
+    );
+    assert_eq!(
+        daily.systemd_timer(),
+        include_str!("golden/onehistory-backup-daily.timer")
+    );
+    assert_eq!(
+        schedule(ScheduleInterval::Hourly).systemd_timer(),
+        include_str!("golden/onehistory-backup-hourly.timer")
+    );
+    assert_eq!(
+        schedule(ScheduleInterval::Weekly).systemd_timer(),
+        include_str!("golden/onehistory-backup-weekly.timer")
+    );
+}
+
+#[test]
+fn test_launchd_plist() {
+    assert_eq!(
+        schedule(ScheduleInterval::Daily).launchd_plist(),
+        include_str!("golden/com.github.1history.backup-daily.plist")
+    );
+    assert_eq!(
+        schedule(ScheduleInterval::Weekly).launchd_plist(),
+        include_str!("golden/com.github.1history.backup-weekly.plist")
+    );
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::create_firefox_history;
+use onehistory::{backup, inspect_history_files, BackupOptions, FileStatus, SilentCollector};
+use std::fs;
+
+#[test]
+fn test_inspect_history_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let profile = dir.path().join("abc.default");
+    fs::create_dir(&profile).unwrap();
+    let good = profile.join("places.sqlite");
+    create_firefox_history(
+        &good,
+        &[
+            ("https://github.com/", "GitHub", 1_642_000_000_000),
+            ("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+        ],
+    );
+    let bad = dir.path().join("bad.sqlite");
+    fs::write(&bad, "not a sqlite file").unwrap();
+    let files = vec![good.clone(), bad];
+    let db_file = dir.path().join("onehistory.db");
+
+    let infos = inspect_history_files(&files, &db_file).unwrap();
+    assert!(!db_file.exists());
+    assert!(infos[0].is_ready());
+    assert_eq!(infos[0].profile, "abc.default");
+    assert_eq!(infos[0].visit_count, Some(2));
+    assert_eq!(infos[0].newest_visit_ms, Some(1_642_000_001_000));
+    assert_eq!(infos[0].last_import_ms, None);
+    assert!(!infos[1].is_ready());
+    match &infos[1].status {
+        FileStatus::Error { message } => assert!(message.starts_with("unknown schema")),
+        FileStatus::Ok => panic!("bad.sqlite should be problematic"),
+    }
+    let json = serde_json::to_value(&infos[1]).unwrap();
+    assert_eq!(json["status"], "error");
+    assert!(json["message"].is_string());
+    let json = serde_json::to_value(&infos[0]).unwrap();
+    assert_eq!(json["status"], "ok");
+    assert_eq!(json["browser"], "Firefox");
+    assert_eq!(json["size_bytes"], fs::metadata(&good).unwrap().len());
+    assert!(json.get("message").is_none());
+
+    backup(
+        files.clone(),
+        &db_file,
+        &BackupOptions::default(),
+        &SilentCollector,
+        &SilentCollector,
+    )
+    .unwrap();
+    let infos = inspect_history_files(&files, &db_file).unwrap();
+    assert_eq!(infos[0].last_import_ms, Some(1_642_000_001_000));
+}
+
+#[test]
+fn test_porcelain() {
+    let dir = tempfile::tempdir().unwrap();
+    let good = dir.path().join("places.sqlite");
+    create_firefox_history(
+        &good,
+        &[("https://github.com/", "GitHub", 1_642_000_000_000)],
+    );
+    let bad = dir.path().join("bad\tname.sqlite");
+    fs::write(&bad, "not a sqlite file").unwrap();
+
+    let infos =
+        inspect_history_files(&[good.clone(), bad], dir.path().join("onehistory.db")).unwrap();
+    let line = infos[0].porcelain();
+    let fields = line.split('\t').collect::<Vec<_>>();
+    assert_eq!(fields.len(), 10);
+    assert_eq!(fields[..2], ["ok", "firefox"]);
+    assert_eq!(
+        fields[4..],
+        [
+            &fs::metadata(&good).unwrap().len().to_string(),
+            "1",
+            "1642000000000",
+            "-",
+            &good.to_string_lossy(),
+            "-"
+        ]
+    );
+
+    let line = infos[1].porcelain();
+    let fields = line.split('\t').collect::<Vec<_>>();
+    assert_eq!(fields.len(), 10);
+    assert_eq!(fields[..2], ["error", "-"]);
+    assert!(fields[8].ends_with("bad\\tname.sqlite"));
+    assert!(fields[9].starts_with("unknown schema"));
+}
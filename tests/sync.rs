@@ -0,0 +1,108 @@
+use flate2::{write::GzEncoder, Compression};
+use onehistory::{
+    export_sync_bundle, import_sync_bundle, Database, SilentCollector, VisitDetail, BUNDLE_VERSION,
+};
+use std::{fs::File, io::Write};
+
+fn visit(url: &str, title: &str, visit_time_ms: i64) -> VisitDetail {
+    VisitDetail {
+        url: url.to_string(),
+        title: title.to_string(),
+        visit_time: visit_time_ms * 1_000 + 123, // PRTime
+        visit_type: 1,
+        source_visit_id: None,
+    }
+}
+
+#[test]
+fn test_sync_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let laptop = Database::open(dir.path().join("laptop.db")).unwrap();
+    let desktop = Database::open(dir.path().join("desktop.db")).unwrap();
+    let bundle = dir.path().join("delta.jsonl.gz");
+
+    laptop
+        .persist(
+            "History",
+            vec![
+                visit("https://github.com/", "GitHub", 1_642_000_000_000),
+                visit("https://emacs-china.org/", "Emacs China", 1_642_000_001_000),
+            ],
+            &SilentCollector,
+        )
+        .unwrap();
+    desktop
+        .persist(
+            "History",
+            vec![visit("https://github.com/", "GitHub", 1_642_000_000_000)],
+            &SilentCollector,
+        )
+        .unwrap();
+
+    let (header, count) = export_sync_bundle(&laptop, "laptop", 0, &bundle).unwrap();
+    assert_eq!(
+        (header.version, header.watermark, count),
+        (BUNDLE_VERSION, 2, 2)
+    );
+    let import = import_sync_bundle(&desktop, &bundle, &SilentCollector).unwrap();
+    assert_eq!(
+        (
+            import.peer.as_str(),
+            import.imported,
+            import.duplicated,
+            import.watermark
+        ),
+        ("laptop", 1, 1, 2)
+    );
+
+    // Applying same bundle twice changes nothing
+    let import = import_sync_bundle(&desktop, &bundle, &SilentCollector).unwrap();
+    assert_eq!((import.imported, import.duplicated), (0, 2));
+    assert_eq!(
+        desktop
+            .count_url_visits("https://emacs-china.org/")
+            .unwrap(),
+        1
+    );
+
+    // Only visits after watermark are exported
+    laptop
+        .persist(
+            "History",
+            vec![visit("https://github.com/", "GitHub", 1_642_000_002_000)],
+            &SilentCollector,
+        )
+        .unwrap();
+    let (header, count) = export_sync_bundle(&laptop, "laptop", 2, &bundle).unwrap();
+    assert_eq!((header.since, header.watermark, count), (2, 3, 1));
+    let import = import_sync_bundle(&desktop, &bundle, &SilentCollector).unwrap();
+    assert_eq!(
+        (import.imported, import.duplicated, import.watermark),
+        (1, 0, 3)
+    );
+    assert_eq!(desktop.count_url_visits("https://github.com/").unwrap(), 2);
+
+    // Watermark never goes backwards
+    desktop.update_sync_watermark("laptop", 1).unwrap();
+    assert_eq!(desktop.select_sync_peers().unwrap()[0].0.as_str(), "laptop");
+    assert_eq!(desktop.select_sync_peers().unwrap()[0].1, 3);
+}
+
+#[test]
+fn test_sync_reject_unknown_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("onehistory.db")).unwrap();
+    let bundle = dir.path().join("delta.jsonl.gz");
+    let mut w = GzEncoder::new(File::create(&bundle).unwrap(), Compression::default());
+    writeln!(
+        w,
+        r#"{{"format":"onehistory-sync","version":{},"peer":"future","since":0,"watermark":1,"exported_at":0}}"#,
+        BUNDLE_VERSION + 1
+    )
+    .unwrap();
+    w.finish().unwrap();
+
+    let err = import_sync_bundle(&db, &bundle, &SilentCollector).unwrap_err();
+    assert!(err.to_string().contains("not supported"), "{err}");
+    assert!(db.select_sync_peers().unwrap().is_empty());
+}